@@ -38,6 +38,17 @@ macro_rules! binary_calc {
 	}
 }
 
+macro_rules! ternary_calc {
+	($name: ident, $operator: expr, $calc: expr) => {
+		pub struct $name { }
+		impl Calc for $name {
+			fn operator() -> &'static str { $operator }
+			fn arg_count() -> i32 { 3 }
+			fn calc(args: &Vec<Sample>) -> Sample { $calc(args[0], args[1], args[2]) }
+		}
+	}
+}
+
  ////
 //// arithmetic operations
 
@@ -84,3 +95,44 @@ unary_calc!(FloorCalc, "floor", |arg: Sample| arg.floor());
 unary_calc!(CeilCalc, "ceil", |arg: Sample| arg.ceil());
 unary_calc!(RoundCalc, "round", |arg: Sample| arg.round());
 unary_calc!(TruncCalc, "trunc", |arg: Sample| arg.trunc());
+unary_calc!(SqrtCalc, "sqrt", |arg: Sample| arg.sqrt());
+unary_calc!(ExpCalc, "exp", |arg: Sample| arg.exp());
+binary_calc!(MinCalc, "min", |lhs: Sample, rhs| lhs.min(rhs));
+binary_calc!(MaxCalc, "max", |lhs: Sample, rhs| lhs.max(rhs));
+// 演算子 ^ と同じ計算だが、to_string で関数呼び出しの形になるよう別に定義する
+binary_calc!(PowFuncCalc, "pow", |lhs: Sample, rhs| lhs.powf(rhs));
+binary_calc!(Atan2Calc, "atan2", |y: Sample, x| y.atan2(x));
+binary_calc!(HypotCalc, "hypot", |x: Sample, y| x.hypot(y));
+// % と異なり、結果は常に rhs と同じ符号になる（負の数を周期的に扱うのに使う）
+binary_calc!(ModCalc, "mod", |lhs: Sample, rhs: Sample| lhs - rhs * (lhs / rhs).floor());
+ternary_calc!(ClampCalc, "clamp", |arg: Sample, min, max| arg.max(min).min(max));
+ternary_calc!(LerpCalc, "lerp", |from: Sample, to: Sample, rate: Sample| from + (to - from) * rate);
+
+ ////
+//// music helpers
+
+unary_calc!(DbToAmpCalc, "dbToAmp", |db: Sample| 10f32.powf(db / 20f32));
+unary_calc!(AmpToDbCalc, "ampToDb", |amp: Sample| 20f32 * amp.log10());
+// ノート番号 69 を A4 = 440 Hz とする 12 平均律
+unary_calc!(NoteToFreqCalc, "noteToFreq", |note: Sample| 440f32 * 2f32.powf((note - 69f32) / 12f32));
+unary_calc!(FreqToNoteCalc, "freqToNote", |freq: Sample| 69f32 + 12f32 * (freq / 440f32).log2());
+unary_calc!(CentsToRatioCalc, "centsToRatio", |cents: Sample| 2f32.powf(cents / 1200f32));
+// 1 拍（四分音符）の秒数
+unary_calc!(BpmToSecondsCalc, "bpmToSeconds", |bpm: Sample| 60f32 / bpm);
+
+#[cfg(test)]
+#[test]
+fn test_music_helpers() {
+	let calc1 = |calc: fn (&Vec<Sample>) -> Sample, arg: Sample| calc(&vec![arg]);
+	let assert_near = |actual: Sample, expected: Sample| assert!((actual - expected).abs() < 1e-3, "{} != {}", actual, expected);
+
+	assert_near(calc1(DbToAmpCalc::calc, -6f32), 0.501);
+	assert_near(calc1(AmpToDbCalc::calc, calc1(DbToAmpCalc::calc, -12f32)), -12f32);
+	assert_near(calc1(NoteToFreqCalc::calc, 60f32), 261.626);
+	assert_near(calc1(FreqToNoteCalc::calc, 880f32), 81f32);
+	assert_near(calc1(CentsToRatioCalc::calc, 1200f32), 2f32);
+	assert_near(calc1(BpmToSecondsCalc::calc, 120f32), 0.5);
+	assert_near(ModCalc::calc(&vec![-1f32, 12f32]), 11f32);
+	assert_near(ClampCalc::calc(&vec![1.5, -1f32, 1f32]), 1f32);
+	assert_near(LerpCalc::calc(&vec![100f32, 200f32, 0.25]), 125f32);
+}
@@ -4,5 +4,6 @@
 // #[macro_use]
 // pub mod parser;
 
+pub mod seed;
 pub mod stack;
 pub mod util;
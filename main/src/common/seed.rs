@@ -0,0 +1,33 @@
+/// 乱数の種。演奏全体で 1 つの種を持ち、乱数を使う箇所ごとにキーから個別の種を導出する。
+/// キーが同じなら実行ごとやスレッドの構成によらず同じ種になるので、同じ種からは同じ音が生成される
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Seed(pub u64);
+impl Seed {
+	/// 種が指定されなかった場合に使う
+	pub fn from_entropy() -> Self {
+		Self(rand::random())
+	}
+
+	pub fn derive(&self, key: &str) -> u64 {
+		// 標準ライブラリのハッシュは Rust のバージョンによって変わりうるので、FNV-1a で自前で計算する
+		let hash = key.bytes().fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+		mix(self.0 ^ mix(hash))
+	}
+}
+
+/// SplitMix64 の撹拌関数。近い値からでも互いに無相関な値を得る
+fn mix(value: u64) -> u64 {
+	let mut z = value.wrapping_add(0x9e3779b97f4a7c15);
+	z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+	z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+	z ^ (z >> 31)
+}
+
+#[cfg(test)]
+#[test]
+fn test_seed() {
+	let seed = Seed(1);
+	assert_eq!(seed.derive("a/0"), Seed(1).derive("a/0"));
+	assert_ne!(seed.derive("a/0"), seed.derive("a/1"));
+	assert_ne!(seed.derive("a/0"), Seed(2).derive("a/0"));
+}
@@ -30,6 +30,8 @@ pub trait Node: Send {
 	fn update(&mut self, _inputs: &Vec<Sample>, _context: &Context, _env: &mut Environment) { }
 	fn finalize(&mut self, _context: &Context, _env: &mut Environment) { }
 	fn process_event(&mut self, _event: &dyn Event, _context: &Context, _env: &mut Environment) { }
+	/// 乱数を使うノードに種を与える。演奏を始める前に一度だけ呼ばれる
+	fn set_seed(&mut self, _seed: u64) { }
 
 	// 以下は node_impl 属性によって自動実装されるため実装不要
 	fn implements_execute(&self) -> bool;
@@ -40,6 +40,8 @@ pub type NodeArgs = HashMap<String, ChanneledNodeIndex>;
 pub trait NodeFactory {
 	fn node_arg_specs(&self) -> Vec<NodeArgSpec>;
 	fn input_channels(&self) -> i32;
+	/// 生成するノードの出力のチャンネル数。ノードを生成する前の検査に使う
+	fn output_channels(&self) -> i32 { 1 }
 	fn default_prop_key(&self) -> Option<String> { None }
 	fn initial_values(&self) -> HashMap<String, Sample> { HashMap::new() }
 	/// piped_upstream は接続の前段となっているノード
@@ -50,6 +52,7 @@ pub struct PanFactory { }
 impl NodeFactory for PanFactory {
 	fn node_arg_specs(&self) -> Vec<NodeArgSpec> { vec![spec("pos", 1)] }
 	fn input_channels(&self) -> i32 { 1 }
+	fn output_channels(&self) -> i32 { 2 }
 	fn create_node(&self, node_args: &NodeArgs, piped_upstream: ChanneledNodeIndex) -> Box<dyn Node> {
 		let input = piped_upstream.as_mono();
 		let pan = node_args.get("pos").unwrap().as_mono();
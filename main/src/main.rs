@@ -9,6 +9,7 @@ mod common;
 
 mod calc;
 mod core;
+mod midi;
 mod mml;
 mod moddl;
 mod node;
@@ -16,13 +17,27 @@ mod seq;
 mod vis;
 mod wave;
 
-use crate::moddl::{
-	player,
-	player_option::*,
+use crate::{
+	midi::{
+		smf::read_smf,
+		to_mml::*,
+	},
+	moddl::{
+		common::read_file,
+		error::*,
+		lambda_function::EVALUATOR_STACK_SIZE,
+		player,
+		player_option::*,
+		repl,
+	},
+};
+use parser::{
+	common::Location,
+	moddl::formatter::format_moddl,
 };
 
 use std::{
-	env, process::exit, thread,
+	collections::hash_map::HashMap, env, path::{Path, PathBuf}, process::exit, rc::Rc, thread,
 };
 
 // パーザを切り出したがエラーを参照するため必要
@@ -34,15 +49,143 @@ fn main() {
 			eprintln!("Please specify a moddl file path.");
 			exit(1);
 		}
+		Some(option) if option == "--midi2mml" => {
+			match env::args().nth(2) {
+				None => {
+					eprintln!("Please specify a MIDI file path.");
+					exit(1);
+				}
+				Some(midi_path) => print_midi_as_mml(&midi_path),
+			}
+		}
+		Some(option) if option == "--fmt" => {
+			match env::args().nth(2) {
+				None => {
+					eprintln!("Please specify a moddl file path.");
+					exit(1);
+				}
+				Some(moddl_path) => print_formatted_moddl(&moddl_path),
+			}
+		}
+		Some(option) if option == "--repl" => {
+			// 演奏と同じく、スタックを大きく取ったスレッドで動かす
+			let result = thread::Builder::new()
+					.stack_size(EVALUATOR_STACK_SIZE)
+					.spawn(|| repl::run_repl().map_err(|e| format!("error: {}: {}", e.loc, e.body)))
+					.unwrap()
+					.join()
+					.unwrap();
+			if let Err(message) = result {
+				eprintln!("{}", message);
+				exit(1);
+			}
+		}
 		Some(moddl_path) => {
-			if let Err(e) = player::play(&PlayerOptions {
+			let mut start_marker = None;
+			let mut loop_markers = None;
+			let mut timeline_path = None;
+			let mut midi_path = None;
+			let mut seed = None;
+			let mut args = env::args().skip(2);
+			while let Some(arg) = args.next() {
+				match (arg.as_str(), args.next()) {
+					("--from", Some(marker)) => { start_marker = Some(marker); },
+					("--loop", Some(markers)) => {
+						match markers.split_once(':') {
+							Some((loop_start, loop_end)) => { loop_markers = Some((loop_start.to_string(), loop_end.to_string())); },
+							None => {
+								eprintln!("--loop must be given as <start marker>:<end marker>.");
+								exit(1);
+							},
+						}
+					},
+					("--timeline", Some(path)) => { timeline_path = Some(path); },
+					("--midi", Some(path)) => { midi_path = Some(path); },
+					("--seed", Some(value)) => {
+						match value.parse::<u64>() {
+							Ok(value) => { seed = Some(value); },
+							Err(_) => {
+								eprintln!("--seed must be a non-negative integer.");
+								exit(1);
+							},
+						}
+					},
+					_ => {
+						eprintln!("Bad option: {}", arg);
+						exit(1);
+					},
+				}
+			}
+			let options = PlayerOptions {
 				moddl_path,
 				// output: PlayerOutput::Wav { path: "out.wav".to_string() },
 				output: PlayerOutput::Audio,
-			}) {
-				println!("error: {}: {}", e.loc, e.body);
+				start_marker,
+				loop_markers,
+				timeline_path,
+				midi_path,
+				seed,
+			};
+			// ModDL の関数の再帰呼び出しは評価器の再帰になるので、スタックを大きく取ったスレッドで演奏する
+			let result = thread::Builder::new()
+					.stack_size(EVALUATOR_STACK_SIZE)
+					.spawn(move || player::play(&options).map_err(|e| format!("error: {}: {}", e.loc, e.body)))
+					.unwrap()
+					.join()
+					.unwrap();
+			if let Err(message) = result {
+				println!("{}", message);
 				exit(1);
 			}
 		}
 	}
 }
+
+/// MML のトラック名に使える文字
+const TRACK_NAMES: &str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789_";
+
+/// MIDI ファイルをチャンネルごとの MML に変換して表示する。トラック名は a から順に割り当てる。
+/// 量子化しないよう、全音符のティック数は MIDI ファイルの分解能に合わせる
+fn print_midi_as_mml(midi_path: &str) {
+	let smf = match std::fs::read(midi_path).map_err(|e| e.to_string()).and_then(|bytes| read_smf(&bytes)) {
+		Ok(smf) => smf,
+		Err(e) => {
+			eprintln!("error: {}: {}", midi_path, e);
+			exit(1);
+		}
+	};
+	let ticks_per_bar = 4 * smf.division.max(1) as i32;
+	let options = MidiImportOptions {
+		ticks_per_bar,
+		kind: MidiSourceKind::Channel,
+		sources: None,
+		cc_map: HashMap::new(),
+	};
+	let imported = smf_to_mml(&smf, &options);
+	if imported.len() > TRACK_NAMES.len() {
+		eprintln!("error: {}: {} MIDI sources found but only {} track names are available", midi_path, imported.len(), TRACK_NAMES.len());
+		exit(1);
+	}
+	println!("@ticksPerBar {}", ticks_per_bar);
+	for (track, ImportedMml { source, mml, polyphonic }) in TRACK_NAMES.chars().zip(imported) {
+		println!("// channel {}{}", source, if polyphonic { " (needs a polyphonic instrument)" } else { "" });
+		for line in mml.lines() {
+			println!("{} {}", track, line);
+		}
+	}
+}
+
+/// moddl ファイルを整形して表示する
+fn print_formatted_moddl(moddl_path: &str) {
+	let result = read_file(Path::new(moddl_path)).and_then(|moddl| {
+		format_moddl(moddl.as_str(), Rc::new(PathBuf::from(moddl_path)))
+		.map_err(|e| error(ErrorType::Syntax(nom_error_to_owned(e)), Location::file(Path::new(moddl_path))))
+	});
+	match result {
+		Ok(formatted) => print!("{}", formatted),
+		Err(e) => {
+			eprintln!("error: {}: {}", e.loc, e.body);
+			exit(1);
+		}
+	}
+}
@@ -0,0 +1,2 @@
+pub mod smf;
+pub mod to_mml;
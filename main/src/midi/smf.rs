@@ -0,0 +1,302 @@
+/// ノート番号やベロシティ、コントロールチェンジの値の最大値
+pub const MAX_MIDI_VALUE: f32 = 127f32;
+
+/// 読み込んだ Standard MIDI File。時間は SMF のティックのまま持つ
+#[derive(Debug)]
+pub struct Smf {
+	pub format: u16,
+	/// 4 分音符あたりのティック数
+	pub division: u16,
+	pub tracks: Vec<Vec<SmfEvent>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SmfEvent {
+	/// トラックの先頭からのティック数
+	pub tick: u32,
+	pub body: SmfEventBody,
+}
+
+/// 変換に使うイベントだけを区別し、それ以外は Other にまとめる
+#[derive(Clone, Debug, PartialEq)]
+pub enum SmfEventBody {
+	/// ベロシティ 0 のノートオンはノートオフとして読む
+	NoteOn { channel: u8, key: u8, velocity: u8 },
+	NoteOff { channel: u8, key: u8 },
+	ControlChange { channel: u8, controller: u8, value: u8 },
+	Tempo { micros_per_quarter: u32 },
+	TimeSignature { numerator: u8, denominator: u8 },
+	TrackName { name: String },
+	Other,
+}
+
+/// SMF を読み込む。エラーの場合はその内容を返す
+pub fn read_smf(bytes: &[u8]) -> Result<Smf, String> {
+	let mut reader = Reader { bytes, pos: 0 };
+
+	let (id, header) = reader.chunk() ?;
+	if id != b"MThd" || header.len() < 6 {
+		return Err("not a standard MIDI file".to_string());
+	}
+	let format = u16::from_be_bytes([header[0], header[1]]);
+	let track_count = u16::from_be_bytes([header[2], header[3]]);
+	let division = u16::from_be_bytes([header[4], header[5]]);
+	if division & 0x8000 != 0 {
+		return Err("SMPTE time division is not supported".to_string());
+	}
+
+	let mut tracks = vec![];
+	while tracks.len() < track_count as usize && ! reader.is_end() {
+		let (id, body) = reader.chunk() ?;
+		// 未知のチャンクは読み飛ばす
+		if id == b"MTrk" {
+			tracks.push(read_track(body) ?);
+		}
+	}
+
+	Ok(Smf { format, division, tracks })
+}
+
+fn read_track(bytes: &[u8]) -> Result<Vec<SmfEvent>, String> {
+	let mut reader = Reader { bytes, pos: 0 };
+	let mut events = vec![];
+	let mut tick = 0u32;
+	let mut running_status = None;
+
+	while ! reader.is_end() {
+		tick = tick.checked_add(reader.variable_length() ?).ok_or_else(|| "track too long".to_string()) ?;
+		let mut status = reader.byte() ?;
+		let body = match status {
+			0xff => {
+				// メタイベントと SysEx の後はランニングステータスを使えない
+				running_status = None;
+				let meta_type = reader.byte() ?;
+				let length = reader.variable_length() ?;
+				let data = reader.bytes(length as usize) ?;
+				match meta_type {
+					// トラックの終わり
+					0x2f => break,
+					0x51 if data.len() >= 3 => {
+						let micros_per_quarter = u32::from_be_bytes([0, data[0], data[1], data[2]]);
+						// テンポが無限大になってしまう
+						if micros_per_quarter == 0 {
+							return Err("tempo of 0 microseconds per quarter note".to_string());
+						}
+						SmfEventBody::Tempo { micros_per_quarter }
+					},
+					0x58 if data.len() >= 2 => SmfEventBody::TimeSignature {
+						numerator: data[0],
+						denominator: 1u8.checked_shl(data[1] as u32).unwrap_or(0),
+					},
+					0x03 => SmfEventBody::TrackName { name: String::from_utf8_lossy(data).to_string() },
+					_ => SmfEventBody::Other,
+				}
+			},
+			0xf0 | 0xf7 => {
+				running_status = None;
+				let length = reader.variable_length() ?;
+				reader.bytes(length as usize) ?;
+				SmfEventBody::Other
+			},
+			_ => {
+				// ステータスバイトが省略されていれば直前のものを使う
+				if status < 0x80 {
+					reader.pos -= 1;
+					status = running_status.ok_or_else(|| "data byte without status".to_string()) ?;
+				}
+				running_status = Some(status);
+				let channel = status & 0x0f;
+				match status & 0xf0 {
+					0x80 => {
+						let key = reader.byte() ?;
+						reader.byte() ?;
+						SmfEventBody::NoteOff { channel, key }
+					},
+					0x90 => {
+						let key = reader.byte() ?;
+						let velocity = reader.byte() ?;
+						if velocity == 0 {
+							SmfEventBody::NoteOff { channel, key }
+						} else {
+							SmfEventBody::NoteOn { channel, key, velocity }
+						}
+					},
+					0xb0 => {
+						let controller = reader.byte() ?;
+						let value = reader.byte() ?;
+						SmfEventBody::ControlChange { channel, controller, value }
+					},
+					0xa0 | 0xe0 => {
+						reader.bytes(2) ?;
+						SmfEventBody::Other
+					},
+					0xc0 | 0xd0 => {
+						reader.byte() ?;
+						SmfEventBody::Other
+					},
+					_ => return Err(format!("unknown status byte: {:#04x}", status)),
+				}
+			},
+		};
+		events.push(SmfEvent { tick, body });
+	}
+
+	Ok(events)
+}
+
+/// SMF を書き出す。各トラックのイベントはティック順に並んでいる必要がある。
+/// トラックの終わりは自動的に加え、Other は書き出さない
+pub fn write_smf(smf: &Smf) -> Vec<u8> {
+	let mut result = vec![];
+	write_chunk(&mut result, b"MThd", &[
+		smf.format.to_be_bytes(),
+		(smf.tracks.len() as u16).to_be_bytes(),
+		smf.division.to_be_bytes(),
+	].concat());
+
+	for track in &smf.tracks {
+		let mut body = vec![];
+		let mut prev_tick = 0;
+		for SmfEvent { tick, body: event } in track {
+			let data = match event {
+				SmfEventBody::NoteOn { channel, key, velocity } => vec![0x90 | channel, *key, *velocity],
+				SmfEventBody::NoteOff { channel, key } => vec![0x80 | channel, *key, 0],
+				SmfEventBody::ControlChange { channel, controller, value } => vec![0xb0 | channel, *controller, *value],
+				SmfEventBody::Tempo { micros_per_quarter } => [&[0xff, 0x51, 0x03], &micros_per_quarter.to_be_bytes()[1 ..]].concat(),
+				SmfEventBody::TimeSignature { numerator, denominator } => {
+					// 分母は 2 の冪として書く。メトロノームの設定は 4 分音符ごととする
+					vec![0xff, 0x58, 0x04, *numerator, denominator.trailing_zeros() as u8, 24, 8]
+				},
+				SmfEventBody::TrackName { name } => [&[0xff, 0x03], variable_length(name.len() as u32).as_slice(), name.as_bytes()].concat(),
+				SmfEventBody::Other => continue,
+			};
+			body.extend(variable_length(tick - prev_tick));
+			body.extend(data);
+			prev_tick = *tick;
+		}
+		body.extend([0x00, 0xff, 0x2f, 0x00]);
+		write_chunk(&mut result, b"MTrk", &body);
+	}
+
+	result
+}
+
+fn write_chunk(result: &mut Vec<u8>, id: &[u8], body: &[u8]) {
+	result.extend(id);
+	result.extend((body.len() as u32).to_be_bytes());
+	result.extend(body);
+}
+
+fn variable_length(value: u32) -> Vec<u8> {
+	let mut result = vec![(value & 0x7f) as u8];
+	let mut rest = value >> 7;
+	while rest > 0 {
+		result.insert(0, (rest & 0x7f) as u8 | 0x80);
+		rest >>= 7;
+	}
+	result
+}
+
+struct Reader<'a> {
+	bytes: &'a [u8],
+	pos: usize,
+}
+impl<'a> Reader<'a> {
+	fn is_end(&self) -> bool { self.pos >= self.bytes.len() }
+
+	fn byte(&mut self) -> Result<u8, String> {
+		Ok(self.bytes(1)?[0])
+	}
+
+	fn bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+		if self.pos + length > self.bytes.len() {
+			return Err("unexpected end of file".to_string());
+		}
+		let result = &self.bytes[self.pos .. self.pos + length];
+		self.pos += length;
+		Ok(result)
+	}
+
+	fn variable_length(&mut self) -> Result<u32, String> {
+		let mut result = 0u32;
+		for _ in 0 .. 4 {
+			let byte = self.byte() ?;
+			result = (result << 7) | (byte & 0x7f) as u32;
+			if byte & 0x80 == 0 { return Ok(result); }
+		}
+		Err("variable length quantity too long".to_string())
+	}
+
+	fn chunk(&mut self) -> Result<(&'a [u8], &'a [u8]), String> {
+		let id = self.bytes(4) ?;
+		let length = self.bytes(4) ?;
+		let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]);
+		let body = self.bytes(length as usize) ?;
+		Ok((id, body))
+	}
+}
+
+#[cfg(test)]
+#[test]
+fn test_read_smf() {
+	let bytes = [
+		b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 96,
+		b'M', b'T', b'r', b'k', 0, 0, 0, 22,
+		0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20, // テンポ 120
+		0x00, 0x90, 60, 100,
+		0x60, 62, 80, // ランニングステータス
+		0x00, 0x80, 60, 0,
+		0x00, 0xff, 0x2f, 0x00,
+	];
+	let smf = read_smf(&bytes).unwrap();
+	assert_eq!(smf.division, 96);
+	assert_eq!(smf.tracks[0], vec![
+		SmfEvent { tick: 0, body: SmfEventBody::Tempo { micros_per_quarter: 500000 } },
+		SmfEvent { tick: 0, body: SmfEventBody::NoteOn { channel: 0, key: 60, velocity: 100 } },
+		SmfEvent { tick: 96, body: SmfEventBody::NoteOn { channel: 0, key: 62, velocity: 80 } },
+		SmfEvent { tick: 96, body: SmfEventBody::NoteOff { channel: 0, key: 60 } },
+	]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_write_smf() {
+	let smf = Smf {
+		format: 1,
+		division: 480,
+		tracks: vec![
+			vec![
+				SmfEvent { tick: 0, body: SmfEventBody::Tempo { micros_per_quarter: 500000 } },
+				SmfEvent { tick: 0, body: SmfEventBody::TimeSignature { numerator: 3, denominator: 4 } },
+			],
+			vec![
+				SmfEvent { tick: 0, body: SmfEventBody::TrackName { name: "a".to_string() } },
+				SmfEvent { tick: 0, body: SmfEventBody::NoteOn { channel: 1, key: 60, velocity: 100 } },
+				SmfEvent { tick: 200, body: SmfEventBody::ControlChange { channel: 1, controller: 7, value: 64 } },
+				SmfEvent { tick: 480, body: SmfEventBody::NoteOff { channel: 1, key: 60 } },
+			],
+		],
+	};
+	let written = read_smf(&write_smf(&smf)).unwrap();
+	assert_eq!(written.format, 1);
+	assert_eq!(written.division, 480);
+	assert_eq!(written.tracks, smf.tracks);
+}
+
+#[cfg(test)]
+#[test]
+fn test_read_track_errors() {
+	// メタイベントの後のランニングステータス
+	assert!(read_track(&[0x00, 0x90, 60, 100, 0x00, 0xff, 0x01, 0x00, 0x00, 62, 80]).is_err());
+	// SysEx の後のランニングステータス
+	assert!(read_track(&[0x00, 0x90, 60, 100, 0x00, 0xf0, 0x01, 0xf7, 0x00, 62, 80]).is_err());
+	// ティック数があふれる
+	let mut bytes = vec![];
+	for _ in 0 .. 17 {
+		bytes.extend([0xff, 0xff, 0xff, 0x7f, 0xff, 0x01, 0x00]);
+	}
+	assert!(read_track(&bytes).is_err());
+	// 0 のテンポ
+	assert!(read_track(&[0x00, 0xff, 0x51, 0x03, 0x00, 0x00, 0x00]).is_err());
+	assert!(read_track(&[0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20]).is_ok());
+}
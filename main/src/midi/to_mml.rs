@@ -0,0 +1,286 @@
+use super::smf::*;
+use crate::seq::{
+	tempo_map::TempoMap,
+	timeline::Timeline,
+};
+
+use std::collections::{
+	btree_map::BTreeMap,
+	btree_set::BTreeSet,
+	hash_map::HashMap,
+};
+
+/// MIDI のチャンネルとトラックのどちらを単位として MML にするか
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MidiSourceKind {
+	Channel,
+	Track,
+}
+
+/// コントロールチェンジをパラメータに割り当てる。0 〜 127 の値を min 〜 max に変換する
+#[derive(Clone, Debug)]
+pub struct CcMapping {
+	pub label: String,
+	pub min: f32,
+	pub max: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct MidiImportOptions {
+	pub ticks_per_bar: i32,
+	pub kind: MidiSourceKind,
+	/// 変換するチャンネル（1 始まり）またはトラック（0 始まり）。None なら音符のあるもの全て
+	pub sources: Option<Vec<usize>>,
+	pub cc_map: HashMap<u8, CcMapping>,
+}
+
+/// 変換した MML。小節ごとに改行する
+#[derive(Debug)]
+pub struct ImportedMml {
+	/// チャンネル（1 始まり）またはトラック（0 始まり）
+	pub source: usize,
+	pub mml: String,
+	/// 和音を含むか（ポリフォニックなトラックでないと演奏できない）
+	pub polyphonic: bool,
+}
+
+const MAX_VELOCITY: f32 = 15f32;
+
+/// SMF を既定の MML に変換する。
+/// テンポと拍子は最初の MML にだけ書くが、小節線は全ての MML に入れる
+pub fn smf_to_mml(smf: &Smf, options: &MidiImportOptions) -> Vec<ImportedMml> {
+	let ticks_per_quarter = smf.division.max(1) as f64;
+	let to_ticks = |tick: u32| (tick as f64 * options.ticks_per_bar as f64 / 4f64 / ticks_per_quarter).round() as i32;
+
+	let mut conductor = Timeline::default();
+	let mut sources = BTreeMap::<usize, Vec<(i32, &SmfEventBody)>>::new();
+	for (track_idx, track) in smf.tracks.iter().enumerate() {
+		for SmfEvent { tick, body } in track {
+			let tick = to_ticks(*tick);
+			let channel = match body {
+				// 0 のテンポは読み込み時に弾いているが、念のため無視する
+				SmfEventBody::Tempo { micros_per_quarter } if *micros_per_quarter > 0 => {
+					conductor.tempo_changes.push((tick, 60_000_000f32 / *micros_per_quarter as f32));
+					continue;
+				},
+				SmfEventBody::TimeSignature { numerator, denominator } if *numerator > 0 && *denominator > 0 => {
+					conductor.meters.push((tick, (*numerator as i32, *denominator as i32)));
+					continue;
+				},
+				SmfEventBody::NoteOn { channel, .. } => channel,
+				SmfEventBody::NoteOff { channel, .. } => channel,
+				SmfEventBody::ControlChange { channel, .. } => channel,
+				_ => continue,
+			};
+			let source = match options.kind {
+				MidiSourceKind::Channel => *channel as usize + 1,
+				MidiSourceKind::Track => track_idx,
+			};
+			sources.entry(source).or_default().push((tick, body));
+		}
+	}
+	conductor.tempo_changes.sort_by_key(|(tick, _)| *tick);
+	conductor.meters.sort_by_key(|(tick, _)| *tick);
+	let tempo_map = TempoMap::new(options.ticks_per_bar, 120f32, std::iter::once(&conductor));
+
+	let selected = options.sources.clone().unwrap_or_else(|| sources.iter()
+			.filter(|(_, events)| events.iter().any(|(_, body)| matches!(body, SmfEventBody::NoteOn { .. })))
+			.map(|(source, _)| *source)
+			.collect());
+	selected.iter().enumerate().map(|(i, source)| {
+		let mut events = sources.remove(source).unwrap_or_default();
+		// チャンネル単位の場合は複数のトラックから集めるので、時間順に並べ直す
+		events.sort_by_key(|(tick, _)| *tick);
+		let conductor = if i == 0 { Some(&conductor) } else { None };
+		let (mml, polyphonic) = events_to_mml(&events, conductor, &tempo_map, options);
+		ImportedMml { source: *source, mml, polyphonic }
+	}).collect()
+}
+
+struct Note {
+	start: i32,
+	end: i32,
+	key: i32,
+	velocity: u8,
+}
+
+fn events_to_mml(events: &[(i32, &SmfEventBody)], conductor: Option<&Timeline>, tempo_map: &TempoMap, options: &MidiImportOptions) -> (String, bool) {
+	let mut notes = vec![];
+	let mut params = vec![];
+	let mut note_ons = HashMap::<(u8, u8), Vec<(i32, u8)>>::new();
+	let mut end = 0;
+	for (tick, body) in events {
+		end = end.max(*tick);
+		match body {
+			SmfEventBody::NoteOn { channel, key, velocity } => {
+				note_ons.entry((*channel, *key)).or_default().push((*tick, *velocity));
+			},
+			SmfEventBody::NoteOff { channel, key } => {
+				// 同じキーが重なっていれば先に鳴らしたものから止める
+				if let Some(ons) = note_ons.get_mut(&(*channel, *key)) {
+					if ! ons.is_empty() {
+						let (start, velocity) = ons.remove(0);
+						notes.push(Note { start, end: *tick, key: *key as i32, velocity });
+					}
+				}
+			},
+			SmfEventBody::ControlChange { controller, value, .. } => {
+				if let Some(CcMapping { label, min, max }) = options.cc_map.get(controller) {
+					let value = min + (max - min) * *value as f32 / MAX_MIDI_VALUE;
+					params.push((*tick, format!("y{},{} ", label, format_number(value))));
+				}
+			},
+			_ => { },
+		}
+	}
+	// 止めていないノートは最後まで伸ばす
+	for ((_, key), ons) in note_ons {
+		for (start, velocity) in ons {
+			notes.push(Note { start, end, key: key as i32, velocity });
+		}
+	}
+	// 量子化で長さが 0 になったノートは捨てる
+	notes.retain(|note| note.end > note.start);
+	if let Some(conductor) = conductor {
+		for (tick, tempo) in &conductor.tempo_changes {
+			end = end.max(*tick);
+			params.push((*tick, format!("t{} ", format_number(*tempo))));
+		}
+	}
+	params.sort_by_key(|(tick, _)| *tick);
+
+	// 最後の小節の終わりまでを変換する
+	let bar_count = if end > 0 { tempo_map.bar_at(end - 1).0 } else { 0 };
+	let end = tempo_map.bar_start(bar_count + 1);
+	let bar_starts: BTreeSet<i32> = (2 ..= bar_count + 1).map(|bar| tempo_map.bar_start(bar)).collect();
+
+	let mut boundaries = BTreeSet::new();
+	boundaries.insert(0);
+	boundaries.extend(bar_starts.iter().copied());
+	boundaries.extend(notes.iter().flat_map(|note| [note.start, note.end]));
+	boundaries.extend(params.iter().map(|(tick, _)| *tick));
+	let boundaries: Vec<_> = boundaries.into_iter().filter(|tick| *tick <= end).collect();
+
+	// 前に書いた MML の調号や音長の設定に影響されないようにしておく
+	let mut result = "k0 q8 ".to_string();
+	let mut polyphonic = false;
+	let mut octave = None;
+	let mut velocity = None;
+	for (i, tick) in boundaries.iter().enumerate() {
+		if bar_starts.contains(tick) {
+			result.push_str("|\n");
+		}
+		if let Some(conductor) = conductor {
+			for (_, (numerator, denominator)) in conductor.meters.iter().filter(|(t, _)| t == tick) {
+				result.push_str(format!("M{}/{} ", numerator, denominator).as_str());
+			}
+		}
+		for (_, param) in params.iter().filter(|(t, _)| t == tick) {
+			result.push_str(param);
+		}
+		let next = match boundaries.get(i + 1) {
+			Some(next) => *next,
+			None => break,
+		};
+
+		let mut sounding: Vec<&Note> = notes.iter().filter(|note| note.start <= *tick && *tick < note.end).collect();
+		sounding.sort_by_key(|note| note.key);
+		sounding.dedup_by_key(|note| note.key);
+		if let Some(new_velocity) = sounding.iter().filter(|note| note.start == *tick).map(|note| note.velocity).max() {
+			if velocity != Some(new_velocity) {
+				let value = new_velocity as f32 * MAX_VELOCITY / MAX_MIDI_VALUE;
+				result.push_str(format!("v{} ", format_number(value)).as_str());
+				velocity = Some(new_velocity);
+			}
+		}
+		let length = format_length(next - tick, options.ticks_per_bar);
+		// 次の区間にも続くノートがあれば、スラーでつなぐ
+		let slur = if sounding.iter().any(|note| note.end > next) { "&" } else { "" };
+		match sounding.as_slice() {
+			[] => { result.push_str(format!("r{} ", length).as_str()); },
+			[note] => {
+				let note_octave = note.key / 12 - 1;
+				if octave != Some(note_octave) {
+					result.push_str(format!("o{} ", note_octave).as_str());
+					octave = Some(note_octave);
+				}
+				result.push_str(format!("{}{}{} ", tone_name(note.key), length, slur).as_str());
+			},
+			_ => {
+				polyphonic = true;
+				// 和音の外のオクターブは最も低い音に合わせ、和音の中では > で上げていく
+				let lowest_octave = sounding[0].key / 12 - 1;
+				if octave != Some(lowest_octave) {
+					result.push_str(format!("o{} ", lowest_octave).as_str());
+					octave = Some(lowest_octave);
+				}
+				let mut chord = "'".to_string();
+				let mut chord_octave = lowest_octave;
+				for note in &sounding {
+					let note_octave = note.key / 12 - 1;
+					for _ in chord_octave .. note_octave { chord.push('>'); }
+					chord_octave = note_octave;
+					chord.push_str(tone_name(note.key));
+				}
+				result.push_str(format!("{}'{}{} ", chord, length, slur).as_str());
+			},
+		}
+	}
+
+	(result.trim_end().to_string(), polyphonic)
+}
+
+fn tone_name(key: i32) -> &'static str {
+	["c", "c+", "d", "d+", "e", "f", "f+", "g", "g+", "a", "a+", "b"][key.rem_euclid(12) as usize]
+}
+
+/// ティック数を、全音符を割り切る音長を ^ でつないだ形式で表す
+fn format_length(ticks: i32, ticks_per_bar: i32) -> String {
+	let mut rest = ticks;
+	let mut elements = vec![];
+	for number in (1 ..= ticks_per_bar).filter(|n| ticks_per_bar % n == 0) {
+		let length = ticks_per_bar / number;
+		while rest >= length {
+			elements.push(number.to_string());
+			rest -= length;
+		}
+	}
+	elements.join("^")
+}
+
+fn format_number(value: f32) -> String {
+	let result = format!("{:.3}", value);
+	result.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+#[cfg(test)]
+#[test]
+fn test_smf_to_mml() {
+	let note_on = |tick, key| SmfEvent { tick, body: SmfEventBody::NoteOn { channel: 0, key, velocity: 127 } };
+	let note_off = |tick, key| SmfEvent { tick, body: SmfEventBody::NoteOff { channel: 0, key } };
+	let smf = Smf {
+		format: 0,
+		division: 96,
+		tracks: vec![vec![
+			SmfEvent { tick: 0, body: SmfEventBody::Tempo { micros_per_quarter: 400000 } },
+			note_on(0, 60),
+			note_off(96, 60),
+			note_on(96, 64),
+			note_on(192, 67),
+			note_off(288, 64),
+			note_off(288, 67),
+		]],
+	};
+	let options = MidiImportOptions {
+		ticks_per_bar: 384,
+		kind: MidiSourceKind::Channel,
+		sources: None,
+		cc_map: HashMap::new(),
+	};
+	let result = smf_to_mml(&smf, &options);
+	assert_eq!(result.len(), 1);
+	assert_eq!(result[0].source, 1);
+	assert_eq!(result[0].mml, "k0 q8 t150 v15 o4 c4 e4& 'eg'4 r4 |");
+	assert!(result[0].polyphonic);
+	assert_eq!(format_length(100, 384), "4^96");
+}
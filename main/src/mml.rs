@@ -1 +1,2 @@
 pub mod default;
+pub mod tuning;
@@ -4,4 +4,7 @@ pub enum Feature {
 	Volume,
 	Velocity,
 	Detune,
+	Vibrato,
+	Tremolo,
+	AutoPan,
 }
@@ -1,15 +1,19 @@
 use crate::{
-	common::stack, mml::default::feature::*, moddl::{console::warn, error::{error, ErrorType, ModdlResult}}, seq::{
+	common::stack, core::common::Sample, mml::{default::feature::*, tuning::Tuning}, moddl::{console::warn, error::{error, nom_error_to_owned, ErrorType, ModdlResult}}, node::lfo::{LFO_WAVEFORM_SAWTOOTH, LFO_WAVEFORM_SINE}, seq::{
 		instruction::*,
 		sequence::*,
 	}
 };
 extern crate parser;
-use parser::{common::Location, mml::ast::*};
-
-use std::collections::{
-	hash_map::HashMap,
-	hash_set::HashSet,
+use parser::{common::{Location, Span}, mml::{ast::*, dialect::MmlDialect}};
+
+use std::{
+	collections::{
+		hash_map::HashMap,
+		hash_set::HashSet,
+	},
+	path::PathBuf,
+	rc::Rc,
 };
 
 /// (qname, key)
@@ -17,41 +21,83 @@ pub type ParamSignature = (String, String);
 pub struct TagSet {
 	pub freq: String,
 	pub note: String,
+	/// ポリフォニックなトラックでは、freq と note の代わりにボイスの割り当てをシーケンサに任せる
+	pub polyphonic: bool,
+	/// ドラムキットのヒットの名前と、それを鳴らすキー。ノート番号を持たないヒットのキーは負
+	pub hits: HashMap<String, i32>,
 }
 
 // TODO 将来はディレクティブで設定できるように
 const MAX_GATE_RATE: f32 = 8f32;
 const MAX_VOLUME: f32 = 15f32;
 const MAX_VELOCITY: f32 = 15f32;
+const MAX_MACRO_EXPANSION_DEPTH: i32 = 64;
 
 const PARAM_NAME_VOLUME: &str = "#volume";
 const PARAM_NAME_VELOCITY: &str = "#velocity";
 const PARAM_NAME_DETUNE: &str = "#detune";
 const PARAM_NAME_TEMPO: &str = "#tempo";
+pub const PARAM_NAME_VIBRATO: &str = "#vibrato";
+pub const PARAM_NAME_TREMOLO: &str = "#tremolo";
+pub const PARAM_NAME_AUTO_PAN: &str = "#autoPan";
 
-pub fn scan_features(CompilationUnit { commands }: &CompilationUnit) -> HashSet<Feature> {
+pub fn scan_features(CompilationUnit { commands }: &CompilationUnit, dialect: &dyn MmlDialect) -> HashSet<Feature> {
 	let mut result = HashSet::new();
-	scan_features_(commands, &mut result);
+	scan_features_(commands, dialect, &mut HashMap::new(), 0, &mut result);
 
 	result
 }
 
-fn scan_features_(commands: &Vec<Command>, result: &mut HashSet<Feature>) {
+/// 引数つきマクロは展開するまで内容がわからないので、ここでも展開して調べる。
+/// 展開に失敗した場合のエラーはシーケンス生成時に報告する
+fn scan_features_(commands: &[Command], dialect: &dyn MmlDialect, param_macros: &mut HashMap<String, ParamMacro>, depth: i32, result: &mut HashSet<Feature>) {
+	let scan_macro = |name: &String, args: &[String], param_macros: &mut HashMap<String, ParamMacro>, result: &mut HashSet<Feature>| {
+		let mac = match param_macros.get(name) {
+			Some(mac) if mac.params.len() == args.len() && depth < MAX_MACRO_EXPANSION_DEPTH => mac.clone(),
+			_ => return,
+		};
+		let body = match substitute_macro_params(&mac.body, &mac.params, args) {
+			Ok(body) => body,
+			Err(_) => return,
+		};
+		if let Ok(CompilationUnit { commands }) = parse_macro_body(&body, dialect) {
+			scan_features_(&commands, dialect, param_macros, depth + 1, result);
+		}
+	};
+
 	for cmd in commands {
 		// Feature を使うコマンドと、内容を持つコマンドだけ処理
 		match cmd {
 			Command::Volume(_) => { result.insert(Feature::Volume); },
 			Command::Velocity(_) => { result.insert(Feature::Velocity); },
 			Command::Detune(_) => { result.insert(Feature::Detune); },
+			Command::Lfo { target, .. } => {
+				result.insert(match target {
+					LfoTarget::Vibrato => Feature::Vibrato,
+					LfoTarget::Tremolo => Feature::Tremolo,
+					LfoTarget::AutoPan => Feature::AutoPan,
+				});
+			},
+			Command::Ramp { command, .. } => { scan_features_(std::slice::from_ref(&**command), dialect, param_macros, depth, result); },
 
 			Command::Loop { content1, content2, .. } => {
-				scan_features_(content1, result);
+				scan_features_(content1, dialect, param_macros, depth, result);
 				if let Some(content2) = content2 {
-					scan_features_(content2, result);
+					scan_features_(content2, dialect, param_macros, depth, result);
+				}
+			},
+			Command::Stack { content } => { scan_features_(content, dialect, param_macros, depth, result); },
+			Command::Choose { alternatives } => {
+				for content in alternatives {
+					scan_features_(content, dialect, param_macros, depth, result);
 				}
 			},
-			Command::Stack { content } => { scan_features_(content, result); },
-			Command::MacroDef { content, .. } => { scan_features_(content, result); },
+			Command::MacroDef { content, .. } => { scan_features_(content, dialect, param_macros, depth, result); },
+			Command::ParamMacroDef { name, params, body } => {
+				param_macros.insert(name.clone(), ParamMacro { params: params.clone(), body: body.clone() });
+			},
+			Command::MacroCall { name, .. } => { scan_macro(name, &[], param_macros, result); },
+			Command::ExpandMacro { name, args, .. } => { scan_macro(name, args, param_macros, result); },
 			_ => { },
 		}
 	}
@@ -61,24 +107,38 @@ pub fn generate_sequences(
 	CompilationUnit { commands }: &CompilationUnit,
 	ticks_per_bar: i32,
 	tag_set: &TagSet,
+	tuning: &Tuning,
+	dialect: &dyn MmlDialect,
 	param_prefix: &str,
 	param_initials: &HashMap<ParamSignature, f32>,
 	param_default_keys: &HashMap<String, String>,
 	evaluate_expr: &mut dyn FnMut (&str) -> ModdlResult<f32>,
+	locate_mml: &dyn Fn (MmlPos) -> Location,
 ) -> ModdlResult<HashMap<String, Sequence>> {
 	let mut stack = init_stack(param_initials);
-	let mut var_seq = 0;
-	let mut seq_seq = 0;
-	let mut sequences = HashMap::new();
-	let mut used_skip = false;
-
-	generate_sequence(SEQUENCE_NAME_MAIN, commands, ticks_per_bar, tag_set, &mut stack, &mut var_seq, &mut seq_seq, &mut sequences,
-			&mut used_skip, param_prefix, param_default_keys, evaluate_expr) ?;
-	if used_skip {
-		sequences.get_mut(SEQUENCE_NAME_MAIN).unwrap().insert(0usize, Instruction::EnterSkipMode);
+	let mut gen = Generator {
+		ticks_per_bar,
+		tag_set,
+		tuning,
+		dialect,
+		param_prefix,
+		param_default_keys,
+		evaluate_expr,
+		locate_mml,
+		var_seq: 0,
+		seq_seq: 0,
+		sequences: HashMap::new(),
+		used_skip: false,
+		expansion_depth: 0,
+		expansion_pos: None,
+	};
+
+	gen.generate_sequence(SEQUENCE_NAME_MAIN, commands, &mut stack) ?;
+	if gen.used_skip {
+		gen.sequences.get_mut(SEQUENCE_NAME_MAIN).unwrap().insert(0usize, Instruction::EnterSkipMode);
 	}
 
-	Ok(sequences)
+	Ok(gen.sequences)
 }
 
 fn make_name(prefix: &str, count: &mut i32) -> String {
@@ -87,192 +147,440 @@ fn make_name(prefix: &str, count: &mut i32) -> String {
 	name
 }
 
-fn generate_sequence(
-	seq_name: &str,
-	commands: &Vec<Command>,
+/// シーケンス生成中に持ち回る設定と状態
+struct Generator<'a> {
 	ticks_per_bar: i32,
-	tag_set: &TagSet,
-	stack: &mut Stack,
-	var_seq: &mut i32,
-	seq_seq: &mut i32,
-	sequences: &mut HashMap<String, Sequence>,
-	used_skip: &mut bool,
-	param_prefix: &str,
-	param_default_keys: &HashMap<String, String>,
-	evaluate_expr: &mut dyn FnMut (&str) -> ModdlResult<f32>,
-) -> ModdlResult<()> {
-	let mut seq = vec![];
-	for command in commands {
+	tag_set: &'a TagSet,
+	tuning: &'a Tuning,
+	/// 引数つきマクロの本体を解析する方言
+	dialect: &'a dyn MmlDialect,
+	param_prefix: &'a str,
+	param_default_keys: &'a HashMap<String, String>,
+	evaluate_expr: &'a mut dyn FnMut (&str) -> ModdlResult<f32>,
+	/// MML の中の位置から ModDL ファイル上の位置を求める
+	locate_mml: &'a dyn Fn (MmlPos) -> Location,
+	var_seq: i32,
+	seq_seq: i32,
+	sequences: HashMap<String, Sequence>,
+	used_skip: bool,
+	/// 引数つきマクロを展開中の深さ
+	expansion_depth: i32,
+	/// 展開中の引数つきマクロのうち、一番外側の呼び出しの位置
+	expansion_pos: Option<MmlPos>,
+}
+impl <'a> Generator<'a> {
+	/// コマンドの MML の中での位置。引数つきマクロの本体は別に解析しているので、展開中はマクロを呼び出した位置にする
+	fn call_pos(&self, pos: MmlPos) -> MmlPos {
+		self.expansion_pos.unwrap_or(pos)
+	}
+
+	fn loc(&self, pos: MmlPos) -> Location {
+		(self.locate_mml)(self.call_pos(pos))
+	}
+
+	fn evaluate(&mut self, number_or_expr: &NumberOrExpr) -> ModdlResult<f32> {
+		evaluate(number_or_expr, self.evaluate_expr)
+	}
+
+	/// 値を設定するコマンドから、パラメータの接頭辞、名前、キー、値を求める
+	fn param_change<'c>(&mut self, command: &'c Command) -> ModdlResult<(&'a str, &'c str, &'c Option<String>, f32)> {
+		let param_prefix = self.param_prefix;
 		match command {
-			Command::Octave(val) => { stack.mml_state_mut().octave = evaluate(val, evaluate_expr) ?; }
-			Command::OctaveIncr => { stack.mml_state_mut().octave += 1f32; }
-			Command::OctaveDecr => { stack.mml_state_mut().octave -= 1f32; }
-			Command::Length(val) => { stack.mml_state_mut().length = *val; }
-			Command::GateRate(val) => { stack.mml_state_mut().gate_rate = evaluate(val, evaluate_expr)?.max(0f32).min(MAX_GATE_RATE); }
-			Command::Tone { tone_name, length, slur } => {
-				let step_ticks = calc_ticks_from_length(&length, ticks_per_bar, stack.mml_state().length) ?;
-				let gate_ticks = (step_ticks as f32 * stack.mml_state().gate_rate / MAX_GATE_RATE) as i32;
-
-
-				// TODO 本当は temperament を挟む
-				let freq = calc_freq_from_tone(stack.mml_state().octave, tone_name);
-				
-				// TODO ちゃんとエラー処理
-				let key = param_default_keys.get(&tag_set.freq).unwrap();
-				// TODO タグは intern したい
-				seq.push(Instruction::Value { tag: tag_set.freq.clone(), key: key.clone(), value: freq });
-				if ! stack.mml_state().slur {
-					seq.push(Instruction::Note { tag: tag_set.note.clone(), note_on: true });
+			// TODO ここで track prefix をかますことで MML には書かないでいいように
+			Command::Parameter { name, key, value } => Ok((param_prefix, name, key, self.evaluate(value) ?)),
+			Command::Volume(value) => Ok((param_prefix, PARAM_NAME_VOLUME, &None, self.evaluate(value)? / MAX_VOLUME)),
+			Command::Velocity(value) => Ok((param_prefix, PARAM_NAME_VELOCITY, &None, self.evaluate(value)? / MAX_VELOCITY)),
+			Command::Detune(value) => Ok((param_prefix, PARAM_NAME_DETUNE, &None, self.evaluate(value) ?)),
+			Command::Tempo(value) => Ok(("" /* global */, PARAM_NAME_TEMPO, &None, self.evaluate(value) ?)),
+			// 値を設定するコマンド以外がランプの対象になることは構文上ない
+			_ => unreachable!(),
+		}
+	}
+
+	fn generate_sequence(&mut self, seq_name: &str, commands: &Vec<Command>, stack: &mut Stack) -> ModdlResult<()> {
+		let mut seq = vec![];
+		self.generate_commands(commands, stack, &mut seq) ?;
+
+		// 始点と終点が一致すると問題になるケースがあるので、空のシーケンスは作らない
+		if seq.is_empty() {
+			seq.push(Instruction::Nop);
+		}
+		self.sequences.insert(seq_name.to_string(), seq);
+
+		Ok(())
+	}
+
+	fn generate_commands(&mut self, commands: &Vec<Command>, stack: &mut Stack, seq: &mut Vec<Instruction>) -> ModdlResult<()> {
+		let ticks_per_bar = self.ticks_per_bar;
+		let tag_set = self.tag_set;
+		let param_default_keys = self.param_default_keys;
+
+		for command in commands {
+			match command {
+				Command::Octave(val) => { stack.mml_state_mut().octave = self.evaluate(val) ?; }
+				Command::OctaveIncr => { stack.mml_state_mut().octave += 1f32; }
+				Command::OctaveDecr => { stack.mml_state_mut().octave -= 1f32; }
+				Command::Length(val) => { stack.mml_state_mut().length = *val; }
+				Command::GateRate(val) => { stack.mml_state_mut().gate_rate = self.evaluate(val)?.max(0f32).min(MAX_GATE_RATE); }
+				Command::KeySignature(key) => { stack.mml_state_mut().key_signature = *key; }
+				Command::Tone { tone_name, length, slur, pos } if tag_set.polyphonic => {
+					let note_number = calc_note_number(stack.mml_state().octave, tone_name, stack.mml_state().key_signature);
+					let keys = self.poly_keys(&[note_number], *pos) ?;
+					self.push_poly_notes(seq, stack, &keys, length, *slur) ?;
+				}
+				Command::Tone { tone_name, length, slur, pos } => {
+					let step_ticks = calc_ticks_from_length(&length, ticks_per_bar, stack.mml_state().length) ?;
+					let gate_ticks = (step_ticks as f32 * stack.mml_state().gate_rate / MAX_GATE_RATE) as i32;
+
+					let freq = calc_freq(self.tuning, calc_note_number(stack.mml_state().octave, tone_name, stack.mml_state().key_signature), &self.loc(*pos)) ?;
+
+					// TODO ちゃんとエラー処理
+					let key = param_default_keys.get(&tag_set.freq).unwrap();
+					// TODO タグは intern したい
+					seq.push(Instruction::Value { tag: tag_set.freq.clone(), key: key.clone(), value: freq });
+					if ! stack.mml_state().slur {
+						seq.push(Instruction::Note { tag: tag_set.note.clone(), note_on: true });
+					}
+					seq.push(Instruction::Wait(gate_ticks));
+					if ! *slur {
+						seq.push(Instruction::Note { tag: tag_set.note.clone(), note_on: false });
+					}
+					if step_ticks - gate_ticks > 0 {
+						seq.push(Instruction::Wait(step_ticks - gate_ticks));
+					}
+
+					stack.mml_state_mut().slur = *slur;
 				}
-				seq.push(Instruction::Wait(gate_ticks));
-				if ! *slur {
-					seq.push(Instruction::Note { tag: tag_set.note.clone(), note_on: false });
+				Command::Chord { tones, length, slur, pos } => {
+					if ! tag_set.polyphonic {
+						return Err(error(ErrorType::ChordOnMonophonicTrack, self.loc(*pos)));
+					}
+					let MmlState { octave, key_signature, .. } = *stack.mml_state();
+					let note_numbers: Vec<_> = tones.iter().map(|ChordTone { octave_offset, tone_name }| {
+						calc_note_number(octave + *octave_offset as f32, tone_name, key_signature)
+					}).collect();
+					let keys = self.poly_keys(&note_numbers, *pos) ?;
+					self.push_poly_notes(seq, stack, &keys, length, *slur) ?;
 				}
-				if step_ticks - gate_ticks > 0 {
-					seq.push(Instruction::Wait(step_ticks - gate_ticks));
+				Command::Hit { name, length, slur, pos } => {
+					let key = *tag_set.hits.get(name)
+							.ok_or_else(|| error(ErrorType::DrumHitNotFound { name: name.clone() }, self.loc(*pos))) ?;
+					// ノート番号を持たないヒットは周波数を 0 とする
+					let freq = if key >= 0 { calc_freq(self.tuning, key as f32, &self.loc(*pos)) ? } else { 0f32 };
+					self.push_poly_notes(seq, stack, &[(key, freq)], length, *slur) ?;
 				}
-
-				stack.mml_state_mut().slur = *slur;
-			}
-			Command::Rest(val) => {
-				let ticks = calc_ticks_from_length(&val, ticks_per_bar, stack.mml_state().length) ?;
-				seq.push(Instruction::Wait(ticks));
-			}
-			Command::Parameter { name, key, value } => {
-				// TODO ここで track prefix をかますことで MML には書かないでいいように
-				// seq.push(Instruction::Value { tag: format!("{}{}", param_prefix, &name), value: *value });
-				push_param_instrc(&mut seq, stack, param_default_keys, param_prefix, &name, key, evaluate(value, evaluate_expr) ?);
-			}
-			Command::Volume(value) => {
-				push_param_instrc(&mut seq, stack, param_default_keys, param_prefix, PARAM_NAME_VOLUME, &None, evaluate(value, evaluate_expr) ? / MAX_VOLUME);
-			}
-			Command::Velocity(value) => {
-				push_param_instrc(&mut seq, stack, param_default_keys, param_prefix, PARAM_NAME_VELOCITY, &None, evaluate(value, evaluate_expr) ? / MAX_VELOCITY);
-			}
-			Command::Detune(value) => {
-				push_param_instrc(&mut seq, stack, param_default_keys, param_prefix, PARAM_NAME_DETUNE, &None, evaluate(value, evaluate_expr) ?);
-			}
-			Command::Tempo(value) => {
-				push_param_instrc(&mut seq, stack, param_default_keys, "" /* global */, PARAM_NAME_TEMPO, &None, evaluate(value, evaluate_expr) ?);
-			}
-			Command::MacroCall { name } => {
-				push(stack);
-				let seq_name = stack.macro_names().get(name);
-				match seq_name {
-					None => unimplemented!("macro not found"), // TODO エラーにする
-					Some(seq_name) => {
-						seq.push(Instruction::Call { seq_name: seq_name.clone() });
-					},
+				Command::Rest(val) => {
+					let ticks = calc_ticks_from_length(&val, ticks_per_bar, stack.mml_state().length) ?;
+					if tag_set.polyphonic {
+						release_slur_keys(seq, stack, &[]);
+					}
+					seq.push(Instruction::Wait(ticks));
 				}
-
-				pop_and_restore_params(stack, &mut seq);
-			}
-			Command::Loop { times, content1, content2 } => {
-				/*
-				content1, content2 をそれぞれ別個の sequence としてコンパイルする。
-				sequence には連番を含んだ一意な名前を振る（#seq0, #seq1 とする）
-				また一意な名前のループカウンタ（#var0 とする）を作り、n - 1 を初期値にする
-					#var0 = n - 1
-				loop_start:
-					call #seq0
-				i		if #var0 == 0 goto loop_end
-				i+1		call #seq2
-				i+2		if #var0 == 0 goto loop_end
-				i+3		dec #var0
-				i+4		goto loop_start
-					loop_end:
-				i+5		delete #var0
-				*/
-				let var_name = if let Some(times) = times {
-					assert!(*times > 0);
-					let var_name = make_name("var", var_seq);
-					seq.push(Instruction::NewVar { name: var_name.clone(), value: times - 1 });
-					Some(var_name)
-				} else {
-					None
-				};
-				let loop_start = seq.len();
-				push(stack);
-				let content1_name = make_name("seq", seq_seq);
-				generate_sequence(content1_name.as_str(), content1, ticks_per_bar, tag_set, stack, var_seq, seq_seq, sequences, used_skip, param_prefix, param_default_keys, evaluate_expr) ?;
-				seq.push(Instruction::Call { seq_name: content1_name });
-
-				if let Some(content2) = content2 {
+				Command::Parameter { .. } | Command::Volume(_) | Command::Velocity(_) | Command::Detune(_) | Command::Tempo(_) => {
+					let (prefix, name, key, value) = self.param_change(command) ?;
+					push_param_instrc(seq, stack, param_default_keys, prefix, name, key, value, None);
+				}
+				Command::Ramp { command, length, curve } => {
+					let ticks = calc_ticks_from_length(length, ticks_per_bar, stack.mml_state().length) ?;
+					let (prefix, name, key, value) = self.param_change(command) ?;
+					push_param_instrc(seq, stack, param_default_keys, prefix, name, key, value, Some((ticks, *curve)));
+				}
+				Command::Lfo { target, rate, depth, delay, waveform, pos } => {
+					let name = match target {
+						LfoTarget::Vibrato => PARAM_NAME_VIBRATO,
+						LfoTarget::Tremolo => PARAM_NAME_TREMOLO,
+						LfoTarget::AutoPan => PARAM_NAME_AUTO_PAN,
+					};
+					// LFO のノードはキーごとに値を受け取る
+					let values = [
+						("rate", Some(rate)),
+						("depth", Some(depth)),
+						("delay", delay.as_ref()),
+						("waveform", waveform.as_ref()),
+					];
+					for (key, value) in values {
+						let value = match value {
+							Some(value) => self.evaluate(value) ?,
+							None => 0f32,
+						};
+						// 波形は番号で指定する。整数でない値や範囲外の値は受け付けない
+						if key == "waveform" && (value.fract() != 0f32 || ! (LFO_WAVEFORM_SINE ..= LFO_WAVEFORM_SAWTOOTH).contains(&(value as i32))) {
+							return Err(error(ErrorType::BadLfoWaveform, self.loc(*pos)));
+						}
+						push_param_instrc(seq, stack, param_default_keys, self.param_prefix, name, &Some(key.to_string()), value, None);
+					}
+				}
+				Command::MacroCall { name, pos } => {
+					// 引数のないマクロとして定義した引数つきマクロは、引数なしでも展開できる
+					if let Some(mac) = stack.param_macros().get(name).cloned() {
+						self.expand_macro(name, &mac, &[], *pos, stack, seq) ?;
+					} else {
+						call_macro(name, &self.loc(*pos), stack, seq) ?;
+					}
+				}
+				Command::Loop { times, content1, content2 } => {
+					/*
+					content1, content2 をそれぞれ別個の sequence としてコンパイルする。
+					sequence には連番を含んだ一意な名前を振る（#seq0, #seq1 とする）
+					また一意な名前のループカウンタ（#var0 とする）を作り、n - 1 を初期値にする
+						#var0 = n - 1
+					loop_start:
+						call #seq0
+					i		if #var0 == 0 goto loop_end
+					i+1		call #seq2
+					i+2		if #var0 == 0 goto loop_end
+					i+3		dec #var0
+					i+4		goto loop_start
+						loop_end:
+					i+5		delete #var0
+					*/
+					let var_name = if let Some(times) = times {
+						assert!(*times > 0);
+						let var_name = make_name("var", &mut self.var_seq);
+						seq.push(Instruction::NewVar { name: var_name.clone(), value: times - 1 });
+						Some(var_name)
+					} else {
+						None
+					};
+					let loop_start = seq.len();
+					push(stack);
+					let content1_name = make_name("seq", &mut self.seq_seq);
+					self.generate_sequence(content1_name.as_str(), content1, stack) ?;
+					seq.push(Instruction::Call { seq_name: content1_name });
+
+					if let Some(content2) = content2 {
+						if let Some(var_name) = &var_name {
+							seq.push(Instruction::If0 {
+								var: var_name.clone(),
+								then: Box::new(Instruction::JumpRel { offset: 5 }),
+							});
+						} else {
+							// TODO 無限ループに : が含まれている。エラーにする
+						}
+
+						// content1 をコンパイルした続きの状態でコンパイルする
+						let content2_name = make_name("seq", &mut self.seq_seq);
+						self.generate_sequence(content2_name.as_str(), content2, stack) ?;
+						seq.push(Instruction::Call { seq_name: content2_name });
+					}
 					if let Some(var_name) = &var_name {
 						seq.push(Instruction::If0 {
 							var: var_name.clone(),
-							then: Box::new(Instruction::JumpRel { offset: 5 }),
+							then: Box::new(Instruction::JumpRel { offset: 3 }),
 						});
-					} else {
-						// TODO 無限ループに : が含まれている。エラーにする
 					}
-
-					// content1 をコンパイルした続きの状態でコンパイルする
-					let content2_name = make_name("seq", seq_seq);
-					generate_sequence(content2_name.as_str(), content2, ticks_per_bar, tag_set, stack, var_seq, seq_seq, sequences, used_skip, param_prefix, param_default_keys, evaluate_expr) ?;
-					seq.push(Instruction::Call { seq_name: content2_name });
+					if let Some(var_name) = &var_name {
+						seq.push(Instruction::DecrVar { name: var_name.clone() });
+					}
+					let cur_idx = seq.len();
+					seq.push(Instruction::JumpRel { offset: -((cur_idx - loop_start) as i32) });
+					// TODO : で脱出したときは 5 つ前が Jump であることを assert する
+					if let Some(var_name) = &var_name {
+						seq.push(Instruction::DeleteVar { name: var_name.clone() });
+					}
+					pop_and_restore_params(stack, seq);
 				}
-				if let Some(var_name) = &var_name {
-					seq.push(Instruction::If0 {
-						var: var_name.clone(),
-						then: Box::new(Instruction::JumpRel { offset: 3 }),
-					});
+				Command::Stack { content } => {
+					push(stack);
+					// 別シーケンスに分ける必要はないかもだが、generate_sequence で再帰するとシーケンスが生成される
+					let content_name = make_name("seq", &mut self.seq_seq);
+					self.generate_sequence(content_name.as_str(), content, stack) ?;
+					seq.push(Instruction::Call { seq_name: content_name });
+					pop_and_restore_params(stack, seq)
 				}
-				if let Some(var_name) = &var_name {
-					seq.push(Instruction::DecrVar { name: var_name.clone() });
+				Command::Choose { alternatives } => {
+					// どれを選んでも続きが同じ状態になるよう、選択肢の中で変えたパラメータは後で全て元に戻す
+					let mut seq_names = vec![];
+					let mut restore_instrcs = vec![];
+					for content in alternatives {
+						push(stack);
+						let seq_name = make_name("seq", &mut self.seq_seq);
+						self.generate_sequence(seq_name.as_str(), content, stack) ?;
+						pop_and_restore_params(stack, &mut restore_instrcs);
+						seq_names.push(seq_name);
+					}
+					seq.push(Instruction::Choose { seq_names });
+					seq.append(&mut restore_instrcs);
 				}
-				let cur_idx = seq.len();
-				seq.push(Instruction::JumpRel { offset: -((cur_idx - loop_start) as i32) });
-				// TODO : で脱出したときは 5 つ前が Jump であることを assert する
-				if let Some(var_name) = &var_name {
-					seq.push(Instruction::DeleteVar { name: var_name.clone() });
+				Command::MacroDef { name, content } => {
+					push(stack);
+					let seq_name = make_name("seq", &mut self.seq_seq);
+					self.generate_sequence(seq_name.as_str(), content, stack) ?;
+					// コンパイルするだけなので params の復元は不要
+					// pop_and_restore_params(stack, param_prefix, seq);
+					stack.pop();
+					stack.macro_names_mut().insert(name.clone(), seq_name);
+				}
+				Command::Marker { name, pos } => {
+					seq.push(Instruction::Marker { name: name.clone(), pos: self.call_pos(*pos) });
+				}
+				Command::Meter { numerator, denominator } => {
+					seq.push(Instruction::Meter { numerator: *numerator, denominator: *denominator });
+				}
+				Command::BarLine { pos } => {
+					// 引数つきマクロの本体は別に解析しているので、位置は当てにならない
+					let pos = if self.expansion_depth == 0 { Some((pos.line, pos.column)) } else { None };
+					seq.push(Instruction::BarLine { pos });
+				}
+				Command::Skip => {
+					seq.push(Instruction::ExitSkipMode);
+					self.used_skip = true;
+				}
+				Command::ParamMacroDef { name, params, body } => {
+					stack.param_macros_mut().insert(name.clone(), ParamMacro { params: params.clone(), body: body.clone() });
+				}
+				Command::ExpandMacro { name, args, pos } => {
+					if let Some(mac) = stack.param_macros().get(name).cloned() {
+						self.expand_macro(name, &mac, args, *pos, stack, seq) ?;
+					} else if stack.macro_names().contains_key(name) {
+						// 引数のないマクロは、引数を与えずに展開する場合に限り通常の呼び出しと同じにする
+						if ! args.is_empty() {
+							return Err(error(ErrorType::MacroArgCountMismatch { name: name.clone(), expected: 0, actual: args.len() }, self.loc(*pos)));
+						}
+						call_macro(name, &self.loc(*pos), stack, seq) ?;
+					} else {
+						return Err(error(ErrorType::MacroNotFound { name: name.clone() }, self.loc(*pos)));
+					}
 				}
-				pop_and_restore_params(stack, &mut seq);
-			}
-			Command::Stack { content } => {
-				push(stack);
-				// 別シーケンスに分ける必要はないかもだが、generate_sequence で再帰するとシーケンスが生成される
-				let content_name = make_name("seq", seq_seq);
-				generate_sequence(content_name.as_str(), content, ticks_per_bar, tag_set, stack, var_seq, seq_seq, sequences, used_skip, param_prefix, param_default_keys, evaluate_expr) ?;
-				seq.push(Instruction::Call { seq_name: content_name });
-				pop_and_restore_params(stack, &mut seq)
 			}
-			Command::MacroDef { name, content } => {
-				push(stack);
-				let seq_name = make_name("seq", seq_seq);
-				generate_sequence(seq_name.as_str(), content, ticks_per_bar, tag_set, stack, var_seq, seq_seq, sequences, used_skip, param_prefix, param_default_keys, evaluate_expr) ?;
-				// コンパイルするだけなので params の復元は不要
-				// pop_and_restore_params(stack, param_prefix, &mut seq);
-				stack.pop();
-				stack.macro_names_mut().insert(name.clone(), seq_name);
+		}
+
+		Ok(())
+	}
+
+	/// 引数つきマクロの本体に引数を埋め込み、解析してその場に展開する
+	fn expand_macro(&mut self, name: &str, mac: &ParamMacro, args: &[String], pos: MmlPos, stack: &mut Stack, seq: &mut Vec<Instruction>) -> ModdlResult<()> {
+		let loc = self.loc(pos);
+		if args.len() != mac.params.len() {
+			return Err(error(ErrorType::MacroArgCountMismatch { name: name.to_string(), expected: mac.params.len(), actual: args.len() }, loc));
+		}
+		// マクロが自分自身を展開し続けるのを防ぐ
+		if self.expansion_depth >= MAX_MACRO_EXPANSION_DEPTH {
+			return Err(error(ErrorType::MacroExpansionTooDeep { name: name.to_string() }, loc));
+		}
+
+		let body = substitute_macro_params(&mac.body, &mac.params, args)
+				.map_err(|param| error(ErrorType::MacroParamNotFound { name: name.to_string(), param }, loc.clone())) ?;
+		let CompilationUnit { commands } = parse_macro_body(&body, self.dialect).map_err(|e| error(e, loc)) ?;
+		let outermost = self.expansion_pos.is_none();
+		if outermost {
+			self.expansion_pos = Some(pos);
+		}
+		self.expansion_depth += 1;
+		let result = self.generate_commands(&commands, stack, seq);
+		self.expansion_depth -= 1;
+		if outermost {
+			self.expansion_pos = None;
+		}
+
+		result
+	}
+
+	/// ノート番号から、ポリフォニックなトラックで鳴らすキーと周波数を求める
+	fn poly_keys(&self, note_numbers: &[f32], pos: MmlPos) -> ModdlResult<Vec<(i32, Sample)>> {
+		note_numbers.iter().map(|note_number| Ok((note_number.round() as i32, calc_freq(self.tuning, *note_number, &self.loc(pos)) ?))).collect()
+	}
+
+	/// ポリフォニックなトラックで単音または和音を鳴らす。
+	/// スラーでつながった音のうち、次の音にも含まれるものは鳴らし直さずに伸ばす
+	fn push_poly_notes(&mut self, seq: &mut Vec<Instruction>, stack: &mut Stack, keys: &[(i32, Sample)], length: &Length, slur: bool) -> ModdlResult<()> {
+		let step_ticks = calc_ticks_from_length(length, self.ticks_per_bar, stack.mml_state().length) ?;
+		let gate_ticks = (step_ticks as f32 * stack.mml_state().gate_rate / MAX_GATE_RATE) as i32;
+
+		let key_numbers: Vec<_> = keys.iter().map(|(key, _)| *key).collect();
+
+		let held_keys = release_slur_keys(seq, stack, &key_numbers);
+		for (key, freq) in keys {
+			if ! held_keys.contains(key) {
+				seq.push(Instruction::PolyNote { key: *key, freq: *freq, note_on: true });
 			}
-			Command::Skip => {
-				seq.push(Instruction::ExitSkipMode);
-				*used_skip = true;
+		}
+		seq.push(Instruction::Wait(gate_ticks));
+		if slur {
+			stack.mml_state_mut().slur_keys = key_numbers;
+		} else {
+			for key in key_numbers {
+				seq.push(Instruction::PolyNote { key, freq: 0f32, note_on: false });
 			}
-			Command::ExpandMacro { name: _ } => unimplemented!(),
 		}
+		if step_ticks - gate_ticks > 0 {
+			seq.push(Instruction::Wait(step_ticks - gate_ticks));
+		}
+
+		Ok(())
 	}
+}
 
-	// 始点と終点が一致すると問題になるケースがあるので、空のシーケンスは作らない
-	if seq.is_empty() {
-		seq.push(Instruction::Nop);
+/// スラーで伸ばしているノートのうち、keep_keys に含まれないものを離す。
+/// スラーで伸ばしていたノートを返す
+fn release_slur_keys(seq: &mut Vec<Instruction>, stack: &mut Stack, keep_keys: &[i32]) -> Vec<i32> {
+	let held_keys = std::mem::take(&mut stack.mml_state_mut().slur_keys);
+	for key in &held_keys {
+		if ! keep_keys.contains(key) {
+			seq.push(Instruction::PolyNote { key: *key, freq: 0f32, note_on: false });
+		}
 	}
-	sequences.insert(seq_name.to_string(), seq);
+
+	held_keys
+}
+
+fn call_macro(name: &str, loc: &Location, stack: &mut Stack, seq: &mut Vec<Instruction>) -> ModdlResult<()> {
+	push(stack);
+	let seq_name = stack.macro_names().get(name)
+			.ok_or_else(|| error(ErrorType::MacroNotFound { name: name.to_string() }, loc.clone())) ?;
+	seq.push(Instruction::Call { seq_name: seq_name.clone() });
+	pop_and_restore_params(stack, seq);
 
 	Ok(())
 }
 
+/// マクロの本体をトラックの方言で解析する。本体の中の位置は当てにならないので、エラーの位置は呼び出し側で与える
+fn parse_macro_body(body: &str, dialect: &dyn MmlDialect) -> Result<CompilationUnit, ErrorType> {
+	dialect.parse(Span::new_extra(body, Rc::new(PathBuf::new())))
+			.map_err(|e| ErrorType::MmlSyntax(nom_error_to_owned(e)))
+}
+
+/// 本体中の %引数名 を引数のテキストで置き換える。
+/// %n8 のように引数名の直後に音長などが続いてもよいよう、% に続く部分に前方一致する最も長い引数名を使う。
+/// 識別子が続かない % は式中の剰余演算子なのでそのまま残し、どの引数名にも当たらない識別子が続く場合はその名前をエラーとして返す
+fn substitute_macro_params(body: &str, params: &[String], args: &[String]) -> Result<String, String> {
+	let mut result = String::new();
+	let mut rest = body;
+	while let Some(percent) = rest.find('%') {
+		result.push_str(&rest[.. percent]);
+		rest = &rest[percent + 1 ..];
+		let param = params.iter().enumerate()
+				.filter(|(_, param)| ! param.is_empty() && rest.starts_with(param.as_str()))
+				.max_by_key(|(_, param)| param.len());
+		match param {
+			Some((i, param)) => {
+				result.push_str(&args[i]);
+				rest = &rest[param.len() ..];
+			},
+			None if rest.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') => {
+				let end = rest.find(|c: char| ! (c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+				return Err(rest[.. end].to_string());
+			},
+			None => { result.push('%'); },
+		}
+	}
+	result.push_str(rest);
+
+	Ok(result)
+}
+
 fn push(stack: &mut Stack) {
 	let mml_state = stack.mml_state().clone();
 	let params = HashMap::new();
 	// TODO params と同様、新規にして参照時に検索するようにしたい
 	let macro_names = stack.macro_names().clone();
+	let param_macros = stack.param_macros().clone();
 
 	stack.push(StackFrame {
 		mml_state,
 		params,
 		macro_names,
+		param_macros,
 	});
 }
 
@@ -301,13 +609,22 @@ fn qualified_param_name(prefix: &str, name: &str) -> String {
 	format!("{}{}", prefix, name)
 }
 
-fn push_param_instrc(seq: &mut Vec<Instruction>, stack: &mut Stack, param_default_keys: &HashMap<String, String>, param_prefix: &str, name: &str, key: &Option<String>, value: f32) {
+/// ramp が Some((ticks, curve)) なら、直前の値から徐々に変化させる
+fn push_param_instrc(seq: &mut Vec<Instruction>, stack: &mut Stack, param_default_keys: &HashMap<String, String>, param_prefix: &str, name: &str, key: &Option<String>, value: f32, ramp: Option<(i32, RampCurve)>) {
 	let param_name = qualified_param_name(param_prefix, name);
 	let key = key.as_ref().or_else(|| param_default_keys.get(&param_name));
 	match key {
 		Some(key) => {
-			seq.push(Instruction::Value { tag: param_name.clone(), key: key.clone(), value });
-			stack.params_mut().insert((param_name, key.clone()), value);
+			let sig = (param_name.clone(), key.clone());
+			match ramp {
+				None => seq.push(Instruction::Value { tag: param_name, key: key.clone(), value }),
+				Some((ticks, curve)) => {
+					// 直前の値はスタックを遡って探す。見つからなければ変化させずに設定する
+					let from = stack.iter_frames().find_map(|frame| frame.params.get(&sig)).copied().unwrap_or(value);
+					seq.push(Instruction::Ramp { tag: param_name, key: key.clone(), from, to: value, ticks, curve });
+				},
+			}
+			stack.params_mut().insert(sig, value);
 		},
 		None => {
 			warn(format!("default key for param {} not found (maybe due to wrong param name)", param_name));
@@ -339,12 +656,9 @@ fn divide_ticks(ticks: i32, denominator: i32, length_spec: &Length) -> ModdlResu
 	}
 }
 
-fn calc_freq_from_tone(octave: f32,
-		ToneName { base_name, accidental }: &ToneName) -> f32 {
-	let note_a4 = 69f32;
-	let freq_a4 = 440f32;
-	// とりあえず平均律のみ…
-	let note_number = 12f32 * (octave + 1f32) + (match base_name {
+fn calc_note_number(octave: f32, ToneName { base_name, accidental }: &ToneName, key_signature: i32) -> f32 {
+	let accidental = accidental.unwrap_or_else(|| key_accidental(key_signature, base_name));
+	12f32 * (octave + 1f32) + (match base_name {
 		ToneBaseName::C => 0,
 		ToneBaseName::D => 2,
 		ToneBaseName::E => 4,
@@ -352,9 +666,33 @@ fn calc_freq_from_tone(octave: f32,
 		ToneBaseName::G => 7,
 		ToneBaseName::A => 9,
 		ToneBaseName::B => 11,
-	} + *accidental) as f32;
+	} + accidental) as f32
+}
 
-	freq_a4 * 2f32.powf((note_number - note_a4) as f32 / 12f32)
+/// 調号によって音名につく臨時記号
+fn key_accidental(key_signature: i32, base_name: &ToneBaseName) -> i32 {
+	// # は F C G D A E B の順に、♭はその逆順につく
+	let order = match base_name {
+		ToneBaseName::F => 0,
+		ToneBaseName::C => 1,
+		ToneBaseName::G => 2,
+		ToneBaseName::D => 3,
+		ToneBaseName::A => 4,
+		ToneBaseName::E => 5,
+		ToneBaseName::B => 6,
+	};
+	if order < key_signature {
+		1
+	} else if 6 - order < - key_signature {
+		-1
+	} else {
+		0
+	}
+}
+
+fn calc_freq(tuning: &Tuning, note_number: f32, loc: &Location) -> ModdlResult<f32> {
+	tuning.freq(note_number)
+			.ok_or_else(|| error(ErrorType::NoteUnmapped { note_number: note_number as i32 }, loc.clone()))
 }
 
 #[derive(Clone)]
@@ -363,7 +701,11 @@ struct MmlState {
 	length: i32,
 	/// スラーの途中（前の音符にスラーがついていた）かどうか
 	slur: bool,
+	/// ポリフォニックなトラックで、スラーの途中のノート
+	slur_keys: Vec<i32>,
 	gate_rate: f32,
+	/// 調号。正なら # の数、負なら ♭ の数
+	key_signature: i32,
 	// detune
 }
 impl MmlState {
@@ -372,7 +714,9 @@ impl MmlState {
 			octave: 4f32,
 			length: 4,
 			slur: false,
+			slur_keys: vec![],
 			gate_rate: MAX_GATE_RATE,
+			key_signature: 0,
 		}
 	}
 }
@@ -382,6 +726,13 @@ struct StackFrame {
 	mml_state: MmlState,
 	params: HashMap<ParamSignature, f32>,
 	macro_names: HashMap<String, String>,
+	param_macros: HashMap<String, ParamMacro>,
+}
+
+#[derive(Clone)]
+struct ParamMacro {
+	params: Vec<String>,
+	body: String,
 }
 
 type Stack = stack::Stack<StackFrame>;
@@ -391,23 +742,28 @@ fn init_stack(param_initials: &HashMap<ParamSignature, f32>) -> Stack {
 		mml_state: MmlState::init(),
 		params: param_initials.clone(),
 		macro_names: HashMap::new(),
+		param_macros: HashMap::new(),
 	})
 }
 trait StackShortcut {
 	fn mml_state(&self) -> &MmlState;
 	fn params(&self) -> &HashMap<ParamSignature, f32>;
 	fn macro_names(&self) -> &HashMap<String, String>;
+	fn param_macros(&self) -> &HashMap<String, ParamMacro>;
 	fn mml_state_mut(&mut self) -> &mut MmlState;
 	fn params_mut(&mut self) -> &mut HashMap<ParamSignature, f32>;
 	fn macro_names_mut(&mut self) -> &mut HashMap<String, String>;
+	fn param_macros_mut(&mut self) -> &mut HashMap<String, ParamMacro>;
 }
 impl StackShortcut for Stack {
 	fn mml_state(&self) -> &MmlState { &self.top().mml_state }
 	fn params(&self) -> &HashMap<ParamSignature, f32> { &self.top().params }
 	fn macro_names(&self) -> &HashMap<String, String> { &self.top().macro_names }
+	fn param_macros(&self) -> &HashMap<String, ParamMacro> { &self.top().param_macros }
 	fn mml_state_mut(&mut self) -> &mut MmlState { &mut self.top_mut().mml_state }
 	fn params_mut(&mut self) -> &mut HashMap<ParamSignature, f32> { &mut self.top_mut().params }
 	fn macro_names_mut(&mut self) -> &mut HashMap<String, String> { &mut self.top_mut().macro_names }
+	fn param_macros_mut(&mut self) -> &mut HashMap<String, ParamMacro> { &mut self.top_mut().param_macros }
 }
 
 fn evaluate(number_or_expr: &NumberOrExpr, evaluate_expr: &mut dyn FnMut (&str) -> ModdlResult<f32>) -> ModdlResult<f32> {
@@ -416,3 +772,96 @@ fn evaluate(number_or_expr: &NumberOrExpr, evaluate_expr: &mut dyn FnMut (&str)
 		NumberOrExpr::Expr(expr) => evaluate_expr(expr.as_str()),
 	}
 }
+
+#[cfg(test)]
+#[test]
+fn test_substitute_macro_params() {
+	let params = vec!["n".to_string(), "len".to_string()];
+	let args = vec!["c+".to_string(), "8.".to_string()];
+	assert_eq!(substitute_macro_params("%n%len >%n<", &params, &args), Ok("c+8. >c+<".to_string()));
+	// 引数名の直後に音長などが続いてもよい
+	assert_eq!(substitute_macro_params("%n8 %nn", &params, &args), Ok("c+8 c+n".to_string()));
+	// 識別子が続かない % はそのまま
+	assert_eq!(substitute_macro_params("y x,=a % 2;", &params, &args), Ok("y x,=a % 2;".to_string()));
+	// 前方一致する引数名のうち最も長いものを使う
+	let params = vec!["l".to_string(), "len".to_string()];
+	assert_eq!(substitute_macro_params("%len %l4", &params, &args), Ok("8. c+4".to_string()));
+	// 引数名に当たらない識別子はエラー
+	assert_eq!(substitute_macro_params("c %x8", &params, &args), Err("x8".to_string()));
+}
+
+#[cfg(test)]
+#[test]
+fn test_key_accidental() {
+	// E♭ 長調（♭ 3 つ）
+	assert_eq!(key_accidental(-3, &ToneBaseName::B), -1);
+	assert_eq!(key_accidental(-3, &ToneBaseName::A), -1);
+	assert_eq!(key_accidental(-3, &ToneBaseName::D), 0);
+	// A 長調（# 3 つ）
+	assert_eq!(key_accidental(3, &ToneBaseName::G), 1);
+	assert_eq!(key_accidental(3, &ToneBaseName::A), 0);
+	assert_eq!(calc_note_number(4f32, &ToneName { base_name: ToneBaseName::F, accidental: None }, 3), 66f32);
+	assert_eq!(calc_note_number(4f32, &ToneName { base_name: ToneBaseName::F, accidental: Some(0) }, 3), 65f32);
+}
+
+#[cfg(test)]
+#[test]
+fn test_note_errors() {
+	use crate::mml::tuning::{parse_kbm, parse_scl};
+	use parser::mml::dialect::{DefaultDialect, MmlDialect};
+
+	// C# などの黒鍵を鳴らさない調律
+	let scale = parse_scl("! test.scl\n!\n5-tone equal\n 5\n!\n240.0\n480.0\n720.0\n960.0\n2/1\n").unwrap();
+	let kbm = parse_kbm("! test.kbm\n12\n0\n127\n60\n69\n432.0\n5\n0\nx\n1\nx\n2\nx\nx\n3\nx\n4\nx\nx\n").unwrap();
+	let tuning = Tuning::scala(scale, Some(kbm), None).unwrap();
+	let generate = |mml: &str, polyphonic: bool| {
+		let ast = DefaultDialect.parse(Span::new_extra(mml, Rc::new(PathBuf::new()))).unwrap();
+		let tag_set = TagSet {
+			freq: "freq".to_string(),
+			note: "note".to_string(),
+			polyphonic,
+			hits: vec![("kick".to_string(), 36), ("snare".to_string(), 37), ("clap".to_string(), -1)].into_iter().collect(),
+		};
+		let locate_mml = |pos: MmlPos| Location { path: Rc::new(PathBuf::new()), line: pos.line, column: pos.column };
+		generate_sequences(&ast, 384, &tag_set, &tuning, &DefaultDialect, "", &HashMap::new(), &HashMap::new(), &mut |_| Ok(0f32), &locate_mml).err()
+	};
+	let error_at = |mml: &str, polyphonic: bool| generate(mml, polyphonic).map(|e| (e.body.to_string(), e.loc.line, e.loc.column));
+
+	// ノート番号を持たないヒットは調律によらず鳴らせる
+	assert!(generate("!kick !clap", true).is_none());
+	// エラーの位置は MML のコマンドの位置
+	assert_eq!(error_at("!kick\n  !snare", true), Some((ErrorType::NoteUnmapped { note_number: 37 }.to_string(), 2, 3)));
+	assert_eq!(error_at("!kick !tom", true), Some((ErrorType::DrumHitNotFound { name: "tom".to_string() }.to_string(), 1, 7)));
+	assert_eq!(error_at("o4 c 'c+eg'", true), Some((ErrorType::NoteUnmapped { note_number: 61 }.to_string(), 1, 6)));
+	assert_eq!(error_at("l8 'ceg'", false), Some((ErrorType::ChordOnMonophonicTrack.to_string(), 1, 4)));
+	// LFO の波形は定義済みの番号でなければならない
+	assert!(generate("@vib 5,10,0,3 r", false).is_none());
+	assert_eq!(error_at("r @vib 5,10,0,4", false), Some((ErrorType::BadLfoWaveform.to_string(), 1, 3)));
+	assert_eq!(error_at("r @trem 5,0.5,0,1.5", false), Some((ErrorType::BadLfoWaveform.to_string(), 1, 3)));
+}
+
+#[cfg(test)]
+#[test]
+fn test_param_macro_dialect() {
+	use parser::mml::dialect::{DefaultDialect, MckDialect};
+
+	// V は MCK の方言にしかないコマンド
+	let ast = CompilationUnit { commands: vec![
+		Command::ParamMacroDef { name: "m".to_string(), params: vec!["n".to_string()], body: "V127 %n".to_string() },
+		Command::ExpandMacro { name: "m".to_string(), args: vec!["r".to_string()], pos: MmlPos { line: 1, column: 3 } },
+	] };
+	let generate = |dialect: &dyn MmlDialect| {
+		let tag_set = TagSet { freq: "freq".to_string(), note: "note".to_string(), polyphonic: false, hits: HashMap::new() };
+		let locate_mml = |pos: MmlPos| Location { path: Rc::new(PathBuf::new()), line: pos.line, column: pos.column };
+		let param_default_keys = vec![("#velocity".to_string(), "value".to_string())].into_iter().collect();
+		generate_sequences(&ast, 384, &tag_set, &Tuning::default(), dialect, "", &HashMap::new(), &param_default_keys, &mut |_| Ok(0f32), &locate_mml)
+	};
+
+	// マクロの本体はトラックの方言で解析する。既定の方言では V から先を読めない
+	let has_velocity = |dialect: &dyn MmlDialect| generate(dialect).unwrap()[SEQUENCE_NAME_MAIN].iter()
+			.any(|instrc| matches!(instrc, Instruction::Value { tag, .. } if tag.contains("#velocity")));
+	assert!(has_velocity(&MckDialect));
+	assert!(! has_velocity(&DefaultDialect));
+	assert!(scan_features(&ast, &MckDialect).contains(&Feature::Velocity));
+	assert!(! scan_features(&ast, &DefaultDialect).contains(&Feature::Velocity));
+}
@@ -0,0 +1,335 @@
+use std::fmt::Display;
+
+/// A4 のノート番号
+const NOTE_A4: i32 = 69;
+const DEFAULT_PITCH: f32 = 440f32;
+
+/// 音高の決め方。
+/// ノート番号（C4 = 60、A4 = 69）から周波数を求める
+#[derive(Clone, Debug)]
+pub enum Tuning {
+	/// 1 オクターブ 12 音の音律。
+	/// cents は主音から数えた各音の主音からの音程（セント）、key は主音のピッチクラス（C = 0）、
+	/// pitch は A4 の周波数
+	Temperament { cents: [f32; 12], key: i32, pitch: f32 },
+	/// Scala 形式で与えられた音律
+	Scala { scale: ScalaScale, mapping: KeyboardMapping },
+}
+impl Tuning {
+	pub fn equal(pitch: f32) -> Self {
+		Self::temperament(Temperament::Equal, 0, pitch)
+	}
+
+	pub fn temperament(temperament: Temperament, key: i32, pitch: f32) -> Self {
+		Self::Temperament { cents: temperament.cents(), key: key.rem_euclid(12), pitch }
+	}
+
+	/// kbm が省略された場合は、C4 を 0 度、A4 を基準音とする直線的な割り当てとする
+	pub fn scala(scale: ScalaScale, mapping: Option<KeyboardMapping>, pitch: Option<f32>) -> Result<Self, TuningError> {
+		if scale.pitches.is_empty() {
+			return Err(TuningError::new("scale has no pitches"));
+		}
+		let mut mapping = mapping.unwrap_or_else(|| KeyboardMapping::linear(scale.pitches.len() as i32, DEFAULT_PITCH));
+		if let Some(pitch) = pitch {
+			mapping.reference_freq = pitch;
+		}
+		if mapping.degree_of(mapping.reference_note).is_none() {
+			return Err(TuningError::new("reference note is not mapped"));
+		}
+
+		Ok(Self::Scala { scale, mapping })
+	}
+
+	/// ノート番号から周波数を求める。
+	/// 鍵盤に割り当てのないノート番号（Scala の kbm で x を指定したものなど）の場合は None を返す。
+	/// ノート番号が整数でない場合、端数は平均律で補う
+	pub fn freq(&self, note_number: f32) -> Option<f32> {
+		let note = note_number.round() as i32;
+		let fraction = note_number - note as f32;
+		let freq = match self {
+			Self::Temperament { cents, key, pitch } => {
+				// 平均律からのずれ（セント）を、A4 のずれが 0 になるように補正して乗せる
+				let deviation = |note: i32| {
+					let degree = (note - key).rem_euclid(12);
+					cents[degree as usize] - 100f32 * degree as f32
+				};
+				let offset = 100f32 * (note - NOTE_A4) as f32 + deviation(note) - deviation(NOTE_A4);
+				pitch * 2f32.powf(offset / 1200f32)
+			}
+			Self::Scala { scale, mapping } => {
+				let cents = scale.cents_of_degree(mapping.degree_of(note) ?);
+				let ref_cents = scale.cents_of_degree(mapping.degree_of(mapping.reference_note) ?);
+				mapping.reference_freq * 2f32.powf((cents - ref_cents) / 1200f32)
+			}
+		};
+
+		Some(freq * 2f32.powf(fraction / 12f32))
+	}
+}
+impl Default for Tuning {
+	fn default() -> Self { Self::equal(DEFAULT_PITCH) }
+}
+
+/// 組み込みの音律
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Temperament {
+	Equal,
+	/// 5 限界の純正律
+	Just,
+	Pythagorean,
+	/// 1/4 コンマ中全音律
+	Meantone,
+	/// ヴェルクマイスター第 3 技法
+	Werckmeister,
+}
+impl Temperament {
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name {
+			"equal" => Some(Self::Equal),
+			"just" => Some(Self::Just),
+			"pythagorean" => Some(Self::Pythagorean),
+			"meantone" => Some(Self::Meantone),
+			"werckmeister" => Some(Self::Werckmeister),
+			_ => None,
+		}
+	}
+
+	/// 主音から数えた各音の、主音からの音程（セント）
+	pub fn cents(&self) -> [f32; 12] {
+		match self {
+			Self::Equal => {
+				let mut result = [0f32; 12];
+				for (i, c) in result.iter_mut().enumerate() { *c = 100f32 * i as f32; }
+				result
+			}
+			Self::Just => ratios_to_cents(&[
+				(1, 1), (16, 15), (9, 8), (6, 5), (5, 4), (4, 3),
+				(45, 32), (3, 2), (8, 5), (5, 3), (9, 5), (15, 8),
+			]),
+			// 主音から上下に 5 度を積む（増 4 度を含み、ウルフは減 6 度と増 5 度の間に置く）
+			Self::Pythagorean => ratios_to_cents(&[
+				(1, 1), (256, 243), (9, 8), (32, 27), (81, 64), (4, 3),
+				(729, 512), (3, 2), (128, 81), (27, 16), (16, 9), (243, 128),
+			]),
+			Self::Meantone => [
+				0f32, 76.049, 193.157, 310.265, 386.314, 503.422,
+				579.471, 696.579, 772.627, 889.735, 1006.843, 1082.892,
+			],
+			Self::Werckmeister => [
+				0f32, 90.225, 192.18, 294.135, 390.225, 498.045,
+				588.27, 696.09, 792.18, 888.27, 996.09, 1092.18,
+			],
+		}
+	}
+}
+
+fn ratios_to_cents(ratios: &[(i32, i32); 12]) -> [f32; 12] {
+	let mut result = [0f32; 12];
+	for (c, (num, den)) in result.iter_mut().zip(ratios.iter()) {
+		*c = ratio_to_cents(*num as f32 / *den as f32);
+	}
+	result
+}
+
+fn ratio_to_cents(ratio: f32) -> f32 {
+	1200f32 * ratio.log2()
+}
+
+/// Scala の .scl ファイルの内容
+#[derive(Clone, Debug)]
+pub struct ScalaScale {
+	pub description: String,
+	/// 1 度以降の各音の、0 度からの音程（セント）。最後の要素が周期（通常はオクターブ）となる
+	pub pitches: Vec<f32>,
+}
+impl ScalaScale {
+	fn cents_of_degree(&self, degree: i32) -> f32 {
+		let count = self.pitches.len() as i32;
+		let period = self.pitches[count as usize - 1];
+		let index = degree.rem_euclid(count);
+		let base = if index == 0 { 0f32 } else { self.pitches[index as usize - 1] };
+
+		base + period * degree.div_euclid(count) as f32
+	}
+}
+
+/// Scala の .kbm ファイルの内容
+#[derive(Clone, Debug)]
+pub struct KeyboardMapping {
+	/// 0 の場合は、すべてのノートを音階の度数に直線的に割り当てる
+	pub map_size: i32,
+	pub first_note: i32,
+	pub last_note: i32,
+	/// 0 度を割り当てるノート
+	pub middle_note: i32,
+	pub reference_note: i32,
+	pub reference_freq: f32,
+	/// map_size 個のノートを進んだときに進む度数
+	pub octave_degree: i32,
+	/// None は割り当てなし（kbm の x）
+	pub mapping: Vec<Option<i32>>,
+}
+impl KeyboardMapping {
+	fn linear(scale_size: i32, reference_freq: f32) -> Self {
+		Self {
+			map_size: 0,
+			first_note: 0,
+			last_note: 127,
+			middle_note: 60,
+			reference_note: NOTE_A4,
+			reference_freq,
+			octave_degree: scale_size,
+			mapping: vec![],
+		}
+	}
+
+	fn degree_of(&self, note: i32) -> Option<i32> {
+		if note < self.first_note || note > self.last_note { return None; }
+
+		let offset = note - self.middle_note;
+		if self.map_size == 0 {
+			Some(offset)
+		} else {
+			let index = offset.rem_euclid(self.map_size) as usize;
+			let octave = offset.div_euclid(self.map_size);
+			self.mapping.get(index).copied().flatten()
+					.map(|degree| degree + octave * self.octave_degree)
+		}
+	}
+}
+
+#[derive(Debug)]
+pub struct TuningError {
+	pub message: String,
+}
+impl TuningError {
+	fn new(message: &str) -> Self { Self { message: message.to_string() } }
+}
+impl Display for TuningError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}", self.message)
+	}
+}
+
+/// ! で始まる行はコメント。
+/// 空白以降は（数値のあとに書かれた説明なので）無視する
+fn scala_lines(source: &str) -> impl Iterator<Item = &str> {
+	source.lines().filter(|line| ! line.starts_with('!'))
+}
+
+fn first_token(line: &str) -> &str {
+	line.split_whitespace().next().unwrap_or("")
+}
+
+pub fn parse_scl(source: &str) -> Result<ScalaScale, TuningError> {
+	let mut lines = scala_lines(source);
+	let description = lines.next().ok_or_else(|| TuningError::new("description line missing"))?.trim().to_string();
+	let count = lines.next()
+			.and_then(|line| first_token(line).parse::<usize>().ok())
+			.ok_or_else(|| TuningError::new("note count missing or invalid"))?;
+
+	let pitches = lines.take(count)
+			.map(|line| parse_scl_pitch(first_token(line)))
+			.collect::<Result<Vec<_>, _>>()?;
+	if pitches.len() != count {
+		return Err(TuningError::new("too few pitches"));
+	}
+
+	Ok(ScalaScale { description, pitches })
+}
+
+/// ピリオドを含むものはセント値、それ以外は比（分母省略可）
+fn parse_scl_pitch(token: &str) -> Result<f32, TuningError> {
+	let bad_pitch = || TuningError { message: format!("bad pitch: {}", token) };
+	if token.contains('.') {
+		token.parse::<f32>().map_err(|_| bad_pitch())
+	} else {
+		let mut parts = token.splitn(2, '/');
+		let num = parts.next().unwrap().parse::<f32>().map_err(|_| bad_pitch())?;
+		let den = parts.next().map(|den| den.parse::<f32>().map_err(|_| bad_pitch())).transpose()?.unwrap_or(1f32);
+		if num <= 0f32 || den <= 0f32 { return Err(bad_pitch()); }
+
+		Ok(ratio_to_cents(num / den))
+	}
+}
+
+pub fn parse_kbm(source: &str) -> Result<KeyboardMapping, TuningError> {
+	let mut lines = scala_lines(source).map(first_token).filter(|token| ! token.is_empty());
+	let mut next_number = |name: &str| lines.next()
+			.and_then(|token| token.parse::<f32>().ok())
+			.ok_or_else(|| TuningError { message: format!("{} missing or invalid", name) });
+
+	let map_size = next_number("map size")? as i32;
+	let first_note = next_number("first note")? as i32;
+	let last_note = next_number("last note")? as i32;
+	let middle_note = next_number("middle note")? as i32;
+	let reference_note = next_number("reference note")? as i32;
+	let reference_freq = next_number("reference frequency")?;
+	let octave_degree = next_number("octave degree")? as i32;
+
+	// 省略された末尾の割り当ては x とみなす
+	let mapping = lines.take(map_size as usize).map(|token| {
+		if token == "x" {
+			Ok(None)
+		} else {
+			token.parse::<i32>().map(Some).map_err(|_| TuningError { message: format!("bad mapping: {}", token) })
+		}
+	}).collect::<Result<Vec<_>, _>>()?;
+
+	Ok(KeyboardMapping {
+		map_size,
+		first_note,
+		last_note,
+		middle_note,
+		reference_note,
+		reference_freq,
+		octave_degree,
+		mapping,
+	})
+}
+
+#[cfg(test)]
+fn assert_freq(tuning: &Tuning, note_number: f32, expected: f32) {
+	let actual = tuning.freq(note_number).unwrap();
+	assert!((actual - expected).abs() < 0.01, "note {}: expected {}, actual {}", note_number, expected, actual);
+}
+
+#[cfg(test)]
+#[test]
+fn test_temperament() {
+	assert_freq(&Tuning::default(), 69f32, 440f32);
+	assert_freq(&Tuning::default(), 60f32, 261.626);
+	assert_freq(&Tuning::equal(442f32), 81f32, 884f32);
+
+	// 主音 C の純正律では、C から見た E が 5/4 になる
+	let just = Tuning::temperament(Temperament::Just, 0, 440f32);
+	assert_freq(&just, 69f32, 440f32);
+	assert_freq(&just, 64f32, just.freq(60f32).unwrap() * 5f32 / 4f32);
+	assert_freq(&just, 67f32, just.freq(60f32).unwrap() * 3f32 / 2f32);
+
+	// 主音を D にすると D から見た A が 3/2 になる
+	let pythagorean_d = Tuning::temperament(Temperament::Pythagorean, 2, 440f32);
+	assert_freq(&pythagorean_d, 62f32, 440f32 * 2f32 / 3f32);
+}
+
+#[cfg(test)]
+#[test]
+fn test_scala() {
+	let scl = "! test.scl\n!\n5-tone equal\n 5\n!\n240.0\n480.0 cents\n720.0\n960.0\n2/1\n";
+	let scale = parse_scl(scl).unwrap();
+	assert_eq!(scale.description, "5-tone equal");
+	assert_eq!(scale.pitches.len(), 5);
+
+	let tuning = Tuning::scala(scale.clone(), None, None).unwrap();
+	assert_freq(&tuning, 69f32, 440f32);
+	assert_freq(&tuning, 74f32, 880f32);
+	assert_freq(&tuning, 70f32, 440f32 * 2f32.powf(240f32 / 1200f32));
+
+	// C D E G A の 5 鍵だけに割り当て、ほかの鍵は鳴らさない
+	let kbm = "! test.kbm\n12\n0\n127\n60\n69\n432.0\n5\n0\nx\n1\nx\n2\nx\nx\n3\nx\n4\nx\nx\n";
+	let tuning = Tuning::scala(scale, Some(parse_kbm(kbm).unwrap()), None).unwrap();
+	assert_freq(&tuning, 69f32, 432f32);
+	assert_freq(&tuning, 67f32, 432f32 * 2f32.powf(-240f32 / 1200f32));
+	assert_freq(&tuning, 72f32, 432f32 * 2f32.powf(240f32 / 1200f32));
+	assert!(tuning.freq(61f32).is_none());
+}
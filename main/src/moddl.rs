@@ -1,4 +1,6 @@
 pub mod builtin;
+pub mod checker;
+pub mod collection;
 pub mod common;
 pub mod console;
 pub mod error;
@@ -8,9 +10,12 @@ pub mod function;
 pub mod import;
 pub mod io;
 pub mod lambda_function;
+pub mod midi_export;
 pub mod path;
 pub mod player;
 pub mod player_context;
 pub mod player_option;
+pub mod repl;
 pub mod scope;
+pub mod string;
 pub mod value;
@@ -2,7 +2,7 @@ use parser::common::Location;
 
 /// ビルトイン変数を提供する。今後プラグインの読み込みなどをここでやる想定
 use super::{
-	error::*, function::*, import::ImportCache, io::*, scope::*, value::*
+	collection::*, error::*, function::*, import::ImportCache, io::*, scope::*, string::*, value::*
 };
 use crate::{
 	core::{
@@ -117,12 +117,60 @@ fn native_builtins(sample_rate: i32) -> HashMap<String, Value> {
 	add_function!(Ceil::name(), Ceil { });
 	add_function!(Round::name(), Round { });
 	add_function!(Trunc::name(), Trunc { });
+	add_function!(Sqrt::name(), Sqrt { });
+	add_function!(Exp::name(), Exp { });
+	add_function!(Min::name(), Min { });
+	add_function!(Max::name(), Max { });
+	add_function!(Pow::name(), Pow { });
+	add_function!(Atan2::name(), Atan2 { });
+	add_function!(Hypot::name(), Hypot { });
+	add_function!(Mod::name(), Mod { });
+	add_function!(Clamp::name(), Clamp { });
+	add_function!(Lerp::name(), Lerp { });
+
+	// music
+	add_function!(DbToAmp::name(), DbToAmp { });
+	add_function!(AmpToDb::name(), AmpToDb { });
+	add_function!(NoteToFreq::name(), NoteToFreq { });
+	add_function!(FreqToNote::name(), FreqToNote { });
+	add_function!(CentsToRatio::name(), CentsToRatio { });
+	add_function!(BpmToSeconds::name(), BpmToSeconds { });
 
 	// array
 	add_function!("at", At { });
 	// concat は flat を使って ModDL で実装する
 	// add_function!("concat", Concat { });
 	add_function!("flat", Flat { });
+	add_function!("len", Len { });
+	add_function!("range", Range { });
+	add_function!("zip", Zip { });
+	add_function!("enumerate", Enumerate { });
+	add_function!("slice", Slice { });
+	add_function!("reverse", Reverse { });
+	add_function!("sort", Sort { });
+	add_function!("find", Find { });
+	add_function!("any", Any { });
+	add_function!("all", All { });
+
+	// assoc
+	add_function!("keys", Keys { });
+	add_function!("values", Values { });
+	add_function!("entries", Entries { });
+	add_function!("has", Has { });
+	add_function!("merge", Merge { });
+	add_function!("remove", Remove { });
+	add_function!("fromEntries", FromEntries { });
+
+	// string
+	add_function!("substring", Substring { });
+	add_function!("split", Split { });
+	add_function!("join", Join { });
+	add_function!("replace", Replace { });
+	add_function!("toUpperCase", ToUpperCase { });
+	add_function!("toLowerCase", ToLowerCase { });
+	add_function!("formatNumber", FormatNumber { });
+	add_function!("format", Format { });
+	add_function!("mml", Mml { });
 
 	// type
 	add_function!("type", Type { });
@@ -214,28 +262,36 @@ impl Function for Delay {
 
 
 use crate::calc::*;
-macro_rules! unary_math_func {
-	($name: ident, $calc_type: ty) => {
+/// 引数が全て数値ならその場で計算し、そうでなければ Calc ノードの構造を返す
+fn call_math_func<C: 'static + Calc>(args: &HashMap<String, Value>, params: &[&str], call_loc: Location) -> ModdlResult<Value> {
+	let mut values = vec![];
+	for param in params {
+		values.push(get_required_arg(args, param, &call_loc) ?);
+	}
+	if let Some(vals) = values.iter().map(|(value, _)| value.as_float()).collect::<Option<Vec<_>>>() {
+		return Ok((ValueBody::Float(C::calc(&vals)), call_loc));
+	}
+
+	let mut strukts = vec![];
+	for (value, loc) in values {
+		let strukt = value.as_node_structure().ok_or_else(|| error(ErrorType::TypeMismatchAny {
+			expected: vec![ValueType::Number, ValueType::NodeStructure],
+		}, loc.clone())) ?;
+		strukts.push((strukt, loc.clone()));
+	}
+	Ok((ValueBody::NodeStructure(NodeStructure::Calc {
+		node_factory: Rc::new(CalcNodeFactory::<C>::new()),
+		args: strukts,
+	}), call_loc))
+}
+
+macro_rules! math_func {
+	($name: ident, $calc_type: ty, $($param: expr),+) => {
 		pub struct $name { }
 		impl Function for $name {
-			fn signature(&self) -> FunctionSignature { vec!["arg".to_string()] }
+			fn signature(&self) -> FunctionSignature { vec![$($param.to_string()),+] }
 			fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
-				let (arg, arg_loc) = args.get(& "arg".to_string())
-						.ok_or_else(|| error(ErrorType::ArgMissing { name: "arg".to_string() }, call_loc.clone())) ?;
-				if let Some(val) = arg.as_float() {
-					Ok((ValueBody::Float(<$calc_type>::calc(&vec![val])), call_loc))
-		
-				} else if let Some(val) = arg.as_node_structure() {
-					Ok((ValueBody::NodeStructure(NodeStructure::Calc {
-						node_factory: Rc::new(CalcNodeFactory::<$calc_type>::new()),
-						args: vec![Box::new(val)],
-					}), call_loc))
-		
-				} else {
-					Err(error(ErrorType::TypeMismatchAny {
-						expected: vec![ValueType::Number, ValueType::NodeStructure],
-					}, arg_loc.clone()))
-				}
+				call_math_func::<$calc_type>(args, &[$($param),+], call_loc)
 			}
 		}
 		impl $name {
@@ -243,6 +299,9 @@ macro_rules! unary_math_func {
 		}
 	}
 }
+macro_rules! unary_math_func {
+	($name: ident, $calc_type: ty) => { math_func!($name, $calc_type, "arg"); }
+}
 
 unary_math_func!(Log, LogCalc);
 unary_math_func!(Log10, Log10Calc);
@@ -255,6 +314,22 @@ unary_math_func!(Floor, FloorCalc);
 unary_math_func!(Ceil, CeilCalc);
 unary_math_func!(Round, RoundCalc);
 unary_math_func!(Trunc, TruncCalc);
+unary_math_func!(Sqrt, SqrtCalc);
+unary_math_func!(Exp, ExpCalc);
+math_func!(Min, MinCalc, "a", "b");
+math_func!(Max, MaxCalc, "a", "b");
+math_func!(Pow, PowFuncCalc, "base", "exponent");
+math_func!(Atan2, Atan2Calc, "y", "x");
+math_func!(Hypot, HypotCalc, "x", "y");
+math_func!(Mod, ModCalc, "a", "b");
+math_func!(Clamp, ClampCalc, "arg", "min", "max");
+math_func!(Lerp, LerpCalc, "from", "to", "rate");
+unary_math_func!(DbToAmp, DbToAmpCalc);
+unary_math_func!(AmpToDb, AmpToDbCalc);
+unary_math_func!(NoteToFreq, NoteToFreqCalc);
+unary_math_func!(FreqToNote, FreqToNoteCalc);
+unary_math_func!(CentsToRatio, CentsToRatioCalc);
+unary_math_func!(BpmToSeconds, BpmToSecondsCalc);
 
 // 最低限の配列操作のため、とりあえず map と reduce を作っておく
 
@@ -384,6 +459,7 @@ impl Function for Type {
 			ValueBody::TrackSet(_) => "TrackSet",
 			ValueBody::IdentifierLiteral(_) => "QuotedIdentifier",
 			ValueBody::String(_) => "String",
+			ValueBody::Mml(_) => "Mml",
 			ValueBody::Array(_) => "Array",
 			ValueBody::Assoc(_) => "Assoc",
 			ValueBody::NodeStructure(_) => "NodeStructure",
@@ -433,3 +509,31 @@ impl Function for ToString {
 // 	}
 // }
 
+
+#[cfg(test)]
+#[test]
+fn test_math_funcs() {
+	use super::evaluator::{evaluate_source, test_scope};
+
+	let functions: Vec<(&str, Rc<dyn Function>)> = vec![
+		("sin", Rc::new(Sin { })), ("min", Rc::new(Min { })), ("clamp", Rc::new(Clamp { })),
+	];
+	let vars = test_scope(functions.into_iter().map(|(name, function)| (name, ValueBody::Function(function))).collect());
+	let eval = |source: &str| evaluate_source(source, &vars);
+
+	// 引数が全て数値なら数値を返す
+	assert_eq!(eval("[min(1, 2), clamp(3, -1, 1), sin(0)]").ok().map(|(value, _)| value.to_str(|s| s.to_string())), Some("[1, 1, 0]".to_string()));
+
+	// ノードを含めば計算するノードになり、数値の引数は定数のノードになる
+	let node = |source: &str| eval(source).ok().and_then(|(value, _)| value.as_node_structure()).map(|strukt| strukt.to_string());
+	assert_eq!(node("clamp(0.5@x, -1, 1)"), Some("clamp(0.5@x, -1, 1)".to_string()));
+	assert_eq!(node("min(1, 0.5@x)"), Some("min(1, 0.5@x)".to_string()));
+	assert_eq!(node("sin(0.5@x)"), Some("sin(0.5@x)".to_string()));
+	assert!(matches!(eval("min(1, 0.5@x)").ok().unwrap().0, ValueBody::NodeStructure(NodeStructure::Calc { .. })));
+
+	// 数値にもノードにもならない引数は、その引数の位置で報告する
+	let e = eval("clamp(0.5@x, [1], 1)").err().unwrap();
+	assert!(matches!(e.body, ErrorType::TypeMismatchAny { .. }));
+	assert_eq!(e.loc.column, 14);
+	assert!(matches!(eval("min([1], 2)").err().unwrap().body, ErrorType::TypeMismatchAny { .. }));
+}
@@ -0,0 +1,196 @@
+use parser::common::Location;
+
+use super::{
+	error::*, lambda_function::Param, player_context::TrackDef, value::*
+};
+use crate::core::node_factory::NodeArgSpec;
+
+use std::collections::hash_map::HashMap;
+
+/// ノードを生成する前に全てのトラックの定義を検査し、チャンネル数の不整合や引数の型の誤りを報告する。
+/// エラーの位置は、問題の箇所を含む最も内側の被演算子や引数の位置になる
+pub fn check_track_defs(track_defs: &[(String, TrackDef, Location)]) -> ModdlResult<()> {
+	// エフェクトの入力になる、トラックの出力のチャンネル数
+	let mut outputs = HashMap::new();
+	let no_placeholders = HashMap::new();
+	for (track, spec, loc) in track_defs {
+		match spec {
+			// 楽器の入力は周波数なのでモノラル
+			TrackDef::Instrument(structure, _) => {
+				outputs.insert(track.clone(), check_structure(structure, 1, &no_placeholders, loc) ?);
+			},
+			TrackDef::DrumKit(hits) => {
+				let mut output = 1;
+				for hit in hits {
+					output = output.max(check_structure(&hit.structure, 1, &no_placeholders, loc) ?);
+				}
+				outputs.insert(track.clone(), output);
+			},
+			TrackDef::Effect(source_tracks, structure) => {
+				let placeholders = source_tracks.iter()
+						.map(|source| (source.clone(), outputs.get(source).copied().unwrap_or(1)))
+						.collect();
+				outputs.insert(track.clone(), check_structure(structure, 1, &placeholders, loc) ?);
+			},
+			// グルーヴの出力はタイマーとして使うのでモノラルでなければならない
+			TrackDef::Groove(structure) => {
+				expect_mono(check_structure(structure, 1, &no_placeholders, loc) ?, loc) ?;
+			},
+		}
+	}
+
+	Ok(())
+}
+
+/// 入力のチャンネル数が input のときの、構造の出力のチャンネル数を求める。
+/// placeholders はプレースホルダのチャンネル数、loc は strukt を含む最も内側の値の位置
+pub fn check_structure(strukt: &NodeStructure, input: i32, placeholders: &HashMap<String, i32>, loc: &Location) -> ModdlResult<i32> {
+	match strukt {
+		NodeStructure::Calc { args, .. } => {
+			// モノラルとステレオが混在していたらステレオに拡張する
+			let mut output = 1;
+			for (arg, arg_loc) in args {
+				match check_structure(arg, input, placeholders, arg_loc) ? {
+					1 => { },
+					2 => { output = 2; },
+					_ => { return Err(error(ErrorType::ChannelMismatch, arg_loc.clone())); },
+				}
+			}
+			Ok(output)
+		},
+		NodeStructure::Connect(lhs, rhs) => {
+			let (lhs, lhs_loc) = &**lhs;
+			let (rhs, rhs_loc) = &**rhs;
+			let lhs_output = check_structure(lhs, input, placeholders, lhs_loc) ?;
+			check_structure(rhs, lhs_output, placeholders, rhs_loc)
+		},
+		NodeStructure::Condition { cond, then, els } => {
+			// 条件分岐はステレオに未対応
+			for (branch, branch_loc) in [cond, then, els].map(|branch| &**branch) {
+				expect_mono(check_structure(branch, input, placeholders, branch_loc) ?, branch_loc) ?;
+			}
+			Ok(1)
+		},
+		NodeStructure::Lambda { input_param, params, args, body, loc: lambda_loc, .. } => {
+			// 引数は呼び出し側のプレースホルダで検査する
+			let mut inner_placeholders = placeholders.clone();
+			inner_placeholders.insert(input_param.clone(), input);
+			for Param { name, default } in params {
+				let value = args.get(name).or(default.as_ref())
+						.ok_or_else(|| error(ErrorType::ArgMissing { name: name.clone() }, lambda_loc.clone())) ?;
+				let (arg, arg_loc) = value.as_node_structure() ?;
+				inner_placeholders.insert(name.clone(), check_structure(&arg, input, placeholders, &arg_loc) ?);
+			}
+			check_structure(body, input, &inner_placeholders, loc)
+		},
+		NodeStructure::NodeCreation { factory, args, .. } => {
+			for NodeArgSpec { name, channels, default } in factory.node_arg_specs() {
+				match args.get(&name) {
+					Some(value) => {
+						let (arg, arg_loc) = value.as_node_structure() ?;
+						// モノラルの引数にステレオを与えると、変換で情報が失われるのでエラーにする
+						if ! can_coerce(check_structure(&arg, input, placeholders, &arg_loc) ?, channels) {
+							return Err(error(ErrorType::ChannelMismatch, arg_loc));
+						}
+					},
+					None if default.is_none() => { return Err(error(ErrorType::ArgMissing { name }, loc.clone())); },
+					None => { },
+				}
+			}
+			match (input, factory.input_channels()) {
+				(_, input_channels) if can_coerce(input, input_channels) => Ok(factory.output_channels()),
+				// モノラル入力のノードにステレオを入れると左右別々にノードを作って結合するので、
+				// ノードの出力はモノラルでなければならない
+				(2, 1) if factory.output_channels() == 1 => Ok(2),
+				_ => Err(error(ErrorType::ChannelMismatch, loc.clone())),
+			}
+		},
+		NodeStructure::Constant { .. } => Ok(1),
+		NodeStructure::Placeholder { name } => Ok(placeholders.get(name).copied().unwrap_or(1)),
+		NodeStructure::LabelGuard(inner) => check_structure(inner, input, placeholders, loc),
+	}
+}
+
+/// 変換なしで、またはモノラルからステレオへの拡張で、指定のチャンネル数にできるか
+fn can_coerce(actual: i32, expected: i32) -> bool {
+	matches!((actual, expected), (1, 1) | (1, 2) | (2, 2))
+}
+
+fn expect_mono(channels: i32, loc: &Location) -> ModdlResult<()> {
+	if channels == 1 { Ok(()) } else { Err(error(ErrorType::ChannelMismatch, loc.clone())) }
+}
+
+#[cfg(test)]
+#[test]
+fn test_check_structure() {
+	use crate::{core::node_factory::{NodeFactory, PanFactory}, node::arith::LimitFactory};
+	use std::{path::PathBuf, rc::Rc};
+
+	let loc_at = |line: u32| Location { path: Rc::new(PathBuf::new()), line, column: 1 };
+	let constant = |value: f32| NodeStructure::Constant { value, label: None };
+	let create = |factory: Rc<dyn NodeFactory>, args: Vec<(&str, NodeStructure, u32)>| NodeStructure::NodeCreation {
+		factory,
+		args: args.into_iter().map(|(name, arg, line)| (name.to_string(), (ValueBody::NodeStructure(arg), loc_at(line)))).collect(),
+		label: None,
+	};
+	let pan = || create(Rc::new(PanFactory { }), vec![("pos", constant(0f32), 2)]);
+	let limit = |min: NodeStructure| create(Rc::new(LimitFactory { }), vec![("min", min, 3), ("max", constant(1f32), 4)]);
+	let connect = |lhs: NodeStructure, rhs: NodeStructure| NodeStructure::Connect(Box::new((lhs, loc_at(7))), Box::new((rhs, loc_at(8))));
+	let check = |strukt: &NodeStructure| check_structure(strukt, 1, &HashMap::new(), &loc_at(1));
+
+	assert_eq!(check(&pan()).ok(), Some(2));
+	// ステレオの入力はモノラルのノードで左右別々に処理される
+	assert_eq!(check(&connect(pan(), limit(constant(-1f32)))).ok(), Some(2));
+	// モノラルの引数にはステレオを与えられない。エラーの位置は引数の位置
+	let error = check(&limit(pan())).err().unwrap();
+	assert!(matches!(error.body, ErrorType::ChannelMismatch));
+	assert_eq!(error.loc.line, 3);
+	// 出力がステレオのノードにステレオを入れることはできない。エラーの位置は入力を受ける側の位置
+	let error = check(&connect(pan(), pan())).err().unwrap();
+	assert!(matches!(error.body, ErrorType::ChannelMismatch));
+	assert_eq!(error.loc.line, 8);
+	let error = check(&NodeStructure::Condition {
+		cond: Box::new((constant(1f32), loc_at(9))),
+		then: Box::new((pan(), loc_at(10))),
+		els: Box::new((constant(0f32), loc_at(11))),
+	}).err().unwrap();
+	assert!(matches!(error.body, ErrorType::ChannelMismatch));
+	assert_eq!(error.loc.line, 10);
+	assert!(matches!(check(&create(Rc::new(LimitFactory { }), vec![("min", constant(0f32), 3)])).err().unwrap().body, ErrorType::ArgMissing { .. }));
+
+	// 式で記述されたノードの入力と引数のチャンネル数はプレースホルダに引き継がれる
+	let lambda = |arg: NodeStructure| NodeStructure::Lambda {
+		input_param: "x".to_string(),
+		params: vec![Param { name: "y".to_string(), default: None }],
+		args: vec![("y".to_string(), (ValueBody::NodeStructure(arg), loc_at(5)))].into_iter().collect(),
+		label: None,
+		body: Box::new(limit(NodeStructure::Placeholder { name: "y".to_string() })),
+		loc: loc_at(6),
+	};
+	assert_eq!(check(&lambda(constant(0f32))).ok(), Some(1));
+	assert!(matches!(check(&lambda(pan())).err().unwrap().body, ErrorType::ChannelMismatch));
+}
+
+#[cfg(test)]
+#[test]
+fn test_check_structure_location() {
+	use super::evaluator::{evaluate_source, test_scope};
+	use crate::core::node_factory::PanFactory;
+	use std::rc::Rc;
+
+	// 式から評価した構造のエラーは、問題のある被演算子を書いた位置で報告する
+	let vars = test_scope(vec![("pan", ValueBody::NodeFactory(Rc::new(PanFactory { })))]);
+	let check = |source: &str| {
+		let strukt = evaluate_source(source, &vars).unwrap().as_node_structure().unwrap().0;
+		check_structure(&strukt, 1, &HashMap::new(), &Location::dummy()).err().unwrap()
+	};
+	let error = check("1 | pan");
+	assert!(matches!(error.body, ErrorType::ArgMissing { .. }));
+	assert_eq!((error.loc.line, error.loc.column), (1, 5));
+	let error = check("pan { pos: 0 } |\n  pan { pos: 0 }");
+	assert!(matches!(error.body, ErrorType::ChannelMismatch));
+	assert_eq!((error.loc.line, error.loc.column), (2, 3));
+	let error = check("if 1@c then pan { pos: 0 } else 0");
+	assert!(matches!(error.body, ErrorType::ChannelMismatch));
+	assert_eq!((error.loc.line, error.loc.column), (1, 13));
+}
@@ -0,0 +1,390 @@
+use parser::common::Location;
+
+// 配列と連想配列を操作するビルトイン関数
+use super::{
+	error::*, function::*, import::ImportCache, scope::*, value::*
+};
+
+use std::{
+	cell::RefCell, cmp::Ordering, collections::hash_map::HashMap, rc::Rc
+};
+
+fn bool_value(value: bool, loc: Location) -> Value {
+	(if value { true_value() } else { false_value() }.0, loc)
+}
+
+fn call_unary(function: &Rc<dyn Function>, function_loc: &Location, arg: &Value, vars: &Rc<RefCell<Scope>>, imports: &mut ImportCache) -> ModdlResult<Value> {
+	let sig = function.signature();
+	check_arity(&sig, 1, function_loc) ?;
+	function.call(& HashMap::from([(sig[0].clone(), arg.clone())]), vars, function_loc.clone(), imports)
+}
+
+/// 連想配列のキーには文字列と識別子リテラルのどちらも使える
+fn get_key(value: &Value) -> ModdlResult<String> {
+	let (value, loc) = value;
+	value.as_string().or_else(|| value.as_identifier_literal())
+			.ok_or_else(|| error(ErrorType::TypeMismatchAny { expected: vec![ValueType::String, ValueType::QuotedIdentifier] }, loc.clone()))
+}
+
+fn get_index(value: &Value) -> ModdlResult<usize> {
+	let (index, loc) = value.as_float() ?;
+	if index.fract() != 0f32 { return Err(error(ErrorType::BadIndex, loc)); }
+	if index < 0f32 { return Err(error(ErrorType::IndexOutOfBounds, loc)); }
+	Ok(index as usize)
+}
+
+/// キーの順に並べた連想配列の要素。HashMap の順序は不定なので、結果が毎回変わらないようにする
+fn sorted_entries(assoc: &HashMap<String, Value>) -> Vec<(&String, &Value)> {
+	let mut entries: Vec<_> = assoc.iter().collect();
+	entries.sort_by_key(|(key, _)| *key);
+	entries
+}
+
+pub struct Len { }
+impl Function for Len {
+	fn signature(&self) -> FunctionSignature { vec!["source".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
+		let (source, source_loc) = get_required_arg(args, "source", &call_loc) ?;
+
+		let len = match source {
+			ValueBody::Array(content) => content.len(),
+			ValueBody::Assoc(content) => content.len(),
+			ValueBody::String(content) => content.chars().count(),
+			_ => Err(error(ErrorType::TypeMismatchAny { expected: vec![ValueType::Array, ValueType::Assoc, ValueType::String] }, source_loc.clone())) ?,
+		};
+
+		Ok((ValueBody::Float(len as f32), call_loc))
+	}
+}
+
+/// range で作れる配列の最大の要素数
+pub const MAX_RANGE_LENGTH: usize = 1_000_000;
+
+pub struct Range { }
+impl Function for Range {
+	fn signature(&self) -> FunctionSignature { vec!["from".to_string(), "to".to_string(), "step".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
+		let (from, from_loc) = get_required_arg(args, "from", &call_loc)?.as_float() ?;
+		let (to, to_loc) = get_required_arg(args, "to", &call_loc)?.as_float() ?;
+		let (step, step_loc) = match get_optional_arg(args, "step") {
+			Some(step) => step.as_float() ?,
+			None => (1f32, call_loc.clone()),
+		};
+		// 無限大や NaN では終わらなくなる
+		for (value, loc) in [(from, from_loc), (to, to_loc), (step, step_loc.clone())] {
+			if ! value.is_finite() { return Err(error(ErrorType::BadRange, loc)); }
+		}
+		if step == 0f32 { return Err(error(ErrorType::BadRangeStep, step_loc)); }
+
+		// to は含まない。要素数を先に求めて、巨大な配列を作らないようにする
+		let (from, to, step) = (from as f64, to as f64, step as f64);
+		let count = ((to - from) / step).ceil().max(0f64);
+		if count > MAX_RANGE_LENGTH as f64 { return Err(error(ErrorType::BadRange, call_loc)); }
+		// 誤差が溜まらないよう、足し込まずに毎回掛け算で求める
+		let result = (0 .. count as usize).map(|i| {
+			(ValueBody::Float((from + i as f64 * step) as f32), call_loc.clone())
+		}).collect();
+
+		Ok((ValueBody::Array(result), call_loc))
+	}
+}
+
+pub struct Zip { }
+impl Function for Zip {
+	fn signature(&self) -> FunctionSignature { vec!["first".to_string(), "second".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
+		let (first, _) = get_required_arg(args, "first", &call_loc)?.as_array() ?;
+		let (second, _) = get_required_arg(args, "second", &call_loc)?.as_array() ?;
+
+		// 長さが違う場合は短い方に合わせる
+		let result = first.iter().zip(second.iter()).map(|(elem1, elem2)| {
+			(ValueBody::Array(vec![elem1.clone(), elem2.clone()]), call_loc.clone())
+		}).collect();
+
+		Ok((ValueBody::Array(result), call_loc))
+	}
+}
+
+pub struct Enumerate { }
+impl Function for Enumerate {
+	fn signature(&self) -> FunctionSignature { vec!["source".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
+		let (source, _) = get_required_arg(args, "source", &call_loc)?.as_array() ?;
+
+		let result = source.iter().enumerate().map(|(i, elem)| {
+			(ValueBody::Array(vec![(ValueBody::Float(i as f32), call_loc.clone()), elem.clone()]), call_loc.clone())
+		}).collect();
+
+		Ok((ValueBody::Array(result), call_loc))
+	}
+}
+
+pub struct Slice { }
+impl Function for Slice {
+	fn signature(&self) -> FunctionSignature { vec!["source".to_string(), "start".to_string(), "end".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
+		let (source, _) = get_required_arg(args, "source", &call_loc)?.as_array() ?;
+		let start = get_index(get_required_arg(args, "start", &call_loc)?) ?;
+		// end は含まない。省略時は末尾まで
+		let end = match get_optional_arg(args, "end") {
+			Some(end) => get_index(end) ?,
+			None => source.len(),
+		};
+		let result = source.get(start .. end).ok_or_else(|| error(ErrorType::IndexOutOfBounds, call_loc.clone())) ?;
+
+		Ok((ValueBody::Array(result.to_vec()), call_loc))
+	}
+}
+
+pub struct Reverse { }
+impl Function for Reverse {
+	fn signature(&self) -> FunctionSignature { vec!["source".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
+		let (source, _) = get_required_arg(args, "source", &call_loc)?.as_array() ?;
+
+		Ok((ValueBody::Array(source.iter().rev().cloned().collect()), call_loc))
+	}
+}
+
+pub struct Sort { }
+impl Function for Sort {
+	fn signature(&self) -> FunctionSignature { vec!["source".to_string(), "key".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, vars: &Rc<RefCell<Scope>>, call_loc: Location, imports: &mut ImportCache) -> ModdlResult<Value> {
+		let (source, _) = get_required_arg(args, "source", &call_loc)?.as_array() ?;
+		let key = match get_optional_arg(args, "key") {
+			Some(key) => Some(key.as_function() ?),
+			None => None,
+		};
+
+		// key を指定した場合は、key で要素を変換した値で比べる。比べられるのは数値どうしか文字列どうしのみ
+		let mut keyed = vec![];
+		for elem in source {
+			let sort_key = match &key {
+				Some((key, key_loc)) => call_unary(key, key_loc, elem, vars, imports) ?,
+				None => elem.clone(),
+			};
+			keyed.push((sort_key, elem.clone()));
+		}
+		let all_numbers = keyed.iter().all(|((k, _), _)| matches!(k, ValueBody::Float(_)));
+		let all_strings = keyed.iter().all(|((k, _), _)| matches!(k, ValueBody::String(_)));
+		if ! all_numbers && ! all_strings {
+			let ((_, loc), _) = keyed.iter().find(|((k, _), _)| ! matches!(k, ValueBody::Float(_) | ValueBody::String(_)))
+					.unwrap_or(&keyed[0]);
+			return Err(error(ErrorType::TypeMismatchAny { expected: vec![ValueType::Number, ValueType::String] }, loc.clone()));
+		}
+
+		// 安定ソート
+		keyed.sort_by(|((k1, _), _), ((k2, _), _)| match (k1, k2) {
+			(ValueBody::Float(v1), ValueBody::Float(v2)) => v1.partial_cmp(v2).unwrap_or(Ordering::Equal),
+			(ValueBody::String(v1), ValueBody::String(v2)) => v1.cmp(v2),
+			_ => Ordering::Equal,
+		});
+
+		Ok((ValueBody::Array(keyed.into_iter().map(|(_, elem)| elem).collect()), call_loc))
+	}
+}
+
+pub struct Find { }
+impl Function for Find {
+	fn signature(&self) -> FunctionSignature { vec!["source".to_string(), "predicate".to_string(), "default".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, vars: &Rc<RefCell<Scope>>, call_loc: Location, imports: &mut ImportCache) -> ModdlResult<Value> {
+		let (source, _) = get_required_arg(args, "source", &call_loc)?.as_array() ?;
+		let (predicate, predicate_loc) = get_required_arg(args, "predicate", &call_loc)?.as_function() ?;
+
+		for elem in source {
+			if call_unary(&predicate, &predicate_loc, elem, vars, imports)?.as_boolean()?.0 {
+				return Ok(elem.clone());
+			}
+		}
+
+		// 見つからなければ default を返す。default もなければエラー
+		get_optional_arg(args, "default").cloned()
+				.ok_or_else(|| error(ErrorType::ElementNotFound, call_loc.clone()))
+	}
+}
+
+pub struct Any { }
+impl Function for Any {
+	fn signature(&self) -> FunctionSignature { vec!["source".to_string(), "predicate".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, vars: &Rc<RefCell<Scope>>, call_loc: Location, imports: &mut ImportCache) -> ModdlResult<Value> {
+		let (source, _) = get_required_arg(args, "source", &call_loc)?.as_array() ?;
+		let (predicate, predicate_loc) = get_required_arg(args, "predicate", &call_loc)?.as_function() ?;
+
+		for elem in source {
+			if call_unary(&predicate, &predicate_loc, elem, vars, imports)?.as_boolean()?.0 {
+				return Ok(bool_value(true, call_loc));
+			}
+		}
+		Ok(bool_value(false, call_loc))
+	}
+}
+
+pub struct All { }
+impl Function for All {
+	fn signature(&self) -> FunctionSignature { vec!["source".to_string(), "predicate".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, vars: &Rc<RefCell<Scope>>, call_loc: Location, imports: &mut ImportCache) -> ModdlResult<Value> {
+		let (source, _) = get_required_arg(args, "source", &call_loc)?.as_array() ?;
+		let (predicate, predicate_loc) = get_required_arg(args, "predicate", &call_loc)?.as_function() ?;
+
+		for elem in source {
+			if ! call_unary(&predicate, &predicate_loc, elem, vars, imports)?.as_boolean()?.0 {
+				return Ok(bool_value(false, call_loc));
+			}
+		}
+		Ok(bool_value(true, call_loc))
+	}
+}
+
+pub struct Keys { }
+impl Function for Keys {
+	fn signature(&self) -> FunctionSignature { vec!["source".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
+		let (source, _) = get_required_arg(args, "source", &call_loc)?.as_assoc() ?;
+
+		let result = sorted_entries(source).into_iter()
+				.map(|(key, _)| (ValueBody::String(key.clone()), call_loc.clone()))
+				.collect();
+
+		Ok((ValueBody::Array(result), call_loc))
+	}
+}
+
+pub struct Values { }
+impl Function for Values {
+	fn signature(&self) -> FunctionSignature { vec!["source".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
+		let (source, _) = get_required_arg(args, "source", &call_loc)?.as_assoc() ?;
+
+		let result = sorted_entries(source).into_iter().map(|(_, value)| value.clone()).collect();
+
+		Ok((ValueBody::Array(result), call_loc))
+	}
+}
+
+pub struct Entries { }
+impl Function for Entries {
+	fn signature(&self) -> FunctionSignature { vec!["source".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
+		let (source, _) = get_required_arg(args, "source", &call_loc)?.as_assoc() ?;
+
+		let result = sorted_entries(source).into_iter().map(|(key, value)| {
+			(ValueBody::Array(vec![(ValueBody::String(key.clone()), call_loc.clone()), value.clone()]), call_loc.clone())
+		}).collect();
+
+		Ok((ValueBody::Array(result), call_loc))
+	}
+}
+
+pub struct Has { }
+impl Function for Has {
+	fn signature(&self) -> FunctionSignature { vec!["source".to_string(), "key".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
+		let (source, _) = get_required_arg(args, "source", &call_loc)?.as_assoc() ?;
+		let key = get_key(get_required_arg(args, "key", &call_loc)?) ?;
+
+		Ok(bool_value(source.contains_key(&key), call_loc))
+	}
+}
+
+pub struct Merge { }
+impl Function for Merge {
+	fn signature(&self) -> FunctionSignature { vec!["first".to_string(), "second".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
+		let (first, _) = get_required_arg(args, "first", &call_loc)?.as_assoc() ?;
+		let (second, _) = get_required_arg(args, "second", &call_loc)?.as_assoc() ?;
+
+		// 同じキーがあれば second の値を使う
+		let mut result = first.clone();
+		result.extend(second.iter().map(|(key, value)| (key.clone(), value.clone())));
+
+		Ok((ValueBody::Assoc(result), call_loc))
+	}
+}
+
+pub struct Remove { }
+impl Function for Remove {
+	fn signature(&self) -> FunctionSignature { vec!["source".to_string(), "key".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
+		let (source, _) = get_required_arg(args, "source", &call_loc)?.as_assoc() ?;
+		let key_val = get_required_arg(args, "key", &call_loc) ?;
+		let key = get_key(key_val) ?;
+
+		let mut result = source.clone();
+		result.remove(&key).ok_or_else(|| error(ErrorType::EntryNotFound { name: key.clone() }, key_val.1.clone())) ?;
+
+		Ok((ValueBody::Assoc(result), call_loc))
+	}
+}
+
+pub struct FromEntries { }
+impl Function for FromEntries {
+	fn signature(&self) -> FunctionSignature { vec!["entries".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
+		let (entries, _) = get_required_arg(args, "entries", &call_loc)?.as_array() ?;
+
+		// 各要素は [key, value] の配列。同じキーがあれば後のものを使う
+		let mut result = HashMap::new();
+		for entry in entries {
+			let (pair, pair_loc) = entry.as_array() ?;
+			let [key, value] = pair.as_slice() else {
+				return Err(error(ErrorType::IndexOutOfBounds, pair_loc));
+			};
+			result.insert(get_key(key) ?, value.clone());
+		}
+
+		Ok((ValueBody::Assoc(result), call_loc))
+	}
+}
+
+#[cfg(test)]
+#[test]
+fn test_collection_functions() {
+	use super::evaluator::{evaluate_source, test_scope};
+
+	let functions: Vec<(&str, Rc<dyn Function>)> = vec![
+		("len", Rc::new(Len { })), ("range", Rc::new(Range { })), ("zip", Rc::new(Zip { })), ("enumerate", Rc::new(Enumerate { })),
+		("slice", Rc::new(Slice { })), ("reverse", Rc::new(Reverse { })), ("sort", Rc::new(Sort { })), ("find", Rc::new(Find { })),
+		("any", Rc::new(Any { })), ("all", Rc::new(All { })), ("keys", Rc::new(Keys { })), ("values", Rc::new(Values { })),
+		("entries", Rc::new(Entries { })), ("has", Rc::new(Has { })), ("merge", Rc::new(Merge { })), ("remove", Rc::new(Remove { })),
+		("fromEntries", Rc::new(FromEntries { })),
+	];
+	let vars = test_scope(functions.into_iter().map(|(name, function)| (name, ValueBody::Function(function))).collect());
+	let eval = |source: &str| evaluate_source(source, &vars).map(|(value, _)| value.to_str(|s| s.to_string()));
+
+	assert_eq!(eval("len([1, 2, 3]) + len({ a: 1 }) + len(\"ab\")").ok(), Some("6".to_string()));
+	assert_eq!(eval("range(0, 3)").ok(), Some("[0, 1, 2]".to_string()));
+	assert_eq!(eval("range(3, 0, -1.5)").ok(), Some("[3, 1.5]".to_string()));
+	assert_eq!(eval("zip([1, 2, 3], [4, 5])").ok(), Some("[[1, 4], [2, 5]]".to_string()));
+	assert_eq!(eval("enumerate([5, 6])~reverse()").ok(), Some("[[1, 6], [0, 5]]".to_string()));
+	assert_eq!(eval("slice([1, 2, 3, 4], 1, 3)").ok(), Some("[2, 3]".to_string()));
+	assert_eq!(eval("sort([3, 1, 2])").ok(), Some("[1, 2, 3]".to_string()));
+	assert_eq!(eval("sort([\"b\", \"c\", \"a\"], s => s)").ok(), Some("[a, b, c]".to_string()));
+	assert_eq!(eval("sort([1, 3, 2], x => -x)").ok(), Some("[3, 2, 1]".to_string()));
+	assert_eq!(eval("find([1, 2, 3], x => x > 1) + find([1], x => x > 1, 10)").ok(), Some("12".to_string()));
+	assert_eq!(eval("[any([1, 2], x => x > 1), all([1, 2], x => x > 1)]").ok(), Some("[1, -1]".to_string()));
+	assert_eq!(eval("[keys({ b: 1, a: 2 }), values({ b: 1, a: 2 })]").ok(), Some("[[a, b], [2, 1]]".to_string()));
+	assert_eq!(eval("entries({ b: 1, a: 2 })").ok(), Some("[[a, 2], [b, 1]]".to_string()));
+	assert_eq!(eval("[has({ a: 1 }, :a), has({ a: 1 }, \"b\")]").ok(), Some("[1, -1]".to_string()));
+	assert_eq!(eval("values(merge({ a: 1, b: 2 }, { b: 3 }) ~remove(:a))").ok(), Some("[3]".to_string()));
+	assert_eq!(eval("values(fromEntries([[\"a\", 1], [:b, 2]]))").ok(), Some("[1, 2]".to_string()));
+
+	assert!(matches!(eval("len(1)").err().unwrap().body, ErrorType::TypeMismatchAny { .. }));
+	assert!(matches!(eval("range(0, 1, 0)").err().unwrap().body, ErrorType::BadRangeStep));
+	// 無限大や NaN を与えると終わらなくなる
+	assert!(matches!(eval("range(0, 1 / 0)").err().unwrap().body, ErrorType::BadRange));
+	assert!(matches!(eval("range(0, 1, 0 / 0)").err().unwrap().body, ErrorType::BadRange));
+	// 要素が多すぎる
+	assert!(matches!(eval("range(0, 1000000000)").err().unwrap().body, ErrorType::BadRange));
+	assert!(matches!(eval("range(0, 1, 1 / 1000000000)").err().unwrap().body, ErrorType::BadRange));
+	assert_eq!(eval("len(range(0, 1000000))").ok(), Some("1000000".to_string()));
+	assert_eq!(eval("range(0, 3, -1)").ok(), Some("[]".to_string()));
+	assert!(matches!(eval("slice([1, 2], 1, 3)").err().unwrap().body, ErrorType::IndexOutOfBounds));
+	assert!(matches!(eval("slice([1, 2], -1)").err().unwrap().body, ErrorType::IndexOutOfBounds));
+	assert!(matches!(eval("slice([1, 2], 0.5)").err().unwrap().body, ErrorType::BadIndex));
+	assert!(matches!(eval("sort([1, \"a\"])").err().unwrap().body, ErrorType::TypeMismatchAny { .. }));
+	assert!(matches!(eval("find([1], x => x > 1)").err().unwrap().body, ErrorType::ElementNotFound));
+	assert!(matches!(eval("remove({ a: 1 }, :b)").err().unwrap().body, ErrorType::EntryNotFound { .. }));
+	assert!(matches!(eval("fromEntries([[1, 2]])").err().unwrap().body, ErrorType::TypeMismatchAny { .. }));
+	assert!(matches!(eval("fromEntries([[:a]])").err().unwrap().body, ErrorType::IndexOutOfBounds));
+	assert!(matches!(eval("keys([1])").err().unwrap().body, ErrorType::TypeMismatch { .. }));
+}
@@ -5,9 +5,9 @@ use parser::common::Location;
 use super::error::{error, ModdlResult};
 
 pub fn read_file(path: &Path) -> ModdlResult<String> {
-	let mut file = File::open(path).map_err(|e| error(e.into(), Location::dummy())) ?;
+	let mut file = File::open(path).map_err(|e| error(e.into(), Location::file(path))) ?;
 	let mut moddl = String::new();
-	file.read_to_string(&mut moddl).map_err(|e| error(e.into(), Location::dummy())) ?;
+	file.read_to_string(&mut moddl).map_err(|e| error(e.into(), Location::file(path))) ?;
 
 	Ok(moddl)
 }
@@ -43,13 +43,45 @@ pub enum ErrorType {
 	GrooveControllerTrackMustBeSingle,
 	GrooveTargetDuplicate { track: String, existing_assign_loc: Location },
 	OptionNotAllowedHere,
+	BadSeed,
+	SeedAlreadyFixed,
 	IndexOutOfBounds,
+	BadIndex,
+	ElementNotFound,
+	BadRangeStep,
+	BadRange,
+	UnclosedFormatPlaceholder,
 	ExportDuplicate,
 	ExportNotFound,
+	ImportCycle { chain: Vec<String> },
 	LabelFilterInconsistent,
 	BadWaveform, // こういうの一つ一つ専用エラーにするのってどうなんだろう…
 
 	TickUnderflow { length: Length },
+	NoteUnmapped { note_number: i32 },
+	UnknownTemperament { name: String },
+	BadTuning,
+	BadTuningFile { path: String, message: String },
+	BadPolyphony,
+	UnknownStealingPolicy { name: String },
+	ChordOnMonophonicTrack,
+	BadDrumKit,
+	DrumHitNotFound { name: String },
+	AutoPanOnStereoTrack,
+	BadLfoWaveform,
+	MacroNotFound { name: String },
+	MacroArgCountMismatch { name: String, expected: usize, actual: usize },
+	MacroExpansionTooDeep { name: String },
+	MacroParamNotFound { name: String, param: String },
+	CallTooDeep { max_depth: usize },
+	MarkerMismatch { name: String, track1: String, tick1: i32, track2: String, tick2: i32 },
+	MarkerNotFound { name: String },
+	BadLoopRange,
+	BadHumanize,
+	BadMidiImport,
+	BadMidiFile { path: String, message: String },
+	UnknownMmlDialect { name: String },
+	MidiImportDialectMismatch { track: String, dialect: String },
 	// TODO イベントキューあふれとか、演奏時のエラーをラップする
 	Playing,
 	File(io::Error),
@@ -68,23 +100,57 @@ impl Display for ErrorType {
 					=> write!(f, "Definition for track ^{} is duplicate: definition already exists at {}.", track, existing_def_loc),
 			Self::VarNotFound { var } => write!(f, "Variable `{}` not found.", var),
 			// NodeFactoryNotFound,
-			// ChannelMismatch,
+			Self::ChannelMismatch => write!(f, "Channel mismatch: the number of channels does not fit here (e.g. a stereo signal is given where a monaural one is required)."),
 			Self::TypeMismatch { expected }=> write!(f, "Type mismatch: expected: {}", expected),
 			Self::TypeMismatchAny { expected }=> write!(f, "Type mismatch: expected one of: {}", expected.iter().join(", ")),
 			Self::ArgMissing { name } => write!(f, "Function argument `{}` missing.", name),
 			Self::ArityMismatch { expected, actual }
 					=> write!(f, "Arity mismatch: given function is expected to take {} argument{}, but actually takes {}.",
 							expected, if *expected == 1 { "" } else { "s" }, actual),
-			// EntryDuplicate { name: String },
-			// EntryNotFound { name: String },
+			Self::EntryDuplicate { name } => write!(f, "Entry `{}` is duplicate.", name),
+			Self::EntryNotFound { name } => write!(f, "Entry `{}` not found.", name),
 			// TooManyUnnamedArgs,
 			Self::GrooveControllerTrackMustBeSingle => write!(f, "Groove controller track must be single."),
 			Self::GrooveTargetDuplicate { track, existing_assign_loc }
 					=> write!(f, "Groove controller track for track {} is duplicate: already assigned at {}.", track, existing_assign_loc),
 			Self::OptionNotAllowedHere => write!(f, "Options must be placed at the head of a source file."),
+			Self::BadSeed => write!(f, "Seed must be a non-negative integer."),
+			Self::SeedAlreadyFixed => write!(f, "Seed must be specified before random numbers are used or another seed is specified."),
 			Self::ExportDuplicate => write!(f, "Duplicate export found."),
 			Self::ExportNotFound => write!(f, "Export expected but not found."),
+			Self::ImportCycle { chain } => write!(f, "Circular import: {}", chain.iter().join(" -> ")),
+			Self::IndexOutOfBounds => write!(f, "Index out of bounds."),
+			Self::BadIndex => write!(f, "Index must be an integer."),
+			Self::ElementNotFound => write!(f, "No element satisfies the predicate."),
+			Self::BadRangeStep => write!(f, "Step of range must not be 0."),
+			Self::BadRange => write!(f, "Start, end and step of range must be finite numbers, and range must not have more than {} elements.", super::collection::MAX_RANGE_LENGTH),
+			Self::UnclosedFormatPlaceholder => write!(f, "Placeholder in format template is not closed with }}."),
 			Self::BadWaveform => write!(f, "Bad waveform specification: either \"data\" or \"path\" (not both) is required, and \"data\" requires \"sampleRate\"."),
+			Self::NoteUnmapped { note_number } => write!(f, "Note number {} is not mapped in the current tuning.", note_number),
+			Self::UnknownTemperament { name } => write!(f, "Unknown temperament: {}", name),
+			Self::BadTuning => write!(f, "Bad tuning specification: \"scl\" and \"kbm\" cannot be used with \"temperament\" or \"key\", and \"key\" must be a pitch class number or a tone name."),
+			Self::BadTuningFile { path, message } => write!(f, "Bad tuning file {}: {}", path, message),
+			Self::BadPolyphony => write!(f, "Bad polyphony specification: \"polyphony\" must be a positive integer."),
+			Self::UnknownStealingPolicy { name } => write!(f, "Unknown voice stealing policy: {}", name),
+			Self::ChordOnMonophonicTrack => write!(f, "Chords can be used only in polyphonic tracks."),
+			Self::BadDrumKit => write!(f, "Bad drum kit specification: hit names must consist of letters and _, \"note\" must be a distinct note number, and \"velocity\" must be a number."),
+			Self::DrumHitNotFound { name } => write!(f, "Drum hit !{} is not defined for the track.", name),
+			Self::AutoPanOnStereoTrack => write!(f, "Auto-pan can be used only in tracks with monaural output."),
+			Self::BadLfoWaveform => write!(f, "LFO waveform must be 0 (sine), 1 (triangle), 2 (square) or 3 (sawtooth)."),
+			Self::MacroNotFound { name } => write!(f, "Macro not found: {}", name),
+			Self::MacroArgCountMismatch { name, expected, actual } => write!(f, "Macro {} takes {} argument(s) but {} given.", name, expected, actual),
+			Self::MacroExpansionTooDeep { name } => write!(f, "Too deep expansion of macro {} (maybe recursive)", name),
+			Self::MacroParamNotFound { name, param } => write!(f, "Macro {} has no parameter named {}", name, param),
+			Self::CallTooDeep { max_depth } => write!(f, "Function calls nested deeper than {} (maybe infinite recursion)", max_depth),
+			Self::MarkerMismatch { name, track1, tick1, track2, tick2 } => write!(f, "Marker {} is at tick {} in track {} but at tick {} in track {}.", name, tick1, track1, tick2, track2),
+			Self::MarkerNotFound { name } => write!(f, "Marker not found: {}", name),
+			Self::BadLoopRange => write!(f, "Loop end must be after loop start."),
+			Self::BadHumanize => write!(f, "Humanize settings must not be negative."),
+			Self::BadMidiImport => write!(f, "Bad MIDI import specification: \"by\" must be :channel or :track, \"select\" must be non-negative integers, and \"cc\" keys must be controller numbers from 0 to 127."),
+			Self::BadMidiFile { path, message } => write!(f, "Bad MIDI file {}: {}", path, message),
+			Self::UnknownMmlDialect { name } => write!(f, "Unknown MML dialect: {}", name),
+			Self::MidiImportDialectMismatch { track, dialect }
+					=> write!(f, "MIDI cannot be imported to track ^{} because it is written in MML dialect {}.", track, dialect),
 			// Playing,
 			// File(io::Error),
 			Self::UnknownError { message } => write!(f, "Unknown error (perhaps due to a bug): {}", message),
@@ -108,6 +174,14 @@ pub fn nom_error_to_owned<'a>(nom_err: nom::Err<nom::error::VerboseError<Span<'a
 	})
 }
 
+/// 構文エラーが起きた位置。入力が足りない場合は None
+pub fn nom_error_location(nom_err: &nom::Err<nom::error::VerboseError<Span>>) -> Option<Location> {
+	match nom_err {
+		nom::Err::Error(e) | nom::Err::Failure(e) => e.errors.first().map(|(part, _)| Location::of(part)),
+		nom::Err::Incomplete(_) => None,
+	}
+}
+
 impl From<io::Error> for ErrorType {
 	fn from(io_err: io::Error) -> Self {
 		Self::File(io_err)
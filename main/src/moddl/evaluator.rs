@@ -19,8 +19,8 @@ pub fn evaluate(expr: &Expr, vars: &Rc<RefCell<Scope>>, imports: &mut ImportCach
 // dbg!(expr as *const Expr);
 	let body = match &expr.body {
 		ExprBody::Connect { lhs, rhs } => {
-			let (l_str, _) = evaluate(lhs, vars, imports)?.as_node_structure() ?;
-			let (r_str, _) = evaluate(rhs, vars, imports)?.as_node_structure() ?;
+			let l_str = evaluate(lhs, vars, imports)?.as_node_structure() ?;
+			let r_str = evaluate(rhs, vars, imports)?.as_node_structure() ?;
 			Ok(ValueBody::NodeStructure(NodeStructure::Connect(Box::new(l_str), Box::new(r_str))))
 		},
 
@@ -44,8 +44,15 @@ pub fn evaluate(expr: &Expr, vars: &Rc<RefCell<Scope>>, imports: &mut ImportCach
 		ExprBody::Plus { arg } => evaluate_unary_structure::<PlusCalc>(arg, vars, imports),
 
 		ExprBody::Identifier(id) => {
-			let (val, _) = vars.borrow().lookup(id).ok_or_else(|| { error(ErrorType::VarNotFound { var: id.clone() }, expr.loc.clone()) }) ?;
-			Ok(val.clone())
+			let found = vars.borrow().lookup(id);
+			match found {
+				Some((val, _)) => Ok(val.clone()),
+				// a4 や bb3 のような名前は、同名の変数がなければ音名とみなす
+				None => match parser::moddl::parser::note_number(id) {
+					Some(note) => Ok(ValueBody::Float(NoteToFreqCalc::calc(&vec![note as f32]))),
+					None => Err(error(ErrorType::VarNotFound { var: id.clone() }, expr.loc.clone())),
+				},
+			}
 		},
 		ExprBody::IdentifierLiteral(id) => Ok(ValueBody::IdentifierLiteral(id.clone())),
 		ExprBody::StringLiteral(content) => Ok(ValueBody::String(content.clone())),
@@ -66,6 +73,13 @@ pub fn evaluate(expr: &Expr, vars: &Rc<RefCell<Scope>>, imports: &mut ImportCach
 			Ok(ValueBody::Assoc(result))
 		},
 		ExprBody::Condition { cond, then, els } => evaluate_conditional_expr(cond, then, els, vars, imports),
+		ExprBody::Let { name, def, body } => {
+			// def を評価するスコープに結果を置くので、def で定義した関数は自分自身を呼び出せる
+			let vars = Scope::child_of(vars.clone());
+			let def_val = evaluate(def, &vars, imports) ?;
+			vars.borrow_mut().set(name, def_val) ?;
+			evaluate(body, &vars, imports).map(|(v, _)| v)
+		},
 		ExprBody::LambdaFunction { params, body } => {
 			let mut param_values: Vec<Param> = vec![];
 			params.iter().try_for_each(|param| {
@@ -82,18 +96,40 @@ pub fn evaluate(expr: &Expr, vars: &Rc<RefCell<Scope>>, imports: &mut ImportCach
 			// 何とかして参照した方が効率的だが
 			Ok(ValueBody::Function(Rc::new(LambdaFunction::new(param_values, *body.clone(), vars))))
 		}
-		ExprBody::LambdaNode { input_param, body } => {
+		ExprBody::LambdaNode { input_param, params, body } => {
+			// 省略時の値は定義した側のスコープで評価する
+			let mut param_values: Vec<Param> = vec![];
+			for param in params {
+				param_values.push(Param {
+					name: param.name.clone(),
+					default: match &param.default {
+						None => None,
+						Some(default) => Some(evaluate(default, vars, imports) ?),
+					},
+				});
+			}
+
 			let vars = Scope::child_of(vars.clone());
-			vars.borrow_mut().set(input_param,
-					(ValueBody::NodeStructure(NodeStructure::Placeholder { name: input_param.clone() }), expr.loc.clone())) ?;
+			// 引数も入力と同様に、ノードの生成時に値が決まる
+			for name in std::iter::once(input_param).chain(params.iter().map(|param| &param.name)) {
+				vars.borrow_mut().set(name,
+						(ValueBody::NodeStructure(NodeStructure::Placeholder { name: name.clone() }), expr.loc.clone())) ?;
+			}
 			let result = Ok(ValueBody::NodeStructure(NodeStructure::Lambda {
 				input_param: input_param.clone(),
+				params: param_values,
+				args: HashMap::new(),
+				label: None,
 				body: Box::new(evaluate(body, &vars, imports)?.as_node_structure()?.0), // TODO loc も引き渡す
+				loc: expr.loc.clone(),
 			}));
 			result
 		},
 		// Expr::ModuleParamExpr { module_def, label: String, ctor_params: AssocArray, signal_params: AssocArray } => {}
 		ExprBody::FloatLiteral(value) => Ok(ValueBody::Float(*value)),
+		ExprBody::UnitLiteral { value, unit } => Ok(ValueBody::Float(convert_unit(*value, *unit))),
+		// 調律の設定によらず、A4 = 440 Hz の 12 平均律で周波数にする
+		ExprBody::NoteLiteral(note) => Ok(ValueBody::Float(NoteToFreqCalc::calc(&vec![*note as f32]))),
 		ExprBody::TrackSetLiteral(tracks) => Ok(ValueBody::TrackSet(tracks.clone())),
 		// Expr::MmlLiteral(String) => {}
 		// Expr::AssocArrayLiteral(AssocArray) => {}
@@ -117,32 +153,58 @@ pub fn evaluate(expr: &Expr, vars: &Rc<RefCell<Scope>>, imports: &mut ImportCach
 			Ok(val.map(|(v, _)| v).ok_or_else(|| error(ErrorType::EntryNotFound { name: name.clone() }, expr.loc.clone()))?.clone())
 		},
 		ExprBody::NodeWithArgs { node_def, /* label, */ args } => {
-			let (factory, _) = evaluate(node_def, vars, imports)?.as_node_factory() ?;
-
-			let arg_names = factory.node_arg_specs().iter().map(|spec| spec.name.clone()).collect();
-			let resolved_args = resolve_args(&arg_names, args, &expr.loc) ?;
-
-			// TODO map() を使いたいがクロージャで ? を使っているとうまくいかず。いい書き方があれば修正
-			let mut value_args = HashMap::new();
-			for (name, expr) in &resolved_args {
-				value_args.insert(name.clone(), evaluate(expr, vars, imports) ?);
+			match evaluate(node_def, vars, imports) ? {
+				// 式で記述されたノードには、params で宣言した引数を渡す
+				(ValueBody::NodeStructure(NodeStructure::Lambda { input_param, params, args: given_args, label, body, .. }), _) => {
+					let arg_names = params.iter().map(|param| param.name.clone()).collect();
+					let resolved_args = resolve_args(&arg_names, args, &expr.loc) ?;
+
+					let mut value_args = given_args;
+					for (name, expr) in &resolved_args {
+						value_args.insert(name.clone(), evaluate(expr, vars, imports) ?);
+					}
+					if let Some(param) = params.iter().find(|param| param.default.is_none() && ! value_args.contains_key(&param.name)) {
+						return Err(error(ErrorType::ArgMissing { name: param.name.clone() }, expr.loc.clone()));
+					}
+
+					Ok(ValueBody::NodeStructure(NodeStructure::Lambda {
+						input_param,
+						params,
+						args: value_args,
+						label,
+						body,
+						loc: expr.loc.clone(),
+					}))
+				},
+				node_def_val => {
+					let (factory, _) = node_def_val.as_node_factory() ?;
+
+					let arg_names = factory.node_arg_specs().iter().map(|spec| spec.name.clone()).collect();
+					let resolved_args = resolve_args(&arg_names, args, &expr.loc) ?;
+
+					// TODO map() を使いたいがクロージャで ? を使っているとうまくいかず。いい書き方があれば修正
+					let mut value_args = HashMap::new();
+					for (name, expr) in &resolved_args {
+						value_args.insert(name.clone(), evaluate(expr, vars, imports) ?);
+					}
+
+					Ok(ValueBody::NodeStructure(NodeStructure::NodeCreation {
+						factory,
+						label: None,
+						args: value_args,
+					}))
+				},
 			}
-
-			Ok(ValueBody::NodeStructure(NodeStructure::NodeCreation {
-				factory,
-				label: None,
-				args: value_args,
-			}))
 		},
 
-		ExprBody::MmlLiteral(_) => unimplemented!(),
+		ExprBody::MmlLiteral(mml) => Ok(ValueBody::Mml(mml.clone())),
 
 		ExprBody::Labeled { label, inner } => {
 			let (inner_val, _) = evaluate(inner, vars, imports) ?;
 
 			let warn_ineffective_label = || warn(format!("ineffective label \"{}\" ignored at {}", &label.0, &expr.loc));
 
-			// ラベルをつけれる対象は、数値定数、引数なしの NodeFactory、引数ありの NodeFactory（NodeCreation）の 3 つ。
+			// ラベルをつけれる対象は、数値定数、引数なしの NodeFactory、引数ありの NodeFactory（NodeCreation）、式で記述されたノード（Lambda）の 4 つ。
 			// 上記の値にラベルをつけると、結果は必ず NodeStructure になる。
 			// 上記以外にラベルをつけるのは無意味であり、警告とともにラベルは無視される
 			// 数値定数はラベルをつけると NodeStructure になるので、表現としては Float と NodeStructure::Constant の 2 通りある。
@@ -158,6 +220,21 @@ pub fn evaluate(expr: &Expr, vars: &Rc<RefCell<Scope>>, imports: &mut ImportCach
 					match strukt {
 						NodeStructure::Constant { value, label: _ } => NodeStructure::Constant { value, label: Some(label.clone()) },
 						NodeStructure::NodeCreation { factory, args, label: _ } => NodeStructure::NodeCreation { factory, args, label: Some(label.clone()) },
+						// インポートしたノードでも、ラベルをつければ中のラベルをその下で使えるようにする
+						NodeStructure::Lambda { input_param, params, args, label: _, body, loc } => NodeStructure::Lambda {
+							input_param,
+							params: params.into_iter().map(|Param { name, default }| Param {
+								name,
+								default: default.map(|(default, loc)| match default {
+									ValueBody::NodeStructure(strukt) => (ValueBody::NodeStructure(unguard_labels(&strukt).clone()), loc),
+									_ => (default, loc),
+								}),
+							}).collect(),
+							args,
+							label: Some(label.clone()),
+							body: Box::new(unguard_labels(&body).clone()),
+							loc,
+						},
 						_ => {
 							warn_ineffective_label();
 							strukt
@@ -174,7 +251,14 @@ pub fn evaluate(expr: &Expr, vars: &Rc<RefCell<Scope>>, imports: &mut ImportCach
 		},
 
 		ExprBody::LabelFilter { strukt, filter } => {
-			let (struct_val, struct_loc) = evaluate(strukt, vars, imports)?.as_node_structure() ?;
+			let value = evaluate(strukt, vars, imports) ?;
+			// 連想配列（import したライブラリなど）に対しては、ラベルの代わりにエントリを選択・改名する
+			if let (ValueBody::Assoc(entries), loc) = &value {
+				let filter = build_label_filter(filter, loc) ?;
+				return Ok((ValueBody::Assoc(filter_entries(entries, loc, &filter) ?), expr.loc.clone()));
+			}
+
+			let (struct_val, struct_loc) = value.as_node_structure() ?;
 			let filter = build_label_filter(filter, &struct_loc) ?;
 
 			Ok(ValueBody::NodeStructure(filter_labels(unguard_labels(&struct_val), &struct_loc, &filter) ?))
@@ -212,6 +296,39 @@ fn filter_labels(strukt: &NodeStructure, loc: &Location, filter: &LabelFilter) -
 	transform_labels(strukt, loc, &transform_label)
 }
 
+fn filter_entries(entries: &HashMap<String, Value>, loc: &Location, filter: &LabelFilter) -> ModdlResult<HashMap<String, Value>> {
+	// 存在しないエントリの指定は書き間違いと思われるのでエラーにする
+	if let Some(label) = filter.list.iter().chain(filter.renames.keys()).find(|label| ! entries.contains_key(&label.0)) {
+		return Err(error(ErrorType::EntryNotFound { name: label.0.clone() }, loc.clone()));
+	}
+
+	let mut result = HashMap::new();
+	for (key, value) in entries {
+		let label = QualifiedLabel(key.clone());
+		let new_key = if let Some(new_label) = filter.renames.get(&label) {
+			new_label.0.clone()
+		} else if filter.list.contains(&label) == matches!(filter.list_type, ListType::Allow) {
+			key.clone()
+		} else {
+			continue;
+		};
+		if result.insert(new_key.clone(), value.clone()).is_some() {
+			return Err(error(ErrorType::EntryDuplicate { name: new_key }, loc.clone()));
+		}
+	}
+
+	Ok(result)
+}
+
+/// 式で記述されたノードの中のラベルに、そのノード自体のラベルを接頭辞としてつける
+pub fn prefix_labels(strukt: &NodeStructure, prefix: &Option<QualifiedLabel>) -> NodeStructure {
+	match prefix {
+		// ラベルを付け替えるだけなのでエラーにはならない
+		Some(prefix) => add_prefix_to_labels(strukt, &Location::dummy(), prefix.0.as_str()).unwrap(),
+		None => strukt.clone(),
+	}
+}
+
 fn add_prefix_to_labels(strukt: &NodeStructure, loc: &Location, prefix: &str) -> ModdlResult<NodeStructure> {
 	let transform_label = |label: &Option<QualifiedLabel>| match label {
 		None => None,
@@ -224,38 +341,41 @@ fn add_prefix_to_labels(strukt: &NodeStructure, loc: &Location, prefix: &str) ->
 fn transform_labels<F>(strukt: &NodeStructure, loc: &Location, transform_label: &F) -> ModdlResult<NodeStructure>
 where F: Fn (&Option<QualifiedLabel>) -> Option<QualifiedLabel> {
 	let recurse = |strukt| transform_labels(strukt, loc, transform_label);
+	let recurse_operand = |(strukt, operand_loc): &Operand| Ok((transform_labels(strukt, loc, transform_label) ?, operand_loc.clone()));
+	let transform_args = |args: &HashMap<String, Value>| transform_labels_in_args(args, loc, transform_label);
 
 	match strukt {
 		NodeStructure::Calc { node_factory, args } => Ok(NodeStructure::Calc {
 			node_factory: node_factory.clone(),
 			args: {
-				let results: ModdlResult<Vec<_>> = args.iter().map(|arg| recurse(arg)).collect();
-				results?.into_iter().map(Box::new).collect()
+				let results: ModdlResult<Vec<_>> = args.iter().map(recurse_operand).collect();
+				results ?
 			},
 		}),
 		NodeStructure::Connect(lhs, rhs) => Ok(NodeStructure::Connect(
-			Box::new(recurse(lhs) ?),
-			Box::new(recurse(rhs) ?),
+			Box::new(recurse_operand(lhs) ?),
+			Box::new(recurse_operand(rhs) ?),
 		)),
 		NodeStructure::Condition { cond, then, els } => Ok(NodeStructure::Condition {
-			cond: Box::new(recurse(cond) ?),
-			then: Box::new(recurse(then) ?),
-			els: Box::new(recurse(els) ?),
+			cond: Box::new(recurse_operand(cond) ?),
+			then: Box::new(recurse_operand(then) ?),
+			els: Box::new(recurse_operand(els) ?),
 		}),
-		NodeStructure::Lambda { input_param, body } => Ok(NodeStructure::Lambda {
+		NodeStructure::Lambda { input_param, params, args, label, body, loc } => Ok(NodeStructure::Lambda {
 			input_param: input_param.clone(),
-			body: Box::new(recurse(body) ?),
+			params: params.clone(),
+			args: transform_args(args) ?,
+			label: transform_label(label),
+			body: Box::new(match label {
+				None => recurse(body) ?,
+				// body の中のラベルはノード自体のラベルの下にあるので、ノード自体のラベルが消えたら見えなくする
+				Some(_) => if transform_label(label).is_some() { *body.clone() } else { NodeStructure::LabelGuard(body.clone()) },
+			}),
+			loc: loc.clone(),
 		}),
 		NodeStructure::NodeCreation { factory, args, label } => Ok(NodeStructure::NodeCreation {
 			factory: factory.clone(),
-			args: args.keys().map(|arg_name| {
-				let (value, value_loc) = &args[arg_name];
-				let new_value = match value {
-					ValueBody::NodeStructure(strukt) => ValueBody::NodeStructure(recurse(strukt) ?),
-					_ => value.clone(),
-				};
-				Ok((arg_name.clone(), (new_value, value_loc.clone())))
-			}).collect::<ModdlResult<HashMap<String, Value>>>() ?,
+			args: transform_args(args) ?,
 			label: transform_label(label),
 		}),
 		NodeStructure::Constant { value, label } => Ok(NodeStructure::Constant {
@@ -268,6 +388,18 @@ where F: Fn (&Option<QualifiedLabel>) -> Option<QualifiedLabel> {
 	}
 }
 
+fn transform_labels_in_args<F>(args: &HashMap<String, Value>, loc: &Location, transform_label: &F) -> ModdlResult<HashMap<String, Value>>
+where F: Fn (&Option<QualifiedLabel>) -> Option<QualifiedLabel> {
+	args.keys().map(|arg_name| {
+		let (value, value_loc) = &args[arg_name];
+		let new_value = match value {
+			ValueBody::NodeStructure(strukt) => ValueBody::NodeStructure(transform_labels(strukt, loc, transform_label) ?),
+			_ => value.clone(),
+		};
+		Ok((arg_name.clone(), (new_value, value_loc.clone())))
+	}).collect()
+}
+
 #[derive(Debug)]
 enum ListType { Allow, Deny }
 #[derive(Debug)]
@@ -390,15 +522,16 @@ fn evaluate_unary_structure<C: Calc + 'static>(
 		return Ok(ValueBody::Float(C::calc(&vec![arg_float])));
 	}
 
-	let (arg_str, _) = arg_val.as_node_structure() ?;
 	Ok(ValueBody::NodeStructure(NodeStructure::Calc {
 		node_factory: Rc::new(CalcNodeFactory::<C>::new()),
-		args: vec![Box::new(arg_str)],
+		args: vec![arg_val.as_node_structure() ?],
 	}))
 }
 
 fn overload_add(lhs: &ValueBody, rhs: &ValueBody) -> Option<ModdlResult<ValueBody>> {
 	match (lhs, rhs) {
+		// MML どうしは空白を挟んでつなぐ。文字列とつなぐと文字列になる
+		(ValueBody::Mml(lhs), ValueBody::Mml(rhs)) => Some(Ok(ValueBody::Mml(format!("{} {}", lhs, rhs)))),
 		(ValueBody::String(lhs), rhs) => {
 			let result = rhs.to_str(|rhs| lhs.clone() + rhs);
 			Some(Ok(ValueBody::String(result)))
@@ -425,6 +558,17 @@ fn overload_add(lhs: &ValueBody, rhs: &ValueBody) -> Option<ModdlResult<ValueBod
 	}
 }
 
+/// 単位つきの数値を、秒・Hz・振幅の比・周波数の比のいずれかに換算する
+fn convert_unit(value: f32, unit: Unit) -> f32 {
+	match unit {
+		Unit::Millisecond => value / 1000f32,
+		Unit::Second | Unit::Hertz => value,
+		Unit::Decibel => DbToAmpCalc::calc(&vec![value]),
+		Unit::Cent => CentsToRatioCalc::calc(&vec![value]),
+		Unit::Semitone => CentsToRatioCalc::calc(&vec![value * 100f32]),
+	}
+}
+
 fn evaluate_binary_structure<C: Calc + 'static>(
 	lhs: &Expr,
 	rhs: &Expr,
@@ -454,11 +598,9 @@ fn evaluate_binary_structure_overloaded<C: Calc + 'static>(
 		return Ok(ValueBody::Float(C::calc(&vec![l_float, r_float])));
 	}
 
-	let (l_str, _) = l_val.as_node_structure() ?;
-	let (r_str, _) = r_val.as_node_structure() ?;
 	Ok(ValueBody::NodeStructure(NodeStructure::Calc {
 		node_factory: Rc::new(CalcNodeFactory::<C>::new()),
-		args: vec![Box::new(l_str), Box::new(r_str)],
+		args: vec![l_val.as_node_structure() ?, r_val.as_node_structure() ?],
 	}))
 }
 
@@ -478,13 +620,10 @@ fn evaluate_conditional_expr(cond: &Expr, then: &Expr, els: &Expr, vars: &Rc<Ref
 	// then と else も NodeStructure でなければならないので、定数式にはならない
 	let then_val = evaluate(then, vars, imports) ?;
 	let else_val = evaluate(els, vars, imports) ?;
-	let (cond_str, _) = cond_val.as_node_structure() ?;
-	let (then_str, _) = then_val.as_node_structure() ?;
-	let (else_str, _) = else_val.as_node_structure() ?;
 	Ok(ValueBody::NodeStructure(NodeStructure::Condition {
-		cond: Box::new(cond_str),
-		then: Box::new(then_str),
-		els: Box::new(else_str),
+		cond: Box::new(cond_val.as_node_structure() ?),
+		then: Box::new(then_val.as_node_structure() ?),
+		els: Box::new(else_val.as_node_structure() ?),
 	}))
 }
 
@@ -510,3 +649,69 @@ fn resolve_args<'a>(arg_names: &'a Vec<String>, args: &'a Args, expr_loc: &Locat
 
 	Ok(result)
 }
+
+/// テスト用に、関数などを入れたルートのスコープを作る
+#[cfg(test)]
+pub fn test_scope(entries: Vec<(&str, ValueBody)>) -> Rc<RefCell<Scope>> {
+	Scope::root(entries.into_iter().map(|(name, value)| (name.to_string(), (value, Location::dummy()))).collect())
+}
+
+/// テスト用に、式のソースを解析して評価する
+#[cfg(test)]
+pub fn evaluate_source(source: &str, vars: &Rc<RefCell<Scope>>) -> ModdlResult<Value> {
+	use crate::wave::waveform_host::WaveformHost;
+	use parser::{common::Span, moddl::parser::expr};
+	use std::path::PathBuf;
+
+	let (_, expr) = expr()(Span::new_extra(source, Rc::new(PathBuf::new()))).unwrap();
+	let mut waveforms = WaveformHost::new();
+	let mut imports = ImportCache::new(&mut waveforms);
+	evaluate(&expr, vars, &mut imports)
+}
+
+#[cfg(test)]
+#[test]
+fn test_unit_and_note_literals() {
+	let vars = test_scope(vec![]);
+	let eval = |source: &str| evaluate_source(source, &vars).ok().and_then(|(value, _)| value.as_float());
+	let assert_near = |actual: Option<f32>, expected: f32| {
+		let actual = actual.unwrap();
+		assert!((actual - expected).abs() < 1e-3, "{} != {}", actual, expected);
+	};
+
+	assert_near(eval("250ms"), 0.25);
+	assert_near(eval("2s + 440Hz"), 442f32);
+	assert_near(eval("-6dB"), 0.501);
+	assert_near(eval("+7ct"), 1.004);
+	assert_near(eval("12st"), 2f32);
+	assert_near(eval("-12st"), 0.5);
+	assert_near(eval("a4"), 440f32);
+	assert_near(eval("c#5 / db5"), 1f32);
+	assert_near(eval("bb3 * 3st"), 277.183);
+	// 音名の形をしていない識別子は従来どおり変数
+	assert!(eval("a4x").is_none());
+	// 同名の変数があれば音名よりも変数を優先する
+	assert_eq!(eval("let a1 = 3; a1"), Some(3f32));
+	assert_eq!(eval("let bb3 = 2; bb3 * 2"), Some(4f32));
+}
+
+#[cfg(test)]
+#[test]
+fn test_lambda_node_args() {
+	let vars = test_scope(vec![]);
+	let eval = |source: &str| evaluate_source(source, &vars);
+
+	// 渡した引数だけを持ち、省略した引数は省略時の値を使う
+	let (value, _) = eval("let amp = =x(gain = 0.5@g, q)=> x * gain * q * 2@a; amp { q: 3 }@bp").ok().unwrap();
+	let ValueBody::NodeStructure(strukt) = value else { panic!() };
+	assert_eq!(strukt.to_string(), "(=x(gain = NodeStructure(0.5@g), q)=> (((Placeholder(x) * Placeholder(gain)) * Placeholder(q)) * 2@a)){ q: 3 }@bp");
+	// 本体の中のラベルはノード自体のラベルの下に置く
+	let NodeStructure::Lambda { label, body, .. } = &strukt else { panic!() };
+	assert_eq!(prefix_labels(body, label).to_string(), "(((Placeholder(x) * Placeholder(gain)) * Placeholder(q)) * 2@bp.a)");
+	assert_eq!(prefix_labels(body, &None).to_string(), body.to_string());
+
+	// 省略時の値のない引数を渡さなければ、呼び出した位置でエラーにする
+	let e = eval("let amp = =x(gain = 0.5, q)=> x * gain * q; amp { gain: 1 }").err().unwrap();
+	assert!(matches!(&e.body, ErrorType::ArgMissing { name } if name == "q"));
+	assert_eq!((e.loc.line, e.loc.column), (1, 45));
+}
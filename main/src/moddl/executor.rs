@@ -1,12 +1,24 @@
 use super::{
-	common::make_seq_tag, console::*, error::*, evaluator::*, import::ImportCache, io::Io, player_context::{MuteSolo, PlayerContext, TrackDef}, scope::*, value::*
+	common::{make_seq_tag, read_file}, console::*, error::*, evaluator::*, import::ImportCache, io::Io, path::resolve_path, player_context::{DrumHit, MmlSource, MuteSolo, PlayerContext, Polyphony, TrackDef}, scope::*, value::*
 };
-use crate::wave::{
-	wav_reader::*, waveform::Waveform,
+use crate::{
+	common::seed::Seed,
+	midi::{
+		smf::*,
+		to_mml::*,
+	},
+	mml::tuning::*,
+	seq::{
+		humanize::Humanize,
+		voice_allocator::StealingPolicy,
+	},
+	wave::{
+		wav_reader::*, waveform::Waveform,
+	},
 };
 extern crate parser;
 use parser::{
-	common::{Location, Span}, moddl::{ast::*, parser::compilation_unit}
+	common::{Location, Span}, mml::dialect::*, moddl::{ast::*, parser::compilation_unit}
 };
 
 use std::{
@@ -15,15 +27,21 @@ use std::{
 
 pub fn process_statements(moddl: &str, root_scope: Rc<RefCell<Scope>>, moddl_path: &Path, imports: &mut ImportCache) -> ModdlResult<PlayerContext> {
 	let mut pctx = PlayerContext::init(moddl_path, root_scope);
+	process_statements_in(moddl, &mut pctx, imports) ?;
 
-	let (_, CompilationUnit { statements }) = compilation_unit()(Span::new_extra(moddl, Rc::new(moddl_path.to_path_buf())))
+	Ok(pctx)
+}
+
+/// 既存の PlayerContext に対して文を処理する。REPL で入力ごとに文を追加していく場合に使う
+pub fn process_statements_in(moddl: &str, pctx: &mut PlayerContext, imports: &mut ImportCache) -> ModdlResult<()> {
+	let (_, CompilationUnit { statements }) = compilation_unit()(Span::new_extra(moddl, Rc::new(pctx.moddl_path.clone())))
 	.map_err(|e| error(ErrorType::Syntax(nom_error_to_owned(e)), Location::dummy())) ?;
 
 	for stmt in &statements {
-		process_statement(&stmt, &mut pctx, imports) ?;
+		process_statement(&stmt, pctx, imports) ?;
 	}
 
-	Ok(pctx)
+	Ok(())
 }
 
 fn process_statement<'a>((stmt, stmt_loc): &'a (Statement, Location), pctx: &mut PlayerContext, imports: &mut ImportCache) -> ModdlResult<()> {
@@ -35,10 +53,25 @@ fn process_statement<'a>((stmt, stmt_loc): &'a (Statement, Location), pctx: &mut
 				},
 				"instrument" => {
 					let tracks = evaluate_and_perform_arg(&args, 0, &pctx.vars, stmt_loc, imports)?.as_track_set()?.0;
+					// 3 番目の引数があればポリフォニック
+					let polyphony = if args.len() > 2 {
+						Some(parse_polyphony_spec(&evaluate_and_perform_arg(&args, 2, &pctx.vars, stmt_loc, imports) ?) ?)
+					} else {
+						None
+					};
 					// let instrm = & args[1];
 					for track in tracks {
 						let instrm = evaluate_and_perform_arg(&args, 1, &pctx.vars, stmt_loc, imports)?.as_node_structure()?.0;
-						pctx.add_track_def(&track, TrackDef::Instrument(instrm), stmt_loc) ?;
+						pctx.add_track_def(&track, TrackDef::Instrument(instrm, polyphony), stmt_loc) ?;
+						pctx.terminal_tracks.insert(track);
+					}
+				}
+				"drumKit" => {
+					// ^d, { kick: { instrument: ..., note: 36, velocity: 0.8, choke: :hat }, snare: ... } のように指定する
+					let tracks = evaluate_and_perform_arg(&args, 0, &pctx.vars, stmt_loc, imports)?.as_track_set()?.0;
+					let hits = parse_drum_kit_spec(&evaluate_and_perform_arg(&args, 1, &pctx.vars, stmt_loc, imports) ?) ?;
+					for track in tracks {
+						pctx.add_track_def(&track, TrackDef::DrumKit(hits.clone()), stmt_loc) ?;
 						pctx.terminal_tracks.insert(track);
 					}
 				}
@@ -115,6 +148,86 @@ fn process_statement<'a>((stmt, stmt_loc): &'a (Statement, Location), pctx: &mut
 					let index = imports.waveforms.add(waveform);
 					pctx.vars.borrow_mut().set(&name, (ValueBody::WaveformIndex(index), value_loc)) ?;
 				}
+				"tuning" => {
+					// 先頭にトラックセットがあればそのトラックだけ、なければ全トラックの音律を指定する
+					let first = evaluate_and_perform_arg(&args, 0, &pctx.vars, stmt_loc, imports) ?;
+					let (tracks, spec) = match first.as_track_set() {
+						Ok((tracks, _)) => (Some(tracks), evaluate_and_perform_arg(&args, 1, &pctx.vars, stmt_loc, imports) ?),
+						Err(_) => (None, first),
+					};
+					let tuning = parse_tuning_spec(&spec, pctx.moddl_path.as_path()) ?;
+					match tracks {
+						Some(tracks) => {
+							for track in tracks {
+								pctx.track_tunings.insert(track, tuning.clone());
+							}
+						},
+						None => { pctx.tuning = tuning; },
+					}
+				}
+				"dialect" => {
+					// @tuning と同様、先頭にトラックセットがあればそのトラックだけ、なければ全トラックの MML の方言を指定する
+					let first = evaluate_and_perform_arg(&args, 0, &pctx.vars, stmt_loc, imports) ?;
+					let (tracks, name) = match first.as_track_set() {
+						Ok((tracks, _)) => (Some(tracks), evaluate_and_perform_arg(&args, 1, &pctx.vars, stmt_loc, imports) ?),
+						Err(_) => (None, first),
+					};
+					let (name, name_loc) = name.as_identifier_literal() ?;
+					let dialect = dialect_by_name(name.as_str())
+							.ok_or_else(|| error(ErrorType::UnknownMmlDialect { name: name.clone() }, name_loc.clone())) ?;
+					match tracks {
+						Some(tracks) => {
+							for track in tracks {
+								pctx.track_dialects.insert(track, dialect);
+							}
+						},
+						None => { pctx.mml_dialect = dialect; },
+					}
+				}
+				"humanize" => {
+					let tracks = evaluate_and_perform_arg(&args, 0, &pctx.vars, stmt_loc, imports)?.as_track_set()?.0;
+					let humanize = parse_humanize_spec(&evaluate_and_perform_arg(&args, 1, &pctx.vars, stmt_loc, imports) ?) ?;
+					for track in tracks {
+						pctx.humanize.insert(track, humanize);
+					}
+				}
+				"midiImport" => {
+					// ^ab, "./song.mid", { by: :channel, select: [1, 2], cc: { 74: :cutoff } } のように指定する
+					let tracks = evaluate_and_perform_arg(&args, 0, &pctx.vars, stmt_loc, imports)?.as_track_set()?.0;
+					let path = evaluate_and_perform_arg(&args, 1, &pctx.vars, stmt_loc, imports)?.as_string() ?;
+					let options = if args.len() > 2 {
+						parse_midi_import_spec(&evaluate_and_perform_arg(&args, 2, &pctx.vars, stmt_loc, imports) ?, pctx.ticks_per_bar) ?
+					} else {
+						MidiImportOptions { ticks_per_bar: pctx.ticks_per_bar, kind: MidiSourceKind::Channel, sources: None, cc_map: HashMap::new() }
+					};
+					let smf = read_midi_file(&path, pctx.moddl_path.as_path()) ?;
+					let imported = smf_to_mml(&smf, &options);
+					if imported.len() > tracks.len() {
+						warn(format!("{} MIDI sources found but only {} tracks given at {}; the rest are ignored", imported.len(), tracks.len(), stmt_loc));
+					}
+					for (track, ImportedMml { source, mml, polyphonic }) in tracks.iter().zip(imported) {
+						let is_polyphonic_track = matches!(pctx.get_track_def(track), Some((TrackDef::Instrument(_, Some(_)) | TrackDef::DrumKit(_), _)));
+						if polyphonic && ! is_polyphonic_track {
+							warn(format!("MIDI source {} has chords but track {} is not polyphonic at {}", source, track, stmt_loc));
+						}
+						append_mml(track, &format!("{}\n", mml), MmlSource::Import(stmt_loc.clone()), stmt_loc, pctx) ?;
+					}
+				}
+				"mml" => {
+					// ^ab, mml("c d e") のように、ModDL で組み立てた MML をトラックに追加する
+					let tracks = evaluate_and_perform_arg(&args, 0, &pctx.vars, stmt_loc, imports)?.as_track_set()?.0;
+					let (mml, _) = evaluate_and_perform_arg(&args, 1, &pctx.vars, stmt_loc, imports)?.as_mml() ?;
+					for track in tracks {
+						append_mml(&track, &format!("{}\n", mml), MmlSource::Directive(stmt_loc.clone()), stmt_loc, pctx) ?;
+					}
+				}
+				"midiCc" => {
+					let tracks = evaluate_and_perform_arg(&args, 0, &pctx.vars, stmt_loc, imports)?.as_track_set()?.0;
+					let cc_map = parse_cc_map_spec(&evaluate_and_perform_arg(&args, 1, &pctx.vars, stmt_loc, imports) ?) ?;
+					for track in tracks {
+						pctx.midi_cc.insert(track, cc_map.clone());
+					}
+				}
 				"ticksPerBar" => {
 					let value = evaluate_and_perform_arg(&args, 0, &pctx.vars, stmt_loc, imports)?.as_float()?.0;
 					// TODO さらに、正の整数であることを検証
@@ -149,6 +262,20 @@ fn process_statement<'a>((stmt, stmt_loc): &'a (Statement, Location), pctx: &mut
 						"defaultLabels" => {
 							pctx.use_default_labels = true;
 						},
+						"seed" => {
+							let (seed, seed_loc) = evaluate_and_perform_arg(&args, 1, &pctx.vars, stmt_loc, imports)?.as_float() ?;
+							// --seed と同じく、負の数や整数でない値は受け付けない
+							if seed < 0f32 || seed.fract() != 0f32 {
+								return Err(error(ErrorType::BadSeed, seed_loc));
+							}
+							// 種は演奏全体で 1 つなので、import されたファイルでは指定できない。
+							// コマンドラインで指定されていればそちらを優先する
+							if imports.in_import() {
+								warn("seed option in imported file ignored".to_string());
+							} else {
+								imports.set_option_seed(Seed(seed as u64), stmt_loc) ?;
+							}
+						},
 						other => {
 							// 前方互換性のため警告にとどめる
 							warn(format!("unknown option ignored: {}", other));
@@ -161,16 +288,9 @@ fn process_statement<'a>((stmt, stmt_loc): &'a (Statement, Location), pctx: &mut
 				}
 			}
 		}
-		Statement::Mml { tracks, mml } => {
+		Statement::Mml { tracks, mml, mml_loc } => {
 			for track in tracks {
-				if pctx.get_track_def(track).is_none() {
-					return Err(error(ErrorType::TrackDefNotFound { track: track.clone() }, stmt_loc.clone()));
-				}
-				if let Some(mml_concat) = pctx.mmls.get_mut(track) {
-					mml_concat.push_str(mml.as_str());
-				} else {
-					pctx.mmls.insert(track.clone(), mml.clone());
-				}
+				append_mml(track, mml, MmlSource::Statement(mml_loc.clone()), stmt_loc, pctx) ?;
 			}
 		}
 	}
@@ -183,6 +303,20 @@ fn process_statement<'a>((stmt, stmt_loc): &'a (Statement, Location), pctx: &mut
 	Ok(())
 }
 
+fn append_mml(track: &String, mml: &str, source: MmlSource, stmt_loc: &Location, pctx: &mut PlayerContext) -> ModdlResult<()> {
+	if pctx.get_track_def(track).is_none() {
+		return Err(error(ErrorType::TrackDefNotFound { track: track.clone() }, stmt_loc.clone()));
+	}
+	if let Some(mml_concat) = pctx.mmls.get_mut(track) {
+		mml_concat.push_str(mml);
+	} else {
+		pctx.mmls.insert(track.clone(), mml.to_string());
+	}
+	pctx.mml_locs.entry(track.clone()).or_default().push(source);
+
+	Ok(())
+}
+
 // 仕様は #16 を参照のこと
 fn parse_waveform_spec(spec: &HashMap<String, Value>, loc: &Location) -> ModdlResult<Waveform> {
 	let get_optional_value = |name: &str| spec.get(& name.to_string());
@@ -239,6 +373,241 @@ fn parse_waveform_spec(spec: &HashMap<String, Value>, loc: &Location) -> ModdlRe
 
 }
 
+// 数値ならボイス数、連想配列ならボイス数と発音を奪う方針の指定
+fn parse_polyphony_spec(spec: &Value) -> ModdlResult<Polyphony> {
+	let (voices, stealing) = match spec.as_assoc() {
+		Ok((spec, loc)) => {
+			let voices = spec.get("polyphony").ok_or_else(|| error(ErrorType::BadPolyphony, loc)) ?;
+			let stealing = match spec.get("stealing") {
+				None => StealingPolicy::Oldest,
+				Some(value) => {
+					let (name, name_loc) = value.as_identifier_literal() ?;
+					StealingPolicy::from_name(name.as_str())
+							.ok_or_else(|| error(ErrorType::UnknownStealingPolicy { name }, name_loc)) ?
+				},
+			};
+			(voices.as_float() ?, stealing)
+		},
+		Err(_) => (spec.as_float() ?, StealingPolicy::Oldest),
+	};
+	let (voices, voices_loc) = voices;
+	if voices < 1f32 || voices.fract() != 0f32 {
+		return Err(error(ErrorType::BadPolyphony, voices_loc));
+	}
+
+	Ok(Polyphony { voices: voices as usize, stealing })
+}
+
+// ヒット名ごとに、楽器だけか、{ instrument, note, velocity, choke } を指定する。
+// 楽器を組み立てる順序を決めるため、ヒットは名前順に並べる
+fn parse_drum_kit_spec(spec: &Value) -> ModdlResult<Vec<DrumHit>> {
+	let (spec, loc) = spec.as_assoc() ?;
+	if spec.is_empty() {
+		return Err(error(ErrorType::BadDrumKit, loc));
+	}
+	let mut names: Vec<_> = spec.keys().collect();
+	names.sort();
+
+	let mut hits = vec![];
+	for (i, name) in names.into_iter().enumerate() {
+		let value = &spec[name];
+		if ! name.chars().all(|c| c.is_ascii_alphabetic() || c == '_') {
+			return Err(error(ErrorType::BadDrumKit, value.1.clone()));
+		}
+		let (structure, note, velocity, choke) = match value.as_assoc() {
+			Ok((hit, hit_loc)) => {
+				let structure = hit.get("instrument").ok_or_else(|| error(ErrorType::ArgMissing { name: "instrument".to_string() }, hit_loc.clone()))?.as_node_structure()?.0;
+				let note = hit.get("note").map(|note| note.as_float()).transpose() ?;
+				let velocity = hit.get("velocity").map(|velocity| velocity.as_float()).transpose()?.map_or(1f32, |(v, _)| v);
+				let choke = hit.get("choke").map(|choke| choke.as_identifier_literal()).transpose()?.map(|(c, _)| c);
+				(structure, note, velocity, choke)
+			},
+			Err(_) => (value.as_node_structure()?.0, None, 1f32, None),
+		};
+		let key = match note {
+			Some((note, note_loc)) => {
+				if note < 0f32 || note.fract() != 0f32 || hits.iter().any(|hit: &DrumHit| hit.key == note as i32) {
+					return Err(error(ErrorType::BadDrumKit, note_loc));
+				}
+				note as i32
+			},
+			None => - (i as i32) - 1,
+		};
+		hits.push(DrumHit { name: name.clone(), key, structure, velocity, choke });
+	}
+
+	Ok(hits)
+}
+
+// { timing: 4, velocity: 0.1, gate: 0.1, seed: 1 } のように指定する。省略したものは揺らがない。
+// seed を省略した場合はトラックの種を使う
+fn parse_humanize_spec(spec: &Value) -> ModdlResult<Humanize> {
+	let (spec, _) = spec.as_assoc() ?;
+	let get_value = |name: &str| -> ModdlResult<f32> {
+		match spec.get(name) {
+			None => Ok(0f32),
+			Some(value) => {
+				let (value, loc) = value.as_float() ?;
+				if value < 0f32 {
+					return Err(error(ErrorType::BadHumanize, loc));
+				}
+				Ok(value)
+			},
+		}
+	};
+
+	Ok(Humanize {
+		timing: get_value("timing") ? as i32,
+		velocity: get_value("velocity") ?,
+		gate: get_value("gate") ?,
+		seed: match spec.get("seed") {
+			None => None,
+			Some(_) => Some(get_value("seed") ? as u64),
+		},
+	})
+}
+
+// 数値なら A4 の周波数を指定した平均律、連想配列なら音律の詳細な指定
+fn parse_tuning_spec((spec, loc): &Value, moddl_path: &Path) -> ModdlResult<Tuning> {
+	if let Some(pitch) = spec.as_float() {
+		return Ok(Tuning::equal(pitch));
+	}
+	let spec = match spec.as_assoc() {
+		Some(spec) => spec,
+		None => return Err(error(ErrorType::TypeMismatchAny { expected: vec![
+			ValueType::Number,
+			ValueType::Assoc,
+		]}, loc.clone())),
+	};
+	let get_optional_value = |name: &str| spec.get(& name.to_string());
+
+	let pitch = get_optional_value("pitch").map(|value| value.as_float()).transpose()?.map(|v| v.0);
+	let temperament = get_optional_value("temperament").map(|value| value.as_identifier_literal()).transpose() ?;
+	let key = get_optional_value("key").map(parse_key_spec).transpose() ?;
+	let scl = get_optional_value("scl").map(|value| value.as_string()).transpose() ?;
+	let kbm = get_optional_value("kbm").map(|value| value.as_string()).transpose() ?;
+
+	match (scl, kbm) {
+		(None, None) => {
+			let temperament = match temperament {
+				None => Temperament::Equal,
+				Some((name, name_loc)) => Temperament::from_name(name.as_str())
+						.ok_or_else(|| error(ErrorType::UnknownTemperament { name }, name_loc)) ?,
+			};
+			Ok(Tuning::temperament(temperament, key.unwrap_or(0), pitch.unwrap_or(440f32)))
+		},
+		(Some(scl), kbm) if temperament.is_none() && key.is_none() => {
+			let read_tuning_file = |(path, path_loc): &(String, Location)| {
+				let resolved = resolve_path(Path::new(path), moddl_path);
+				read_file(resolved.as_path()).map_err(|e| error(e.body, path_loc.clone()))
+			};
+			let bad_tuning_file = |path: &String, path_loc: &Location, e: TuningError| error(ErrorType::BadTuningFile {
+				path: path.clone(),
+				message: e.message,
+			}, path_loc.clone());
+
+			let scale = parse_scl(read_tuning_file(&scl)?.as_str())
+					.map_err(|e| bad_tuning_file(&scl.0, &scl.1, e)) ?;
+			let mapping = match &kbm {
+				None => None,
+				Some(kbm) => Some(parse_kbm(read_tuning_file(kbm)?.as_str())
+						.map_err(|e| bad_tuning_file(&kbm.0, &kbm.1, e)) ?),
+			};
+			Tuning::scala(scale, mapping, pitch).map_err(|e| bad_tuning_file(&scl.0, &scl.1, e))
+		},
+		_ => Err(error(ErrorType::BadTuning, loc.clone())),
+	}
+}
+
+// by には :channel か :track、select にはチャンネル（1 始まり）かトラック（0 始まり）の番号の配列、
+// cc にはコントロールチェンジの番号ごとにラベルか { label, min, max } を指定する
+fn parse_midi_import_spec(spec: &Value, ticks_per_bar: i32) -> ModdlResult<MidiImportOptions> {
+	let (spec, _) = spec.as_assoc() ?;
+	let kind = match spec.get("by") {
+		None => MidiSourceKind::Channel,
+		Some(value) => {
+			let (name, name_loc) = value.as_identifier_literal() ?;
+			match name.as_str() {
+				"channel" => MidiSourceKind::Channel,
+				"track" => MidiSourceKind::Track,
+				_ => return Err(error(ErrorType::BadMidiImport, name_loc)),
+			}
+		},
+	};
+	let sources = match spec.get("select") {
+		None => None,
+		Some(value) => Some(value.as_array()?.0.iter().map(|source| {
+			let (source, loc) = source.as_float() ?;
+			if source < 0f32 || source.fract() != 0f32 {
+				return Err(error(ErrorType::BadMidiImport, loc));
+			}
+			Ok(source as usize)
+		}).collect::<ModdlResult<Vec<_>>>() ?),
+	};
+	let cc_map = match spec.get("cc") {
+		None => HashMap::new(),
+		Some(value) => parse_cc_map_spec(value) ?,
+	};
+
+	Ok(MidiImportOptions { ticks_per_bar, kind, sources, cc_map })
+}
+
+// { 74: :cutoff, 1: { label: :vibrato, min: 0, max: 20 } } のように、コントロールチェンジの番号ごとに
+// ラベルか { label, min, max } を指定する。min と max を省略した場合は 0 と 1
+fn parse_cc_map_spec(spec: &Value) -> ModdlResult<HashMap<u8, CcMapping>> {
+	let (spec, loc) = spec.as_assoc() ?;
+	let mut result = HashMap::new();
+	for (controller, mapping) in spec {
+		let controller = controller.parse::<u8>().ok().filter(|c| *c < 128)
+				.ok_or_else(|| error(ErrorType::BadMidiImport, loc.clone())) ?;
+		let mapping = match mapping.as_assoc() {
+			Ok((mapping, mapping_loc)) => {
+				let label = mapping.get("label").ok_or_else(|| error(ErrorType::BadMidiImport, mapping_loc)) ?;
+				let get_value = |name: &str, default: f32| mapping.get(name).map(|v| v.as_float()).transpose().map(|v| v.map_or(default, |v| v.0));
+				CcMapping {
+					label: label.as_identifier_literal()?.0,
+					min: get_value("min", 0f32) ?,
+					max: get_value("max", 1f32) ?,
+				}
+			},
+			Err(_) => CcMapping { label: mapping.as_identifier_literal()?.0, min: 0f32, max: 1f32 },
+		};
+		result.insert(controller, mapping);
+	}
+
+	Ok(result)
+}
+
+fn read_midi_file((path, path_loc): &(String, Location), moddl_path: &Path) -> ModdlResult<Smf> {
+	let resolved = resolve_path(Path::new(path), moddl_path);
+	let bytes = std::fs::read(resolved).map_err(|e| error(e.into(), path_loc.clone())) ?;
+	read_smf(&bytes).map_err(|message| error(ErrorType::BadMidiFile { path: path.clone(), message }, path_loc.clone()))
+}
+
+// 主音は 0 (C) ～ 11 (B) の数値か、"e-" "f+" のような音名で指定する
+fn parse_key_spec(value: &Value) -> ModdlResult<i32> {
+	if let Ok((key, _)) = value.as_float() {
+		return Ok(key as i32);
+	}
+	let (name, loc) = value.as_string() ?;
+	let mut chars = name.chars();
+	let base = match chars.next() {
+		Some('c') => 0,
+		Some('d') => 2,
+		Some('e') => 4,
+		Some('f') => 5,
+		Some('g') => 7,
+		Some('a') => 9,
+		Some('b') => 11,
+		_ => return Err(error(ErrorType::BadTuning, loc)),
+	};
+	chars.try_fold(base, |key, c| match c {
+		'+' | '#' => Ok(key + 1),
+		'-' => Ok(key - 1),
+		_ => Err(error(ErrorType::BadTuning, loc.clone())),
+	})
+}
+
 fn evaluate_and_perform_arg(args: &Vec<Expr>, index: usize, vars: &Rc<RefCell<Scope>>, stmt_loc: &Location, imports: &mut ImportCache) -> ModdlResult<Value> {
 	if index < args.len() {
 		let mut value = evaluate(&args[index], vars, imports) ?;
@@ -265,3 +634,94 @@ fn set_mute_solo(mute_solo: MuteSolo, tracks: &Vec<String>, pctx: &mut PlayerCon
 		(*pctx).mute_solo_tracks.insert(t.clone());
 	});
 }
+
+#[cfg(test)]
+#[test]
+fn test_dialect() {
+	use crate::{midi::smf::*, wave::waveform_host::WaveformHost};
+	use std::fs;
+
+	let dir = std::env::temp_dir().join(format!("moddl_test_dialect_{}", std::process::id()));
+	fs::create_dir_all(&dir).unwrap();
+	let smf = Smf {
+		format: 0,
+		division: 96,
+		tracks: vec![vec![
+			SmfEvent { tick: 0, body: SmfEventBody::NoteOn { channel: 0, key: 60, velocity: 127 } },
+			SmfEvent { tick: 96, body: SmfEventBody::NoteOff { channel: 0, key: 60 } },
+		]],
+	};
+	fs::write(dir.join("song.mid"), write_smf(&smf)).unwrap();
+
+	let mut waveforms = WaveformHost::new();
+	let mut imports = ImportCache::new(&mut waveforms);
+	let root_scope = Scope::root(HashMap::new());
+	let mut process = |moddl: &str| process_statements(moddl, root_scope.clone(), &dir.join("main.moddl"), &mut imports).unwrap();
+	let dialect = |pctx: &PlayerContext, track: &str| pctx.get_dialect(&track.to_string()).map(|dialect| dialect.name());
+
+	// @dialect は MML より後に書いてもよく、トラックごとの指定は全体の指定より優先する
+	let pctx = process("@instrument ^abc, 0\nabc c\n@dialect :mck\n@dialect ^b, :default\n");
+	assert_eq!(dialect(&pctx, "a").unwrap(), "mck");
+	assert_eq!(dialect(&pctx, "b").unwrap(), DEFAULT_DIALECT_NAME);
+
+	// MIDI を取り込んだトラックは、取り込みの前後どちらで方言を指定しても既定の方言でなければならない
+	for moddl in [
+		"@instrument ^ab, 0\n@midiImport ^a, \"./song.mid\"\n@dialect ^a, :mck\n",
+		"@instrument ^ab, 0\n@dialect :mck\n@midiImport ^a, \"./song.mid\"\n",
+	] {
+		let pctx = process(moddl);
+		let e = dialect(&pctx, "a").err().unwrap();
+		match e.body {
+			ErrorType::MidiImportDialectMismatch { track, dialect } => {
+				assert_eq!((track.as_str(), dialect.as_str()), ("a", "mck"));
+				assert_eq!(e.loc.line, moddl.lines().position(|line| line.starts_with("@midiImport")).unwrap() as u32 + 1);
+			},
+			e => panic!("unexpected error: {:?}", e),
+		}
+	}
+	assert_eq!(dialect(&process("@instrument ^ab, 0\n@midiImport ^a, \"./song.mid\"\n@dialect ^b, :mck\n"), "a").unwrap(), DEFAULT_DIALECT_NAME);
+
+	fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(test)]
+#[test]
+fn test_seed_option() {
+	use super::io::Rand;
+	use crate::wave::waveform_host::WaveformHost;
+	use std::path::PathBuf;
+
+	// 実行ごとに新しい種と rand で文を処理し、r の値を返す
+	let run = |moddl: &str, command_line_seed: Option<u64>| -> ModdlResult<Option<f32>> {
+		let mut waveforms = WaveformHost::new();
+		let mut imports = ImportCache::new(&mut waveforms);
+		if let Some(seed) = command_line_seed {
+			imports.set_command_line_seed(Seed(seed));
+		}
+		let root_scope = test_scope(vec![("rand", ValueBody::Io(Rc::new(RefCell::new(Rand::new()))))]);
+		let pctx = process_statements(moddl, root_scope, &PathBuf::new(), &mut imports) ?;
+		let r = pctx.vars.borrow().lookup(&"r".to_string()).and_then(|(value, _)| value.as_float());
+		Ok(r)
+	};
+
+	// 同じ種からは実行ごとに同じ乱数が得られる
+	let r = run("@option :seed, 42\n@let :r, rand\n", None).unwrap();
+	assert!(r.is_some());
+	assert_eq!(run("@option :seed, 42\n@let :r, rand\n", None).unwrap(), r);
+	assert_ne!(run("@option :seed, 43\n@let :r, rand\n", None).unwrap(), r);
+	// コマンドラインの種は @option seed より優先する
+	assert_eq!(run("@option :seed, 43\n@let :r, rand\n", Some(42)).unwrap(), r);
+
+	let error_at = |moddl: &str| run(moddl, None).err().map(|e| (e.body, e.loc.line));
+	// 乱数を使った後や、種を指定した後には種を指定できない
+	assert!(matches!(error_at("@let :r, rand\n@option :seed, 42\n"), Some((ErrorType::OptionNotAllowedHere, 2))));
+	assert!(matches!(error_at("@option :seed, 42\n@option :seed, 43\n"), Some((ErrorType::SeedAlreadyFixed, 2))));
+	// REPL で演奏した後のように、文の処理より前に種が決まっている場合
+	let mut waveforms = WaveformHost::new();
+	let mut imports = ImportCache::new(&mut waveforms);
+	imports.seed();
+	let e = process_statements("@option :seed, 42\n", Scope::root(HashMap::new()), &PathBuf::new(), &mut imports).err().unwrap();
+	assert!(matches!(e.body, ErrorType::SeedAlreadyFixed));
+	assert!(matches!(error_at("@option :seed, -1\n"), Some((ErrorType::BadSeed, 1))));
+	assert!(matches!(error_at("@option :seed, 1.5\n"), Some((ErrorType::BadSeed, 1))));
+}
@@ -2,30 +2,82 @@ use std::{cell::RefCell, collections::HashMap, path::{Path, PathBuf}, rc::Rc};
 
 use parser::common::Location;
 
-use crate::wave::waveform_host::WaveformHost;
+use crate::{common::seed::Seed, wave::waveform_host::WaveformHost};
 
-use super::{common::read_file, error::{error, ErrorType, ModdlResult}, executor::process_statements, path::resolve_path, scope::Scope, value::{NodeStructure, Value, ValueBody}};
+use super::{common::read_file, error::{error, ErrorType, ModdlResult}, executor::process_statements, lambda_function::Param, path::resolve_path, scope::Scope, value::{NodeStructure, Value, ValueBody}};
 
 pub struct ImportCache<'a> {
 	imports: HashMap<PathBuf, Value>,
 	pub waveforms: &'a mut WaveformHost,
+	/// 読み込み中のファイル（ルートの moddl ファイルから順に）。循環 import を検出するのに使う
+	importing: Vec<PathBuf>,
+	/// 乱数の種。指定されないまま最初に使われたときに決める
+	seed: Option<Seed>,
+	/// 種がコマンドラインで指定されたか。その場合は @option seed を無視する
+	seed_from_command_line: bool,
 }
 impl <'a> ImportCache<'a> {
 	pub fn new(waveforms: &'a mut WaveformHost) -> Self {
 		Self {
 			imports: HashMap::new(),
 			waveforms,
+			importing: vec![],
+			seed: None,
+			seed_from_command_line: false,
 		}
 	}
 
+	pub fn seed(&mut self) -> Seed {
+		*self.seed.get_or_insert_with(Seed::from_entropy)
+	}
+
+	/// コマンドラインで指定された種を設定する。@option seed より優先する
+	pub fn set_command_line_seed(&mut self, seed: Seed) {
+		self.seed = Some(seed);
+		self.seed_from_command_line = true;
+	}
+
+	/// @option seed で指定された種を設定する。
+	/// 乱数を使った後や、すでに種を指定した後では種を変えられないのでエラーにする
+	pub fn set_option_seed(&mut self, seed: Seed, loc: &Location) -> ModdlResult<()> {
+		if self.seed_from_command_line { return Ok(()); }
+		if self.seed.is_some() {
+			return Err(error(ErrorType::SeedAlreadyFixed, loc.clone()));
+		}
+		self.seed = Some(seed);
+
+		Ok(())
+	}
+
+	/// import されたファイルを処理中か
+	pub fn in_import(&self) -> bool {
+		self.importing.len() > 1
+	}
+
+	/// ルートの moddl ファイルを設定する。そのファイルを import した場合も循環として検出できるようになる
+	pub fn set_root_moddl(&mut self, moddl_path: &Path) {
+		self.importing = vec![canonical_path(moddl_path)];
+	}
+
 	pub fn import(&mut self, path: &Path, base_path: &Path, root_scope: Rc<RefCell<Scope>>, loc: &Location) -> ModdlResult<Value> {
 		let abs_path = resolve_path(path, base_path);
 		match self.imports.get(&abs_path) {
 			Some(cached) => Ok(cached.clone()),
 			None => {
+				// 読み込み中のファイルをもう一度 import すると無限に再帰してしまう
+				let canonical = canonical_path(abs_path.as_path());
+				if let Some(start) = self.importing.iter().position(|path| *path == canonical) {
+					let chain = self.importing[start ..].iter().chain(std::iter::once(&canonical))
+							.map(|path| path.display().to_string())
+							.collect();
+					return Err(error(ErrorType::ImportCycle { chain }, loc.clone()));
+				}
+
 				let moddl = read_file(abs_path.as_path()) ?;
-				let pctx = process_statements(moddl.as_str(), root_scope, abs_path.as_path(), self) ?;
-				match pctx.export {
+				self.importing.push(canonical);
+				let pctx = process_statements(moddl.as_str(), root_scope, abs_path.as_path(), self);
+				self.importing.pop();
+				match pctx?.export {
 					None => Err(error(ErrorType::ExportNotFound, loc.clone())),
 					Some(value) => {
 						let result = guard_labels(value);
@@ -39,6 +91,11 @@ impl <'a> ImportCache<'a> {
 	}
 }
 
+/// 同じファイルを別の書き方で指定しても同一と判定できるよう、できれば正規化する
+fn canonical_path(path: &Path) -> PathBuf {
+	path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
 fn guard_labels((val, loc): Value) -> Value {
 	let new_val = match val {
 		ValueBody::NodeStructure(strukt) => {
@@ -47,6 +104,17 @@ fn guard_labels((val, loc): Value) -> Value {
 					NodeStructure::LabelGuard(_) => {
 						strukt.clone()
 					},
+					// 式で記述されたノードは引数を渡して呼び出せるよう、中身だけを保護する
+					NodeStructure::Lambda { input_param, params, args, label, body, loc } => {
+						NodeStructure::Lambda {
+							input_param,
+							params: params.into_iter().map(|Param { name, default }| Param { name, default: default.map(guard_labels) }).collect(),
+							args,
+							label,
+							body: Box::new(NodeStructure::LabelGuard(body)),
+							loc,
+						}
+					},
 					_ => {
 						NodeStructure::LabelGuard(Box::new(strukt.clone()))
 					},
@@ -64,3 +132,37 @@ fn guard_labels((val, loc): Value) -> Value {
 
 	(new_val, loc)
 }
+
+#[cfg(test)]
+#[test]
+fn test_import() {
+	use super::{builtin::Import, function::Function};
+	use std::fs;
+
+	let dir = std::env::temp_dir().join(format!("moddl_test_import_{}", std::process::id()));
+	fs::create_dir_all(&dir).unwrap();
+	fs::write(dir.join("lib.moddl"), "@export { pulse: 1, triangle: 2, noise: 3 }\n").unwrap();
+	fs::write(dir.join("selective.moddl"), "@export import(\"./lib.moddl\")#(pulse, triangle -> tri)\n").unwrap();
+	fs::write(dir.join("a.moddl"), "@export import(\"./b.moddl\")\n").unwrap();
+	fs::write(dir.join("b.moddl"), "@export import(\"./a.moddl\")\n").unwrap();
+
+	let root_scope = Scope::root(vec![("import".to_string(), (ValueBody::Function(Rc::new(Import { }) as Rc<dyn Function>), Location::dummy()))].into_iter().collect());
+	let mut waveforms = WaveformHost::new();
+	let mut imports = ImportCache::new(&mut waveforms);
+	let mut import = |name: &str| imports.import(Path::new(&format!("./{}", name)), &dir.join("main.moddl"), root_scope.clone(), &Location::dummy());
+
+	let (selected, _) = import("selective.moddl").unwrap();
+	let mut keys: Vec<_> = selected.as_assoc().unwrap().keys().cloned().collect();
+	keys.sort();
+	assert_eq!(keys, vec!["pulse".to_string(), "tri".to_string()]);
+
+	match import("a.moddl").err().unwrap().body {
+		ErrorType::ImportCycle { chain } => {
+			let names: Vec<_> = chain.iter().map(|path| Path::new(path).file_name().unwrap().to_str().unwrap().to_string()).collect();
+			assert_eq!(names, vec!["a.moddl", "b.moddl", "a.moddl"]);
+		},
+		e => panic!("unexpected error: {:?}", e),
+	}
+
+	fs::remove_dir_all(&dir).unwrap();
+}
@@ -13,16 +13,18 @@ pub trait Io {
 }
 
 pub struct Rand {
-	gen: StdRng,
+	/// 組み込みの値は種が決まる前に作られるので、最初に使うときに生成する
+	gen: Option<StdRng>,
 }
 impl Rand {
 	pub fn new() -> Self {
-		Self { gen: StdRng::from_entropy() }
+		Self { gen: None }
 	}
 }
 impl Io for Rand {
-	fn perform(&mut self, loc: &Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
-		Ok((ValueBody::Float(self.gen.gen()), loc.clone()))
+	fn perform(&mut self, loc: &Location, imports: &mut ImportCache) -> ModdlResult<Value> {
+		let gen = self.gen.get_or_insert_with(|| StdRng::seed_from_u64(imports.seed().derive("rand")));
+		Ok((ValueBody::Float(gen.gen()), loc.clone()))
 	}
 }
 
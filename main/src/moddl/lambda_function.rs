@@ -11,6 +11,15 @@ use std::{
 	rc::Rc,
 };
 
+/// 式で記述された関数の呼び出しの深さの上限。無限再帰でスタックが溢れる前に止める
+const MAX_CALL_DEPTH: usize = 256;
+/// 関数の呼び出し 1 段で評価器が使うスタック。
+/// デバッグビルドで再帰する関数を評価すると 1 段あたり 150 ～ 200KB 程度使うので、余裕を持たせる
+const STACK_PER_CALL: usize = 256 * 1024;
+/// 評価器を動かすスレッドのスタックの大きさ。上限の深さまで関数を呼び出せるようにする
+pub const EVALUATOR_STACK_SIZE: usize = MAX_CALL_DEPTH * STACK_PER_CALL + 8 * 1024 * 1024;
+
+#[derive(Clone)]
 pub struct Param {
 	pub name: String,
 	pub default: Option<Value>,
@@ -29,9 +38,14 @@ impl LambdaFunction {
 }
 impl Function for LambdaFunction {
 	fn signature(&self) -> FunctionSignature { self.params.iter().map(|param| param.name.clone()).collect() }
-	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, imports: &mut ImportCache) -> ModdlResult<Value> {
+	fn call(&self, args: &HashMap<String, Value>, vars: &Rc<RefCell<Scope>>, call_loc: Location, imports: &mut ImportCache) -> ModdlResult<Value> {
+		// 再帰呼び出しが止まらない場合にスタックが溢れる前に止める
+		if vars.borrow().call_depth() >= MAX_CALL_DEPTH {
+			return Err(error(ErrorType::CallTooDeep { max_depth: MAX_CALL_DEPTH }, call_loc));
+		}
+
 		// 引数のスコープを追加
-		let mut child_vars = Scope::child_of(self.vars.clone());
+		let mut child_vars = Scope::call_frame(self.vars.clone(), vars);
 		self.params.iter().try_for_each(|param| {
 			let value = args.get(&param.name).or(param.default.as_ref())
 					.ok_or_else(|| error(ErrorType::ArgMissing { name: param.name.clone() }, call_loc.clone())) ?;
@@ -42,3 +56,24 @@ impl Function for LambdaFunction {
 		evaluate(&self.body, &mut child_vars, imports)
 	}
 }
+
+#[cfg(test)]
+#[test]
+fn test_recursive_call() {
+	use std::thread;
+
+	// 上限までの再帰呼び出しには既定のスタックでは足りないので、演奏時と同様に大きなスタックで評価する
+	thread::Builder::new().stack_size(EVALUATOR_STACK_SIZE).spawn(|| {
+		let vars = test_scope(vec![]);
+		let eval = |source: &str| evaluate_source(source, &vars);
+
+		let (value, _) = eval("let fact = n => if n <= 1 then 1 else n * fact(n - 1); fact(5)").ok().unwrap();
+		assert_eq!(value.as_float(), Some(120f32));
+		// 上限の深さまでは呼び出せる
+		let count = format!("let count = n => if n <= 1 then 1 else 1 + count(n - 1); count({})", MAX_CALL_DEPTH);
+		let (value, _) = eval(count.as_str()).ok().unwrap();
+		assert_eq!(value.as_float(), Some(MAX_CALL_DEPTH as f32));
+		let Err(e) = eval("let inf = n => inf(n + 1); inf(0)") else { panic!() };
+		assert!(matches!(e.body, ErrorType::CallTooDeep { .. }));
+	}).unwrap().join().unwrap();
+}
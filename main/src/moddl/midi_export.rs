@@ -0,0 +1,412 @@
+use super::{
+	console::warn,
+	error::*,
+	evaluator::prefix_labels,
+	lambda_function::Param,
+	player_context::{PlayerContext, TrackDef},
+	value::*,
+};
+use crate::{
+	calc::sample_to_bool,
+	core::common::*,
+	midi::{
+		smf::*,
+		to_mml::CcMapping,
+	},
+	seq::{
+		instruction::Instruction,
+		sequencer::*,
+		tempo_map::TempoMap,
+		timeline::flatten,
+	},
+};
+extern crate parser;
+use parser::common::Location;
+
+use std::{
+	collections::hash_map::HashMap,
+	path::Path,
+};
+
+/// 書き出す MIDI ファイルの 4 分音符あたりのティック数。グルーヴで揺れた位置を表せるよう細かくしておく
+const DIVISION: i32 = 480;
+const CC_VOLUME: u8 = 7;
+/// GM ではドラムに使うチャンネル（0 始まり）なので、ドラムキットのトラックにだけ割り当てる
+const DRUM_CHANNEL: u8 = 9;
+
+/// 演奏されるインストラクションを Type 1 の MIDI ファイルに書き出す。
+/// ループやマクロは展開し、グルーヴはその関数を計算して位置をずらす。
+/// #velocity はノートのベロシティ、#volume は CC 7、@midiCc で指定したパラメータはそれぞれの CC にする。
+/// 無限ループはその 1 周目まで、ランダムな選択は演奏時と同じ選択肢を書き出し、*** で飛ばす区間は書き出さない。
+/// ドラムキットのトラックは GM のドラムのチャンネル（10）に書き出す。
+/// それ以外のトラックが残りの 15 チャンネルより多い場合は、警告した上でチャンネルを使い回す
+pub fn export_midi(path: &str, sequencers: &[(String, Sequencer)], pctx: &PlayerContext, tempo_map: &TempoMap) -> ModdlResult<()> {
+	let flattened: Vec<_> = sequencers.iter().map(|(_, seqr)| flatten(seqr.sequences(), seqr.choice_seed()).0).collect();
+	let skip_end = skip_end(&flattened);
+	let grooves: HashMap<&String, Groove> = pctx.track_defs.iter().filter_map(|(track, def, _)| match def {
+		TrackDef::Groove(structure) => {
+			let (seq_tag, _) = pctx.grooves.get(track) ?;
+			let labels = sequencers.iter().zip(&flattened).find(|((_, seqr), _)| seqr.name() == track)
+					.map(|(_, instrcs)| collect_params(instrcs))
+					.unwrap_or_default();
+			Some((seq_tag, Groove { track: track.clone(), structure, labels, cycle: pctx.groove_cycle }))
+		},
+		_ => None,
+	}).collect();
+
+	let mut tracks = vec![conductor_track(pctx.tempo, pctx.ticks_per_bar, tempo_map, skip_end)];
+	let channels: Vec<u8> = (0 .. 16u8).filter(|c| *c != DRUM_CHANNEL).collect();
+	let mut channel_users: Vec<Vec<&str>> = vec![vec![]; channels.len()];
+	let mut drum_users: Vec<&str> = vec![];
+	let mut melodic_tracks = 0;
+	let mut unevaluable_grooves = vec![];
+	for ((seq_tag, seqr), instrcs) in sequencers.iter().zip(&flattened) {
+		// グルーヴを制御するトラックは音を出さない
+		if grooves.values().any(|groove| groove.track == seqr.name()) { continue; }
+
+		let groove = grooves.get(seq_tag);
+		let to_midi_tick = |tick: i32| {
+			let tick = match groove {
+				Some(groove) => groove.even_tick(tick).unwrap_or_else(|| {
+					if ! unevaluable_grooves.contains(&groove.track) { unevaluable_grooves.push(groove.track.clone()); }
+					tick as f32
+				}),
+				None => tick as f32,
+			};
+			((tick - skip_end as f32).max(0f32) * 4f32 * DIVISION as f32 / pctx.ticks_per_bar as f32).round() as u32
+		};
+		let no_cc = HashMap::new();
+		let cc_map = pctx.midi_cc.get(seqr.name()).unwrap_or(&no_cc);
+		let is_drum_kit = matches!(pctx.get_track_def(&seqr.name().to_string()), Some((TrackDef::DrumKit(_), _)));
+		let channel = if is_drum_kit {
+			drum_users.push(seqr.name());
+			DRUM_CHANNEL
+		} else {
+			// 空いているチャンネルがなければ、先頭から使い回す
+			let i = melodic_tracks % channels.len();
+			melodic_tracks += 1;
+			channel_users[i].push(seqr.name());
+			channels[i]
+		};
+		tracks.push(instructions_to_track(seqr.name(), instrcs, channel, cc_map, skip_end, to_midi_tick));
+	}
+	for (channel, users) in channels.iter().zip(&channel_users) {
+		if users.len() > 1 {
+			warn(format!("tracks {} share MIDI channel {} because there are more than {} tracks other than drum kits", users.join(", "), channel + 1, channels.len()));
+		}
+	}
+	if drum_users.len() > 1 {
+		warn(format!("drum kit tracks {} share MIDI channel {}", drum_users.join(", "), DRUM_CHANNEL + 1));
+	}
+	for track in unevaluable_grooves {
+		warn(format!("groove of track {} cannot be exported because it contains nodes other than calculations; exported without groove", track));
+	}
+
+	let smf = Smf { format: 1, division: DIVISION as u16, tracks };
+	std::fs::write(path, write_smf(&smf)).map_err(|e| error(e.into(), Location::file(Path::new(path))))
+}
+
+/// *** で飛ばす区間の終わりのティック。飛ばしている間は演奏の時間が進まないので、書き出すときはその分を詰める。
+/// スキップは全トラックに及ぶので、いずれかのトラックが飛ばし始めたら、最初に抜ける位置までを飛ばす
+fn skip_end(flattened: &[Vec<(i32, &Instruction)>]) -> i32 {
+	let instrcs = || flattened.iter().flatten();
+	if ! instrcs().any(|(_, instrc)| matches!(instrc, Instruction::EnterSkipMode)) { return 0; }
+
+	instrcs().filter(|(_, instrc)| matches!(instrc, Instruction::ExitSkipMode))
+			.map(|(tick, _)| *tick)
+			.min()
+			.unwrap_or(0)
+}
+
+/// テンポと拍子のイベントだけを持つトラック。飛ばす区間での変更は先頭にまとめる
+fn conductor_track(initial_tempo: Sample, ticks_per_bar: i32, tempo_map: &TempoMap, skip_end: i32) -> Vec<SmfEvent> {
+	let to_midi_tick = |tick: i32| ((tick - skip_end).max(0) as f32 * 4f32 * DIVISION as f32 / ticks_per_bar as f32).round() as u32;
+	let mut result = vec![];
+	for (tick, (numerator, denominator)) in tempo_map.meters() {
+		result.push(SmfEvent { tick: to_midi_tick(*tick), body: SmfEventBody::TimeSignature {
+			numerator: *numerator as u8,
+			denominator: *denominator as u8,
+		} });
+	}
+	let mut prev_micros = None;
+	for (tick, tempo) in std::iter::once(&(0, initial_tempo)).chain(tempo_map.tempo_changes()) {
+		let micros_per_quarter = (60_000_000f32 / tempo.max(1f32)).round() as u32;
+		// ランプはティックごとに展開されているので、変化のないものは省く
+		if prev_micros == Some(micros_per_quarter) { continue; }
+		prev_micros = Some(micros_per_quarter);
+		result.push(SmfEvent { tick: to_midi_tick(*tick), body: SmfEventBody::Tempo { micros_per_quarter } });
+	}
+	result.sort_by_key(|event| event.tick);
+
+	result
+}
+
+/// skip_end より前のノートは書き出さない
+fn instructions_to_track(track: &str, instrcs: &[(i32, &Instruction)], channel: u8, cc_map: &HashMap<u8, CcMapping>, skip_end: i32, mut to_midi_tick: impl FnMut (i32) -> u32) -> Vec<SmfEvent> {
+	let params = collect_params(instrcs);
+	let param_at = |tag: &str, tick: i32| params.get(tag)
+			.and_then(|changes| changes.iter().take_while(|(t, _)| *t <= tick).last())
+			.map(|(_, value)| *value);
+	let velocity_tag = format!("{}.#velocity", track);
+	let velocity_at = |tick: i32| to_midi_value(param_at(velocity_tag.as_str(), tick).unwrap_or(1f32)).max(1);
+
+	let mut result = vec![SmfEvent { tick: 0, body: SmfEventBody::TrackName { name: track.to_string() } }];
+	let mut note = |result: &mut Vec<SmfEvent>, tick: i32, key: u8, note_on: bool| {
+		let body = if note_on {
+			SmfEventBody::NoteOn { channel, key, velocity: velocity_at(tick) }
+		} else {
+			SmfEventBody::NoteOff { channel, key }
+		};
+		result.push(SmfEvent { tick: to_midi_tick(tick), body });
+	};
+
+	// モノフォニックなトラックは周波数の変更とノートオン・オフが別のインストラクションになっている
+	let freq_tag = format!("{}_freq", track);
+	let mut freq = 0f32;
+	let mut sounding = None;
+	// ポリフォニックなトラックのノートの識別子と、それに割り当てたノート番号
+	let mut poly_keys = HashMap::new();
+	for (tick, instrc) in instrcs {
+		let skipped = *tick < skip_end;
+		match instrc {
+			Instruction::Value { tag, value, .. } if *tag == freq_tag => {
+				freq = *value;
+				// スラーで音高が変わった場合は、ノートを分ける
+				if let Some(key) = sounding {
+					if freq_to_key(freq) != Some(key) {
+						note(&mut result, *tick, key, false);
+						sounding = freq_to_key(freq);
+						if let Some(key) = sounding { note(&mut result, *tick, key, true); }
+					}
+				}
+			},
+			Instruction::Note { tag, note_on } if tag == track => {
+				if let Some(key) = sounding.take() { note(&mut result, *tick, key, false); }
+				if *note_on && ! skipped {
+					sounding = freq_to_key(freq);
+					if let Some(key) = sounding { note(&mut result, *tick, key, true); }
+				}
+			},
+			Instruction::PolyNote { key: id, freq, note_on } => {
+				if *note_on {
+					if let Some(key) = freq_to_key(*freq).filter(|_| ! skipped) {
+						poly_keys.insert(*id, key);
+						note(&mut result, *tick, key, true);
+					}
+				} else if let Some(key) = poly_keys.remove(id) {
+					note(&mut result, *tick, key, false);
+				}
+			},
+			_ => { },
+		}
+	}
+	if let Some((tick, _)) = instrcs.last() {
+		if let Some(key) = sounding { note(&mut result, *tick, key, false); }
+		for key in poly_keys.into_values() { note(&mut result, *tick, key, false); }
+	}
+
+	let volume = CcMapping { label: "#volume".to_string(), min: 0f32, max: 1f32 };
+	for (controller, CcMapping { label, min, max }) in std::iter::once((&CC_VOLUME, &volume)).chain(cc_map.iter()) {
+		let changes = match params.get(&format!("{}.{}", track, label)) {
+			Some(changes) => changes,
+			None => continue,
+		};
+		let mut prev = None;
+		for (tick, value) in changes {
+			let value = to_midi_value((value - min) / (max - min));
+			if prev == Some(value) { continue; }
+			prev = Some(value);
+			result.push(SmfEvent { tick: to_midi_tick(*tick), body: SmfEventBody::ControlChange { channel, controller: *controller, value } });
+		}
+	}
+
+	result.sort_by_key(|event| event.tick);
+	result
+}
+
+/// パラメータのタグごとの値の変化。ランプはティックごとの値に展開する
+fn collect_params(instrcs: &[(i32, &Instruction)]) -> HashMap<String, Vec<(i32, Sample)>> {
+	let mut result = HashMap::<String, Vec<(i32, Sample)>>::new();
+	for (tick, instrc) in instrcs {
+		match instrc {
+			Instruction::Value { tag, value, .. } => { result.entry(tag.clone()).or_default().push((*tick, *value)); },
+			Instruction::Ramp { tag, from, to, ticks, curve, .. } => {
+				let changes = result.entry(tag.clone()).or_default();
+				// シーケンサと同じく、直前の値が分かっていればそこから変化させる
+				let from = changes.last().map_or(*from, |(_, value)| *value);
+				for t in 0 ..= *ticks {
+					changes.push((tick + t, ramp_value(from, *to, t, *ticks, *curve)));
+				}
+			},
+			_ => { },
+		}
+	}
+
+	result
+}
+
+fn freq_to_key(freq: Sample) -> Option<u8> {
+	if freq <= 0f32 { return None; }
+	Some((69f32 + 12f32 * (freq / 440f32).log2()).round().clamp(0f32, MAX_MIDI_VALUE) as u8)
+}
+
+fn to_midi_value(value: Sample) -> u8 {
+	(value * MAX_MIDI_VALUE).round().clamp(0f32, MAX_MIDI_VALUE) as u8
+}
+
+struct Groove<'a> {
+	/// グルーヴを制御するトラック
+	track: String,
+	structure: &'a NodeStructure,
+	/// 制御するトラックで設定したパラメータの値の変化
+	labels: HashMap<String, Vec<(i32, Sample)>>,
+	cycle: i32,
+}
+impl <'a> Groove<'a> {
+	/// グルーヴをかけたトラックのティックを、イーブンのティックに変換する。
+	/// Tick と同じく、周期の中で関数の値が初めてティックの値以上になる位置を求める。
+	/// 関数が計算できないノードを含む場合は None
+	fn even_tick(&self, tick: i32) -> Option<f32> {
+		let cycle_start = tick - tick % self.cycle;
+		let target = (tick % self.cycle) as f32;
+		if target == 0f32 { return Some(cycle_start as f32); }
+
+		let label_value = |label: &str| self.labels.get(&format!("{}.{}", self.track, label))
+				.and_then(|changes| changes.iter().take_while(|(t, _)| *t <= cycle_start).last())
+				.map(|(_, value)| *value);
+		// 関数は単調増加であるとみなして二分探索する
+		let (mut low, mut high) = (0f32, self.cycle as f32);
+		for _ in 0 .. 32 {
+			let mid = (low + high) / 2f32;
+			if evaluate_groove(self.structure, mid, &mut vec![], &label_value, false)? >= target {
+				high = mid;
+			} else {
+				low = mid;
+			}
+		}
+
+		Some(cycle_start as f32 + high)
+	}
+}
+
+/// グルーヴの関数を、ノードを作らずに計算する。計算できないノードを含む場合は None
+fn evaluate_groove(strukt: &NodeStructure, input: Sample, placeholders: &mut Vec<(String, Sample)>, label_value: &dyn Fn (&str) -> Option<Sample>, inside_label_guard: bool) -> Option<Sample> {
+	match strukt {
+		NodeStructure::Calc { node_factory, args } => {
+			let args = args.iter()
+					.map(|(arg, _)| evaluate_groove(arg, input, placeholders, label_value, inside_label_guard))
+					.collect::<Option<Vec<_>>>() ?;
+			Some(node_factory.calc(&args))
+		},
+		NodeStructure::Connect(lhs, rhs) => {
+			let lhs = evaluate_groove(&lhs.0, input, placeholders, label_value, inside_label_guard) ?;
+			evaluate_groove(&rhs.0, lhs, placeholders, label_value, inside_label_guard)
+		},
+		NodeStructure::Condition { cond, then, els } => {
+			let branch = if sample_to_bool(evaluate_groove(&cond.0, input, placeholders, label_value, inside_label_guard) ?) { then } else { els };
+			evaluate_groove(&branch.0, input, placeholders, label_value, inside_label_guard)
+		},
+		NodeStructure::Lambda { input_param, params, args, label, body, .. } => {
+			// 引数は呼び出し側のプレースホルダで評価する
+			let mut arg_values = vec![];
+			for Param { name, default } in params {
+				let strukt = match args.get(name) {
+					Some(value) => value.as_node_structure().ok()?.0,
+					None => prefix_labels(&default.as_ref()?.as_node_structure().ok()?.0, label),
+				};
+				arg_values.push((name.clone(), evaluate_groove(&strukt, input, placeholders, label_value, inside_label_guard) ?));
+			}
+			let depth = placeholders.len();
+			placeholders.push((input_param.clone(), input));
+			placeholders.extend(arg_values);
+			let result = evaluate_groove(&prefix_labels(body, label), input, placeholders, label_value, inside_label_guard);
+			placeholders.truncate(depth);
+			result
+		},
+		NodeStructure::Constant { value, label } => match label {
+			Some(label) if ! inside_label_guard => Some(label_value(label.0.as_str()).unwrap_or(*value)),
+			_ => Some(*value),
+		},
+		NodeStructure::Placeholder { name } => placeholders.iter().rev().find(|(n, _)| n == name).map(|(_, value)| *value),
+		NodeStructure::LabelGuard(inner) => evaluate_groove(inner, input, placeholders, label_value, true),
+		NodeStructure::NodeCreation { .. } => None,
+	}
+}
+
+#[cfg(test)]
+#[test]
+fn test_instructions_to_track() {
+	let instrcs = vec![
+		Instruction::Value { tag: "a.#velocity".to_string(), key: "value".to_string(), value: 0.5 },
+		Instruction::Value { tag: "a_freq".to_string(), key: "value".to_string(), value: 440f32 },
+		Instruction::Note { tag: "a".to_string(), note_on: true },
+		Instruction::Note { tag: "a".to_string(), note_on: false },
+	];
+	let instrcs: Vec<_> = vec![0, 0, 0, 96].into_iter().zip(instrcs.iter()).collect();
+	let track = instructions_to_track("a", &instrcs, 1, &HashMap::new(), 0, |tick| tick as u32 * 5);
+	assert_eq!(track, vec![
+		SmfEvent { tick: 0, body: SmfEventBody::TrackName { name: "a".to_string() } },
+		SmfEvent { tick: 0, body: SmfEventBody::NoteOn { channel: 1, key: 69, velocity: 64 } },
+		SmfEvent { tick: 480, body: SmfEventBody::NoteOff { channel: 1, key: 69 } },
+	]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_skip() {
+	// c *** d のように、飛ばした区間のノートは書き出さず、その分を詰める
+	let freq = |value| Instruction::Value { tag: "a_freq".to_string(), key: "value".to_string(), value };
+	let note = |note_on| Instruction::Note { tag: "a".to_string(), note_on };
+	let instrcs = vec![
+		Instruction::EnterSkipMode,
+		freq(440f32), note(true), note(false),
+		Instruction::ExitSkipMode,
+		freq(880f32), note(true), note(false),
+	];
+	let instrcs: Vec<_> = vec![0, 0, 0, 96, 96, 96, 96, 192].into_iter().zip(instrcs.iter()).collect();
+	let skip_end = skip_end(&[instrcs.clone()]);
+	assert_eq!(skip_end, 96);
+	let track = instructions_to_track("a", &instrcs, 1, &HashMap::new(), skip_end, |tick| (tick - skip_end).max(0) as u32);
+	assert_eq!(track, vec![
+		SmfEvent { tick: 0, body: SmfEventBody::TrackName { name: "a".to_string() } },
+		SmfEvent { tick: 0, body: SmfEventBody::NoteOn { channel: 1, key: 81, velocity: 127 } },
+		SmfEvent { tick: 96, body: SmfEventBody::NoteOff { channel: 1, key: 81 } },
+	]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_evaluate_groove_with_lambda_args() {
+	use crate::calc::{AddCalc, MulCalc};
+	use parser::moddl::ast::QualifiedLabel;
+	use std::rc::Rc;
+
+	let placeholder = |name: &str| (NodeStructure::Placeholder { name: name.to_string() }, Location::dummy());
+	let float = |value| (ValueBody::Float(value), Location::dummy());
+	// =x(scale, offset = 1@o)=> x * scale + offset
+	let lambda = |args: Vec<(&str, Value)>, label: Option<&str>| NodeStructure::Lambda {
+		input_param: "x".to_string(),
+		params: vec![
+			Param { name: "scale".to_string(), default: None },
+			Param { name: "offset".to_string(), default: Some((ValueBody::NodeStructure(NodeStructure::Constant { value: 1f32, label: Some(QualifiedLabel("o".to_string())) }), Location::dummy())) },
+		],
+		args: args.into_iter().map(|(name, value)| (name.to_string(), value)).collect(),
+		label: label.map(|label| QualifiedLabel(label.to_string())),
+		body: Box::new(NodeStructure::Calc {
+			node_factory: Rc::new(CalcNodeFactory::<AddCalc>::new()),
+			args: vec![
+				(NodeStructure::Calc { node_factory: Rc::new(CalcNodeFactory::<MulCalc>::new()), args: vec![placeholder("x"), placeholder("scale")] }, Location::dummy()),
+				placeholder("offset"),
+			],
+		}),
+		loc: Location::dummy(),
+	};
+	let label_value = |label: &str| if label == "g.o" { Some(10f32) } else { None };
+
+	assert_eq!(evaluate_groove(&lambda(vec![("scale", float(3f32))], None), 2f32, &mut vec![], &label_value, false), Some(7f32));
+	assert_eq!(evaluate_groove(&lambda(vec![("scale", float(3f32)), ("offset", float(2f32))], None), 2f32, &mut vec![], &label_value, false), Some(8f32));
+	// 省略時の値のラベルはノード自体のラベルの下に置かれる
+	assert_eq!(evaluate_groove(&lambda(vec![("scale", float(3f32))], Some("g")), 2f32, &mut vec![], &label_value, false), Some(16f32));
+	// 必要な引数がなければ計算できない
+	assert_eq!(evaluate_groove(&lambda(vec![], None), 2f32, &mut vec![], &label_value, false), None);
+}
@@ -1,9 +1,9 @@
 use super::{
-	builtin::builtin_vars, common::{make_seq_tag, read_file}, error::*, evaluator::*, executor::process_statements, import::ImportCache, io::Io, player_context::{MuteSolo, TrackDef}, player_option::*, scope::*, value::*
+	builtin::builtin_vars, checker::check_track_defs, common::{make_seq_tag, read_file}, console::warn, error::*, evaluator::*, executor::process_statements, import::ImportCache, lambda_function::Param, midi_export::export_midi, io::Io, player_context::{DrumHit, MuteSolo, PlayerContext, Polyphony, TrackDef}, player_option::*, scope::*, value::*
 };
 use crate::{
 	calc::*,
-	common::stack::*,
+	common::{seed::Seed, stack::*},
 	core::{
 		common::*,
 		context::*,
@@ -13,13 +13,17 @@ use crate::{
 		node_factory::*,
 		node_host::*,
 	},
-	mml::default::{
-		feature::Feature,
-		sequence_generator::*,
+	mml::{
+		default::{
+			feature::Feature,
+			sequence_generator::*,
+		},
+		tuning::Tuning,
 	},
 	node::{
 		audio::*,
 		cond::*,
+		lfo::*,
 		prim::*,
 		stereo::*,
 		system::*,
@@ -27,18 +31,22 @@ use crate::{
 		var::*,
 	},
 	seq::{
+		humanize::Humanize,
 		sequencer::*,
+		tempo_map::TempoMap,
 		tick::*,
+		timeline::*,
+		voice_allocator::*,
 	},
 	vis::visualizer::*, wave::waveform_host::WaveformHost,
 };
 extern crate parser;
 use parser::{
-	common::{Location, Span}, mml::default_mml_parser, moddl::{ast::QualifiedLabel, parser::expr}
+	common::{Location, Span}, mml::{ast::MmlPos, dialect::MmlDialect}, moddl::{ast::QualifiedLabel, parser::expr}
 };
 
 use std::{
-	borrow::Borrow, cell::RefCell, collections::hash_map::HashMap, path::Path, rc::Rc, sync::{
+	borrow::Borrow, cell::RefCell, collections::{hash_map::HashMap, hash_set::HashSet}, path::Path, rc::Rc, sync::{
 		mpsc, Arc
 	}, thread
 };
@@ -47,14 +55,27 @@ use std::{
 
 const TAG_SEQUENCER: &str = "seq";
 
+pub const SAMPLE_RATE: i32 = 44100; // TODO 値を外から渡せるように
+
 pub fn play(options: &PlayerOptions) -> ModdlResult<()> {
 	let moddl_path = Path::new(&options.moddl_path);
 	let moddl = read_file(moddl_path) ?;
-	let sample_rate = 44100; // TODO 値を外から渡せるように
 	let mut waveforms = WaveformHost::new();
 	let mut imports = ImportCache::new(&mut waveforms);
-	let root_vars = Scope::root(builtin_vars(sample_rate, &mut imports) ?);
+	imports.set_root_moddl(moddl_path);
+	if let Some(seed) = options.seed {
+		imports.set_command_line_seed(Seed(seed));
+	}
+	let root_vars = Scope::root(builtin_vars(SAMPLE_RATE, &mut imports) ?);
 	let mut pctx = process_statements(moddl.as_str(), root_vars, moddl_path, &mut imports) ?;
+
+	play_context(&mut pctx, options, &mut imports)
+}
+
+/// 文を処理し終えた PlayerContext からノードを組み立てて演奏する。
+/// REPL のように、スコープや import のキャッシュを保ったまま何度も演奏する場合はこちらを直接呼ぶ
+pub fn play_context(pctx: &mut PlayerContext, options: &PlayerOptions, imports: &mut ImportCache) -> ModdlResult<()> {
+	check_track_defs(&pctx.track_defs) ?;
 	
 	// TODO シングルマシン（シングルスレッド）モードは現状これだけだとだめ（Tick が重複してすごい速さで演奏される）
 	let mut nodes = AllNodes::new(false);
@@ -70,11 +91,14 @@ pub fn play(options: &PlayerOptions) -> ModdlResult<()> {
 			timer.node(MACHINE_MAIN).as_mono(), pctx.groove_cycle, even_tag.clone())));
 
 	let mut output_nodes = HashMap::<String, NodeId>::new();
+	let mut sequencers = vec![];
 
 	// for (track, mml) in &pctx.mmls {
-	for (track, spec, _) in &pctx.track_defs {
+	for (track, spec, def_loc) in &pctx.track_defs {
 		let submachine_idx = nodes.add_submachine(track.clone());
 		let mml = &pctx.mmls.get(track).map(|mml| mml.as_str()).unwrap_or("");
+		let locate_mml = |pos| pctx.locate_mml(track, pos);
+		let dialect = pctx.get_dialect(track) ?;
 		let output_node = {
 			// @mute で指定されているか、@solo で指定されていなければ、ミュート対象
 			if pctx.mute_solo_tracks.contains(track) == (pctx.mute_solo == MuteSolo::Mute) {
@@ -85,21 +109,29 @@ pub fn play(options: &PlayerOptions) -> ModdlResult<()> {
 					None => even_tag.clone(),
 				};
 				match spec {
-					TrackDef::Instrument(structure) => {
-						Some(build_nodes_by_mml(track.as_str(), structure, mml, pctx.moddl_path.as_path(), pctx.ticks_per_bar, &seq_tag, &mut nodes, submachine_idx,
-								&mut PlaceholderStack::init(HashMap::new()), None, pctx.tempo, pctx.use_default_labels, &pctx.vars, &mut imports) ?)
+					TrackDef::Instrument(structure, polyphony) => {
+						let voicing = match polyphony {
+							None => Voicing::Mono(structure),
+							Some(polyphony) => Voicing::Poly(structure, *polyphony),
+						};
+						Some(build_nodes_by_mml(track.as_str(), voicing, mml, &locate_mml, def_loc, dialect, pctx.moddl_path.as_path(), pctx.ticks_per_bar, pctx.get_tuning(track), pctx.humanize.get(track).copied(), &seq_tag, &mut sequencers, &mut nodes, submachine_idx,
+								&mut PlaceholderStack::init(HashMap::new()), None, pctx.tempo, pctx.use_default_labels, &pctx.vars, imports) ?)
+					}
+					TrackDef::DrumKit(hits) => {
+						Some(build_nodes_by_mml(track.as_str(), Voicing::DrumKit(hits), mml, &locate_mml, def_loc, dialect, pctx.moddl_path.as_path(), pctx.ticks_per_bar, pctx.get_tuning(track), pctx.humanize.get(track).copied(), &seq_tag, &mut sequencers, &mut nodes, submachine_idx,
+								&mut PlaceholderStack::init(HashMap::new()), None, pctx.tempo, pctx.use_default_labels, &pctx.vars, imports) ?)
 					}
 					TrackDef::Effect(source_tracks, structure) => {
 						let mut placeholders = PlaceholderStack::init(HashMap::new());
 						source_tracks.iter().for_each(|track| {
 							placeholders.top_mut().insert(track.clone(), output_nodes[track]);
 						});
-						Some(build_nodes_by_mml(track.as_str(), structure, mml, pctx.moddl_path.as_path(), pctx.ticks_per_bar, &seq_tag, &mut nodes, submachine_idx,
-								&mut placeholders, None, pctx.tempo, pctx.use_default_labels, &pctx.vars, &mut imports) ?)
+						Some(build_nodes_by_mml(track.as_str(), Voicing::Mono(structure), mml, &locate_mml, def_loc, dialect, pctx.moddl_path.as_path(), pctx.ticks_per_bar, pctx.get_tuning(track), pctx.humanize.get(track).copied(), &seq_tag, &mut sequencers, &mut nodes, submachine_idx,
+								&mut placeholders, None, pctx.tempo, pctx.use_default_labels, &pctx.vars, imports) ?)
 					}
 					TrackDef::Groove(structure) => {
-						let groovy_timer = build_nodes_by_mml(track.as_str(), structure, mml, pctx.moddl_path.as_path(), pctx.ticks_per_bar, &seq_tag, &mut nodes, MACHINE_MAIN,
-								&mut PlaceholderStack::init(HashMap::new()), Some(timer), pctx.tempo, pctx.use_default_labels, &pctx.vars, &mut imports)
+						let groovy_timer = build_nodes_by_mml(track.as_str(), Voicing::Mono(structure), mml, &locate_mml, def_loc, dialect, pctx.moddl_path.as_path(), pctx.ticks_per_bar, pctx.get_tuning(track), pctx.humanize.get(track).copied(), &seq_tag, &mut sequencers, &mut nodes, MACHINE_MAIN,
+								&mut PlaceholderStack::init(HashMap::new()), Some(timer), pctx.tempo, pctx.use_default_labels, &pctx.vars, imports)
 								?.node(MACHINE_MAIN).as_mono();
 						nodes.add_node(MACHINE_MAIN, Box::new(Tick::new(groovy_timer, pctx.groove_cycle, seq_tag.clone())));
 
@@ -114,6 +146,22 @@ pub fn play(options: &PlayerOptions) -> ModdlResult<()> {
 		};
 	}
 
+	let timelines: Vec<_> = sequencers.iter().map(|(_, seqr)| (seqr.name().to_string(), walk(seqr.sequences(), seqr.choice_seed()))).collect();
+	let tempo_map = TempoMap::new(pctx.ticks_per_bar, pctx.tempo, timelines.iter().map(|(_, t)| t));
+	check_markers(&timelines, pctx) ?;
+	check_bars(&timelines, &tempo_map, pctx);
+	if let Some(path) = &options.timeline_path {
+		return export_timeline(Path::new(path), &timelines, &tempo_map);
+	}
+	if let Some(path) = &options.midi_path {
+		return export_midi(path, &sequencers, pctx, &tempo_map);
+	}
+	let range = playback_range(&timelines, &tempo_map, &options.start_marker, &options.loop_markers, pctx) ?;
+	for (seq_tag, mut seqr) in sequencers {
+		seqr.set_playback_range(range);
+		nodes.add_node_with_tag(MACHINE_MAIN, seq_tag, Box::new(seqr));
+	}
+
 	let mut terminal_tracks: Vec<&String> = pctx.terminal_tracks.iter().collect();
 	terminal_tracks.sort_unstable();
 	let terminal_nodes: Vec<NodeId> = terminal_tracks.iter().map(|t| output_nodes[*t]).collect();
@@ -184,7 +232,8 @@ pub fn play(options: &PlayerOptions) -> ModdlResult<()> {
 	// 	events
 	// });
 
-	let nodes_result = nodes.result();
+	let mut nodes_result = nodes.result();
+	seed_nodes(&mut nodes_result, imports.seed());
 
 	let broadcast_pairs = make_broadcast_pairs(nodes_result.len());
 	let broadcaster = Broadcaster::new(broadcast_pairs.senders);
@@ -193,7 +242,8 @@ pub fn play(options: &PlayerOptions) -> ModdlResult<()> {
 	// TODO コマンドオプションで指定されたときだけ出力する
 	// output_structure(&nodes_result, &sends_to_receives);
 
-	let waveforms = Arc::new(waveforms);
+	// 演奏中はスレッド間で共有し、演奏が終わったら ImportCache に戻す
+	let waveforms = Arc::new(std::mem::replace(imports.waveforms, WaveformHost::new()));
 	let joins: Vec<_> = nodes_result.into_iter()
 			.zip(broadcast_pairs.receivers.into_iter())
 			.map(|(mut machine_spec, broadcast_receiver)| {
@@ -203,7 +253,7 @@ pub fn play(options: &PlayerOptions) -> ModdlResult<()> {
 			// TODO skip_mode_events が供給できていない
 			let mut machine = Machine::new(machine_spec.name);
 
-			machine.play(&mut Context::new(sample_rate), &mut machine_spec.nodes, &waveforms,
+			machine.play(&mut Context::new(SAMPLE_RATE), &mut machine_spec.nodes, &waveforms,
 					broadcaster_, broadcast_receiver, None);
 		})
 	}).collect();
@@ -211,10 +261,114 @@ pub fn play(options: &PlayerOptions) -> ModdlResult<()> {
 		// TODO エラー処理
 		let _ = j.join();
 	}
+	if let Ok(waveforms) = Arc::try_unwrap(waveforms) {
+		*imports.waveforms = waveforms;
+	}
+
+	Ok(())
+}
+
+/// 同じ名前のマーカーが全トラックで同じ位置にあることを確認する。
+/// 繰り返しの回数が違う場合は、短い方に合わせて比べる
+/// エラーは後から現れたトラックの、位置が食い違ったマーカーの位置で報告する
+fn check_markers(timelines: &[(String, Timeline)], pctx: &PlayerContext) -> ModdlResult<()> {
+	let mut names: Vec<&String> = timelines.iter().flat_map(|(_, t)| t.markers.iter().map(|(name, ..)| name)).collect();
+	names.sort_unstable();
+	names.dedup();
+	for name in names {
+		let mut first: Option<(&String, Vec<i32>)> = None;
+		for (track, timeline) in timelines {
+			let markers: Vec<_> = timeline.markers.iter().filter(|(n, ..)| n == name).collect();
+			if markers.is_empty() { continue; }
+			match &first {
+				None => { first = Some((track, markers.iter().map(|(_, tick, _)| *tick).collect())); },
+				Some((track1, ticks1)) => {
+					if let Some((tick1, (_, tick2, pos))) = ticks1.iter().zip(markers.iter()).find(|(t1, (_, t2, _))| *t1 != t2) {
+						return Err(error(ErrorType::MarkerMismatch {
+							name: name.clone(),
+							track1: track1.to_string(),
+							tick1: *tick1,
+							track2: track.clone(),
+							tick2: *tick2,
+						}, pctx.locate_mml(track, *pos)));
+					}
+				},
+			}
+		}
+	}
 
 	Ok(())
 }
 
+/// 長さが正しくない小節を警告する
+fn check_bars(timelines: &[(String, Timeline)], tempo_map: &TempoMap, pctx: &PlayerContext) {
+	for (track, timeline) in timelines {
+		for BadBar { bar, length, expected, pos } in timeline.bad_bars(tempo_map) {
+			let loc = pos.map(|(line, column)| format!(" at {}", pctx.locate_mml(track, MmlPos { line, column })));
+			warn(format!("bar {} of track {} is {} ticks long but should be {} ticks{}", bar, track, length, expected, loc.unwrap_or_default()));
+		}
+	}
+}
+
+/// マーカーが現れる位置。
+/// その名前のマーカーがなく、名前が数値なら小節番号とみなして、その小節の先頭の位置を返す。
+/// 名前はコマンドラインで指定したものなので、見つからない場合のエラーにはファイル上の位置がない
+fn marker_ticks(timelines: &[(String, Timeline)], tempo_map: &TempoMap, name: &str, pctx: &PlayerContext) -> ModdlResult<Vec<i32>> {
+	if let Some(ticks) = timelines.iter().map(|(_, t)| t.marker_ticks(name)).find(|ticks| ! ticks.is_empty()) {
+		return Ok(ticks);
+	}
+	match name.parse::<i32>() {
+		Ok(bar) if bar >= 1 => Ok(vec![tempo_map.bar_start(bar)]),
+		_ => Err(error(ErrorType::MarkerNotFound { name: name.to_string() }, Location::file(&pctx.moddl_path))),
+	}
+}
+
+/// マーカーを最初に書いた位置。小節番号で指定した場合など、MML にマーカーがなければファイル全体を指す
+fn marker_loc(timelines: &[(String, Timeline)], name: &str, pctx: &PlayerContext) -> Location {
+	timelines.iter()
+			.find_map(|(track, timeline)| timeline.markers.iter().find(|(n, ..)| n == name).map(|(_, _, pos)| pctx.locate_mml(track, *pos)))
+			.unwrap_or_else(|| Location::file(&pctx.moddl_path))
+}
+
+fn playback_range(timelines: &[(String, Timeline)], tempo_map: &TempoMap, start_marker: &Option<String>, loop_markers: &Option<(String, String)>, pctx: &PlayerContext) -> ModdlResult<PlaybackRange> {
+	let loop_range = match loop_markers {
+		Some((loop_start, loop_end)) => {
+			let loop_start_tick = marker_ticks(timelines, tempo_map, loop_start, pctx)?[0];
+			// 終点は始点より後にある最初の位置を使う
+			let loop_end_tick = marker_ticks(timelines, tempo_map, loop_end, pctx)?.into_iter()
+					.find(|tick| *tick > loop_start_tick)
+					.ok_or_else(|| error(ErrorType::BadLoopRange, marker_loc(timelines, loop_end, pctx))) ?;
+			Some((loop_start_tick, loop_end_tick))
+		},
+		None => None,
+	};
+	// 開始位置の指定がなくループの指定があれば、ループの始点から演奏する
+	let start = match (start_marker, loop_range) {
+		(Some(start_marker), _) => marker_ticks(timelines, tempo_map, start_marker, pctx)?[0],
+		(None, Some((loop_start, _))) => loop_start,
+		(None, None) => 0,
+	};
+	if let (Some((_, loop_end)), Some(start_marker)) = (loop_range, start_marker) {
+		if start >= loop_end {
+			return Err(error(ErrorType::BadLoopRange, marker_loc(timelines, start_marker, pctx)));
+		}
+	}
+
+	Ok(PlaybackRange { start, loop_range })
+}
+
+/// トラックごとのマーカーの位置を、ティック・小節・秒で TSV に書き出す
+fn export_timeline(path: &Path, timelines: &[(String, Timeline)], tempo_map: &TempoMap) -> ModdlResult<()> {
+	let mut result = "track\tmarker\ttick\tbar\tseconds\n".to_string();
+	for (track, timeline) in timelines {
+		for (name, tick, _) in &timeline.markers {
+			let (bar, _) = tempo_map.bar_at(*tick);
+			result.push_str(format!("{}\t{}\t{}\t{}\t{:.3}\n", track, name, tick, bar, tempo_map.ticks_to_seconds(*tick)).as_str());
+		}
+	}
+	std::fs::write(path, result).map_err(|e| error(e.into(), Location::file(path)))
+}
+
 struct BroadcastPairs {
 	senders: Vec<mpsc::Sender<GlobalEvent>>,
 	receivers: Vec<mpsc::Receiver<GlobalEvent>>,
@@ -245,11 +399,20 @@ impl Iterator for EventIter {
 const VAR_DEFAULT_KEY: &str = "value"; // TODO VarFactory を設けてそこから取るようにする
 
 // TODO 引数を整理できるか
-fn build_nodes_by_mml<'a>(track: &str, instrm_def: &NodeStructure, mml: &'a str, moddl_path: &Path, ticks_per_bar: i32, seq_tag: &String, nodes: &mut AllNodes, submachine_idx: MachineIndex, placeholders: &mut PlaceholderStack, override_input: Option<NodeId>,
+/// トラックの楽器の組み立て方
+enum Voicing<'a> {
+	Mono(&'a NodeStructure),
+	/// ボイスの数だけ同じ楽器を組み立てる
+	Poly(&'a NodeStructure, Polyphony),
+	/// ヒットごとに楽器を組み立て、キーでどれを鳴らすかを決める
+	DrumKit(&'a [DrumHit]),
+}
+
+fn build_nodes_by_mml<'a>(track: &str, voicing: Voicing, mml: &'a str, locate_mml: &dyn Fn (MmlPos) -> Location, def_loc: &Location, dialect: &dyn MmlDialect, moddl_path: &Path, ticks_per_bar: i32, tuning: &Tuning, humanize: Option<Humanize>, seq_tag: &String, sequencers: &mut Vec<(String, Sequencer)>, nodes: &mut AllNodes, submachine_idx: MachineIndex, placeholders: &mut PlaceholderStack, override_input: Option<NodeId>,
 		tempo: f32, use_default_labels: bool, vars: &Rc<RefCell<Scope>>, imports: &mut ImportCache)
 		-> ModdlResult<NodeId> {
 	let moddl_path_rc = Rc::new(moddl_path.to_path_buf());
-	let (_, ast) = default_mml_parser::compilation_unit()(Span::new_extra(mml, moddl_path_rc.clone()))
+	let ast = dialect.parse(Span::new_extra(mml, moddl_path_rc.clone()))
 	.map_err(|e| error(ErrorType::MmlSyntax(nom_error_to_owned(e)), Location::dummy())) ?;
 	let freq_tag = format!("{}_freq", track);
 
@@ -263,24 +426,21 @@ fn build_nodes_by_mml<'a>(track: &str, instrm_def: &NodeStructure, mml: &'a str,
 	const DETUNE_INIT: f32 = 0f32;
 	// let var_default_key = 
 
-	let features = scan_features(&ast);
+	let features = scan_features(&ast, dialect);
 
-	let mut input = match override_input {
-		Some(input) => input,
-		None => nodes.add_node_with_tag(submachine_idx, freq_tag.clone(), Box::new(Var::new(0f32))),
+	// ノートオンを受け取るタグ。ポリフォニックならボイスごとにある
+	let note_tags: Vec<String> = match voicing {
+		Voicing::Mono(_) => vec![track.to_string()],
+		Voicing::Poly(_, Polyphony { voices, .. }) => (0 .. voices).map(|i| format!("{}#{}", track, i)).collect(),
+		Voicing::DrumKit(hits) => (0 .. hits.len()).map(|i| format!("{}#{}", track, i)).collect(),
 	};
-	if features.contains(&Feature::Detune) {
-		// セント単位のデチューン
-		// freq_detuned = freq * 2 ^ (detune / 1200)
+
+	let detune = if features.contains(&Feature::Detune) {
 		// TODO タグ名は feature requirements として generate_sequences の際に受け取る
-		let detune = nodes.add_node_with_tag(submachine_idx, format!("{}.#detune", &track), Box::new(Var::new(DETUNE_INIT)));
-		let cents_per_oct = nodes.add_node(submachine_idx, Box::new(Constant::new(1200f32)));
-		let detune_oct = divide(Some(track), nodes, submachine_idx, detune, cents_per_oct) ?; // 必ず成功するはず
-		let const_2 = nodes.add_node(submachine_idx, Box::new(Constant::new(2f32)));
-		let freq_ratio = power(Some(track), nodes, submachine_idx, const_2, detune_oct) ?; // 必ず成功するはず
-		let freq_detuned = multiply(Some(track), nodes, submachine_idx, input, freq_ratio) ?; // 必ず成功するはず
-		input = freq_detuned;
-	}
+		Some(nodes.add_node_with_tag(submachine_idx, format!("{}.#detune", &track), Box::new(Var::new(DETUNE_INIT))))
+	} else {
+		None
+	};
 
 	let mut inits: HashMap<(String, String), Sample> = vec![
 		((format!("{}.#velocity", &track), VAR_DEFAULT_KEY.to_string()), VELOCITY_INIT),
@@ -289,16 +449,93 @@ fn build_nodes_by_mml<'a>(track: &str, instrm_def: &NodeStructure, mml: &'a str,
 		(("#tempo".to_string(), VAR_DEFAULT_KEY.to_string()), tempo),
 	].into_iter().collect();
 	let mut label_defaults: HashMap<String, String> = inits.iter().map(|((label, key), _)| (label.clone(), key.clone())).into_iter().collect();
+	// LFO はキーが複数あるので、既定のキーは明示的に決める
+	for lfo in [PARAM_NAME_VIBRATO, PARAM_NAME_TREMOLO, PARAM_NAME_AUTO_PAN] {
+		for key in LFO_KEYS {
+			inits.insert((format!("{}.{}", &track, lfo), key.to_string()), 0f32);
+		}
+		label_defaults.insert(format!("{}.{}", &track, lfo), LFO_DEFAULT_KEY.to_string());
+	}
 	// TODO DRY
 	label_defaults.insert(format!("{}_freq", track), VAR_DEFAULT_KEY.to_string());
-	/* let label_defaults =  */collect_label_defaults(instrm_def, track, use_default_labels, &mut label_defaults);
-	let instrm = build_instrument(track, instrm_def, nodes, submachine_idx, input, placeholders, &label_defaults, use_default_labels, &mut inits) ?;
+	let instrm_defs = match voicing {
+		Voicing::Mono(instrm_def) | Voicing::Poly(instrm_def, _) => vec![instrm_def],
+		Voicing::DrumKit(hits) => hits.iter().map(|hit| &hit.structure).collect(),
+	};
+	for instrm_def in instrm_defs {
+		/* let label_defaults =  */collect_label_defaults(instrm_def, track, use_default_labels, &mut label_defaults);
+	}
+
+	let mut hit_keys = HashMap::new();
+	let (instrm, voices) = match voicing {
+		Voicing::Mono(instrm_def) => {
+			let mut input = match override_input {
+				Some(input) => input,
+				None => nodes.add_node_with_tag(submachine_idx, freq_tag.clone(), Box::new(Var::new(0f32))),
+			};
+			input = detune_voice(track, track, input, detune, &features, nodes, submachine_idx) ?;
+			let instrm = build_instrument(track, track, instrm_def, nodes, submachine_idx, input, placeholders, &label_defaults, use_default_labels, &mut inits) ?;
+			let instrm = add_voice_lfos(track, track, instrm, &features, def_loc, nodes, submachine_idx) ?;
+
+			(instrm, None)
+		},
+		Voicing::Poly(instrm_def, Polyphony { stealing, .. }) => {
+			// ボイスの数だけ楽器を組み立てて足し合わせる。
+			// ラベルのタグはトラック名で修飾するので、パラメータの変更は全ボイスに届く
+			let mut voice_tags = vec![];
+			let mut sum = None;
+			for note_tag in &note_tags {
+				let voice_freq_tag = format!("{}_freq", note_tag);
+				let input = nodes.add_node_with_tag(submachine_idx, voice_freq_tag.clone(), Box::new(Var::new(0f32)));
+				let input = detune_voice(track, note_tag, input, detune, &features, nodes, submachine_idx) ?;
+				let voice = build_instrument(track, note_tag, instrm_def, nodes, submachine_idx, input, placeholders, &label_defaults, use_default_labels, &mut inits) ?;
+				let voice = add_voice_lfos(track, note_tag, voice, &features, def_loc, nodes, submachine_idx) ?;
+				sum = Some(match sum {
+					None => voice,
+					Some(sum) => add(Some(track), nodes, submachine_idx, sum, voice) ?,
+				});
+				voice_tags.push(VoiceTags { note: note_tag.clone(), freq: voice_freq_tag });
+			}
+
+			// ボイス数は 1 以上なので必ず Some
+			(sum.unwrap(), Some(VoiceAllocator::new(voice_tags, VAR_DEFAULT_KEY.to_string(), stealing)))
+		},
+		Voicing::DrumKit(hits) => {
+			// ポリフォニックと同様にヒットごとのボイスを足し合わせる。ヒットの音量は楽器の出力に掛ける
+			let mut voice_tags = vec![];
+			let mut routes = vec![];
+			let mut sum = None;
+			for (hit, note_tag) in hits.iter().zip(&note_tags) {
+				let voice_freq_tag = format!("{}_freq", note_tag);
+				let input = nodes.add_node_with_tag(submachine_idx, voice_freq_tag.clone(), Box::new(Var::new(0f32)));
+				let input = detune_voice(track, note_tag, input, detune, &features, nodes, submachine_idx) ?;
+				let mut voice = build_instrument(track, note_tag, &hit.structure, nodes, submachine_idx, input, placeholders, &label_defaults, use_default_labels, &mut inits) ?;
+				voice = add_voice_lfos(track, note_tag, voice, &features, def_loc, nodes, submachine_idx) ?;
+				if hit.velocity != 1f32 {
+					let velocity = nodes.add_node(submachine_idx, Box::new(Constant::new(hit.velocity)));
+					voice = multiply(Some(note_tag.as_str()), nodes, submachine_idx, voice, velocity) ?;
+				}
+				sum = Some(match sum {
+					None => voice,
+					Some(sum) => add(Some(track), nodes, submachine_idx, sum, voice) ?,
+				});
+				voice_tags.push(VoiceTags { note: note_tag.clone(), freq: voice_freq_tag });
+				routes.push(HitRoute { key: hit.key, choke: hit.choke.clone() });
+				hit_keys.insert(hit.name.clone(), hit.key);
+			}
+
+			// ヒットは 1 つ以上あるので必ず Some
+			(sum.unwrap(), Some(VoiceAllocator::drum_kit(voice_tags, VAR_DEFAULT_KEY.to_string(), routes)))
+		},
+	};
 
 	// let label_defaults = collect_label_defaults(instrm_def, track);
 
 	let tag_set = TagSet {
 		freq: freq_tag.clone(),
 		note: track.to_string(),
+		polyphonic: voices.is_some(),
+		hits: hit_keys,
 	};
 	let mut evaluate_expr = |expr_str: &str| {
 		// TODO 位置情報の補正が必要
@@ -325,11 +562,18 @@ fn build_nodes_by_mml<'a>(track: &str, instrm_def: &NodeStructure, mml: &'a str,
 		}
 	};
 
-	let seqs = generate_sequences(&ast, ticks_per_bar, &tag_set, format!("{}.", &track).as_str(), &inits, &label_defaults, &mut evaluate_expr) ?;
-	let _seqr = nodes.add_node_with_tag(MACHINE_MAIN, seq_tag.to_string(), Box::new(Sequencer::new(track.to_string(), seqs)));
+	let seqs = generate_sequences(&ast, ticks_per_bar, &tag_set, tuning, dialect, format!("{}.", &track).as_str(), &inits, &label_defaults, &mut evaluate_expr, locate_mml) ?;
+	// マーカーの位置を全トラックで揃えてから演奏範囲を設定するので、ここではノードに追加しない
+	let mut seqr = Sequencer::new(track.to_string(), seqs, voices);
+	seqr.set_seed(imports.seed().derive(&format!("track/{}", track)));
+	if let Some(humanize) = humanize {
+		seqr.set_humanize(humanize, (format!("{}.#velocity", &track), VAR_DEFAULT_KEY.to_string()));
+	}
+	sequencers.push((seq_tag.to_string(), seqr));
 
 	let mut output = instrm;
-	if features.contains(&Feature::Velocity) {
+	// ベロシティを揺らす場合は、MML でベロシティを指定していなくてもノードが必要
+	if features.contains(&Feature::Velocity) || humanize.is_some_and(|h| h.velocity > 0f32) {
 		// TODO タグ名は feature requirements として generate_sequences の際に受け取る
 		let vel = nodes.add_node_with_tag(submachine_idx, format!("{}.#velocity", &track), Box::new(Var::new(VELOCITY_INIT)));
 		let output_vel = multiply(Some(track), nodes, submachine_idx, output, vel) ?; // 必ず成功するはず
@@ -348,6 +592,61 @@ fn build_nodes_by_mml<'a>(track: &str, instrm_def: &NodeStructure, mml: &'a str,
 	Ok(output)
 }
 
+const LFO_KEYS: [&str; 4] = ["rate", "depth", "delay", "waveform"];
+/// LFO のラベルだけでパラメータを指定したときのキー
+const LFO_DEFAULT_KEY: &str = "depth";
+
+/// MML の LFO コマンドで制御するノードを作る。
+/// パラメータは {track}.{name} のタグで、ノートオンはボイスのノートのタグで受け取る。
+/// ノートオンで位相とディレイをリセットするので、ボイス間では共有しない
+fn add_lfo(track: &str, note_tag: &str, name: &str, mode: LfoMode, nodes: &mut AllNodes, submachine_idx: MachineIndex) -> NodeId {
+	let tags = vec![note_tag.to_string(), format!("{}.{}", track, name)];
+	nodes.add_node_with_tags(submachine_idx, tags, Box::new(Lfo::new(mode)))
+}
+
+/// ボイスの周波数の入力にデチューンとビブラートを掛ける。デチューンの値はトラックの全ボイスで共有する
+fn detune_voice(track: &str, note_tag: &str, input: NodeId, detune: Option<NodeId>, features: &HashSet<Feature>,
+		nodes: &mut AllNodes, submachine_idx: MachineIndex) -> ModdlResult<NodeId> {
+	let vibrato = if features.contains(&Feature::Vibrato) {
+		Some(add_lfo(track, note_tag, PARAM_NAME_VIBRATO, LfoMode::Bipolar, nodes, submachine_idx))
+	} else {
+		None
+	};
+	let detune = match (detune, vibrato) {
+		(Some(detune), Some(vibrato)) => add(Some(note_tag), nodes, submachine_idx, detune, vibrato) ?, // 必ず成功するはず
+		(Some(detune), None) | (None, Some(detune)) => detune,
+		(None, None) => { return Ok(input); },
+	};
+
+	// セント単位のデチューン
+	// freq_detuned = freq * 2 ^ (detune / 1200)
+	let cents_per_oct = nodes.add_node(submachine_idx, Box::new(Constant::new(1200f32)));
+	let detune_oct = divide(Some(note_tag), nodes, submachine_idx, detune, cents_per_oct) ?; // 必ず成功するはず
+	let const_2 = nodes.add_node(submachine_idx, Box::new(Constant::new(2f32)));
+	let freq_ratio = power(Some(note_tag), nodes, submachine_idx, const_2, detune_oct) ?; // 必ず成功するはず
+	multiply(Some(note_tag), nodes, submachine_idx, input, freq_ratio) // 必ず成功するはず
+}
+
+/// ボイスの出力にトレモロとオートパンを掛ける。def_loc はトラックを定義した位置
+fn add_voice_lfos(track: &str, note_tag: &str, voice: NodeId, features: &HashSet<Feature>, def_loc: &Location,
+		nodes: &mut AllNodes, submachine_idx: MachineIndex) -> ModdlResult<NodeId> {
+	let mut output = voice;
+	if features.contains(&Feature::Tremolo) {
+		let tremolo = add_lfo(track, note_tag, PARAM_NAME_TREMOLO, LfoMode::Unipolar, nodes, submachine_idx);
+		output = multiply(Some(note_tag), nodes, submachine_idx, output, tremolo) ?; // 必ず成功するはず
+	}
+	if features.contains(&Feature::AutoPan) {
+		// 定位を揺らすのはモノラルの出力に限る
+		if output.channels() != 1 {
+			return Err(error(ErrorType::AutoPanOnStereoTrack, def_loc.clone()));
+		}
+		let pos = add_lfo(track, note_tag, PARAM_NAME_AUTO_PAN, LfoMode::Bipolar, nodes, submachine_idx);
+		let pan = Box::new(Pan::new(output.node(submachine_idx).as_mono(), pos.node(submachine_idx).as_mono()));
+		output = nodes.add_node_with_tag(submachine_idx, note_tag.to_string(), pan);
+	}
+	Ok(output)
+}
+
 fn collect_label_defaults(instrm_def: &NodeStructure, track: &str, use_default_labels: bool, result: &mut HashMap<String, String>) /* -> HashMap<String, String> */ {
 	fn visit_struct(strukt: &NodeStructure, track: &str, use_default_labels: bool, result: &mut HashMap<String, String>) {
 		match strukt {
@@ -370,19 +669,33 @@ fn collect_label_defaults(instrm_def: &NodeStructure, track: &str, use_default_l
 				}
 			},
 			NodeStructure::Calc { args, .. } => {
-				for arg in args { visit_struct(arg, track, use_default_labels, result); }
+				for (arg, _) in args { visit_struct(arg, track, use_default_labels, result); }
 			},
 			NodeStructure::Connect(lhs, rhs) => {
-				visit_struct(lhs, track, use_default_labels, result);
-				visit_struct(rhs, track, use_default_labels, result);
+				visit_struct(&lhs.0, track, use_default_labels, result);
+				visit_struct(&rhs.0, track, use_default_labels, result);
 			},
 			NodeStructure::Condition { cond, then, els } => {
-				visit_struct(cond, track, use_default_labels, result);
-				visit_struct(then, track, use_default_labels, result);
-				visit_struct(els, track, use_default_labels, result);
+				visit_struct(&cond.0, track, use_default_labels, result);
+				visit_struct(&then.0, track, use_default_labels, result);
+				visit_struct(&els.0, track, use_default_labels, result);
 			},
-			NodeStructure::Lambda { body, .. } => {
-				visit_struct(body, track, use_default_labels, result);
+			NodeStructure::Lambda { params, args, label, body, .. } => {
+				for Param { name, default } in params {
+					match args.get(name).or(default.as_ref()) {
+						// 省略時の値の中のラベルは body の中のラベルと同様に扱う
+						Some((ValueBody::NodeStructure(arg), _)) if ! args.contains_key(name) => {
+							visit_struct(&prefix_labels(arg, label), track, use_default_labels, result);
+						},
+						Some((ValueBody::NodeStructure(arg), _)) => { visit_struct(arg, track, use_default_labels, result); },
+						_ => { },
+					}
+					// 互換性対応：NodeCreation と同様に全て Var と見なす
+					if use_default_labels {
+						result.insert(format!("{}.{}", track, lambda_arg_label(label, name).0), VAR_DEFAULT_KEY.to_string());
+					}
+				}
+				visit_struct(&prefix_labels(body, label), track, use_default_labels, result);
 			},
 			NodeStructure::Constant { label, .. } => {
 				if let Some(label) = label {
@@ -404,10 +717,19 @@ fn collect_label_defaults(instrm_def: &NodeStructure, track: &str, use_default_l
 	// result
 }
 
+/// 式で記述されたノードの引数につける既定のラベル。ノード自体のラベルがあればその下に置く
+fn lambda_arg_label(label: &Option<QualifiedLabel>, name: &str) -> QualifiedLabel {
+	match label {
+		Some(label) => QualifiedLabel(format!("{}.{}", label.0, name)),
+		None => QualifiedLabel(name.to_string()),
+	}
+}
+
 pub type PlaceholderStack = Stack<HashMap<String, NodeId>>;
 
 fn build_instrument(
 	track: &str,
+	note_tag: &str,
 	instrm_def: &NodeStructure,
 	nodes: &mut AllNodes,
 	submachine_idx: MachineIndex,
@@ -419,6 +741,7 @@ fn build_instrument(
 ) -> ModdlResult<NodeId> {
 	fn visit_struct(
 		track: &str,
+		note_tag: &str,
 		strukt: &NodeStructure,
 		nodes: &mut AllNodes,
 		submachine_idx: MachineIndex,
@@ -433,13 +756,13 @@ fn build_instrument(
 		// 関数にするとライフタイム関係？のエラーが取れなかったので…
 		macro_rules! recurse {
 			// $const_tag は、直下が定数値（ノードの種類としては Var）であった場合に付与するタグ
-			($strukt: expr, $input: expr, $inside_label_guard: expr, $const_tag: expr) => { visit_struct(track, $strukt, nodes, submachine_idx, $input, /* Some( */$const_tag/* ) */, placeholders, label_defaults, use_default_labels, inits, $inside_label_guard) };
-			($strukt: expr, $input: expr, $inside_label_guard: expr) => { visit_struct(track, $strukt, nodes, submachine_idx, $input, None, placeholders, label_defaults, use_default_labels, inits, $inside_label_guard) };
+			($strukt: expr, $input: expr, $inside_label_guard: expr, $const_tag: expr) => { visit_struct(track, note_tag, $strukt, nodes, submachine_idx, $input, /* Some( */$const_tag/* ) */, placeholders, label_defaults, use_default_labels, inits, $inside_label_guard) };
+			($strukt: expr, $input: expr, $inside_label_guard: expr) => { visit_struct(track, note_tag, $strukt, nodes, submachine_idx, $input, None, placeholders, label_defaults, use_default_labels, inits, $inside_label_guard) };
 		}
 		// 関数にすると（同上）
 		macro_rules! add_node {
-			// トラックに属する node は全てトラック名のタグをつける
-			($new_node: expr) => { Ok(nodes.add_node_with_tag(submachine_idx, track.to_string(), $new_node)) }
+			// トラックに属する node は全てトラック名（ポリフォニックならボイス名）のタグをつける
+			($new_node: expr) => { Ok(nodes.add_node_with_tag(submachine_idx, note_tag.to_string(), $new_node)) }
 		}
 
 		// ノードの引数をデフォルトを考慮して解決する
@@ -466,7 +789,7 @@ fn build_instrument(
 				let arg_name = arg_val.map(|(_, (value, _))| value.label()).flatten()
 						.or_else(|| if use_default_labels { Some(QualifiedLabel(name.clone())) } else { None })/* .unwrap_or(name.clone()) */;
 				let arg_node = recurse!(&strukt, input, inside_label_guard, arg_name) ?;
-				let coerced_arg_node = match coerce_input(Some(track), nodes, submachine_idx, arg_node, channels) {
+				let coerced_arg_node = match coerce_input(Some(note_tag), nodes, submachine_idx, arg_node, channels) {
 					Some(result) => result,
 					// モノラルであるべき node_arg にステレオが与えられた場合、
 					// 勝手にモノラルに変換するとロスが発生するのでエラーにする
@@ -482,25 +805,25 @@ fn build_instrument(
 			NodeStructure::Calc { node_factory, args } => {
 				// TODO Result が絡んでるときも map できれいに書きたい
 				let mut arg_nodes = vec![];
-				for arg in args {
+				for (arg, _) in args {
 					arg_nodes.push(recurse!(arg, input, inside_label_guard) ?);
 				}
 
-				create_calc_node(Some(track), nodes, submachine_idx, arg_nodes, node_factory.borrow())
+				create_calc_node(Some(note_tag), nodes, submachine_idx, arg_nodes, node_factory.borrow())
 			},
 
 			NodeStructure::Connect(lhs, rhs) => {
 				// TODO mono/stereo 変換
-				let l_node = recurse!(lhs, input, inside_label_guard) ?;
-				recurse!(rhs, l_node, inside_label_guard)
+				let l_node = recurse!(&lhs.0, input, inside_label_guard) ?;
+				recurse!(&rhs.0, l_node, inside_label_guard)
 			},
 
 			NodeStructure::Condition { cond, then, els } => {
-				let cond_result = recurse!(cond, input, inside_label_guard) ?;
+				let cond_result = recurse!(&cond.0, input, inside_label_guard) ?;
 				let cond_result = ensure_on_machine(nodes, cond_result, submachine_idx);
-				let then_result = recurse!(then, input, inside_label_guard) ?;
+				let then_result = recurse!(&then.0, input, inside_label_guard) ?;
 				let then_result = ensure_on_machine(nodes, then_result, submachine_idx);
-				let else_result = recurse!(els, input, inside_label_guard) ?;
+				let else_result = recurse!(&els.0, input, inside_label_guard) ?;
 				let else_result = ensure_on_machine(nodes, else_result, submachine_idx);
 				// let max_delay = * vec![cond_result_on_machine.1, then_result_on_machine.1, else_result_on_machine.1].iter().max().unwrap();
 				// TODO ステレオ対応（入力のどれかがステレオならステレオに拡張する）
@@ -510,11 +833,29 @@ fn build_instrument(
 				add_node!(node)
 			},
 
-			NodeStructure::Lambda { input_param, body } => {
+			NodeStructure::Lambda { input_param, params, args, label, body, loc } => {
+				// 引数は呼び出し側のプレースホルダで評価するので、入力を積む前に生成しておく
+				let mut arg_nodes = vec![];
+				for Param { name, default } in params {
+					let arg_val = args.get(name);
+					let strukt = match arg_val.or(default.as_ref()) {
+						Some(value) => value.as_node_structure()?.0,
+						// 必要な引数が与えられていない
+						None => Err(error(ErrorType::ArgMissing { name: name.clone() }, loc.clone())) ?,
+					};
+					// 省略時の値の中のラベルは body の中のラベルと同様に扱う
+					let strukt = if arg_val.is_some() { strukt } else { prefix_labels(&strukt, label) };
+					// ラベルが明示されていればそちらを使う
+					let arg_label = strukt.label()
+							.or_else(|| if use_default_labels { Some(lambda_arg_label(label, name)) } else { None });
+					arg_nodes.push((name.clone(), recurse!(&strukt, input, inside_label_guard, arg_label) ?));
+				}
+
 				placeholders.push_clone();
 				placeholders.top_mut().insert(input_param.clone(), input);
+				placeholders.top_mut().extend(arg_nodes);
 
-				let result = recurse!(body, input, inside_label_guard);
+				let result = recurse!(&prefix_labels(body, label), input, inside_label_guard);
 
 				placeholders.pop();
 
@@ -542,7 +883,7 @@ fn build_instrument(
 					}
 				}
 
-				apply_input(Some(track), nodes, submachine_idx, factory, &node_args, full_tag,input)
+				apply_input(Some(note_tag), nodes, submachine_idx, factory, &node_args, full_tag,input)
 			}
 			// TODO Constant は、NodeCreation で VarFactory を使ったのと同じにできるはず。共通化する
 			NodeStructure::Constant { value, label } => {
@@ -560,7 +901,7 @@ fn build_instrument(
 						// TODO ここで label_defaults から見つからないことはありえないはずだが、補足できるエラー（内部エラー的な）として軟着陸させた方がよさそう
 						let default = label_defaults.get(&tag).unwrap();
 						inits.insert((tag.clone(), default.clone()), *value);
-						Ok(nodes.add_node_with_tags(submachine_idx, vec![track.to_string(), tag], node))
+						Ok(nodes.add_node_with_tags(submachine_idx, vec![note_tag.to_string(), tag], node))
 					},
 					None => add_node!(node),
 				}
@@ -576,7 +917,7 @@ fn build_instrument(
 		}
 	}
 
-	visit_struct(track, instrm_def, nodes, submachine_idx, freq, None, placeholders, label_defaults, use_default_labels, inits, false)
+	visit_struct(track, note_tag, instrm_def, nodes, submachine_idx, freq, None, placeholders, label_defaults, use_default_labels, inits, false)
 }
 
 // fn create_node_by_factory(factory: &Rc<dyn NodeFactory>, args: &HashMap<String, Value>) {
@@ -688,6 +1029,17 @@ fn apply_input(
 }
 
 const MACHINE_MAIN: MachineIndex = MachineIndex(0usize);
+
+/// マシン名とマシン内での位置から各ノードの種を導出する。
+/// トラックごとにマシンを分けているので、他のトラックを増減してもノードの種は変わらない
+fn seed_nodes(machines: &mut [MachineSpec], seed: Seed) {
+	for MachineSpec { name, nodes } in machines {
+		for (index, node) in nodes.nodes_mut().iter_mut().enumerate() {
+			node.set_seed(seed.derive(&format!("{}/{}", name, index)));
+		}
+	}
+}
+
 struct AllNodes {
 	single_machine: bool,
 	machines: Vec<MachineSpec>,
@@ -861,3 +1213,107 @@ binary!(greater, GtCalc);
 binary!(greater_or_equal, GeCalc);
 binary!(and, AndCalc);
 binary!(or, OrCalc);
+
+#[cfg(test)]
+#[test]
+fn test_lambda_node_labels() {
+	let vars = test_scope(vec![]);
+	let eval = |source: &str| evaluate_source(source, &vars).ok().unwrap().as_node_structure().ok().unwrap().0;
+	let label_defaults = |strukt: &NodeStructure, use_default_labels: bool| {
+		let mut result = HashMap::new();
+		collect_label_defaults(strukt, "a", use_default_labels, &mut result);
+		let mut labels: Vec<_> = result.into_keys().collect();
+		labels.sort();
+		labels
+	};
+
+	// 省略時の値の中のラベルは、ノード自体のラベルの下に置く。渡した引数の中のラベルはそのまま
+	let strukt = eval("let amp = =x(gain = 0.5@g, q = 2)=> x * gain * q; amp { q: 3@r }@bp");
+	assert_eq!(label_defaults(&strukt, false), vec!["a.bp.g", "a.r"]);
+	// 互換性対応：既定のラベルでは、引数の名前もノード自体のラベルの下に置く
+	assert_eq!(label_defaults(&strukt, true), vec!["a.bp.g", "a.bp.gain", "a.bp.q", "a.r"]);
+	assert_eq!(lambda_arg_label(&None, "q").0, "q");
+
+	// 省略時の値のない引数が渡されていなければ、ノードを書いた位置でエラーにする
+	let strukt = eval("let amp = =x(q)=> x * q; amp");
+	let mut nodes = AllNodes::new(true);
+	let freq = nodes.add_node(MACHINE_MAIN, Box::new(Var::new(0f32)));
+	let e = build_instrument("a", "a", &strukt, &mut nodes, MACHINE_MAIN, freq, &mut PlaceholderStack::init(HashMap::new()), &HashMap::new(), false, &mut HashMap::new()).err().unwrap();
+	assert!(matches!(&e.body, ErrorType::ArgMissing { name } if name == "q"));
+	assert_eq!((e.loc.line, e.loc.column), (1, 11));
+}
+
+#[cfg(test)]
+#[test]
+fn test_playback_range_errors() {
+	use super::player_context::MmlSource;
+	use std::path::PathBuf;
+
+	let moddl_path = PathBuf::from("song.moddl");
+	let mut pctx = PlayerContext::init(&moddl_path, Scope::root(HashMap::new()));
+	pctx.mml_locs.insert("a".to_string(), vec![MmlSource::Statement(Location { path: Rc::new(moddl_path.clone()), line: 5, column: 3 })]);
+	let timeline = Timeline {
+		markers: vec![("A".to_string(), 0, MmlPos { line: 1, column: 3 }), ("B".to_string(), 384, MmlPos { line: 1, column: 10 })],
+		..Default::default()
+	};
+	let timelines = vec![("a".to_string(), timeline)];
+	let tempo_map = TempoMap::new(384, 120f32, timelines.iter().map(|(_, t)| t));
+	let error_at = |start: Option<&str>, (loop_start, loop_end): (&str, &str)| {
+		let e = playback_range(&timelines, &tempo_map, &start.map(|s| s.to_string()), &Some((loop_start.to_string(), loop_end.to_string())), &pctx).err().unwrap();
+		assert!(matches!(e.body, ErrorType::BadLoopRange));
+		e.loc.to_string()
+	};
+
+	// 終点が見つからなければ終点のマーカー、開始位置がループの外なら開始位置のマーカーの位置で報告する
+	assert_eq!(error_at(None, ("B", "A")), "song.moddl, line 5, column 5");
+	assert_eq!(error_at(Some("B"), ("A", "B")), "song.moddl, line 5, column 12");
+	// 小節番号で指定した場合はファイル全体を指す
+	assert_eq!(error_at(None, ("A", "1")), "song.moddl");
+	assert!(playback_range(&timelines, &tempo_map, &None, &Some(("A".to_string(), "B".to_string())), &pctx).is_ok());
+}
+
+#[cfg(test)]
+#[test]
+fn test_seed_choices() {
+	use super::executor::process_statements;
+	use crate::wave::waveform_host::WaveformHost;
+	use std::fs;
+
+	let dir = std::env::temp_dir().join(format!("moddl_test_seed_choices_{}", std::process::id()));
+	fs::create_dir_all(&dir).unwrap();
+	let timeline_path = dir.join("timeline.tsv");
+	// 選んだ選択肢をマーカーの並びとして書き出す
+	let choices = |moddl: &str, seed: Option<u64>| {
+		let mut waveforms = WaveformHost::new();
+		let mut imports = ImportCache::new(&mut waveforms);
+		if let Some(seed) = seed {
+			imports.set_command_line_seed(Seed(seed));
+		}
+		let mut pctx = process_statements(moddl, Scope::root(HashMap::new()), &dir.join("main.moddl"), &mut imports).unwrap();
+		let options = PlayerOptions {
+			moddl_path: String::new(),
+			output: PlayerOutput::Null,
+			start_marker: None,
+			loop_markers: None,
+			timeline_path: Some(timeline_path.to_string_lossy().to_string()),
+			midi_path: None,
+			seed,
+		};
+		play_context(&mut pctx, &options, &mut imports).unwrap();
+		fs::read_to_string(&timeline_path).unwrap().lines().skip(1)
+				.map(|line| line.split('\t').take(2).collect::<Vec<_>>().join(":"))
+				.collect::<Vec<_>>()
+	};
+	let moddl = "@instrument ^ab, 0\na [16 ?[@@x r / @@y r]]\nb [16 ?[@@z r / @@w r]]\n";
+
+	// 同じ種からは同じ選択肢を選び、種を変えると選び方も変わる
+	assert_eq!(choices(moddl, Some(1)).len(), 32);
+	assert_eq!(choices(moddl, Some(1)), choices(moddl, Some(1)));
+	assert_ne!(choices(moddl, Some(1)), choices(moddl, Some(2)));
+	// 揺らぎの種を省略した場合も、曲の種に従う
+	let humanized = format!("{}@humanize ^ab, {{ timing: 4 }}\n", moddl);
+	assert_eq!(choices(&humanized, Some(1)), choices(moddl, Some(1)));
+	assert_ne!(choices(&humanized, Some(1)), choices(&humanized, Some(2)));
+
+	fs::remove_dir_all(&dir).unwrap();
+}
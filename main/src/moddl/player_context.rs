@@ -1,8 +1,19 @@
 use super::{
 	error::*, scope::*, value::*,
 };
+use crate::{
+	midi::to_mml::CcMapping,
+	mml::tuning::Tuning,
+	seq::{
+		humanize::Humanize,
+		voice_allocator::StealingPolicy,
+	},
+};
 extern crate parser;
-use parser::common::Location;
+use parser::{
+	common::Location,
+	mml::{ast::MmlPos, dialect::*},
+};
 
 use std::{
 	cell::RefCell,
@@ -24,6 +35,8 @@ pub struct PlayerContext {
 	pub groove_cycle: i32,
 	// トラックごとの MML を蓄積
 	pub mmls: BTreeMap<String, String>,
+	// トラックごとに、MML の各行を書いた位置
+	pub mml_locs: HashMap<String, Vec<MmlSource>>,
 	pub mute_solo: MuteSolo,
 	pub mute_solo_tracks: HashSet<String>,
 	pub vars: Rc<RefCell<Scope>>,
@@ -33,6 +46,16 @@ pub struct PlayerContext {
 	pub allows_option_here: bool,
 	// #21 パラメータ名を暗黙にラベルにする。互換動作
 	pub use_default_labels: bool,
+	// 全トラック共通の音律と、@tuning でトラックごとに指定された音律
+	pub tuning: Tuning,
+	pub track_tunings: HashMap<String, Tuning>,
+	// @humanize でトラックごとに指定された揺らぎ
+	pub humanize: HashMap<String, Humanize>,
+	// @midiCc でトラックごとに指定された、MIDI ファイルに書き出すコントロールチェンジ
+	pub midi_cc: HashMap<String, HashMap<u8, CcMapping>>,
+	// 全トラック共通の MML の方言と、@dialect でトラックごとに指定された方言
+	pub mml_dialect: &'static dyn MmlDialect,
+	pub track_dialects: HashMap<String, &'static dyn MmlDialect>,
 }
 impl PlayerContext {
 	pub fn init(moddl_path: &Path, root_scope: Rc<RefCell<Scope>>) -> Self {
@@ -49,6 +72,7 @@ impl PlayerContext {
 			grooves: HashMap::new(),
 			groove_cycle: 384,
 			mmls: BTreeMap::new(),
+			mml_locs: HashMap::new(),
 			mute_solo: MuteSolo::Mute,
 			mute_solo_tracks: HashSet::new(),
 			vars,
@@ -56,7 +80,44 @@ impl PlayerContext {
 			seq_tags: HashSet::new(),
 			allows_option_here: true,
 			use_default_labels: false,
+			tuning: Tuning::default(),
+			track_tunings: HashMap::new(),
+			humanize: HashMap::new(),
+			midi_cc: HashMap::new(),
+			mml_dialect: &DefaultDialect,
+			track_dialects: HashMap::new(),
+		}
+	}
+
+	pub fn get_tuning(&self, track: &String) -> &Tuning {
+		self.track_tunings.get(track).unwrap_or(&self.tuning)
+	}
+
+	/// @dialect は MML より後に書いてもよいので、全ての文を処理した後に解決する。
+	/// @midiImport で取り込んだ MML は既定の方言で書かれているので、他の方言のトラックには取り込めない
+	pub fn get_dialect(&self, track: &String) -> ModdlResult<&'static dyn MmlDialect> {
+		let dialect = self.track_dialects.get(track).copied().unwrap_or(self.mml_dialect);
+		if dialect.name() != DEFAULT_DIALECT_NAME {
+			let import_loc = self.mml_locs.get(track).and_then(|sources| sources.iter().find_map(|source| match source {
+				MmlSource::Import(loc) => Some(loc),
+				_ => None,
+			}));
+			if let Some(loc) = import_loc {
+				return Err(error(ErrorType::MidiImportDialectMismatch { track: track.clone(), dialect: dialect.name().to_string() }, loc.clone()));
+			}
 		}
+
+		Ok(dialect)
+	}
+
+	/// トラックの MML の中の位置を、その MML を書いた ModDL ファイル上の位置に変換する。
+	/// 試聴などで MML を書いた位置がなければ、トラックを定義した位置にする
+	pub fn locate_mml(&self, track: &String, pos: MmlPos) -> Location {
+		self.mml_locs.get(track)
+				.and_then(|sources| sources.get((pos.line as usize).checked_sub(1) ?))
+				.map(|source| source.loc(pos.column))
+				.or_else(|| self.get_track_def(track).map(|(_, loc)| loc.clone()))
+				.unwrap_or_else(Location::dummy)
 	}
 
 	pub fn get_track_def(&self, track: &String) -> Option<(&TrackDef, &Location)> {
@@ -79,11 +140,53 @@ impl PlayerContext {
 	}
 }
 
+/// トラックの MML の 1 行をどこに書いたか
+#[derive(Clone, Debug)]
+pub enum MmlSource {
+	/// MML 文。位置は MML の先頭
+	Statement(Location),
+	/// @mml で組み立てた MML。位置はディレクティブ
+	Directive(Location),
+	/// @midiImport で取り込んだ MML。位置はディレクティブ
+	Import(Location),
+}
+impl MmlSource {
+	/// MML の行の中の列に対応する位置。組み立てた MML ではディレクティブの位置になる
+	pub fn loc(&self, column: usize) -> Location {
+		match self {
+			Self::Statement(loc) => Location { column: loc.column + column - 1, ..loc.clone() },
+			Self::Directive(loc) | Self::Import(loc) => loc.clone(),
+		}
+	}
+}
+
 #[derive(PartialEq)]
 pub enum MuteSolo { Mute, Solo }
 
 pub enum TrackDef {
-	Instrument(NodeStructure),
+	/// ポリフォニックな instrument の場合はボイス数などの指定を伴う
+	Instrument(NodeStructure, Option<Polyphony>),
 	Effect(HashSet<String>, NodeStructure),
 	Groove(NodeStructure),
+	/// ヒットごとに楽器を持ち、MML のキーやヒット名でどれを鳴らすか選ぶ
+	DrumKit(Vec<DrumHit>),
+}
+
+#[derive(Clone)]
+pub struct DrumHit {
+	/// MML で !name として鳴らす名前
+	pub name: String,
+	/// 鳴らすキー（ノート番号）。ノート番号を指定しなかったヒットには負の値を振る
+	pub key: i32,
+	pub structure: NodeStructure,
+	/// 楽器の出力に掛ける音量
+	pub velocity: f32,
+	/// 同じグループのヒットは同時に鳴らさない（後から鳴らしたヒットが前のヒットを止める）
+	pub choke: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+pub struct Polyphony {
+	pub voices: usize,
+	pub stealing: StealingPolicy,
 }
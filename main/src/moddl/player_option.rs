@@ -1,6 +1,16 @@
 pub struct PlayerOptions {
 	pub moddl_path: String,
 	pub output: PlayerOutput,
+	/// 演奏を開始するマーカー。その名前のマーカーがなく数値なら小節番号
+	pub start_marker: Option<String>,
+	/// 繰り返し演奏する区間の始点と終点のマーカー。start_marker と同じく小節番号でもよい
+	pub loop_markers: Option<(String, String)>,
+	/// 指定されたら、演奏せずにマーカーの位置をこのファイルに書き出す
+	pub timeline_path: Option<String>,
+	/// 指定されたら、演奏せずに演奏内容をこの MIDI ファイルに書き出す
+	pub midi_path: Option<String>,
+	/// 乱数の種。指定されたら @option seed より優先する
+	pub seed: Option<u64>,
 }
 
 pub enum PlayerOutput {
@@ -0,0 +1,215 @@
+use super::{
+	builtin::builtin_vars, error::*, evaluator::evaluate, executor::process_statements_in, import::ImportCache, io::Io,
+	player::{play_context, SAMPLE_RATE}, player_context::{PlayerContext, TrackDef}, player_option::*, scope::*, value::*,
+};
+use crate::wave::waveform_host::WaveformHost;
+extern crate parser;
+use parser::{
+	common::{Location, Span}, moddl::{ast::Expr, parser::expr}
+};
+use nom::combinator::all_consuming;
+
+use std::{
+	cell::RefCell, env, io::{self, BufRead, Write}, rc::Rc
+};
+
+/// 試聴に使うトラック名。試聴は専用の PlayerContext で行うので、入力済みのトラックとは衝突しない
+const AUDITION_TRACK: &str = "audition";
+/// /mml で楽器を指定していないときの楽器
+const DEFAULT_INSTRUMENT: &str = "pulseOsc * adsrEnv";
+/// /play で楽器を試聴するときの MML
+const AUDITION_MML: &str = "o4 l2 c";
+
+const HELP: &str = "\
+<expr>          evaluate an expression and print the value
+@<directive>    process a directive (e.g. @let :x, 1)
+<track> <mml>   append MML to tracks, to be played by /song
+/play [<expr>]  audition a node structure, and use it for /mml
+/mml <mml>      play MML with the auditioned node structure
+/song           play all the tracks defined so far
+/help           show this help
+/quit           exit";
+
+/// 入力をまたいでスコープと import のキャッシュを保持し、式の評価や文の処理、その場での試聴を行う
+pub fn run_repl() -> ModdlResult<()> {
+	let mut waveforms = WaveformHost::new();
+	let mut imports = ImportCache::new(&mut waveforms);
+	let root_vars = Scope::root(builtin_vars(SAMPLE_RATE, &mut imports) ?);
+	// 相対パスの import はカレントディレクトリを基準にする
+	let repl_path = env::current_dir()
+			.map_err(|e| error(ErrorType::File(e), Location::dummy())) ?
+			.join("<repl>");
+	imports.set_root_moddl(&repl_path);
+	let mut repl = Repl {
+		pctx: PlayerContext::init(&repl_path, root_vars),
+		instrument: None,
+	};
+
+	println!("ModDL REPL (type /help for help)");
+	let mut lines = io::stdin().lock().lines();
+	loop {
+		print!("> ");
+		let _ = io::stdout().flush();
+		let line = match lines.next() {
+			Some(line) => line.map_err(|e| error(ErrorType::File(e), Location::dummy())) ?,
+			None => break,
+		};
+		match repl.process_line(line.trim(), &mut imports) {
+			Ok(true) => { },
+			Ok(false) => break,
+			// エラーがあっても入力は続けられる
+			Err(e) => eprintln!("error: {}: {}", e.loc, e.body),
+		}
+	}
+
+	Ok(())
+}
+
+struct Repl {
+	pctx: PlayerContext,
+	/// /play で最後に試聴した楽器
+	instrument: Option<(NodeStructure, Location)>,
+}
+
+impl Repl {
+	/// 1 行の入力を処理する。終了するときは false を返す
+	fn process_line(&mut self, line: &str, imports: &mut ImportCache) -> ModdlResult<bool> {
+		let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+		let rest = rest.trim();
+		match command {
+			"" => { },
+			"/quit" | "/exit" => { return Ok(false); },
+			"/help" => { println!("{}", HELP); },
+			"/play" => {
+				if ! rest.is_empty() {
+					self.instrument = Some(self.evaluate_line(rest, imports)?.as_node_structure() ?);
+				}
+				let instrument = self.instrument(imports) ?;
+				self.audition(instrument, AUDITION_MML, imports) ?;
+			},
+			"/mml" => {
+				let instrument = self.instrument(imports) ?;
+				self.audition(instrument, rest, imports) ?;
+			},
+			"/song" => {
+				let options = audio_options(&self.pctx);
+				play_context(&mut self.pctx, &options, imports) ?;
+			},
+			_ if line.starts_with('@') => {
+				process_statements_in(line, &mut self.pctx, imports) ?;
+			},
+			_ => {
+				// 式として解釈できなければ、MML の行として処理する
+				match self.parse_expr(line) {
+					Ok(expr) => {
+						let value = self.evaluate_expr(&expr, imports) ?;
+						println!("{}", value.0.to_str(|s| s.to_string()));
+					},
+					Err(_) => {
+						process_statements_in(line, &mut self.pctx, imports) ?;
+					},
+				}
+			},
+		}
+
+		Ok(true)
+	}
+
+	/// 入力全体を 1 つの式として解析する。構文エラーは入力した行の中の位置で報告する
+	fn parse_expr(&self, input: &str) -> ModdlResult<Box<Expr>> {
+		let span = Span::new_extra(input, Rc::new(self.pctx.moddl_path.clone()));
+		let (_, expr) = all_consuming(expr())(span.clone())
+		.map_err(|e| {
+			let loc = nom_error_location(&e).unwrap_or_else(|| Location::of(&span));
+			error(ErrorType::Syntax(nom_error_to_owned(e)), loc)
+		}) ?;
+
+		Ok(expr)
+	}
+
+	fn evaluate_expr(&self, expr: &Expr, imports: &mut ImportCache) -> ModdlResult<Value> {
+		// TODO evaluate_and_perform_arg と共通化
+		let mut value = evaluate(expr, &self.pctx.vars, imports) ?;
+		while value.as_io().is_ok() {
+			let (io, loc) = value.as_io().unwrap();
+			value = RefCell::<dyn Io>::borrow_mut(&io).perform(&loc, imports) ?;
+		}
+
+		Ok(value)
+	}
+
+	fn evaluate_line(&self, input: &str, imports: &mut ImportCache) -> ModdlResult<Value> {
+		self.evaluate_expr(&*self.parse_expr(input) ?, imports)
+	}
+
+	/// 試聴に使う楽器。/play で指定されていなければ既定の楽器
+	fn instrument(&self, imports: &mut ImportCache) -> ModdlResult<(NodeStructure, Location)> {
+		match &self.instrument {
+			Some(instrument) => Ok(instrument.clone()),
+			None => self.evaluate_line(DEFAULT_INSTRUMENT, imports)?.as_node_structure(),
+		}
+	}
+
+	/// 入力済みの変数やテンポを引き継いだ一時的なトラックで、楽器に MML を演奏させる
+	fn audition(&self, (instrument, loc): (NodeStructure, Location), mml: &str, imports: &mut ImportCache) -> ModdlResult<()> {
+		let mut pctx = PlayerContext::init(&self.pctx.moddl_path, self.pctx.vars.clone());
+		pctx.tempo = self.pctx.tempo;
+		pctx.ticks_per_bar = self.pctx.ticks_per_bar;
+		pctx.tuning = self.pctx.tuning.clone();
+		pctx.add_track_def(&AUDITION_TRACK.to_string(), TrackDef::Instrument(instrument, None), &loc) ?;
+		pctx.terminal_tracks.insert(AUDITION_TRACK.to_string());
+		pctx.mmls.insert(AUDITION_TRACK.to_string(), mml.to_string());
+
+		let options = audio_options(&pctx);
+		play_context(&mut pctx, &options, imports)
+	}
+}
+
+fn audio_options(pctx: &PlayerContext) -> PlayerOptions {
+	PlayerOptions {
+		moddl_path: pctx.moddl_path.to_string_lossy().to_string(),
+		output: PlayerOutput::Audio,
+		start_marker: None,
+		loop_markers: None,
+		timeline_path: None,
+		midi_path: None,
+		seed: None,
+	}
+}
+
+#[cfg(test)]
+#[test]
+fn test_process_line() {
+	use crate::wave::waveform_host::WaveformHost;
+	use std::{collections::HashMap, path::PathBuf};
+
+	let mut waveforms = WaveformHost::new();
+	let mut imports = ImportCache::new(&mut waveforms);
+	let mut repl = Repl {
+		pctx: PlayerContext::init(&PathBuf::from("<repl>"), Scope::root(HashMap::new())),
+		instrument: None,
+	};
+
+	// 文で定義した変数やトラックは以降の行でも使える
+	assert!(repl.process_line("@let :x, 3", &mut imports).is_ok());
+	assert!(repl.process_line("@instrument ^a, 1", &mut imports).is_ok());
+	assert!(repl.process_line("x * 2", &mut imports).is_ok());
+	assert!(repl.process_line("@let :y, x * 2", &mut imports).is_ok());
+	// 式として解釈できる行は評価し、MML として処理しない
+	assert!(repl.process_line("y", &mut imports).is_ok());
+	assert!(repl.pctx.mmls.is_empty());
+	// 式として解釈できない行は MML としてトラックに追加する
+	assert!(repl.process_line("a cde", &mut imports).is_ok());
+	assert!(repl.process_line("a fg", &mut imports).is_ok());
+	assert_eq!(repl.pctx.mmls.get("a").map(|mml| mml.split_whitespace().collect::<String>()), Some("cdefg".to_string()));
+	// 未定義のトラックへの MML はエラーになるが、それまでの状態は残る
+	assert!(matches!(repl.process_line("b cde", &mut imports).err().unwrap().body, ErrorType::TrackDefNotFound { .. }));
+	assert!(matches!(repl.process_line("z", &mut imports).err().unwrap().body, ErrorType::VarNotFound { .. }));
+	assert_eq!(repl.evaluate_line("y", &mut imports).ok().and_then(|(value, _)| value.as_float()), Some(6f32));
+	// 式の構文エラーは入力した行の中の位置で報告する
+	let e = repl.parse_expr("(1 + 2").err().unwrap();
+	assert!(matches!(e.body, ErrorType::Syntax(_)));
+	assert_eq!(e.loc.to_string(), "<repl>, line 1, column 7");
+
+	assert!(matches!(repl.process_line("/quit", &mut imports), Ok(false)));
+}
@@ -13,20 +13,36 @@ use std::{
 pub struct Scope {
 	entries: HashMap<String, Value>,
 	parent: Option<Rc<RefCell<Self>>>,
+	/// このスコープを評価している、式で記述された関数の呼び出しの深さ
+	call_depth: usize,
 }
 impl Scope {
 	pub fn root(entries: HashMap<String, Value>) -> Rc<RefCell<Self>> {
 		Rc::new(RefCell::new(Self {
 			entries,
 			parent: None,
+			call_depth: 0,
 		}))
 	}
 	pub fn child_of(parent: Rc<RefCell<Self>>) -> Rc<RefCell<Self>> {
+		let call_depth = parent.borrow().call_depth;
 		Rc::new(RefCell::new(Self {
 			entries: HashMap::new(),
 			parent: Some(parent),
+			call_depth,
 		}))
 	}
+	/// 関数の呼び出しで引数を置くスコープ。変数は関数を定義したスコープから引き継ぎ、
+	/// 呼び出しの深さは呼び出し元のスコープより 1 つ深くする
+	pub fn call_frame(closure: Rc<RefCell<Self>>, caller: &Rc<RefCell<Self>>) -> Rc<RefCell<Self>> {
+		Rc::new(RefCell::new(Self {
+			entries: HashMap::new(),
+			parent: Some(closure),
+			call_depth: caller.borrow().call_depth + 1,
+		}))
+	}
+
+	pub fn call_depth(&self) -> usize { self.call_depth }
 
 	pub fn lookup(&self, name: &String) -> Option<Value> {
 		match self.entries.get(name) {
@@ -0,0 +1,211 @@
+use parser::common::Location;
+
+// 文字列を操作するビルトイン関数。長さは len で得られる
+use super::{
+	error::*, function::*, import::ImportCache, scope::*, value::*
+};
+
+use std::{
+	cell::RefCell, collections::hash_map::HashMap, rc::Rc
+};
+
+fn get_string(args: &HashMap<String, Value>, name: &str, call_loc: &Location) -> ModdlResult<String> {
+	Ok(get_required_arg(args, name, call_loc)?.as_string()?.0)
+}
+
+fn get_char_index(value: &Value) -> ModdlResult<usize> {
+	let (index, loc) = value.as_float() ?;
+	if index.fract() != 0f32 { return Err(error(ErrorType::BadIndex, loc)); }
+	if index < 0f32 { return Err(error(ErrorType::IndexOutOfBounds, loc)); }
+	Ok(index as usize)
+}
+
+fn string_value(value: String, loc: Location) -> ModdlResult<Value> {
+	Ok((ValueBody::String(value), loc))
+}
+
+pub struct Substring { }
+impl Function for Substring {
+	fn signature(&self) -> FunctionSignature { vec!["source".to_string(), "start".to_string(), "end".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
+		let source: Vec<char> = get_string(args, "source", &call_loc)?.chars().collect();
+		// 位置は文字単位で数える。end は含まず、省略時は末尾まで
+		let start = get_char_index(get_required_arg(args, "start", &call_loc)?) ?;
+		let end = match get_optional_arg(args, "end") {
+			Some(end) => get_char_index(end) ?,
+			None => source.len(),
+		};
+		let result = source.get(start .. end).ok_or_else(|| error(ErrorType::IndexOutOfBounds, call_loc.clone())) ?;
+
+		string_value(result.iter().collect(), call_loc)
+	}
+}
+
+pub struct Split { }
+impl Function for Split {
+	fn signature(&self) -> FunctionSignature { vec!["source".to_string(), "separator".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
+		let source = get_string(args, "source", &call_loc) ?;
+		let separator = get_string(args, "separator", &call_loc) ?;
+
+		// 区切りが空文字列なら 1 文字ずつに分ける
+		let parts: Vec<String> = if separator.is_empty() {
+			source.chars().map(|c| c.to_string()).collect()
+		} else {
+			source.split(separator.as_str()).map(|part| part.to_string()).collect()
+		};
+
+		Ok((ValueBody::Array(parts.into_iter().map(|part| (ValueBody::String(part), call_loc.clone())).collect()), call_loc))
+	}
+}
+
+pub struct Join { }
+impl Function for Join {
+	fn signature(&self) -> FunctionSignature { vec!["source".to_string(), "separator".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
+		let (source, _) = get_required_arg(args, "source", &call_loc)?.as_array() ?;
+		let separator = match get_optional_arg(args, "separator") {
+			Some(separator) => separator.as_string()?.0,
+			None => "".to_string(),
+		};
+
+		// 文字列以外の要素は toString と同様に文字列にする
+		let parts: Vec<String> = source.iter().map(|(elem, _)| elem.to_str(|s| s.to_string())).collect();
+
+		string_value(parts.join(separator.as_str()), call_loc)
+	}
+}
+
+pub struct Replace { }
+impl Function for Replace {
+	fn signature(&self) -> FunctionSignature { vec!["source".to_string(), "from".to_string(), "to".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
+		let source = get_string(args, "source", &call_loc) ?;
+		let from = get_string(args, "from", &call_loc) ?;
+		let to = get_string(args, "to", &call_loc) ?;
+
+		// 全ての出現箇所を置き換える。空文字列を置き換えることはしない
+		let result = if from.is_empty() { source } else { source.replace(from.as_str(), to.as_str()) };
+
+		string_value(result, call_loc)
+	}
+}
+
+pub struct ToUpperCase { }
+impl Function for ToUpperCase {
+	fn signature(&self) -> FunctionSignature { vec!["source".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
+		string_value(get_string(args, "source", &call_loc)?.to_uppercase(), call_loc)
+	}
+}
+
+pub struct ToLowerCase { }
+impl Function for ToLowerCase {
+	fn signature(&self) -> FunctionSignature { vec!["source".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
+		string_value(get_string(args, "source", &call_loc)?.to_lowercase(), call_loc)
+	}
+}
+
+pub struct FormatNumber { }
+impl Function for FormatNumber {
+	fn signature(&self) -> FunctionSignature { vec!["value".to_string(), "precision".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
+		let (value, _) = get_required_arg(args, "value", &call_loc)?.as_float() ?;
+		// 小数点以下の桁数
+		let precision = get_char_index(get_required_arg(args, "precision", &call_loc)?) ?;
+
+		string_value(format!("{:.*}", precision, value), call_loc)
+	}
+}
+
+pub struct Format { }
+impl Function for Format {
+	fn signature(&self) -> FunctionSignature { vec!["template".to_string(), "args".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
+		let (template, template_loc) = get_required_arg(args, "template", &call_loc)?.as_string() ?;
+		let (values, values_loc) = get_required_arg(args, "args", &call_loc) ?;
+
+		// {0} は配列の要素、{name} は連想配列のエントリで置き換える。{{ と }} は { と } そのもの
+		let lookup = |key: &str| -> ModdlResult<String> {
+			let value = match values {
+				ValueBody::Array(content) => {
+					let index = key.parse::<usize>().map_err(|_| error(ErrorType::IndexOutOfBounds, template_loc.clone())) ?;
+					content.get(index).ok_or_else(|| error(ErrorType::IndexOutOfBounds, template_loc.clone())) ?
+				},
+				ValueBody::Assoc(content) => {
+					content.get(key).ok_or_else(|| error(ErrorType::EntryNotFound { name: key.to_string() }, template_loc.clone())) ?
+				},
+				_ => Err(error(ErrorType::TypeMismatchAny { expected: vec![ValueType::Array, ValueType::Assoc] }, values_loc.clone())) ?,
+			};
+			Ok(value.0.to_str(|s| s.to_string()))
+		};
+
+		let mut result = String::new();
+		let mut chars = template.chars().peekable();
+		while let Some(c) = chars.next() {
+			match c {
+				'{' if chars.peek() == Some(&'{') => { chars.next(); result.push('{'); },
+				'}' if chars.peek() == Some(&'}') => { chars.next(); result.push('}'); },
+				'{' => {
+					let mut key = String::new();
+					loop {
+						match chars.next() {
+							Some('}') => { break; },
+							Some(c) => { key.push(c); },
+							None => { return Err(error(ErrorType::UnclosedFormatPlaceholder, template_loc.clone())); },
+						}
+					}
+					result.push_str(lookup(key.trim())?.as_str());
+				},
+				_ => { result.push(c); },
+			}
+		}
+
+		string_value(result, call_loc)
+	}
+}
+
+pub struct Mml { }
+impl Function for Mml {
+	fn signature(&self) -> FunctionSignature { vec!["source".to_string()] }
+	fn call(&self, args: &HashMap<String, Value>, _vars: &Rc<RefCell<Scope>>, call_loc: Location, _imports: &mut ImportCache) -> ModdlResult<Value> {
+		// 解析はトラックの方言が決まる演奏時に行う
+		Ok((ValueBody::Mml(get_string(args, "source", &call_loc) ?), call_loc))
+	}
+}
+
+#[cfg(test)]
+#[test]
+fn test_string_functions() {
+	use super::evaluator::{evaluate_source, test_scope};
+
+	let functions: Vec<(&str, Rc<dyn Function>)> = vec![
+		("substring", Rc::new(Substring { })), ("split", Rc::new(Split { })), ("join", Rc::new(Join { })), ("replace", Rc::new(Replace { })),
+		("toUpperCase", Rc::new(ToUpperCase { })), ("toLowerCase", Rc::new(ToLowerCase { })), ("formatNumber", Rc::new(FormatNumber { })),
+		("format", Rc::new(Format { })), ("mml", Rc::new(Mml { })),
+	];
+	let vars = test_scope(functions.into_iter().map(|(name, function)| (name, ValueBody::Function(function))).collect());
+	let eval = |source: &str| evaluate_source(source, &vars).map(|(value, _)| value);
+
+	let as_string = |value: ModdlResult<ValueBody>| value.ok().and_then(|value| value.as_string());
+	assert_eq!(as_string(eval("substring(\"ドレミファ\", 1, 3)")), Some("レミ".to_string()));
+	assert_eq!(as_string(eval("split(\"a,b,,c\", \",\")~join(\"/\")")), Some("a/b//c".to_string()));
+	assert_eq!(as_string(eval("split(\"abc\", \"\")~join(\" \")")), Some("a b c".to_string()));
+	assert_eq!(as_string(eval("replace(\"cdcd\", \"d\", \"e\")~toUpperCase()")), Some("CECE".to_string()));
+	assert_eq!(as_string(eval("toLowerCase(\"ABC\")")), Some("abc".to_string()));
+	assert_eq!(as_string(eval("formatNumber(3.14159, 2)")), Some("3.14".to_string()));
+	assert_eq!(as_string(eval("format(\"o{0} l{1} {{x}}\", [4, 8])")), Some("o4 l8 {x}".to_string()));
+	assert_eq!(as_string(eval("format(\"{ name }.wav\", { name: \"kick\" })")), Some("kick.wav".to_string()));
+	assert_eq!(eval("mml(\"cde\") + mml(\"fg\")").ok().and_then(|value| value.as_mml()), Some("cde fg".to_string()));
+
+	assert!(matches!(eval("substring(\"abc\", 2, 4)").err().unwrap().body, ErrorType::IndexOutOfBounds));
+	assert!(matches!(eval("format(\"{1}\", [0])").err().unwrap().body, ErrorType::IndexOutOfBounds));
+	assert!(matches!(eval("format(\"{a}\", { b: 0 })").err().unwrap().body, ErrorType::EntryNotFound { .. }));
+	// 閉じていない { は書式の文字列の位置でエラーにする
+	let e = eval("format(\"o{0} l{1\", [4, 8])").err().unwrap();
+	assert!(matches!(e.body, ErrorType::UnclosedFormatPlaceholder));
+	assert_eq!((e.loc.line, e.loc.column), (1, 9));
+	assert!(matches!(eval("substring(\"abc\", 0.5)").err().unwrap().body, ErrorType::BadIndex));
+	assert!(matches!(eval("mml(1)").err().unwrap().body, ErrorType::TypeMismatch { .. }));
+}
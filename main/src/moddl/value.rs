@@ -5,6 +5,7 @@ use parser::moddl::ast::QualifiedLabel;
 use super::error::{ModdlResult, error, ErrorType};
 use super::io::Io;
 use super::function::*;
+use super::lambda_function::Param;
 use crate::{
 	core::node_factory::*,
 	wave::waveform_host::WaveformIndex,
@@ -26,6 +27,8 @@ pub trait CalcNodeFactoryTrait {
 	fn operator(&self) -> &str;
 	fn create_mono(&self, args: Vec<MonoNodeIndex>) -> Box<dyn Node>;
 	fn create_stereo(&self, args: Vec<StereoNodeIndex>) -> Box<dyn Node>;
+	/// ノードを作らずに値を計算する
+	fn calc(&self, args: &Vec<Sample>) -> Sample;
 }
 // #[derive(Clone)]
 pub struct CalcNodeFactory<C: 'static + Calc> {
@@ -42,16 +45,27 @@ impl <C: 'static + Calc> CalcNodeFactoryTrait for CalcNodeFactory<C> {
 	fn create_stereo(&self, args: Vec<StereoNodeIndex>) -> Box<dyn Node> {
 		Box::new(StereoCalc::<C>::new(args))
 	}
+	fn calc(&self, args: &Vec<Sample>) -> Sample { C::calc(args) }
 }
 
 /// 生成すべき Node の構造を表現する型。
 /// Value から直接 Node を生成すると問題が多いので、一旦この形式を挟む
 #[derive(Clone)]
 pub enum NodeStructure {
-	Calc{ node_factory: Rc<dyn CalcNodeFactoryTrait>, args: Vec<Box<NodeStructure>> },
-	Connect(Box<NodeStructure>, Box<NodeStructure>),
-	Condition { cond: Box<NodeStructure>, then: Box<NodeStructure>, els: Box<NodeStructure> },
-	Lambda { input_param: String, body: Box<NodeStructure> },
+	Calc{ node_factory: Rc<dyn CalcNodeFactoryTrait>, args: Vec<Operand> },
+	Connect(Box<Operand>, Box<Operand>),
+	Condition { cond: Box<Operand>, then: Box<Operand>, els: Box<Operand> },
+	/// 式で記述されたノード。input_param の他に名前つき引数 params を受け取れる。
+	/// 引数は NodeCreation と同じく { name: value } で渡し、ラベルは body の中のラベルの接頭辞になる
+	Lambda {
+		input_param: String,
+		params: Vec<Param>,
+		args: HashMap<String, Value>,
+		label: Option<QualifiedLabel>,
+		body: Box<NodeStructure>,
+		/// ノードを書いた位置。引数を渡した場合は呼び出した位置
+		loc: Location,
+	},
 	NodeCreation {
 		factory: Rc<dyn NodeFactory>,
 		args: HashMap<String, Value>,
@@ -67,7 +81,9 @@ pub enum NodeStructure {
 impl NodeStructure {
 	pub fn label(&self) -> Option<QualifiedLabel> {
 		match self {
-			NodeStructure::NodeCreation { label, .. } | NodeStructure::Constant { label, .. } => label.clone(),
+			NodeStructure::NodeCreation { label, .. }
+			| NodeStructure::Constant { label, .. }
+			| NodeStructure::Lambda { label, .. } => label.clone(),
 			_ => None,
 		}
 	}
@@ -75,35 +91,36 @@ impl NodeStructure {
 	pub fn to_string(&self) -> String {
 		match self {
 			NodeStructure::Calc{ node_factory, args } => {
+				// min や clamp など名前で呼ぶ関数は、引数の数によらず関数呼び出しの形にする
+				let is_function = node_factory.operator().chars().all(|c| c.is_ascii_alphanumeric());
 				match args.len() {
-					2 => format!("({} {} {})", args[0].to_string(), node_factory.operator(), args[1].to_string()),
+					2 if ! is_function => format!("({} {} {})", args[0].0.to_string(), node_factory.operator(), args[1].0.to_string()),
 					_ => {
-						// Calc に 3 項以上のものはないので、ここで処理するのは単項演算だけのはずだが、
-						// もし 3 項以上あった場合は全ての引数を列挙する
-						let content = args.iter().map(|a| a.to_string()).join(", ");
+						let content = args.iter().map(|(a, _)| a.to_string()).join(", ");
 						format!("{}({})", node_factory.operator(), content)
 					},
 				}
 			},
-			Self::Connect(lhs, rhs) => format!("({} | {})", lhs.to_string(), rhs.to_string()),
-			Self::Condition { cond, then, els } => format!("(if {} then {} else {})", cond.to_string(), then.to_string(), els.to_string()),
-			Self::Lambda { input_param, body } => format!("(={}=> {})", input_param, body.to_string()),
-			Self::NodeCreation { factory: _, args, label } => {
-				// TODO NodeFactory には名前をつけたい
-				// TODO その他の情報もなるべく出したい
-				let factory_str = "(NodeFactory)";
-				let args_str = match args.len() {
+			Self::Connect(lhs, rhs) => format!("({} | {})", lhs.0.to_string(), rhs.0.to_string()),
+			Self::Condition { cond, then, els } => format!("(if {} then {} else {})", cond.0.to_string(), then.0.to_string(), els.0.to_string()),
+			Self::Lambda { input_param, params, args, label, body, .. } => {
+				let params_str = match params.len() {
 					0 => "".to_string(),
 					_ => {
-						let content = args.iter().map(|(k, (v, _))| format!("{}: {}", k, v.force_to_string())).join(", ");
-						format!("{{ {} }}", content)
+						let content = params.iter().map(|Param { name, default }| match default {
+							None => name.clone(),
+							Some((default, _)) => format!("{} = {}", name, default.force_to_string()),
+						}).join(", ");
+						format!("({})", content)
 					},
 				};
-				let label_str = match label {
-					None => "".to_string(),
-					Some(label) => format!("@{}", label.0),
-				};
-				format!("{}{}{}", factory_str, args_str, label_str)
+				format!("(={}{}=> {}){}{}", input_param, params_str, body.to_string(), args_to_string(args), label_to_string(label))
+			},
+			Self::NodeCreation { factory: _, args, label } => {
+				// TODO NodeFactory には名前をつけたい
+				// TODO その他の情報もなるべく出したい
+				let factory_str = "(NodeFactory)";
+				format!("{}{}{}", factory_str, args_to_string(args), label_to_string(label))
 			},
 			Self::Constant { value, label } => match label {
 				None => value.to_string(),
@@ -115,7 +132,26 @@ impl NodeStructure {
 	}
 }
 
+fn args_to_string(args: &HashMap<String, Value>) -> String {
+	match args.len() {
+		0 => "".to_string(),
+		_ => {
+			let content = args.iter().map(|(k, (v, _))| format!("{}: {}", k, v.force_to_string())).join(", ");
+			format!("{{ {} }}", content)
+		},
+	}
+}
+
+fn label_to_string(label: &Option<QualifiedLabel>) -> String {
+	match label {
+		None => "".to_string(),
+		Some(label) => format!("@{}", label.0),
+	}
+}
+
 pub type Value = (ValueBody, Location);
+/// 演算や接続の被演算子。検査で問題の箇所を報告できるよう、被演算子の式の位置を持つ
+pub type Operand = (NodeStructure, Location);
 
 pub trait ValueExtraction {
 	fn as_float(&self) -> ModdlResult<(f32, Location)>;
@@ -124,6 +160,7 @@ pub trait ValueExtraction {
 	fn as_track_set(&self) -> ModdlResult<(Vec<String>, Location)>;
 	fn as_identifier_literal(&self) -> ModdlResult<(String, Location)>;
 	fn as_string(&self) -> ModdlResult<(String, Location)>;
+	fn as_mml(&self) -> ModdlResult<(String, Location)>;
 	fn as_array(&self) -> ModdlResult<(&Vec<Value>, Location)>;
 	fn as_assoc(&self) -> ModdlResult<(&HashMap<String, Value>, Location)>;
 	fn as_node_structure(&self) -> ModdlResult<(NodeStructure, Location)>;
@@ -150,6 +187,7 @@ impl ValueExtraction for Value {
 	fn as_track_set(&self) -> ModdlResult<(Vec<String>, Location)> { extract(self.0.as_track_set() , &self.1, ValueType::TrackSet) }
 	fn as_identifier_literal(&self) -> ModdlResult<(String, Location)> { extract(self.0.as_identifier_literal() , &self.1, ValueType::QuotedIdentifier) }
 	fn as_string(&self) -> ModdlResult<(String, Location)> { extract(self.0.as_string() , &self.1, ValueType::String) }
+	fn as_mml(&self) -> ModdlResult<(String, Location)> { extract(self.0.as_mml() , &self.1, ValueType::Mml) }
 	fn as_array(&self) -> ModdlResult<(&Vec<Value>, Location)> { extract(self.0.as_array() , &self.1, ValueType::Array) }
 	fn as_assoc(&self) -> ModdlResult<(&HashMap<String, Value>, Location)> { extract(self.0.as_assoc() , &self.1, ValueType::Assoc) }
 	fn as_node_structure(&self) -> ModdlResult<(NodeStructure, Location)> { extract_any(self.0.as_node_structure() , &self.1,
@@ -166,6 +204,8 @@ pub enum ValueBody {
 	TrackSet(Vec<String>),
 	IdentifierLiteral(String),
 	String(String),
+	/// トラックに追加できる MML。文字列から変換して作る
+	Mml(String),
 	Array(Vec<Value>),
 	Assoc(HashMap<String, Value>),
 	/// ノードの構造に関するツリー表現
@@ -212,6 +252,13 @@ impl ValueBody {
 		}
 	}
 
+	pub fn as_mml(&self) -> Option<String> {
+		match self {
+			Self::Mml(content) => Some(content.clone()),
+			_ => None,
+		}
+	}
+
 	pub fn as_array(&self) -> Option<&Vec<Value>> {
 		match self {
 			Self::Array(content) => Some(content),
@@ -294,6 +341,7 @@ impl ValueBody {
 			Self::IdentifierLiteral(id) => format!(":{}", id),
 			// 文字列だけは「式っぽい」整形を受けず中身そのままなので、少し毛色が違う
 			Self::String(value) => value.clone(),
+			Self::Mml(value) => value.clone(),
 			Self::Array(elems) => {
 				let content = elems.iter().map(|(e, _)| e.force_to_string()).join(", ");
 				format!("[{}]", content)
@@ -320,6 +368,7 @@ pub enum ValueType {
 	TrackSet,
 	QuotedIdentifier,
 	String,
+	Mml,
 	Array,
 	Assoc,
 	NodeStructure,
@@ -97,6 +97,7 @@ impl WavFileOutFactory {
 impl NodeFactory for WavFileOutFactory {
 	fn node_arg_specs(&self) -> Vec<NodeArgSpec> { vec![] }
 	fn input_channels(&self) -> i32 { self.channels }
+	fn output_channels(&self) -> i32 { 0 }
 	fn create_node(&self, _node_args: &NodeArgs, piped_upstream: ChanneledNodeIndex) -> Box<dyn Node> {
 		Box::new(WavFileOut::new(piped_upstream, self.path.clone()))
 	}
@@ -0,0 +1,177 @@
+use crate::core::{
+	common::*,
+	context::*,
+	event::*,
+	machine::*,
+	node::*,
+};
+use super::{
+	envelope::{NoteEvent, EVENT_TYPE_NOTE},
+	var::{SetEvent, EVENT_TYPE_SET},
+};
+use node_macro::node_impl;
+
+ ////
+//// LFO（MML のビブラート・トレモロ・オートパン用）
+
+/// LFO の出力の形式
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LfoMode {
+	/// -depth から depth の間で振れる。ディレイ中は 0
+	Bipolar,
+	/// 1 - depth から 1 の間で振れる（音量に掛ける用）。ディレイ中は 1
+	Unipolar,
+}
+
+/// LFO の波形。MML の @vib などの 4 番目の引数で番号を指定する
+pub const LFO_WAVEFORM_SINE: i32 = 0;
+pub const LFO_WAVEFORM_TRIANGLE: i32 = 1;
+pub const LFO_WAVEFORM_SQUARE: i32 = 2;
+pub const LFO_WAVEFORM_SAWTOOTH: i32 = 3;
+
+/// パラメータはノード入力でなく SetEvent で受け取る。
+/// キーは rate（Hz）、depth、delay（秒）、waveform（LFO_WAVEFORM_* のいずれか）。
+/// ノートオンのたびに位相とディレイをリセットする
+pub struct Lfo {
+	mode: LfoMode,
+	rate: Sample,
+	depth: Sample,
+	delay: Sample,
+	waveform: Sample,
+
+	phase: f32,
+	elapsed: SampleCount,
+}
+impl Lfo {
+	pub fn new(mode: LfoMode) -> Self {
+		Self {
+			mode,
+			rate: 0f32,
+			depth: 0f32,
+			delay: 0f32,
+			waveform: 0f32,
+			phase: 0f32,
+			elapsed: 0,
+		}
+	}
+
+	fn wave(&self) -> Sample {
+		match self.waveform as i32 {
+			LFO_WAVEFORM_TRIANGLE => 1f32 - 2f32 * (self.phase / PI - 1f32).abs(),
+			LFO_WAVEFORM_SQUARE => if self.phase < PI { 1f32 } else { -1f32 },
+			LFO_WAVEFORM_SAWTOOTH => self.phase / PI - 1f32,
+			// LFO_WAVEFORM_SINE。範囲外の値は MML の処理で弾いている
+			_ => self.phase.sin(),
+		}
+	}
+
+	fn delaying(&self, sample_rate: Sample) -> bool {
+		(self.elapsed as f32) < self.delay * sample_rate
+	}
+
+	fn value(&self, sample_rate: Sample) -> Sample {
+		let delaying = self.delaying(sample_rate);
+		let wave = if delaying { 0f32 } else { self.wave() };
+		match self.mode {
+			LfoMode::Bipolar => self.depth * wave,
+			LfoMode::Unipolar => if delaying { 1f32 } else { 1f32 - self.depth.max(0f32).min(1f32) * (1f32 - wave) / 2f32 },
+		}
+	}
+
+	fn advance(&mut self, sample_rate: Sample) {
+		if self.delaying(sample_rate) {
+			self.elapsed += 1;
+			return;
+		}
+		self.phase = (self.phase + TWO_PI * self.rate / sample_rate) % TWO_PI;
+	}
+
+	fn note_on(&mut self) {
+		self.phase = 0f32;
+		self.elapsed = 0;
+	}
+
+	fn set(&mut self, key: &str, value: Sample) {
+		match key {
+			"rate" => { self.rate = value; },
+			"depth" => { self.depth = value; },
+			"delay" => { self.delay = value; },
+			"waveform" => { self.waveform = value; },
+			_ => { },
+		}
+	}
+}
+#[node_impl]
+impl Node for Lfo {
+	fn channels(&self) -> i32 { 1 }
+	fn upstreams(&self) -> Upstreams { vec![] }
+	fn activeness(&self) -> Activeness { Activeness::Active }
+	fn execute(&mut self, _inputs: &Vec<Sample>, output: &mut [Sample], context: &Context, _env: &mut Environment) {
+		output_mono(output, self.value(context.sample_rate_f32()));
+	}
+	fn update(&mut self, _inputs: &Vec<Sample>, context: &Context, _env: &mut Environment) {
+		self.advance(context.sample_rate_f32());
+	}
+	fn process_event(&mut self, event: &dyn Event, _context: &Context, _env: &mut Environment) {
+		match event.event_type() {
+			EVENT_TYPE_NOTE => {
+				let event = event.downcast_ref::<NoteEvent>().unwrap();
+				if event.note_on() {
+					self.note_on();
+				}
+			},
+
+			EVENT_TYPE_SET => {
+				let event = event.downcast_ref::<SetEvent>().unwrap();
+				self.set(event.key(), event.value());
+			},
+
+			_ => { },
+		}
+	}
+}
+
+#[cfg(test)]
+#[test]
+fn test_lfo() {
+	// 1 秒で 4 サンプルとし、1 Hz なら 1 サンプルで 1/4 周期進む
+	const SAMPLE_RATE: Sample = 4f32;
+	let approx = |actual: Sample, expected: Sample| (actual - expected).abs() < 1e-4;
+	let lfo = |mode, waveform: i32, delay: Sample| {
+		let mut lfo = Lfo::new(mode);
+		lfo.set("rate", 1f32);
+		lfo.set("depth", 0.5f32);
+		lfo.set("delay", delay);
+		lfo.set("waveform", waveform as Sample);
+		lfo
+	};
+	// 1 周期分の出力
+	let values = |mut lfo: Lfo| (0 .. 4).map(|_| {
+		let value = lfo.value(SAMPLE_RATE);
+		lfo.advance(SAMPLE_RATE);
+		value
+	}).collect::<Vec<_>>();
+	let assert_values = |actual: Vec<Sample>, expected: [Sample; 4]| {
+		assert!(actual.iter().zip(expected).all(|(a, e)| approx(*a, e)), "{:?} != {:?}", actual, expected);
+	};
+
+	// 正弦波、三角波、矩形波、のこぎり波
+	assert_values(values(lfo(LfoMode::Bipolar, LFO_WAVEFORM_SINE, 0f32)), [0f32, 0.5f32, 0f32, -0.5f32]);
+	assert_values(values(lfo(LfoMode::Bipolar, LFO_WAVEFORM_TRIANGLE, 0f32)), [-0.5f32, 0f32, 0.5f32, 0f32]);
+	assert_values(values(lfo(LfoMode::Bipolar, LFO_WAVEFORM_SQUARE, 0f32)), [0.5f32, 0.5f32, -0.5f32, -0.5f32]);
+	assert_values(values(lfo(LfoMode::Bipolar, LFO_WAVEFORM_SAWTOOTH, 0f32)), [-0.5f32, -0.25f32, 0f32, 0.25f32]);
+	// 単極は 1 - depth から 1 の間で振れる
+	assert_values(values(lfo(LfoMode::Unipolar, LFO_WAVEFORM_SINE, 0f32)), [0.75f32, 1f32, 0.75f32, 0.5f32]);
+
+	// ディレイの間は揺れず、その後は位相 0 から始まる
+	assert_values(values(lfo(LfoMode::Bipolar, LFO_WAVEFORM_SINE, 0.5f32)), [0f32, 0f32, 0f32, 0.5f32]);
+	assert_values(values(lfo(LfoMode::Unipolar, LFO_WAVEFORM_SINE, 0.5f32)), [1f32, 1f32, 0.75f32, 1f32]);
+
+	// ノートオンで位相とディレイがリセットされる
+	let mut delayed = lfo(LfoMode::Bipolar, LFO_WAVEFORM_SINE, 0.25f32);
+	for _ in 0 .. 3 {
+		delayed.advance(SAMPLE_RATE);
+	}
+	delayed.note_on();
+	assert_values(values(delayed), [0f32, 0f32, 0.5f32, 0f32]);
+}
@@ -32,6 +32,9 @@ impl Node for UniformNoise {
 	fn execute(&mut self, _inputs: &Vec<Sample>, output: &mut [Sample], _context: &Context, _env: &mut Environment) {
 		output_mono(output, 2f32 * self.gen.gen::<f32>() - 1f32);
 	}
+	fn set_seed(&mut self, seed: u64) {
+		self.gen = StdRng::seed_from_u64(seed);
+	}
 }
 
 pub struct UniformNoiseFactory { }
@@ -1,6 +1,10 @@
 pub mod common;
 pub mod context;
+pub mod humanize;
 pub mod instruction;
 pub mod sequence;
 pub mod sequencer;
+pub mod tempo_map;
 pub mod tick;
+pub mod timeline;
+pub mod voice_allocator;
@@ -0,0 +1,113 @@
+use crate::core::common::*;
+
+use rand::{
+	prelude::*,
+	rngs::StdRng,
+};
+
+use std::collections::hash_map::HashMap;
+
+/// 演奏に加える揺らぎの設定
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Humanize {
+	/// ノートオンを前後にずらす最大のティック数
+	pub timing: i32,
+	/// ベロシティを増減させる最大の割合
+	pub velocity: Sample,
+	/// 音長を増減させる最大の割合
+	pub gate: Sample,
+	/// 乱数の種。省略した場合はトラックの種を使う
+	pub seed: Option<u64>,
+}
+
+/// シーケンサの出力を遅らせる量とベロシティの倍率を決める。
+/// ノートを前にもずらせるよう、全ての出力を timing だけ遅らせた上で 0 から timing の 2 倍まで遅らせる。
+/// その分シーケンサは timing だけ先に進めておく（lead を参照）。
+/// 出力の順序が入れ替わらないよう、遅らせた後のティックは単調に増加させる
+#[derive(Clone)]
+pub struct Humanizer {
+	spec: Humanize,
+	/// 経過したティック数
+	now: i32,
+	/// 今のノートを遅らせている量。次のノートオンまでの出力は全て同じだけ遅らせる
+	delay: i32,
+	/// 最後に出力するティック
+	last_due: i32,
+	/// ノートごとの、ノートオンを遅らせる前のティック
+	note_on_ticks: HashMap<String, i32>,
+}
+impl Humanizer {
+	pub fn new(spec: Humanize) -> Self {
+		Self {
+			spec,
+			now: 0,
+			delay: spec.timing,
+			last_due: 0,
+			note_on_ticks: HashMap::new(),
+		}
+	}
+
+	pub fn now(&self) -> i32 { self.now }
+
+	/// 出力を遅らせる量の基準。シーケンサはこれだけ先に進めておく
+	pub fn lead(&self) -> i32 { self.spec.timing }
+
+	pub fn advance(&mut self) {
+		self.now += 1;
+	}
+
+	/// ノートオンを出力するティックと、ベロシティに掛ける値を求める
+	pub fn note_on(&mut self, note: &str, rng: &mut StdRng) -> (i32, Sample) {
+		let timing = self.spec.timing;
+		self.delay = timing + if timing > 0 { rng.gen_range(- timing ..= timing) } else { 0 };
+		self.note_on_ticks.insert(note.to_string(), self.now);
+		let velocity = if self.spec.velocity > 0f32 { 1f32 + rng.gen_range(- self.spec.velocity ..= self.spec.velocity) } else { 1f32 };
+
+		(self.schedule(self.delay), velocity.max(0f32))
+	}
+
+	/// ノートオフを出力するティックを求める。音長はノートオンからの実際の長さに対して増減させる
+	pub fn note_off(&mut self, note: &str, rng: &mut StdRng) -> i32 {
+		let gate_change = match self.note_on_ticks.remove(note) {
+			Some(note_on_tick) if self.spec.gate > 0f32 => {
+				((self.now - note_on_tick) as Sample * rng.gen_range(- self.spec.gate ..= self.spec.gate)).round() as i32
+			},
+			_ => 0,
+		};
+
+		self.schedule(self.delay + gate_change)
+	}
+
+	/// ノート以外の出力をするティックを求める
+	pub fn other(&mut self) -> i32 {
+		self.schedule(self.delay)
+	}
+
+	fn schedule(&mut self, delay: i32) -> i32 {
+		self.last_due = self.last_due.max(self.now + delay.max(0));
+		self.last_due
+	}
+}
+
+#[cfg(test)]
+#[test]
+fn test_humanizer() {
+	let mut rng = StdRng::seed_from_u64(0);
+	let mut humanizer = Humanizer::new(Humanize { timing: 8, velocity: 0.5, gate: 0.5, seed: None });
+	assert_eq!(humanizer.lead(), 8);
+	let mut offsets = vec![];
+	for _ in 0 .. 100 {
+		let (note_on, velocity) = humanizer.note_on("a", &mut rng);
+		// 先に進めた分を差し引くと、格子の前後にずれる
+		let offset = note_on - humanizer.now() - humanizer.lead();
+		assert!((-8 ..= 8).contains(&offset));
+		offsets.push(offset);
+		assert!((0.5f32 ..= 1.5f32).contains(&velocity));
+		for _ in 0 .. 10 { humanizer.advance(); }
+		// ノートオフはノートオンより前にならない
+		assert!(humanizer.note_off("a", &mut rng) >= note_on);
+		for _ in 0 .. 10 { humanizer.advance(); }
+	}
+	assert!(offsets.iter().any(|offset| *offset < 0));
+	assert!(offsets.iter().any(|offset| *offset > 0));
+}
@@ -5,13 +5,21 @@ use super::{
 use crate::core::{
 	common::*,
 };
+extern crate parser;
+use parser::mml::ast::{MmlPos, RampCurve};
 
 #[derive(Clone, Debug)]
 pub enum Instruction {
 	Nop,
 	// TODO tag は intern した文字列にする
 	Note { tag: String, note_on: bool },
+	/// ポリフォニックなトラックのノート。ボイスへの割り当てはシーケンサが行う。
+	/// key はノートの識別に使うノート番号。freq はノートオフの場合は使わない
+	PolyNote { key: i32, freq: Sample, note_on: bool },
 	Value { tag: String, key: String, value: Sample },
+	/// 値を ticks ティックかけて from から to まで変化させる。変化の途中でも後続のインストラクションは実行する。
+	/// シーケンサが直前の値を把握している場合は from の代わりにそちらを使う
+	Ramp { tag: String, key: String, from: Sample, to: Sample, ticks: i32, curve: RampCurve },
 	Wait(i32),
 
 	NewVar { name: String, value: i32 }, // TODO 型をつける
@@ -20,7 +28,17 @@ pub enum Instruction {
 	Call { seq_name: String },
 	JumpAbs { seq_name: Option<String>, pos: InstructionIndex },
 	JumpRel { offset: i32 },
+	/// シーケンスのいずれかをランダムに選んで呼び出す
+	Choose { seq_names: Vec<String> },
 	If0 { var: String, then: Box<Instruction> }, // いずれもっと汎用的な instrc で置換できるかもしれない
 	EnterSkipMode,
 	ExitSkipMode,
+	/// 演奏時には何もしない。タイムラインでの位置を求めるのに使う。
+	/// pos は MML の中での位置で、マクロの展開先ではマクロを呼び出した位置
+	Marker { name: String, pos: MmlPos },
+	/// 拍子の変更。演奏時には何もしない
+	Meter { numerator: i32, denominator: i32 },
+	/// 小節線。演奏時には何もしない。
+	/// 位置は MML の中での行と列で、マクロの展開先など位置が分からないものは None
+	BarLine { pos: Option<(u32, usize)> },
 }
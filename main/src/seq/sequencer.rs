@@ -1,5 +1,6 @@
 use crate::common;
 use crate::core::{
+	common::*,
 	event::*,
 	machine::*,
 	node::*,
@@ -13,20 +14,49 @@ use crate::node::{
 use super::{
 	common::*,
 	instruction::*,
+	humanize::*,
 	tick::EVENT_TYPE_TICK,
 	sequence::*,
+	voice_allocator::*,
 };
 use node_macro::node_impl;
+extern crate parser;
+use parser::mml::ast::RampCurve;
+use rand::{
+	prelude::*,
+	rngs::StdRng,
+};
 
-use std::collections::hash_map::HashMap;
+use std::collections::{
+	hash_map::HashMap,
+	hash_set::HashSet,
+	VecDeque,
+};
 
 pub struct Sequencer {
 	sequences: HashMap<String, Sequence>,
 	// TODO 今後 context は任意個になる予定
 	context: Context,
+	range: PlaybackRange,
+	/// 処理したティック数（開始位置までを飛ばした分も含む）
+	ticks: i32,
+	/// ループの始点に達したときの状態
+	loop_start_context: Option<Context>,
+	/// 選択肢を選ぶ乱数の種
+	choice_seed: u64,
+}
+
+/// 演奏する範囲（ティック単位）
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PlaybackRange {
+	/// 演奏を開始する位置。ここまでは音を出さずに進める
+	pub start: i32,
+	/// 繰り返し演奏する区間の始点と終点
+	pub loop_range: Option<(i32, i32)>,
 }
 impl Sequencer {
-	pub fn new(name: String, sequences: HashMap<String, Sequence>) -> Self {
+	/// voices はポリフォニックなトラックの場合に与える
+	pub fn new(name: String, sequences: HashMap<String, Sequence>, voices: Option<VoiceAllocator>) -> Self {
 		Self {
 			sequences,
 			context: Context {
@@ -37,8 +67,62 @@ impl Sequencer {
 					vars: Vars::new(),
 				}),
 				wait: 0,
+				voices,
+				values: HashMap::new(),
+				ramps: vec![],
+				held_notes: HashSet::new(),
+				silent: false,
+				rng: StdRng::seed_from_u64(0),
+				choice_rng: StdRng::seed_from_u64(0),
+				humanizer: None,
+				pending: VecDeque::new(),
 			},
+			range: PlaybackRange::default(),
+			ticks: 0,
+			loop_start_context: None,
+			choice_seed: 0,
+		}
+	}
+
+	pub fn name(&self) -> &str { &self.context.name }
+
+	pub fn sequences(&self) -> &HashMap<String, Sequence> { &self.sequences }
+
+	pub fn choice_seed(&self) -> u64 { self.choice_seed }
+
+	pub fn set_playback_range(&mut self, range: PlaybackRange) {
+		self.range = range;
+	}
+
+	/// 揺らぎと選択肢の選び方に使う乱数の種を設定する。トラックごとに別の種を与える
+	pub fn set_seed(&mut self, seed: u64) {
+		self.context.rng = StdRng::seed_from_u64(seed);
+		self.choice_seed = seed;
+		self.context.choice_rng = StdRng::seed_from_u64(seed);
+	}
+
+	/// 演奏に揺らぎを加える。velocity はベロシティを設定するタグとキー。
+	/// 揺らぎの種を指定した場合は選択肢の選び方にも使う
+	pub fn set_humanize(&mut self, humanize: Humanize, velocity: (String, String)) {
+		if let Some(seed) = humanize.seed {
+			self.set_seed(seed);
+		}
+		self.context.humanizer = Some((Humanizer::new(humanize), velocity));
+	}
+
+	/// 揺らぎでノートを前にずらせるよう、演奏位置より先に進めておくティック数
+	fn lead(&self) -> i32 {
+		self.context.humanizer.as_ref().map_or(0, |(humanizer, _)| humanizer.lead())
+	}
+
+	fn tick(&mut self, context: &CoreContext, env: &mut Environment) {
+		if let Some((loop_start, _)) = self.range.loop_range {
+			if self.ticks == loop_start + self.lead() {
+				self.loop_start_context = Some(self.context.clone());
+			}
 		}
+		self.context.tick(&mut self.sequences, context, env);
+		self.ticks += 1;
 	}
 }
 
@@ -51,7 +135,31 @@ impl Node for Sequencer {
 		if event.event_type() != EVENT_TYPE_TICK { return; }
 
 //		println!("tick at sample {}", context.elapsed_samples());
-		self.context.tick(&mut self.sequences, context, env);
+		if self.ticks == 0 {
+			if self.range.start > 0 {
+				// 開始位置までは音を出さずに進め、その時点での値だけを設定する
+				self.context.silent = true;
+				while self.ticks < self.range.start {
+					self.tick(context, env);
+				}
+				self.context.silent = false;
+				self.context.flush_values(env, context);
+			}
+			for _ in 0 .. self.lead() {
+				self.tick(context, env);
+			}
+		}
+		let lead = self.lead();
+		if let (Some((loop_start, loop_end)), Some(loop_start_context)) = (self.range.loop_range, &self.loop_start_context) {
+			if self.ticks == loop_end + lead {
+				self.context.release_notes(env, context);
+				self.context = loop_start_context.clone();
+				self.context.silent = false;
+				self.context.flush_values(env, context);
+				self.ticks = loop_start + lead;
+			}
+		}
+		self.tick(context, env);
 	}
 
 	// fn execute(&mut self, _inputs: &Vec<Sample>, _output: &mut [Sample], _context: &CoreContext, _env: &mut Environment) {
@@ -73,13 +181,34 @@ struct StackFrame {
 	vars: Vars,
 }
 
+#[derive(Clone)]
 struct Context {
 	name: String,
 	stack: Stack,
 	wait: i32,
+	voices: Option<VoiceAllocator>,
+	/// このシーケンサが最後に設定した値。ランプの開始値に使う
+	values: HashMap<(String, String), Sample>,
+	ramps: Vec<Ramp>,
+	/// ノートオン中のタグ
+	held_notes: HashSet<String>,
+	/// 開始位置まで飛ばしている間は、音を出さずに値の変化だけを記録する
+	silent: bool,
+	/// 揺らぎに使う乱数
+	rng: StdRng,
+	/// 選択肢を選ぶ乱数。タイムラインで同じ選択肢をたどれるよう、揺らぎとは別に持つ
+	choice_rng: StdRng,
+	/// 揺らぎを加える場合は、ベロシティを設定するタグとキーも持つ
+	humanizer: Option<(Humanizer, (String, String))>,
+	/// 揺らぎによって遅らせた出力と、出力するティック
+	pending: VecDeque<(i32, Instruction)>,
 }
 impl Context {
 	fn tick(&mut self, sequences: &mut HashMap<String, Sequence>, context: &CoreContext, env: &mut Environment) {
+		// ウェイト中もランプは進める
+		self.advance_ramps(context, env);
+		self.output_pending(env, context);
+
 		if self.wait > 0 {
 			self.wait -= 1;
 			if self.wait > 0 { return; }
@@ -123,10 +252,27 @@ impl Context {
 				// nop
 			}
 			Instruction::Note { tag, note_on } => {
-				env.broadcast_event(context.elapsed_samples(), Box::new(NoteEvent::new(EventTarget::Tag(tag.clone()), *note_on)));
+				if self.silent { return; }
+				self.output_humanized(instrc, Some((tag, *note_on)), env, context);
+			}
+			Instruction::PolyNote { key, note_on, .. } => {
+				if self.silent { return; }
+				self.output_humanized(instrc, Some((key.to_string().as_str(), *note_on)), env, context);
 			}
 			Instruction::Value { tag, key, value } => {
-				env.broadcast_event(context.elapsed_samples(), Box::new(SetEvent::new(EventTarget::Tag(tag.clone()), key.clone(), *value)));
+				// 変化中の値を直接設定した場合は、変化を打ち切る
+				self.ramps.retain(|ramp| ramp.tag != *tag || ramp.key != *key);
+				self.set_value(tag, key, *value, env, context);
+			}
+			Instruction::Ramp { tag, key, from, to, ticks, curve } => {
+				let from = self.values.get(&(tag.clone(), key.clone())).copied().unwrap_or(*from);
+				self.ramps.retain(|ramp| ramp.tag != *tag || ramp.key != *key);
+				if *ticks > 0 {
+					self.ramps.push(Ramp { tag: tag.clone(), key: key.clone(), from, to: *to, ticks: *ticks, elapsed: 0, curve: *curve });
+					self.set_value(tag, key, from, env, context);
+				} else {
+					self.set_value(tag, key, *to, env, context);
+				}
 			}
 			Instruction::Wait(wait) => {
 				self.wait = *wait;
@@ -151,6 +297,10 @@ impl Context {
 				new_top.seq_idx = SequenceName(seq_name.clone());
 				new_top.instrc_idx = -1; // この後インクリメントされるので 1 引いておく
 			}
+			Instruction::Choose { seq_names } => {
+				let seq_name = &seq_names[choose_index(&mut self.choice_rng, seq_names.len())];
+				self.process_instruction(&Instruction::Call { seq_name: seq_name.clone() }, env, context);
+			}
 			Instruction::JumpAbs { seq_name, pos } => {
 				let top = self.stack.top_mut();
 				if let Some(seq_name) = seq_name { top.seq_idx = SequenceName(seq_name.clone()); }
@@ -171,7 +321,219 @@ impl Context {
 			Instruction::ExitSkipMode => {
 				env.broadcast_event(context.elapsed_samples(), Box::new(ExitSkipModeEvent { }));
 			}
+			Instruction::Marker { .. } | Instruction::BarLine { .. } | Instruction::Meter { .. } => {
+				// 演奏時には何もしない
+			}
+		}
+	}
+
+	/// 揺らぎを加えて出力する。note はノートの識別子とノートオンかどうか
+	fn output_humanized(&mut self, instrc: &Instruction, note: Option<(&str, bool)>, env: &mut Environment, context: &CoreContext) {
+		let (humanizer, velocity_target) = match &mut self.humanizer {
+			Some(humanizer) => humanizer,
+			None => return self.output(instrc, env, context),
+		};
+		let now = humanizer.now();
+		let due = match note {
+			Some((note, true)) => {
+				let (due, velocity) = humanizer.note_on(note, &mut self.rng);
+				if velocity != 1f32 {
+					let (tag, key) = velocity_target.clone();
+					let value = self.values.get(&(tag.clone(), key.clone())).copied().unwrap_or(1f32) * velocity;
+					self.schedule(now, due, Instruction::Value { tag, key, value }, env, context);
+				}
+				due
+			},
+			Some((note, false)) => humanizer.note_off(note, &mut self.rng),
+			None => humanizer.other(),
+		};
+		self.schedule(now, due, instrc.clone(), env, context);
+	}
+
+	fn schedule(&mut self, now: i32, due: i32, instrc: Instruction, env: &mut Environment, context: &CoreContext) {
+		if due > now {
+			self.pending.push_back((due, instrc));
+		} else {
+			self.output(&instrc, env, context);
+		}
+	}
+
+	/// 遅らせていた出力のうち、時間になったものを出力する
+	fn output_pending(&mut self, env: &mut Environment, context: &CoreContext) {
+		let now = match &mut self.humanizer {
+			Some((humanizer, _)) => {
+				humanizer.advance();
+				humanizer.now()
+			},
+			None => return,
+		};
+		while self.pending.front().is_some_and(|(due, _)| *due <= now) {
+			let (_, instrc) = self.pending.pop_front().unwrap();
+			self.output(&instrc, env, context);
+		}
+	}
+
+	/// ノートや値を出力する
+	fn output(&mut self, instrc: &Instruction, env: &mut Environment, context: &CoreContext) {
+		match instrc {
+			Instruction::Note { tag, note_on } => {
+				env.broadcast_event(context.elapsed_samples(), Box::new(NoteEvent::new(EventTarget::Tag(tag.clone()), *note_on)));
+				if *note_on {
+					self.held_notes.insert(tag.clone());
+				} else {
+					self.held_notes.remove(tag);
+				}
+			}
+			Instruction::PolyNote { key, freq, note_on } => {
+				if let Some(voices) = &mut self.voices {
+					if *note_on {
+						voices.note_on(*key, *freq, context.elapsed_samples(), env);
+					} else {
+						voices.note_off(*key, context.elapsed_samples(), env);
+					}
+				}
+			}
+			Instruction::Value { tag, key, value } => {
+				env.broadcast_event(context.elapsed_samples(), Box::new(SetEvent::new(EventTarget::Tag(tag.clone()), key.clone(), *value)));
+			}
+			_ => { },
+		}
+	}
+
+	/// 値を設定する。揺らぎを加える場合は、ノートと同じだけ遅らせて出力する
+	fn set_value(&mut self, tag: &str, key: &str, value: Sample, env: &mut Environment, context: &CoreContext) {
+		self.values.insert((tag.to_string(), key.to_string()), value);
+		if ! self.silent {
+			self.output_humanized(&Instruction::Value { tag: tag.to_string(), key: key.to_string(), value }, None, env, context);
+		}
+	}
+
+	/// 記録しておいた値を全て設定し直す
+	fn flush_values(&self, env: &mut Environment, context: &CoreContext) {
+		for ((tag, key), value) in &self.values {
+			env.broadcast_event(context.elapsed_samples(), Box::new(SetEvent::new(EventTarget::Tag(tag.clone()), key.clone(), *value)));
+		}
+	}
+
+	fn release_notes(&mut self, env: &mut Environment, context: &CoreContext) {
+		self.pending.clear();
+		for tag in self.held_notes.drain() {
+			env.broadcast_event(context.elapsed_samples(), Box::new(NoteEvent::new(EventTarget::Tag(tag), false)));
 		}
+		if let Some(voices) = &mut self.voices {
+			voices.release_all(context.elapsed_samples(), env);
+		}
+	}
+
+	fn advance_ramps(&mut self, context: &CoreContext, env: &mut Environment) {
+		if self.ramps.is_empty() { return; }
+
+		let mut ramps = std::mem::take(&mut self.ramps);
+		for ramp in &mut ramps {
+			ramp.elapsed += 1;
+			self.set_value(&ramp.tag, &ramp.key, ramp.value(), env, context);
+		}
+		ramps.retain(|ramp| ramp.elapsed < ramp.ticks);
+		self.ramps = ramps;
+	}
+}
+
+#[derive(Clone)]
+struct Ramp {
+	tag: String,
+	key: String,
+	from: Sample,
+	to: Sample,
+	ticks: i32,
+	elapsed: i32,
+	curve: RampCurve,
+}
+impl Ramp {
+	fn value(&self) -> Sample {
+		ramp_value(self.from, self.to, self.elapsed, self.ticks, self.curve)
+	}
+}
+
+/// 選択肢のうちどれを選ぶか。タイムラインでも同じ種の乱数で同じ順に選べば、演奏時と同じ選択肢になる
+pub fn choose_index(rng: &mut StdRng, count: usize) -> usize {
+	rng.gen_range(0 .. count)
+}
+
+/// ランプの途中の値
+pub fn ramp_value(from: Sample, to: Sample, elapsed: i32, ticks: i32, curve: RampCurve) -> Sample {
+	let rate = if ticks > 0 { elapsed as Sample / ticks as Sample } else { 1f32 };
+	match curve {
+		// 指数的な変化は符号が同じ 0 でない値の間でしかできないので、それ以外は直線で代用する
+		RampCurve::Exponential if from * to > 0f32 => from * (to / from).powf(rate),
+		_ => from + (to - from) * rate,
+	}
+}
+
+#[cfg(test)]
+#[test]
+fn test_ramp_value() {
+	let approx = |actual: Sample, expected: Sample| (actual - expected).abs() < 1e-4;
+
+	// 始点、中間、終点
+	assert!(approx(ramp_value(0f32, 1f32, 0, 96, RampCurve::Linear), 0f32));
+	assert!(approx(ramp_value(0f32, 1f32, 48, 96, RampCurve::Linear), 0.5f32));
+	assert!(approx(ramp_value(0f32, 1f32, 96, 96, RampCurve::Linear), 1f32));
+	assert!(approx(ramp_value(220f32, 880f32, 0, 96, RampCurve::Exponential), 220f32));
+	assert!(approx(ramp_value(220f32, 880f32, 48, 96, RampCurve::Exponential), 440f32));
+	assert!(approx(ramp_value(220f32, 880f32, 96, 96, RampCurve::Exponential), 880f32));
+	// 符号が違うか 0 を含む場合の指数的な変化は直線になる
+	assert!(approx(ramp_value(-1f32, 1f32, 48, 96, RampCurve::Exponential), 0f32));
+	assert!(approx(ramp_value(0f32, 1f32, 48, 96, RampCurve::Exponential), 0.5f32));
+	// 長さ 0 なら即座に終点
+	assert!(approx(ramp_value(0f32, 1f32, 0, 0, RampCurve::Linear), 1f32));
+
+	// Tick ごとに進めると、最後の Tick でちょうど終点の値になって終わる
+	let mut ramp = Ramp { tag: "a.#volume".to_string(), key: "value".to_string(), from: 1f32, to: 0f32, ticks: 4, elapsed: 0, curve: RampCurve::Linear };
+	let mut values = vec![];
+	while ramp.elapsed < ramp.ticks {
+		ramp.elapsed += 1;
+		values.push(ramp.value());
 	}
+	assert_eq!(values, vec![0.75f32, 0.5f32, 0.25f32, 0f32]);
 }
 
+#[cfg(test)]
+#[test]
+fn test_track_seeds() {
+	use crate::common::seed::Seed;
+	use super::timeline::walk;
+	use parser::mml::ast::MmlPos;
+
+	// ?[ @@a c | @@b c ] を 16 回繰り返す
+	let mut sequences = HashMap::new();
+	sequences.insert(SEQUENCE_NAME_MAIN.to_string(), vec![
+		Instruction::NewVar { name: "#var0".to_string(), value: 15 },
+		Instruction::Choose { seq_names: vec!["#seq0".to_string(), "#seq1".to_string()] },
+		Instruction::If0 { var: "#var0".to_string(), then: Box::new(Instruction::JumpRel { offset: 3 }) },
+		Instruction::DecrVar { name: "#var0".to_string() },
+		Instruction::JumpRel { offset: -3 },
+		Instruction::DeleteVar { name: "#var0".to_string() },
+	]);
+	for (seq_name, marker) in [("#seq0", "a"), ("#seq1", "b")] {
+		sequences.insert(seq_name.to_string(), vec![
+			Instruction::Marker { name: marker.to_string(), pos: MmlPos { line: 1, column: 1 } },
+			Instruction::Wait(96),
+		]);
+	}
+	let choices = |track: &str, humanize: Option<Humanize>| {
+		let mut seqr = Sequencer::new(track.to_string(), sequences.clone(), None);
+		seqr.set_seed(Seed(1).derive(&format!("track/{}", track)));
+		if let Some(humanize) = humanize {
+			seqr.set_humanize(humanize, (format!("{}.#velocity", track), "value".to_string()));
+		}
+		walk(seqr.sequences(), seqr.choice_seed()).markers.into_iter().map(|(name, ..)| name).collect::<Vec<_>>()
+	};
+
+	// 同じ MML でもトラックごとに選び方が違う
+	assert_eq!(choices("a", None), choices("a", None));
+	assert_ne!(choices("a", None), choices("b", None));
+	// 種を省略した揺らぎはトラックの種を使い、指定した種はトラックによらない
+	assert_eq!(choices("a", Some(Humanize { timing: 4, ..Default::default() })), choices("a", None));
+	let seeded = Humanize { seed: Some(3), ..Default::default() };
+	assert_eq!(choices("a", Some(seeded)), choices("b", Some(seeded)));
+}
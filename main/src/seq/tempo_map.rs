@@ -0,0 +1,112 @@
+use crate::core::common::*;
+use super::timeline::Timeline;
+
+/// 曲全体のテンポと拍子の変化。ティックと秒や小節番号との変換に使う
+#[derive(Clone, Debug)]
+pub struct TempoMap {
+	/// 4/4 拍子の 1 小節（全音符）のティック数
+	ticks_per_bar: i32,
+	initial_tempo: Sample,
+	/// テンポの変更の位置と値。ティック順に並ぶ
+	tempo_changes: Vec<(i32, Sample)>,
+	/// 拍子の変更の位置と、拍子の分子と分母。ティック順に並ぶ
+	meters: Vec<(i32, (i32, i32))>,
+}
+impl TempoMap {
+	/// テンポと拍子は全トラックで共有するので、全トラックのタイムラインの変更をまとめる
+	pub fn new<'a>(ticks_per_bar: i32, initial_tempo: Sample, timelines: impl Iterator<Item = &'a Timeline>) -> Self {
+		let mut tempo_changes = vec![];
+		let mut meters = vec![];
+		for timeline in timelines {
+			tempo_changes.extend(timeline.tempo_changes.iter().copied());
+			meters.extend(timeline.meters.iter().copied());
+		}
+		tempo_changes.sort_by_key(|(tick, _)| *tick);
+		meters.sort_by_key(|(tick, _)| *tick);
+
+		Self { ticks_per_bar, initial_tempo, tempo_changes, meters }
+	}
+
+	pub fn tempo_changes(&self) -> &[(i32, Sample)] { &self.tempo_changes }
+	pub fn meters(&self) -> &[(i32, (i32, i32))] { &self.meters }
+
+	/// ティック数を秒数に変換する
+	pub fn ticks_to_seconds(&self, tick: i32) -> f32 {
+		let mut seconds = 0f32;
+		let mut tempo = self.initial_tempo;
+		let mut pos = 0;
+		for (change_tick, change_tempo) in &self.tempo_changes {
+			if *change_tick >= tick { break; }
+			seconds += self.seconds_per_tick(tempo) * (change_tick - pos) as f32;
+			pos = *change_tick;
+			tempo = *change_tempo;
+		}
+		seconds + self.seconds_per_tick(tempo) * (tick - pos) as f32
+	}
+
+	fn seconds_per_tick(&self, tempo: Sample) -> f32 {
+		// TickTimer と同じく、テンポは 4 分音符の数で数える
+		240f32 / tempo / self.ticks_per_bar as f32
+	}
+
+	/// その位置での 1 小節のティック数
+	pub fn bar_length(&self, tick: i32) -> i32 {
+		let meter = self.meters.iter().take_while(|(change_tick, _)| *change_tick <= tick).last();
+		match meter {
+			Some((_, (numerator, denominator))) => self.ticks_per_bar * numerator / denominator,
+			None => self.ticks_per_bar,
+		}
+	}
+
+	/// ティックを含む小節の番号（1 始まり）と、小節の先頭からのティック数。
+	/// 小節の途中で拍子を変えた場合は、そこから新しい小節とする
+	pub fn bar_at(&self, tick: i32) -> (i32, i32) {
+		let mut bar = 1;
+		let mut pos = 0;
+		for (change_tick, _) in &self.meters {
+			if *change_tick <= pos { continue; }
+			if *change_tick > tick { break; }
+			let length = self.bar_length(pos);
+			bar += (change_tick - pos + length - 1) / length;
+			pos = *change_tick;
+		}
+		let length = self.bar_length(pos);
+		(bar + (tick - pos) / length, (tick - pos) % length)
+	}
+
+	/// 小節の先頭のティック
+	pub fn bar_start(&self, bar: i32) -> i32 {
+		let mut remaining = bar - 1;
+		let mut pos = 0;
+		for (change_tick, _) in &self.meters {
+			if *change_tick <= pos { continue; }
+			let length = self.bar_length(pos);
+			let bars = (change_tick - pos + length - 1) / length;
+			if remaining < bars { break; }
+			remaining -= bars;
+			pos = *change_tick;
+		}
+		pos + remaining * self.bar_length(pos)
+	}
+}
+
+#[cfg(test)]
+#[test]
+fn test_tempo_map() {
+	// 4/4 で 2 小節、3/4 で 2 小節、テンポは 2 小節目から 2 倍
+	let timeline = Timeline {
+		tempo_changes: vec![(384, 240f32)],
+		meters: vec![(0, (4, 4)), (768, (3, 4))],
+		..Default::default()
+	};
+	let map = TempoMap::new(384, 120f32, std::iter::once(&timeline));
+	assert_eq!(map.ticks_to_seconds(384), 2f32);
+	assert_eq!(map.ticks_to_seconds(768), 3f32);
+	assert_eq!(map.bar_length(767), 384);
+	assert_eq!(map.bar_length(768), 288);
+	assert_eq!(map.bar_at(0), (1, 0));
+	assert_eq!(map.bar_at(800), (3, 32));
+	assert_eq!(map.bar_at(1056), (4, 0));
+	assert_eq!(map.bar_start(4), 1056);
+	assert_eq!(map.bar_start(2), 384);
+}
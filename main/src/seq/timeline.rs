@@ -0,0 +1,316 @@
+use crate::core::common::*;
+use super::{
+	instruction::*,
+	sequence::*,
+	sequencer::{choose_index, ramp_value},
+	tempo_map::TempoMap,
+};
+
+use parser::mml::ast::MmlPos;
+use rand::{
+	prelude::*,
+	rngs::StdRng,
+};
+
+use std::collections::hash_map::HashMap;
+
+/// シーケンスを時間をかけずにたどって求めた、マーカー等の位置（ティック）
+#[derive(Clone, Debug, Default)]
+pub struct Timeline {
+	/// 現れた順のマーカー名と位置（ティック）と、MML の中でのマーカーの位置。ループ内のマーカーは繰り返した回数だけ現れる
+	pub markers: Vec<(String, i32, MmlPos)>,
+	/// #tempo の変更の位置と値。ランプはティックごとの値に展開する
+	pub tempo_changes: Vec<(i32, Sample)>,
+	/// 拍子の変更の位置と、拍子の分子と分母
+	pub meters: Vec<(i32, (i32, i32))>,
+	/// 小節線の位置と、MML の中での小節線の行と列
+	pub bar_lines: Vec<(i32, Option<(u32, usize)>)>,
+	/// 曲の長さ。無限ループがある場合は None（ループの 1 周目までをたどる）
+	pub end: Option<i32>,
+}
+impl Timeline {
+	pub fn marker_ticks(&self, name: &str) -> Vec<i32> {
+		self.markers.iter().filter(|(n, ..)| n == name).map(|(_, tick, _)| *tick).collect()
+	}
+
+	/// 長さが拍子と合わない小節を求める。
+	/// 小節線で終わっている小節だけを対象とし、最初の小節は曲の先頭から数える。
+	/// 最初の小節が 1 小節より短ければ弱起とみなして、0 小節目として扱う。
+	/// 小節線を省略して複数の小節をまとめて書いてもよいので、小節線の間に収まる小節の数だけ小節番号を進める
+	pub fn bad_bars(&self, tempo_map: &TempoMap) -> Vec<BadBar> {
+		let mut result = vec![];
+		let mut bar = match self.bar_lines.first() {
+			Some((tick, _)) if *tick < tempo_map.bar_length(0) => 0,
+			_ => 1,
+		};
+		let mut bar_start = 0;
+		for (tick, pos) in &self.bar_lines {
+			if bar == 0 {
+				bar = 1;
+				bar_start = *tick;
+				continue;
+			}
+			// 小節線までに収まる小節の数を数え、最後の小節の長さを調べる
+			let mut last_start = bar_start;
+			let mut expected = tempo_map.bar_length(last_start);
+			while last_start + expected < *tick {
+				last_start += expected;
+				expected = tempo_map.bar_length(last_start);
+				bar += 1;
+			}
+			let length = tick - last_start;
+			if length != expected {
+				result.push(BadBar { bar, length, expected, pos: *pos });
+			}
+			bar += 1;
+			bar_start = *tick;
+		}
+
+		result
+	}
+}
+
+/// 長さが正しくない小節
+#[derive(Clone, Debug, PartialEq)]
+pub struct BadBar {
+	/// 小節番号（1 始まり。弱起の小節は数えない）
+	pub bar: i32,
+	pub length: i32,
+	/// 拍子から求めた本来の長さ
+	pub expected: i32,
+	/// 小節の終わりの小節線の、MML の中での行と列
+	pub pos: Option<(u32, usize)>,
+}
+
+const TEMPO_TAG: &str = "#tempo";
+
+/// シーケンサと同じ規則でインストラクションを実行し、タイムラインを求める。choice_seed はシーケンサが選択肢を選ぶ乱数の種
+pub fn walk(sequences: &HashMap<String, Sequence>, choice_seed: u64) -> Timeline {
+	let (instrcs, end) = flatten(sequences, choice_seed);
+	let mut timeline = Timeline { end, ..Default::default() };
+	for (tick, instrc) in instrcs {
+		match instrc {
+			Instruction::Marker { name, pos } => { timeline.markers.push((name.clone(), tick, *pos)); },
+			Instruction::BarLine { pos } => { timeline.bar_lines.push((tick, *pos)); },
+			Instruction::Meter { numerator, denominator } => { timeline.meters.push((tick, (*numerator, *denominator))); },
+			Instruction::Value { tag, value, .. } if tag == TEMPO_TAG => { timeline.tempo_changes.push((tick, *value)); },
+			Instruction::Ramp { tag, from, to, ticks, curve, .. } if tag == TEMPO_TAG => {
+				// シーケンサと同じく、直前のテンポが分かっていればそこから変化させる
+				let from = timeline.tempo_changes.last().map_or(*from, |(_, value)| *value);
+				for t in 0 ..= *ticks {
+					timeline.tempo_changes.push((tick + t, ramp_value(from, *to, t, *ticks, *curve)));
+				}
+			},
+			_ => { },
+		}
+	}
+
+	timeline
+}
+
+/// シーケンサと同じ規則でインストラクションを実行し、制御用以外のインストラクションを実行するティックとともに並べる。
+/// *** で飛ばす区間は、EnterSkipMode と ExitSkipMode で示す。
+/// 無限ループがある場合は 1 周目までを返し、曲の長さは None とする。
+/// 選択肢はシーケンサと同じ種の乱数で選ぶので、演奏時と同じものをたどる
+pub fn flatten(sequences: &HashMap<String, Sequence>, choice_seed: u64) -> (Vec<(i32, &Instruction)>, Option<i32>) {
+	let mut result = vec![];
+	let mut choice_rng = StdRng::seed_from_u64(choice_seed);
+	let mut stack = vec![Frame { seq_name: SEQUENCE_NAME_MAIN.to_string(), instrc_idx: 0, vars: vec![] }];
+	let mut tick = 0;
+	// 後ろ向きのジャンプをした時点の状態と、その時点までに並べたインストラクションの数。
+	// 同じ状態に戻ったら無限ループとみなし、2 周目で並べたものは捨てる
+	let mut visited = HashMap::new();
+
+	loop {
+		let frame = stack.last_mut().unwrap();
+		let sequence = &sequences[&frame.seq_name];
+		if frame.instrc_idx >= sequence.len() {
+			stack.pop();
+			if stack.is_empty() { break; }
+			stack.last_mut().unwrap().instrc_idx += 1;
+			continue;
+		}
+
+		let mut next_idx = frame.instrc_idx as i32 + 1;
+		let mut instrc = &sequence[frame.instrc_idx];
+		// If0 は条件を満たせば中身を実行する
+		if let Instruction::If0 { var, then } = instrc {
+			if frame.var(var) != Some(0) {
+				frame.instrc_idx += 1;
+				continue;
+			}
+			instrc = then;
+		}
+		match instrc {
+			Instruction::Wait(wait) => { tick += wait; },
+			Instruction::NewVar { name, value } => { frame.set_var(name, *value); },
+			Instruction::DecrVar { name } => {
+				if let Some(value) = frame.var(name) { frame.set_var(name, value - 1); }
+			},
+			Instruction::DeleteVar { name } => { frame.vars.retain(|(n, _)| n != name); },
+			Instruction::Call { seq_name } => {
+				let vars = frame.vars.clone();
+				stack.push(Frame { seq_name: seq_name.clone(), instrc_idx: 0, vars });
+				continue;
+			},
+			Instruction::Choose { seq_names } => {
+				let vars = frame.vars.clone();
+				let seq_name = seq_names[choose_index(&mut choice_rng, seq_names.len())].clone();
+				stack.push(Frame { seq_name, instrc_idx: 0, vars });
+				continue;
+			},
+			Instruction::JumpAbs { seq_name, pos } => {
+				if let Some(seq_name) = seq_name { frame.seq_name = seq_name.clone(); }
+				next_idx = pos.0 as i32;
+			},
+			Instruction::JumpRel { offset } => { next_idx = frame.instrc_idx as i32 + offset; },
+			Instruction::Nop => { },
+			_ => { result.push((tick, instrc)); },
+		}
+
+		let frame = stack.last_mut().unwrap();
+		if next_idx <= frame.instrc_idx as i32 {
+			let state: Vec<_> = stack.iter().map(|f| {
+				let mut vars = f.vars.clone();
+				vars.sort();
+				(f.seq_name.clone(), f.instrc_idx, vars)
+			}).collect();
+			if let Some(count) = visited.insert((state, next_idx), result.len()) {
+				result.truncate(count);
+				return (result, None);
+			}
+		}
+		stack.last_mut().unwrap().instrc_idx = next_idx as usize;
+	}
+
+	(result, Some(tick))
+}
+
+struct Frame {
+	seq_name: String,
+	instrc_idx: usize,
+	vars: Vec<(String, i32)>,
+}
+impl Frame {
+	fn var(&self, name: &str) -> Option<i32> {
+		self.vars.iter().find(|(n, _)| n == name).map(|(_, v)| *v)
+	}
+	fn set_var(&mut self, name: &str, value: i32) {
+		match self.vars.iter_mut().find(|(n, _)| n == name) {
+			Some((_, v)) => { *v = value; },
+			None => { self.vars.push((name.to_string(), value)); },
+		}
+	}
+}
+
+#[cfg(test)]
+#[test]
+fn test_walk() {
+	// @@a c4 [ @@b c4 ] @@c
+	let mut sequences = HashMap::new();
+	sequences.insert(SEQUENCE_NAME_MAIN.to_string(), vec![
+		Instruction::Marker { name: "a".to_string(), pos: MmlPos { line: 1, column: 1 } },
+		Instruction::Wait(96),
+		Instruction::NewVar { name: "#var0".to_string(), value: 1 },
+		Instruction::Call { seq_name: "#seq0".to_string() },
+		Instruction::If0 { var: "#var0".to_string(), then: Box::new(Instruction::JumpRel { offset: 3 }) },
+		Instruction::DecrVar { name: "#var0".to_string() },
+		Instruction::JumpRel { offset: -3 },
+		Instruction::DeleteVar { name: "#var0".to_string() },
+		Instruction::Marker { name: "c".to_string(), pos: MmlPos { line: 1, column: 1 } },
+	]);
+	sequences.insert("#seq0".to_string(), vec![
+		Instruction::Marker { name: "b".to_string(), pos: MmlPos { line: 1, column: 1 } },
+		Instruction::Wait(96),
+	]);
+	let timeline = walk(&sequences, 0);
+	assert_eq!(timeline.marker_ticks("a"), vec![0]);
+	assert_eq!(timeline.marker_ticks("b"), vec![96, 192]);
+	assert_eq!(timeline.marker_ticks("c"), vec![288]);
+	assert_eq!(timeline.end, Some(288));
+
+	// 無限ループ
+	sequences.insert(SEQUENCE_NAME_MAIN.to_string(), vec![
+		Instruction::Call { seq_name: "#seq0".to_string() },
+		Instruction::JumpRel { offset: -1 },
+	]);
+	let timeline = walk(&sequences, 0);
+	assert_eq!(timeline.marker_ticks("b"), vec![0]);
+	assert_eq!(timeline.end, None);
+}
+
+#[cfg(test)]
+#[test]
+fn test_walk_choose() {
+	// [ {@@a c | @@b c} ]16
+	let mut sequences = HashMap::new();
+	sequences.insert(SEQUENCE_NAME_MAIN.to_string(), vec![
+		Instruction::NewVar { name: "#var0".to_string(), value: 15 },
+		Instruction::Choose { seq_names: vec!["#seq0".to_string(), "#seq1".to_string()] },
+		Instruction::If0 { var: "#var0".to_string(), then: Box::new(Instruction::JumpRel { offset: 3 }) },
+		Instruction::DecrVar { name: "#var0".to_string() },
+		Instruction::JumpRel { offset: -3 },
+		Instruction::DeleteVar { name: "#var0".to_string() },
+	]);
+	for (seq_name, marker) in [("#seq0", "a"), ("#seq1", "b")] {
+		sequences.insert(seq_name.to_string(), vec![
+			Instruction::Marker { name: marker.to_string(), pos: MmlPos { line: 1, column: 1 } },
+			Instruction::Wait(96),
+		]);
+	}
+	// シーケンサと同じ種の乱数で、同じ順に選ぶ
+	let mut rng = StdRng::seed_from_u64(1);
+	let expected: Vec<_> = (0 .. 16).map(|_| ["a", "b"][choose_index(&mut rng, 2)]).collect();
+	let timeline = walk(&sequences, 1);
+	let markers: Vec<_> = timeline.markers.iter().map(|(name, ..)| name.as_str()).collect();
+	assert_eq!(markers, expected);
+	assert!(markers.contains(&"a") && markers.contains(&"b"));
+	assert_eq!(timeline.end, Some(96 * 16));
+}
+
+#[cfg(test)]
+#[test]
+fn test_walk_tempo_ramp() {
+	use parser::mml::ast::RampCurve;
+
+	// t60 c4 t(120,180)4 c4
+	let mut sequences = HashMap::new();
+	sequences.insert(SEQUENCE_NAME_MAIN.to_string(), vec![
+		Instruction::Value { tag: TEMPO_TAG.to_string(), key: "value".to_string(), value: 60f32 },
+		Instruction::Wait(96),
+		Instruction::Ramp { tag: TEMPO_TAG.to_string(), key: "value".to_string(), from: 120f32, to: 180f32, ticks: 4, curve: RampCurve::Linear },
+		Instruction::Wait(96),
+	]);
+	let timeline = walk(&sequences, 0);
+	// ランプは指定された開始値ではなく、直前のテンポから変化させる
+	assert_eq!(timeline.tempo_changes, vec![(0, 60f32), (96, 60f32), (97, 90f32), (98, 120f32), (99, 150f32), (100, 180f32)]);
+
+	// 直前のテンポがなければ指定された開始値から変化させる
+	sequences.get_mut(SEQUENCE_NAME_MAIN).unwrap().drain(0 .. 2);
+	let timeline = walk(&sequences, 0);
+	assert_eq!(timeline.tempo_changes.first(), Some(&(0, 120f32)));
+}
+
+#[cfg(test)]
+#[test]
+fn test_bad_bars() {
+	let timeline = Timeline {
+		bar_lines: vec![(384, Some((1, 10))), (672, Some((1, 20))), (1056, None), (1344, None)],
+		meters: vec![(1056, (3, 4))],
+		..Default::default()
+	};
+	let tempo_map = TempoMap::new(384, 120f32, std::iter::once(&timeline));
+	assert_eq!(timeline.bad_bars(&tempo_map), vec![BadBar { bar: 2, length: 288, expected: 384, pos: Some((1, 20)) }]);
+
+	// 弱起の小節は 0 小節目とし、小節線を省略した小節も数える
+	let timeline = Timeline {
+		bar_lines: vec![(96, None), (480, None), (1248, None), (1536, Some((2, 5))), (2112, Some((3, 5)))],
+		..Default::default()
+	};
+	let tempo_map = TempoMap::new(384, 120f32, std::iter::once(&timeline));
+	assert_eq!(timeline.bad_bars(&tempo_map), vec![
+		BadBar { bar: 4, length: 288, expected: 384, pos: Some((2, 5)) },
+		// 1 小節半の区間は、2 つ目の小節が短い
+		BadBar { bar: 6, length: 192, expected: 384, pos: Some((3, 5)) },
+	]);
+}
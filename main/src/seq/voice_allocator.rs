@@ -0,0 +1,235 @@
+use crate::core::{
+	common::*,
+	event::*,
+	machine::*,
+};
+use crate::node::{
+	envelope::NoteEvent,
+	var::*,
+};
+
+/// 空きボイスがないときに、どのボイスを奪って新しいノートに割り当てるか
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StealingPolicy {
+	/// 最も前に発音したボイス
+	Oldest,
+	/// 最も後に発音したボイス
+	Newest,
+	/// 最も低いノートのボイス
+	Lowest,
+	/// 最も高いノートのボイス
+	Highest,
+	/// 奪わない（新しいノートを鳴らさない）
+	None,
+}
+impl StealingPolicy {
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name {
+			"oldest" => Some(Self::Oldest),
+			"newest" => Some(Self::Newest),
+			"lowest" => Some(Self::Lowest),
+			"highest" => Some(Self::Highest),
+			"none" => Some(Self::None),
+			_ => None,
+		}
+	}
+}
+
+/// 1 つのボイスに宛てるイベントのタグ
+#[derive(Clone, Debug)]
+pub struct VoiceTags {
+	pub note: String,
+	pub freq: String,
+}
+
+/// ドラムキットで、ボイス（ヒット）を鳴らすキーと、同時に鳴らさないヒットのグループ
+#[derive(Clone, Debug)]
+pub struct HitRoute {
+	pub key: i32,
+	pub choke: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+struct VoiceState {
+	/// 発音中のノート番号。リリース後は None
+	key: Option<i32>,
+	/// 最後に発音またはリリースした順番
+	last_used: u64,
+}
+
+/// ポリフォニックなトラックで、ノートをボイスに割り当てる。
+/// 割り当てはシーケンサが演奏時に行う
+#[derive(Clone)]
+pub struct VoiceAllocator {
+	voices: Vec<VoiceTags>,
+	freq_key: String,
+	stealing: StealingPolicy,
+	/// ドラムキットの場合、ボイスごとのキー。キーに対応するボイスでだけ発音する
+	routes: Option<Vec<HitRoute>>,
+	states: Vec<VoiceState>,
+	counter: u64,
+}
+impl VoiceAllocator {
+	pub fn new(voices: Vec<VoiceTags>, freq_key: String, stealing: StealingPolicy) -> Self {
+		let states = voices.iter().map(|_| VoiceState { key: None, last_used: 0 }).collect();
+		Self { voices, freq_key, stealing, routes: None, states, counter: 0 }
+	}
+
+	/// ドラムキット用。routes はボイスと同じ順に並べる
+	pub fn drum_kit(voices: Vec<VoiceTags>, freq_key: String, routes: Vec<HitRoute>) -> Self {
+		Self { routes: Some(routes), ..Self::new(voices, freq_key, StealingPolicy::None) }
+	}
+
+	pub fn note_on(&mut self, key: i32, freq: Sample, elapsed_samples: SampleCount, env: &mut Environment) {
+		let (voice, released) = match self.allocate(key) {
+			Some(allocation) => allocation,
+			None => return,
+		};
+		for i in released {
+			self.send_note(i, false, elapsed_samples, env);
+		}
+		let tags = &self.voices[voice];
+		env.broadcast_event(elapsed_samples, Box::new(SetEvent::new(EventTarget::Tag(tags.freq.clone()), self.freq_key.clone(), freq)));
+		self.send_note(voice, true, elapsed_samples, env);
+	}
+
+	/// ノートを割り当てるボイスと、発音の前にリリースするボイスを決めて、ボイスの状態を更新する。
+	/// 割り当てられない（ドラムキットにないキーか、奪わない設定で空きがない）ときは None
+	fn allocate(&mut self, key: i32) -> Option<(usize, Vec<usize>)> {
+		let (voice, mut released) = match &self.routes {
+			// ドラムキットではキーでボイスが決まる。同じグループで鳴っている他のヒットは止める
+			Some(routes) => {
+				let voice = routes.iter().position(|route| route.key == key) ?;
+				let choked: Vec<_> = match &routes[voice].choke {
+					Some(group) => (0 .. routes.len()).filter(|&i| {
+						i != voice && routes[i].choke.as_ref() == Some(group) && self.states[i].key.is_some()
+					}).collect(),
+					None => vec![],
+				};
+				(voice, choked)
+			},
+			// 同じノートが鳴っていればそのボイスで発音し直す。
+			// そうでなければ、空いているボイスのうち最も前にリリースしたものを使う
+			None => {
+				let voice = self.find_voice(key)
+						.or_else(|| self.free_voices().min_by_key(|&i| self.states[i].last_used))
+						.or_else(|| self.voice_to_steal()) ?;
+				(voice, vec![])
+			},
+		};
+
+		for &i in &released {
+			self.touch(i, None);
+		}
+		// 発音し直すか奪う場合は、鳴っているノートを先に止める
+		if self.states[voice].key.is_some() {
+			released.push(voice);
+		}
+		self.touch(voice, Some(key));
+
+		Some((voice, released))
+	}
+
+	pub fn note_off(&mut self, key: i32, elapsed_samples: SampleCount, env: &mut Environment) {
+		if let Some(voice) = self.release(key) {
+			self.send_note(voice, false, elapsed_samples, env);
+		}
+	}
+
+	/// ノートを鳴らしているボイスを空きにする。奪われたノートの場合は None
+	fn release(&mut self, key: i32) -> Option<usize> {
+		let voice = self.find_voice(key) ?;
+		self.touch(voice, None);
+		Some(voice)
+	}
+
+	/// 発音中の全てのボイスをリリースする
+	pub fn release_all(&mut self, elapsed_samples: SampleCount, env: &mut Environment) {
+		for voice in 0 .. self.states.len() {
+			if self.states[voice].key.is_some() {
+				self.send_note(voice, false, elapsed_samples, env);
+				self.touch(voice, None);
+			}
+		}
+	}
+
+	fn find_voice(&self, key: i32) -> Option<usize> {
+		self.states.iter().position(|s| s.key == Some(key))
+	}
+
+	fn free_voices(&self) -> impl Iterator<Item = usize> + '_ {
+		self.states.iter().enumerate().filter(|(_, s)| s.key.is_none()).map(|(i, _)| i)
+	}
+
+	fn voice_to_steal(&self) -> Option<usize> {
+		let voices = 0 .. self.states.len();
+		match self.stealing {
+			StealingPolicy::Oldest => voices.min_by_key(|&i| self.states[i].last_used),
+			StealingPolicy::Newest => voices.max_by_key(|&i| self.states[i].last_used),
+			StealingPolicy::Lowest => voices.min_by_key(|&i| self.states[i].key),
+			StealingPolicy::Highest => voices.max_by_key(|&i| self.states[i].key),
+			StealingPolicy::None => None,
+		}
+	}
+
+	fn touch(&mut self, voice: usize, key: Option<i32>) {
+		self.counter += 1;
+		self.states[voice] = VoiceState { key, last_used: self.counter };
+	}
+
+	fn send_note(&self, voice: usize, note_on: bool, elapsed_samples: SampleCount, env: &mut Environment) {
+		let tag = self.voices[voice].note.clone();
+		env.broadcast_event(elapsed_samples, Box::new(NoteEvent::new(EventTarget::Tag(tag), note_on)));
+	}
+}
+
+#[cfg(test)]
+#[test]
+fn test_voice_stealing() {
+	let allocator = |stealing| {
+		let voices = (0 .. 2).map(|i| VoiceTags { note: format!("a#{}", i), freq: format!("a#{}_freq", i) }).collect();
+		VoiceAllocator::new(voices, "value".to_string(), stealing)
+	};
+	// 2 ボイスに 3 つのノートを順に割り当て、3 つ目のノートが奪ったボイスとリリースされたボイスを返す
+	let steal = |stealing, keys: [i32; 3]| {
+		let mut allocator = allocator(stealing);
+		assert_eq!(allocator.allocate(keys[0]), Some((0, vec![])));
+		assert_eq!(allocator.allocate(keys[1]), Some((1, vec![])));
+		allocator.allocate(keys[2])
+	};
+
+	assert_eq!(steal(StealingPolicy::Oldest, [60, 64, 67]), Some((0, vec![0])));
+	assert_eq!(steal(StealingPolicy::Newest, [60, 64, 67]), Some((1, vec![1])));
+	assert_eq!(steal(StealingPolicy::Lowest, [64, 60, 67]), Some((1, vec![1])));
+	assert_eq!(steal(StealingPolicy::Highest, [64, 60, 55]), Some((0, vec![0])));
+	assert_eq!(steal(StealingPolicy::None, [60, 64, 67]), None);
+
+	// 空きボイスがあれば奪わない。同じノートは同じボイスで発音し直す
+	let mut allocator = allocator(StealingPolicy::Oldest);
+	allocator.allocate(60);
+	allocator.allocate(64);
+	assert_eq!(allocator.release(60), Some(0));
+	assert_eq!(allocator.allocate(67), Some((0, vec![])));
+	assert_eq!(allocator.allocate(64), Some((1, vec![1])));
+}
+
+#[cfg(test)]
+#[test]
+fn test_drum_kit() {
+	let voices = (0 .. 3).map(|i| VoiceTags { note: format!("d#{}", i), freq: format!("d#{}_freq", i) }).collect();
+	let route = |key, choke: Option<&str>| HitRoute { key, choke: choke.map(|c| c.to_string()) };
+	let mut allocator = VoiceAllocator::drum_kit(voices, "value".to_string(), vec![
+		route(42, Some("hh")),
+		route(46, Some("hh")),
+		route(36, None),
+	]);
+
+	assert_eq!(allocator.allocate(46), Some((1, vec![])));
+	assert_eq!(allocator.allocate(36), Some((2, vec![])));
+	// 同じグループで鳴っているヒットだけを止める
+	assert_eq!(allocator.allocate(42), Some((0, vec![1])));
+	// 止めたヒットはもう止めない
+	assert_eq!(allocator.allocate(42), Some((0, vec![0])));
+	// キットにないキーは鳴らさない
+	assert_eq!(allocator.allocate(38), None);
+}
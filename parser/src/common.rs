@@ -1,6 +1,6 @@
 extern crate nom;
 extern crate nom_locate;
-use std::{fmt::Display, path::PathBuf, rc::Rc};
+use std::{fmt::Display, path::{Path, PathBuf}, rc::Rc};
 
 //use nom::regexp::str::*;
 use nom::{
@@ -50,6 +50,12 @@ pub fn re_find<'a>(regex: Regex) -> impl FnMut (Span<'a>) -> IResult<Span<'a>, &
 }
 
 pub type Span<'a> = LocatedSpan<&'a str, Rc<PathBuf>>;
+
+/// テスト用に、パスのない入力を作る
+#[cfg(test)]
+pub fn test_span(input: &str) -> Span<'_> {
+	Span::new_extra(input, Rc::new(PathBuf::new()))
+}
 #[derive(Clone, Debug)]
 pub struct Located<T> {
 	pub body: T,
@@ -83,6 +89,10 @@ impl Location {
 			column: span.get_utf8_column(),
 		}
 	}
+	/// ファイル全体を指す位置。ファイルの読み書きのエラーなど、ファイルの中の位置を持たないものに使う
+	pub fn file(path: &Path) -> Self {
+		Self { path: Rc::new(path.to_path_buf()), line: 0, column: 0 }
+	}
 	/// 位置情報をすぐに引っ張れないところはとりあえずこれにしておく。最終的には廃止するつもり
 	pub fn dummy() -> Self {
 		Self{ path: Rc::new(PathBuf::from("")), line: 0, column: 0 }
@@ -90,7 +100,12 @@ impl Location {
 }
 impl Display for Location {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-		write!(f, "{}, line {}, column {}", self.path.to_str().unwrap_or("(not available)"), self.line, self.column)
+		let path = self.path.to_str().unwrap_or("(not available)");
+		// ファイル全体を指す位置は行と列を持たない
+		if self.line == 0 && ! path.is_empty() {
+			return write!(f, "{}", path);
+		}
+		write!(f, "{}, line {}, column {}", path, self.line, self.column)
 	}
 }
 
@@ -195,7 +210,8 @@ pub_parser![float, f32, {
 #[cfg(test)]
 #[test]
 fn test_float() {
-	assert_eq!(float()("3.14"), Ok(("", 3.14f32)));
+	let (rest, value) = float()(test_span("2.75")).unwrap();
+	assert_eq!((*rest.fragment(), value), ("", 2.75f32));
 	// TODO 他にも
 }
 
@@ -1,2 +1,5 @@
 pub mod ast;
 pub mod default_mml_parser;
+pub mod dialect;
+pub mod mck_mml_parser;
+pub mod printer;
@@ -1,3 +1,5 @@
+use crate::common::Location;
+
 #[derive(Debug, PartialEq)]
 pub struct CompilationUnit {
 	pub commands: Vec<Command>,
@@ -20,23 +22,75 @@ pub enum Command {
 	Volume(NumberOrExpr),
 	Velocity(NumberOrExpr),
 	Detune(NumberOrExpr),
-	Tone { tone_name: ToneName, length: Length, slur: bool },
+	Tone { tone_name: ToneName, length: Length, slur: bool, pos: MmlPos },
+	/// 和音。ポリフォニックなトラックでのみ使える
+	Chord { tones: Vec<ChordTone>, length: Length, slur: bool, pos: MmlPos },
 	Rest(Length),
+	/// ドラムキットのヒットを名前で鳴らす。!name で書く
+	Hit { name: String, length: Length, slur: bool, pos: MmlPos },
 	Parameter { name: String, key: Option<String>, value: NumberOrExpr },
 	Tempo(NumberOrExpr),
-	MacroCall { name: String },
+	/// パラメータを length の長さをかけて目標値まで変化させる。
+	/// command は値を設定するコマンド（Parameter, Volume, Velocity, Detune, Tempo）
+	Ramp { command: Box<Command>, length: Length, curve: RampCurve },
+	/// ノートオンのたびに開始する LFO。delay と waveform は省略時 0
+	Lfo { target: LfoTarget, rate: NumberOrExpr, depth: NumberOrExpr, delay: Option<NumberOrExpr>, waveform: Option<NumberOrExpr>, pos: MmlPos },
+	MacroCall { name: String, pos: MmlPos },
 	/// times は Some(n) で有限、None で無限、
 	/// content1 は : より前（: がない場合は全部）、content 2 は : より後（: がない場合は None）
 	Loop { times: Option<i32>, content1: Vec<Command>, content2: Option<Vec<Command>> },
 	// LoopBreak,
 	Stack { content: Vec<Command> },
+	/// 選択肢のいずれかを、実行するたびにランダムに選ぶ
+	Choose { alternatives: Vec<Vec<Command>> },
 	MacroDef { name: String, content: Vec<Command> },
+	/// 引数つきのマクロ。本体は展開時に %引数名 を置換してから解析するので、テキストのまま持つ
+	ParamMacroDef { name: String, params: Vec<String>, body: String },
 	Skip,
-	ExpandMacro { name: String },
+	/// @@name で書くマーカー。トラック間で位置を揃えたり、演奏の開始位置に使う
+	Marker { name: String, pos: MmlPos },
+	/// 調号。正なら # の数、負なら ♭ の数
+	KeySignature(i32),
+	/// 拍子。小節線のチェックや小節番号に使う
+	Meter { numerator: i32, denominator: i32 },
+	/// 小節線。小節の長さのチェックに使う
+	BarLine { pos: MmlPos },
+	/// マクロをその場に展開する。args は引数のテキスト
+	ExpandMacro { name: String, args: Vec<String>, pos: MmlPos },
 }
 
 pub type Length = Vec<LengthElement>;
 
+/// MML の中でのコマンドの位置。エラーの報告に使う。
+/// line はトラックに MML を追加した順での行（1 始まり）、column はその行の MML の中での列（1 始まり）
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MmlPos {
+	pub line: u32,
+	pub column: usize,
+}
+impl MmlPos {
+	pub fn of(loc: &Location) -> Self {
+		Self { line: loc.line, column: loc.column }
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LfoTarget {
+	/// デチューン（セント単位）を揺らす
+	Vibrato,
+	/// 音量を揺らす
+	Tremolo,
+	/// 定位を揺らす
+	AutoPan,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RampCurve {
+	Linear,
+	/// 比が一定の割合で変化する。周波数やテンポなどに向く
+	Exponential,
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct LengthElement {
 	/// 音長を示す数値。省略の場合は None。音長 4. に対して 4、.. に対して None となる
@@ -46,10 +100,19 @@ pub struct LengthElement {
 	pub dots: i32,
 }
 
+/// 和音の構成音。
+/// 和音の中に書いたオクターブ変更は和音の中でだけ有効なので、オクターブは和音の外のオクターブからの相対値で持つ
+#[derive(Debug, PartialEq)]
+pub struct ChordTone {
+	pub octave_offset: i32,
+	pub tone_name: ToneName,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct ToneName {
 	pub base_name: ToneBaseName,
-	pub accidental: i32,
+	/// 臨時記号。省略した場合は None で、調号に従う。ナチュラルは Some(0)
+	pub accidental: Option<i32>,
 }
 
 #[derive(Debug, PartialEq)]
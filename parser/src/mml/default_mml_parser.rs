@@ -13,6 +13,7 @@ use nom::{
 	bytes::complete::*,
 	character::complete::*,
 	combinator::*,
+	error::{ErrorKind, ParseError, VerboseError},
 	IResult,
 	multi::*,
 	sequence::*,
@@ -39,6 +40,8 @@ parser![accidentals, i32, {
 	alt((
 		map_res(many1_count(ss!(char('+'))), |sharps| ok(sharps as i32)),
 		map_res(many1_count(ss!(char('-'))), |flats| ok(- (flats as i32))),
+		// ナチュラル
+		map_res(ss!(char('=')), |_| ok(0)),
 	))
 }];
 
@@ -58,30 +61,112 @@ parser![length, Length, {
 	)
 }];
 
-parser![tone_command, Command, {
+parser![tone_name, ToneName, {
 	map_res(
 		tuple((
 			ss!(re_find(re(r"[cdefgab]"))),
 			ss!(opt(accidentals())),
-			ss!(length()),
-			ss!(opt(char('&'))),
 		)),
-		|(base_name, accidentals, length, slur)| ok(Command::Tone {
-			tone_name: ToneName {
-				base_name: match base_name {
-					"c" => ToneBaseName::C,
-					"d" => ToneBaseName::D,
-					"e" => ToneBaseName::E,
-					"f" => ToneBaseName::F,
-					"g" => ToneBaseName::G,
-					"a" => ToneBaseName::A,
-					"b" => ToneBaseName::B,
-					_ => unreachable!(),
-				},
-				accidental: accidentals.unwrap_or(0),
+		|(base_name, accidentals)| ok(ToneName {
+			base_name: match base_name {
+				"c" => ToneBaseName::C,
+				"d" => ToneBaseName::D,
+				"e" => ToneBaseName::E,
+				"f" => ToneBaseName::F,
+				"g" => ToneBaseName::G,
+				"a" => ToneBaseName::A,
+				"b" => ToneBaseName::B,
+				_ => unreachable!(),
+			},
+			accidental: accidentals,
+		})
+	)
+}];
+
+// k+3 や k-3 のように # や ♭ の数で書くか、k e- や k cm のように主音で書く（m をつけると短調）
+parser![key_signature, i32, {
+	verify(alt((
+		map_res(re_find(re(r"[+-]?[0-9]+")), |matched| matched.parse::<i32>()),
+		map_res(
+			tuple((
+				ss!(tone_name()),
+				opt(char('m')),
+			)),
+			|(ToneName { base_name, accidental }, minor)| {
+				// 五度圏上の位置から # や ♭ の数を求める
+				let fifths = match base_name {
+					ToneBaseName::F => -1,
+					ToneBaseName::C => 0,
+					ToneBaseName::G => 1,
+					ToneBaseName::D => 2,
+					ToneBaseName::A => 3,
+					ToneBaseName::E => 4,
+					ToneBaseName::B => 5,
+				} + 7 * accidental.unwrap_or(0) - if minor.is_some() { 3 } else { 0 };
+				ok(fifths)
 			},
+		),
+	)), |fifths| (-7 ..= 7).contains(fifths))
+}];
+
+parser![tone_command, Command, {
+	map_res(
+		loc(tuple((
+			ss!(tone_name()),
+			ss!(length()),
+			ss!(opt(char('&'))),
+		))),
+		|((tone_name, length, slur), loc)| ok(Command::Tone {
+			tone_name,
 			length,
 			slur: slur.is_some(),
+			pos: MmlPos::of(&loc),
+		})
+	)
+}];
+
+// 'ceg'4 のように書く。和音の中では < > でオクターブを変えられる（和音の後には影響しない）
+parser![chord_command, Command, {
+	map_res(
+		loc(tuple((
+			delimited(
+				ss!(char('\'')),
+				many1(tuple((
+					many0(ss!(one_of("<>"))),
+					ss!(tone_name()),
+				))),
+				ss!(char('\'')),
+			),
+			ss!(length()),
+			ss!(opt(char('&'))),
+		))),
+		|((elements, length, slur), loc)| {
+			let mut octave_offset = 0;
+			let tones = elements.into_iter().map(|(octave_changes, tone_name)| {
+				for c in octave_changes {
+					octave_offset += if c == '>' { 1 } else { -1 };
+				}
+				ChordTone { octave_offset, tone_name }
+			}).collect();
+
+			ok(Command::Chord { tones, length, slur: slur.is_some(), pos: MmlPos::of(&loc) })
+		}
+	)
+}];
+
+// !kick4 のように書く。音長と区別するため、ヒットの名前には数字を使えない
+parser![hit_command, Command, {
+	map_res(
+		loc(tuple((
+			preceded(ss!(char('!')), ss!(re_find(re(r"[a-zA-Z_]+")))),
+			ss!(length()),
+			ss!(opt(char('&'))),
+		))),
+		|((name, length, slur), loc)| ok(Command::Hit {
+			name: name.to_string(),
+			length,
+			slur: slur.is_some(),
+			pos: MmlPos::of(&loc),
 		})
 	)
 }];
@@ -104,6 +189,58 @@ parser![parameter_command, Command, {
 	)
 }];
 
+// パラメータの値を設定するコマンド。~ で音長を続けると、値を徐々に変化させる
+parser![value_command, Command, {
+	alt((
+		unary_command!(char('V'), number_or_expr(), Command::Volume),
+		unary_command!(char('v'), number_or_expr(), Command::Velocity),
+		unary_command!(re_find(re(r"@d")), number_or_expr(), Command::Detune),
+		parameter_command(),
+		unary_command!(char('t'), number_or_expr(), Command::Tempo),
+	))
+}];
+// y cutoff,2000 ~1 のように書くと直線的に、~* とすると指数的に変化する
+parser![value_or_ramp_command, Command, {
+	map_res(
+		tuple((
+			value_command(),
+			opt(preceded(
+				ss!(char('~')),
+				tuple((
+					ss!(opt(char('*'))),
+					ss!(length()),
+				)),
+			)),
+		)),
+		|(command, ramp)| ok(match ramp {
+			None => command,
+			Some((exponential, length)) => Command::Ramp {
+				command: Box::new(command),
+				length,
+				curve: if exponential.is_some() { RampCurve::Exponential } else { RampCurve::Linear },
+			},
+		}),
+	)
+}];
+
+// @vib rate,depth,delay,waveform のように書く。delay と waveform は省略できる
+parser![lfo_command, Command, {
+	map_res(
+		loc(tuple((
+			ss!(alt((
+				map_res(tag("@vib"), |_| ok(LfoTarget::Vibrato)),
+				map_res(tag("@trem"), |_| ok(LfoTarget::Tremolo)),
+				map_res(tag("@apan"), |_| ok(LfoTarget::AutoPan)),
+			))),
+			ss!(number_or_expr()),
+			preceded(ss!(char(',')), ss!(number_or_expr())),
+			opt(preceded(ss!(char(',')), ss!(number_or_expr()))),
+			opt(preceded(ss!(char(',')), ss!(number_or_expr()))),
+		))),
+		|((target, rate, depth, delay, waveform), loc)| ok(Command::Lfo { target, rate, depth, delay, waveform, pos: MmlPos::of(&loc) }),
+	)
+}];
+
 parser![loop_command, Command, {
 	// 型の無限再帰を避けるため手続きで書く
 	|input| {
@@ -125,6 +262,18 @@ parser![loop_command, Command, {
 	}
 }];
 
+// ?[ a / b / c ] のように書く
+parser![choose_command, Command, {
+	// 型の無限再帰を避けるため手続きで書く
+	|input| {
+		let (input, _) = ss!(tag("?["))(input) ?;
+		let (input, alternatives) = separated_list1(ss!(char('/')), many0(command()))(input) ?;
+		let (input, _) = ss!(char(']'))(input) ?;
+
+		Ok((input, Command::Choose { alternatives }))
+	}
+}];
+
 parser![stack_command, Command, {
 	// 型の無限再帰を避けるため手続きで書く
 	|input| {
@@ -147,14 +296,122 @@ parser![macro_def_command, Command, {
 	|input| {
 		let (input, _) = ss!(tag("@$"))(input) ?;
 		let (input, name) = ss!(identifier())(input) ?;
+		let (input, params) = opt(delimited(
+			ss!(char('(')),
+			separated_list0(ss!(char(',')), ss!(identifier())),
+			ss!(char(')')),
+		))(input) ?;
 		let (input, _) = ss!(char('['))(input) ?;
-		let (input, content) = many0(command())(input) ?;
-		let (input, _) = ss!(char(']'))(input) ?;
+		let name = name.to_string();
+		match params {
+			None => {
+				let (input, content) = many0(command())(input) ?;
+				let (input, _) = ss!(char(']'))(input) ?;
+
+				Ok((input, Command::MacroDef { name, content }))
+			},
+			Some(params) => {
+				let (input, body) = raw_macro_body()(input) ?;
+				let (input, _) = ss!(char(']'))(input) ?;
+				let params = params.into_iter().map(|p| p.to_string()).collect();
 
-		Ok((input, Command::MacroDef { name: name.to_string(), content }))
+				Ok((input, Command::ParamMacroDef { name, params, body }))
+			},
+		}
 	}
 }];
 
+// 引数つきマクロの本体。対応する ] の手前までをテキストのまま取り出す
+parser![raw_macro_body, String, {
+	|input: Span<'a>| {
+		let mut depth = 0;
+		for (i, c) in input.fragment().chars().enumerate() {
+			match c {
+				'[' => { depth += 1; },
+				']' if depth == 0 => {
+					let (input, body) = take(i)(input) ?;
+					return Ok((input, body.fragment().to_string()));
+				},
+				']' => { depth -= 1; },
+				_ => { },
+			}
+		}
+
+		Err(nom::Err::Error(VerboseError::from_error_kind(input, ErrorKind::Char)))
+	}
+}];
+
+// マクロの引数。, で区切り、( ) の中と式（= から ; まで）の中の , では区切らない
+parser![raw_macro_args, Vec<String>, {
+	|input: Span<'a>| {
+		let (input, _) = char('(')(input) ?;
+		let mut args = vec![];
+		let mut arg = String::new();
+		let mut depth = 0;
+		let mut in_expr = false;
+		for (i, c) in input.fragment().chars().enumerate() {
+			match c {
+				'=' if ! in_expr => { in_expr = true; },
+				';' if in_expr => { in_expr = false; },
+				'(' if ! in_expr => { depth += 1; },
+				')' if ! in_expr && depth == 0 => {
+					if ! arg.trim().is_empty() || ! args.is_empty() {
+						args.push(arg.trim().to_string());
+					}
+					let (input, _) = take(i + 1)(input) ?;
+					let (input, _) = many0(space())(input) ?;
+					return Ok((input, args));
+				},
+				')' if ! in_expr => { depth -= 1; },
+				',' if ! in_expr && depth == 0 => {
+					args.push(arg.trim().to_string());
+					arg.clear();
+					continue;
+				},
+				_ => { },
+			}
+			arg.push(c);
+		}
+
+		Err(nom::Err::Error(VerboseError::from_error_kind(input, ErrorKind::Char)))
+	}
+}];
+
+// $name はマクロの呼び出し、$name(...) は引数を与えてその場に展開する
+parser![macro_call_command, Command, {
+	map_res(
+		loc(tuple((
+			preceded(ss!(char('$')), identifier()),
+			alt((
+				map_res(raw_macro_args(), |args| ok(Some(args))),
+				map_res(many0(space()), |_| ok(None)),
+			)),
+		))),
+		|((name, args), loc)| ok(match args {
+			None => Command::MacroCall { name: name.to_string(), pos: MmlPos::of(&loc) },
+			Some(args) => Command::ExpandMacro { name: name.to_string(), args, pos: MmlPos::of(&loc) },
+		}),
+	)
+}];
+
+parser![bar_line_command, Command, {
+	map_res(
+		loc(ss!(char('|'))),
+		|(_, loc)| ok(Command::BarLine { pos: MmlPos::of(&loc) }),
+	)
+}];
+
+// M3/4 のように書く。分母は 2 のべき乗に限る
+parser![meter_command, Command, {
+	map_res(
+		verify(
+			preceded(ss!(char('M')), separated_pair(ss!(integer()), ss!(char('/')), ss!(integer()))),
+			|(numerator, denominator)| *numerator > 0 && *denominator > 0 && (*denominator as u32).is_power_of_two(),
+		),
+		|(numerator, denominator)| ok(Command::Meter { numerator, denominator }),
+	)
+}];
+
 parser![number_or_expr, NumberOrExpr, {
 	alt((
 		map_res(float(), |num| ok(NumberOrExpr::Number(num))),
@@ -170,18 +427,25 @@ parser![command, Command, {
 		nullary_command!(char('<'), Command::OctaveDecr),
 		unary_command!(alt((char('l'), char('L'))), integer(), Command::Length),
 		unary_command!(char('q'), number_or_expr(), Command::GateRate),
-		unary_command!(char('V'), number_or_expr(), Command::Volume),
-		unary_command!(char('v'), number_or_expr(), Command::Velocity),
-		unary_command!(re_find(re(r"@d")), number_or_expr(), Command::Detune),
+		unary_command!(char('k'), key_signature(), Command::KeySignature),
+		value_or_ramp_command(),
+		lfo_command(),
 		tone_command(),
+		chord_command(),
+		hit_command(),
 		unary_command!(char('r'), length(), Command::Rest),
-		parameter_command(),
-		unary_command!(char('t'), number_or_expr(), Command::Tempo),
-		unary_command!(char('$'), identifier(), |name: &str| Command::MacroCall { name: name.to_string() }),
+		macro_call_command(),
 		loop_command(),
 		stack_command(),
+		choose_command(),
 		macro_def_command(),
 		skip_command(),
+		map_res(
+			loc(preceded(ss!(tag("@@")), ss!(identifier()))),
+			|(name, loc)| ok(Command::Marker { name: name.to_string(), pos: MmlPos::of(&loc) }),
+		),
+		bar_line_command(),
+		meter_command(),
 	))
 }];
 
@@ -203,12 +467,12 @@ pub_parser![compilation_unit, CompilationUnit, {
 #[test]
 fn test_compilation_unit() {
 	assert_eq!(
-			compilation_unit()("o4l8v15").unwrap().1,
+			compilation_unit()(test_span("o4l8v15")).unwrap().1,
 			CompilationUnit {
 				commands: vec![
-					Command::Octave(4),
+					Command::Octave(NumberOrExpr::Number(4f32)),
 					Command::Length(8),
-					Command::Velocity(15.0),
+					Command::Velocity(NumberOrExpr::Number(15f32)),
 				]});
 }
 
@@ -216,45 +480,45 @@ fn test_compilation_unit() {
 fn test_compilation_unit_spaces() {
 	{
 		let expected = CompilationUnit {
-			commands: vec![Command::Octave(4)],
+			commands: vec![Command::Octave(NumberOrExpr::Number(4f32))],
 		};
-		assert_eq!(compilation_unit()("o4").unwrap().1, expected);
-		assert_eq!(compilation_unit()("  o4").unwrap().1, expected);
-		assert_eq!(compilation_unit()("o  4").unwrap().1, expected);
-		assert_eq!(compilation_unit()("o4  ").unwrap().1, expected);
+		assert_eq!(compilation_unit()(test_span("o4")).unwrap().1, expected);
+		assert_eq!(compilation_unit()(test_span("  o4")).unwrap().1, expected);
+		assert_eq!(compilation_unit()(test_span("o  4")).unwrap().1, expected);
+		assert_eq!(compilation_unit()(test_span("o4  ")).unwrap().1, expected);
 	}
 	{
 		let expected = CompilationUnit {
-			commands: vec![Command::GateRate(7.5)],
+			commands: vec![Command::GateRate(NumberOrExpr::Number(7.5f32))],
 		};
-		assert_eq!(compilation_unit()("q7.5").unwrap().1, expected);
-		assert_eq!(compilation_unit()("  q7.5").unwrap().1, expected);
-		assert_eq!(compilation_unit()("q  7.5").unwrap().1, expected);
-		assert_eq!(compilation_unit()("q7.5  ").unwrap().1, expected);
+		assert_eq!(compilation_unit()(test_span("q7.5")).unwrap().1, expected);
+		assert_eq!(compilation_unit()(test_span("  q7.5")).unwrap().1, expected);
+		assert_eq!(compilation_unit()(test_span("q  7.5")).unwrap().1, expected);
+		assert_eq!(compilation_unit()(test_span("q7.5  ")).unwrap().1, expected);
 	}
 	{
 		let expected = CompilationUnit {
-			commands: vec![Command::Octave(4), Command::GateRate(7.5)],
+			commands: vec![Command::Octave(NumberOrExpr::Number(4f32)), Command::GateRate(NumberOrExpr::Number(7.5f32))],
 		};
-		assert_eq!(compilation_unit()("o4 q7.5").unwrap().1, expected);
+		assert_eq!(compilation_unit()(test_span("o4 q7.5")).unwrap().1, expected);
 	}
 	{
 		let expected = CompilationUnit {
-			commands: vec![Command::GateRate(7.5), Command::Octave(4)],
+			commands: vec![Command::GateRate(NumberOrExpr::Number(7.5f32)), Command::Octave(NumberOrExpr::Number(4f32))],
 		};
-		assert_eq!(compilation_unit()("q7.5 o4").unwrap().1, expected);
+		assert_eq!(compilation_unit()(test_span("q7.5 o4")).unwrap().1, expected);
 	}
 	{
 		let expected = CompilationUnit {
 			commands: vec![Command::OctaveIncr, Command::OctaveDecr],
 		};
-		assert_eq!(compilation_unit()("> <").unwrap().1, expected);
+		assert_eq!(compilation_unit()(test_span("> <")).unwrap().1, expected);
 	}
 	{
 		let expected = CompilationUnit {
 			commands: vec![Command::OctaveDecr, Command::OctaveIncr],
 		};
-		assert_eq!(compilation_unit()("< >").unwrap().1, expected);
+		assert_eq!(compilation_unit()(test_span("< >")).unwrap().1, expected);
 	}
 }
 
@@ -269,46 +533,39 @@ fn test_compilation_unit_tones() {
 				},
 				length,
 				slur,
+				pos: MmlPos { line: 1, column: 1 },
 			},
 		]
 	};
-	let length_default = || Length { elements: vec![LengthElement { number: None, dots: 0 }] };
-
-	assert_eq!(compilation_unit()("c ").unwrap().1, expected(ToneBaseName::C, 0, length_default(), false));
-	assert_eq!(compilation_unit()("d+ ").unwrap().1, expected(ToneBaseName::D, 1, length_default(), false));
-	assert_eq!(compilation_unit()("e ++ + ").unwrap().1, expected(ToneBaseName::E, 3, length_default(), false));
-	assert_eq!(compilation_unit()("f -").unwrap().1, expected(ToneBaseName::F, -1, length_default(), false));
-	assert_eq!(compilation_unit()("g -- - ").unwrap().1, expected(ToneBaseName::G, -3, length_default(), false));
-
-	assert_eq!(compilation_unit()("a8 ").unwrap().1, expected(ToneBaseName::A, 0,
-			Length {
-				elements: vec![
-					LengthElement { number: Some(8), dots: 0 },
-				]
-			}, false));
-	assert_eq!(compilation_unit()("b-^4. ^ 2...^-32& ").unwrap().1, expected(ToneBaseName::B, -1,
-				Length {
-					elements: vec![
-						LengthElement { number: None, dots: 0 },
-						LengthElement { number: Some(4), dots: 1 },
-						LengthElement { number: Some(2), dots: 3 },
-						LengthElement { number: Some(-32), dots: 0 },
-					]
-				}, true));
+	let length_default = || vec![LengthElement { number: None, dots: 0 }];
+
+	assert_eq!(compilation_unit()(test_span("c ")).unwrap().1, expected(ToneBaseName::C, None, length_default(), false));
+	assert_eq!(compilation_unit()(test_span("d+ ")).unwrap().1, expected(ToneBaseName::D, Some(1), length_default(), false));
+	assert_eq!(compilation_unit()(test_span("e ++ + ")).unwrap().1, expected(ToneBaseName::E, Some(3), length_default(), false));
+	assert_eq!(compilation_unit()(test_span("f -")).unwrap().1, expected(ToneBaseName::F, Some(-1), length_default(), false));
+	assert_eq!(compilation_unit()(test_span("g -- - ")).unwrap().1, expected(ToneBaseName::G, Some(-3), length_default(), false));
+
+	assert_eq!(compilation_unit()(test_span("a8 ")).unwrap().1, expected(ToneBaseName::A, None,
+			vec![LengthElement { number: Some(8), dots: 0 }], false));
+	assert_eq!(compilation_unit()(test_span("b-^4. ^ 2...^-32& ")).unwrap().1, expected(ToneBaseName::B, Some(-1),
+			vec![
+				LengthElement { number: None, dots: 0 },
+				LengthElement { number: Some(4), dots: 1 },
+				LengthElement { number: Some(2), dots: 3 },
+				LengthElement { number: Some(-32), dots: 0 },
+			], true));
 }
 
 #[test]
 fn test_compilation_unit_rest() {
 	let expected = |command| CompilationUnit { commands: vec![command] };
-	let length_default = || Length { elements: vec![LengthElement { number: None, dots: 0 }] };
-
-	assert_eq!(compilation_unit()("r").unwrap().1, expected(Command::Rest(length_default())));
-	assert_eq!(compilation_unit()("r 4^-96 . ").unwrap().1, expected(Command::Rest(
-		Length {
-			elements: vec![
-				LengthElement { number: Some(4), dots: 0 },
-				LengthElement { number: Some(-96), dots: 1 },
-			],
-		}
+	let length_default = || vec![LengthElement { number: None, dots: 0 }];
+
+	assert_eq!(compilation_unit()(test_span("r")).unwrap().1, expected(Command::Rest(length_default())));
+	assert_eq!(compilation_unit()(test_span("r 4^-96 . ")).unwrap().1, expected(Command::Rest(
+		vec![
+			LengthElement { number: Some(4), dots: 0 },
+			LengthElement { number: Some(-96), dots: 1 },
+		]
 	)));
 }
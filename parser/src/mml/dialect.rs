@@ -0,0 +1,41 @@
+use super::{
+	ast::CompilationUnit,
+	default_mml_parser,
+	mck_mml_parser,
+};
+use crate::common::Span;
+
+use nom::error::VerboseError;
+
+/// MML の方言。どの方言も共通の AST に変換する
+pub trait MmlDialect {
+	fn name(&self) -> &'static str;
+	fn parse<'a>(&self, mml: Span<'a>) -> Result<CompilationUnit, nom::Err<VerboseError<Span<'a>>>>;
+}
+
+pub const DEFAULT_DIALECT_NAME: &str = "default";
+
+/// 名前から方言を得る。:default はこのプロジェクト独自の MML、:mck は MCK/PMD の MML のサブセット
+pub fn dialect_by_name(name: &str) -> Option<&'static dyn MmlDialect> {
+	match name {
+		DEFAULT_DIALECT_NAME => Some(&DefaultDialect),
+		"mck" => Some(&MckDialect),
+		_ => None,
+	}
+}
+
+pub struct DefaultDialect;
+impl MmlDialect for DefaultDialect {
+	fn name(&self) -> &'static str { DEFAULT_DIALECT_NAME }
+	fn parse<'a>(&self, mml: Span<'a>) -> Result<CompilationUnit, nom::Err<VerboseError<Span<'a>>>> {
+		default_mml_parser::compilation_unit()(mml).map(|(_, unit)| unit)
+	}
+}
+
+pub struct MckDialect;
+impl MmlDialect for MckDialect {
+	fn name(&self) -> &'static str { "mck" }
+	fn parse<'a>(&self, mml: Span<'a>) -> Result<CompilationUnit, nom::Err<VerboseError<Span<'a>>>> {
+		mck_mml_parser::compilation_unit()(mml).map(|(_, unit)| unit)
+	}
+}
@@ -0,0 +1,174 @@
+use super::{
+	ast::*,
+};
+
+use crate::{
+	common::{Span, integer, loc, ok},
+};
+
+extern crate nom;
+use nom::{
+	branch::alt,
+	bytes::complete::*,
+	character::complete::*,
+	combinator::*,
+	IResult,
+	multi::*,
+	sequence::*,
+};
+
+// MCK や PMD で書かれた曲を取り込むための MML。対応するのは次のコマンドのみ：
+// 音符（臨時記号は + # - =）、r、o、> <、l、q（0～8）、Q（PMD、0～8）、v（0～15）、V（PMD、0～127）、t、
+// [ ... | ... ]n のループ、L（曲のループ位置）、& のタイ、; から行末までのコメント
+
+// ; のコメントの他、ModDL と同様に // や /* */ のコメントも読み飛ばす
+parser![space, char, {
+	alt((
+		one_of(" \t\r\n"),
+		map_res(preceded(char(';'), many0(none_of("\r\n"))), |_| ok(' ')),
+		map_res(preceded(tag("//"), many0(none_of("\r\n"))), |_| ok(' ')),
+		map_res(tuple((tag("/*"), take_until("*/"), tag("*/"))), |_| ok(' ')),
+	))
+}];
+
+macro_rules! unary_command {
+	($name_parser: expr, $arg_parser: expr, $ctor: expr) => {
+		map_res(
+			preceded(ss!($name_parser), ss!($arg_parser)),
+			|value| ok($ctor(value)),
+		)
+	}
+}
+
+parser![number, NumberOrExpr, {
+	map_res(integer(), |value| ok(NumberOrExpr::Number(value as f32)))
+}];
+
+parser![accidentals, i32, {
+	alt((
+		map_res(many1_count(ss!(one_of("+#"))), |sharps| ok(sharps as i32)),
+		map_res(many1_count(ss!(char('-'))), |flats| ok(- (flats as i32))),
+		map_res(ss!(char('=')), |_| ok(0)),
+	))
+}];
+
+parser![length_element, LengthElement, {
+	map_res(
+		tuple((
+			opt(ss!(integer())),
+			many0_count(ss!(char('.'))),
+		)),
+		|(number, dots)| ok(LengthElement { number, dots: dots as i32 })
+	)
+}];
+parser![length, Length, {
+	separated_list0(ss!(char('^')), ss!(length_element()))
+}];
+
+parser![tone_command, Command, {
+	map_res(
+		loc(tuple((
+			ss!(one_of("cdefgab")),
+			opt(accidentals()),
+			ss!(length()),
+			ss!(opt(char('&'))),
+		))),
+		|((base_name, accidental, length, slur), loc)| ok(Command::Tone {
+			tone_name: ToneName {
+				base_name: match base_name {
+					'c' => ToneBaseName::C,
+					'd' => ToneBaseName::D,
+					'e' => ToneBaseName::E,
+					'f' => ToneBaseName::F,
+					'g' => ToneBaseName::G,
+					'a' => ToneBaseName::A,
+					'b' => ToneBaseName::B,
+					_ => unreachable!(),
+				},
+				accidental,
+			},
+			length,
+			slur: slur.is_some(),
+			pos: MmlPos::of(&loc),
+		})
+	)
+}];
+
+parser![loop_command, Command, {
+	// 型の無限再帰を避けるため手続きで書く
+	|input| {
+		let (input, _) = ss!(char('['))(input) ?;
+		let (input, content1) = many0(command())(input) ?;
+		// | 以降は最後の回には実行しない
+		let (input, content2) = opt(preceded(ss!(char('|')), many0(command())))(input) ?;
+		let (input, _) = ss!(char(']'))(input) ?;
+		let (input, times_in_mml) = opt(ss!(integer()))(input) ?;
+
+		// 回数省略は 2、0 は無限ループ
+		let times = match times_in_mml {
+			None => Some(2),
+			Some(t) => if t == 0 { None } else { Some(t) },
+		};
+
+		Ok((input, Command::Loop { times, content1, content2 }))
+	}
+}];
+
+parser![command, Command, {
+	alt((
+		tone_command(),
+		unary_command!(char('r'), length(), Command::Rest),
+		unary_command!(char('o'), number(), Command::Octave),
+		map_res(ss!(char('>')), |_| ok(Command::OctaveIncr)),
+		map_res(ss!(char('<')), |_| ok(Command::OctaveDecr)),
+		unary_command!(char('l'), integer(), Command::Length),
+		unary_command!(one_of("qQ"), number(), Command::GateRate),
+		unary_command!(char('v'), number(), Command::Velocity),
+		unary_command!(char('V'), integer(), |value| Command::Velocity(NumberOrExpr::Number(value as f32 * 15f32 / 127f32))),
+		unary_command!(char('t'), number(), Command::Tempo),
+		loop_command(),
+	))
+}];
+
+pub_parser![compilation_unit, CompilationUnit, {
+	map_res(
+			all_consuming(
+					preceded(
+						many0(space()),
+						tuple((
+							many0(command()),
+							// L 以降を無限に繰り返す
+							opt(preceded(ss!(char('L')), many0(command()))),
+						)),
+					)),
+			|(mut commands, looped)| {
+				if let Some(content1) = looped {
+					commands.push(Command::Loop { times: None, content1, content2: None });
+				}
+				ok(CompilationUnit { commands })
+			})
+}];
+
+#[cfg(test)]
+#[test]
+fn test_compilation_unit() {
+	use std::{path::PathBuf, rc::Rc};
+
+	let (_, CompilationUnit { commands }) = compilation_unit()(Span::new_extra("t120 o4 l8 c#4. d-&d ; comment\n[e | f]3 L g", Rc::new(PathBuf::new()))).unwrap();
+	let tone = |base_name, accidental, length: Length, slur, (line, column)| Command::Tone { tone_name: ToneName { base_name, accidental }, length, slur, pos: MmlPos { line, column } };
+	let default_length = || vec![LengthElement { number: None, dots: 0 }];
+	assert_eq!(commands, vec![
+		Command::Tempo(NumberOrExpr::Number(120f32)),
+		Command::Octave(NumberOrExpr::Number(4f32)),
+		Command::Length(8),
+		tone(ToneBaseName::C, Some(1), vec![LengthElement { number: Some(4), dots: 1 }], false, (1, 12)),
+		tone(ToneBaseName::D, Some(-1), default_length(), true, (1, 17)),
+		tone(ToneBaseName::D, None, default_length(), false, (1, 20)),
+		Command::Loop {
+			times: Some(3),
+			content1: vec![tone(ToneBaseName::E, None, default_length(), false, (2, 2))],
+			content2: Some(vec![tone(ToneBaseName::F, None, default_length(), false, (2, 6))]),
+		},
+		Command::Loop { times: None, content1: vec![tone(ToneBaseName::G, None, default_length(), false, (2, 12))], content2: None },
+	]);
+}
@@ -0,0 +1,143 @@
+use super::ast::*;
+
+/// AST を既定の MML の書式で文字列に戻す。コマンドは空白で区切る。
+/// 小節線の位置や空白、コメントは元に戻らない
+pub fn print_mml(CompilationUnit { commands }: &CompilationUnit) -> String {
+	print_commands(commands)
+}
+
+pub fn print_commands(commands: &[Command]) -> String {
+	commands.iter().map(print_command).collect::<Vec<_>>().join(" ")
+}
+
+pub fn print_command(command: &Command) -> String {
+	match command {
+		Command::Octave(value) => format!("o{}", print_number_or_expr(value)),
+		Command::OctaveIncr => ">".to_string(),
+		Command::OctaveDecr => "<".to_string(),
+		Command::Length(length) => format!("l{}", length),
+		Command::GateRate(value) => format!("q{}", print_number_or_expr(value)),
+		Command::Volume(value) => format!("V{}", print_number_or_expr(value)),
+		Command::Velocity(value) => format!("v{}", print_number_or_expr(value)),
+		Command::Detune(value) => format!("@d{}", print_number_or_expr(value)),
+		// 臨時記号のない音名の直後に負の音長（ティック数）を書くと臨時記号と区別できないが、そのような AST は MML からは作られない
+		Command::Tone { tone_name, length, slur, .. } => format!("{}{}{}", print_tone_name(tone_name), print_length(length), print_slur(*slur)),
+		Command::Chord { tones, length, slur, .. } => {
+			let mut result = "'".to_string();
+			let mut octave_offset = 0;
+			for ChordTone { octave_offset: offset, tone_name } in tones {
+				let change = if *offset > octave_offset { ">" } else { "<" };
+				result.push_str(change.repeat((offset - octave_offset).unsigned_abs() as usize).as_str());
+				octave_offset = *offset;
+				result.push_str(print_tone_name(tone_name).as_str());
+			}
+			format!("{}'{}{}", result, print_length(length), print_slur(*slur))
+		},
+		Command::Rest(length) => format!("r{}", print_length(length)),
+		Command::Hit { name, length, slur, .. } => format!("!{}{}{}", name, print_length(length), print_slur(*slur)),
+		Command::Parameter { name, key, value } => {
+			let key = key.as_ref().map(|key| format!(":{}", key)).unwrap_or_default();
+			format!("y{}{},{}", name, key, print_number_or_expr(value))
+		},
+		Command::Tempo(value) => format!("t{}", print_number_or_expr(value)),
+		Command::Ramp { command, length, curve } => {
+			let curve = match curve {
+				RampCurve::Linear => "",
+				RampCurve::Exponential => "*",
+			};
+			format!("{}~{}{}", print_command(command), curve, print_length(length))
+		},
+		Command::Lfo { target, rate, depth, delay, waveform, .. } => {
+			let name = match target {
+				LfoTarget::Vibrato => "@vib",
+				LfoTarget::Tremolo => "@trem",
+				LfoTarget::AutoPan => "@apan",
+			};
+			let mut result = format!("{}{},{}", name, print_number_or_expr(rate), print_number_or_expr(depth));
+			// waveform だけを書くことはできないので、その場合は delay に省略時の値を書く
+			match (delay, waveform) {
+				(None, None) => { },
+				(Some(delay), None) => { result.push_str(format!(",{}", print_number_or_expr(delay)).as_str()); },
+				(delay, Some(waveform)) => {
+					let delay = delay.as_ref().map(print_number_or_expr).unwrap_or_else(|| "0".to_string());
+					result.push_str(format!(",{},{}", delay, print_number_or_expr(waveform)).as_str());
+				},
+			}
+			result
+		},
+		Command::MacroCall { name, .. } => format!("${}", name),
+		Command::Loop { times, content1, content2 } => {
+			// 回数の省略は 2、0 は無限ループ
+			let times = match times {
+				Some(2) => "".to_string(),
+				Some(times) => format!("{} ", times),
+				None => "0 ".to_string(),
+			};
+			let content2 = content2.as_ref().map(|content| format!(" : {}", print_commands(content))).unwrap_or_default();
+			format!("[{}{}{}]", times, print_commands(content1), content2)
+		},
+		Command::Stack { content } => format!("{{{}}}", print_commands(content)),
+		Command::Choose { alternatives } => {
+			format!("?[{}]", alternatives.iter().map(|content| print_commands(content)).collect::<Vec<_>>().join(" / "))
+		},
+		Command::MacroDef { name, content } => format!("@${}[{}]", name, print_commands(content)),
+		Command::ParamMacroDef { name, params, body } => format!("@${}({})[{}]", name, params.join(","), body),
+		Command::Skip => "***".to_string(),
+		Command::Marker { name, .. } => format!("@@{}", name),
+		Command::KeySignature(fifths) => format!("k{}{}", if *fifths > 0 { "+" } else { "" }, fifths),
+		Command::Meter { numerator, denominator } => format!("M{}/{}", numerator, denominator),
+		Command::BarLine { .. } => "|".to_string(),
+		Command::ExpandMacro { name, args, .. } => format!("${}({})", name, args.join(", ")),
+	}
+}
+
+fn print_number_or_expr(value: &NumberOrExpr) -> String {
+	match value {
+		NumberOrExpr::Number(number) => number.to_string(),
+		NumberOrExpr::Expr(expr) => format!("={};", expr),
+	}
+}
+
+fn print_tone_name(ToneName { base_name, accidental }: &ToneName) -> String {
+	let base_name = match base_name {
+		ToneBaseName::C => "c",
+		ToneBaseName::D => "d",
+		ToneBaseName::E => "e",
+		ToneBaseName::F => "f",
+		ToneBaseName::G => "g",
+		ToneBaseName::A => "a",
+		ToneBaseName::B => "b",
+	};
+	let accidental = match accidental {
+		None => "".to_string(),
+		Some(0) => "=".to_string(),
+		Some(sharps) if *sharps > 0 => "+".repeat(*sharps as usize),
+		Some(flats) => "-".repeat(flats.unsigned_abs() as usize),
+	};
+	format!("{}{}", base_name, accidental)
+}
+
+fn print_length(length: &Length) -> String {
+	length.iter().map(|LengthElement { number, dots }| {
+		format!("{}{}", number.map(|n| n.to_string()).unwrap_or_default(), ".".repeat(*dots as usize))
+	}).collect::<Vec<_>>().join("^")
+}
+
+fn print_slur(slur: bool) -> &'static str {
+	if slur { "&" } else { "" }
+}
+
+#[cfg(test)]
+#[test]
+fn test_print_mml() {
+	use super::default_mml_parser::compilation_unit;
+	use crate::common::Span;
+	use std::{path::PathBuf, rc::Rc};
+
+	let parse = |mml: &str| compilation_unit()(Span::new_extra(mml, Rc::new(PathBuf::new()))).unwrap().1;
+	let mml = "o4l8 v12.5 k-2 M3/4 @@a c+4.^16& 'c>eg'2 r !sd16& | [3 c d : e] ?[f / g] y cutoff:value,=x * 2; ~*4 @vib 5,10,0,=w; $foo(1, 2) |";
+	let printed = print_mml(&parse(mml));
+	assert_eq!(printed, "o4 l8 v12.5 k-2 M3/4 @@a c+4.^16& 'c>eg'2 r !sd16& | [3 c d : e] ?[f / g] ycutoff:value,=x * 2;~*4 @vib5,10,0,=w; $foo(1, 2) |");
+	// 出力した MML を読み直しても同じになる
+	assert_eq!(print_mml(&parse(printed.as_str())), printed);
+}
@@ -1,2 +1,3 @@
 pub mod ast;
+pub mod formatter;
 pub mod parser;
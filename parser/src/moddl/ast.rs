@@ -8,7 +8,8 @@ pub struct CompilationUnit {
 #[derive(Debug)]
 pub enum Statement {
 	Directive { name: String, args: Vec<Expr> },
-	Mml { tracks: Vec<String>, mml: String },
+	/// mml_loc は MML の先頭の位置
+	Mml { tracks: Vec<String>, mml: String, mml_loc: Location },
 }
 
 pub type Assoc = Vec<(String, Box<Expr>)>;
@@ -42,6 +43,23 @@ pub enum LabelFilterSpec {
 	Rename(QualifiedLabel, QualifiedLabel),
 }
 
+/// 数値リテラルにつける単位
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Unit {
+	/// ms。秒に換算する
+	Millisecond,
+	/// s
+	Second,
+	/// Hz
+	Hertz,
+	/// dB。振幅の比に換算する
+	Decibel,
+	/// ct。周波数の比に換算する
+	Cent,
+	/// st（半音）。周波数の比に換算する
+	Semitone,
+}
+
 pub type Expr = Located<ExprBody>;
 
 #[derive(Clone, Debug)]
@@ -66,13 +84,17 @@ pub enum ExprBody {
 	Plus { arg: Box<Expr> },
 	Identifier(String),
 	Condition { cond: Box<Expr>, then: Box<Expr>, els: Box<Expr> },
+	Let { name: String, def: Box<Expr>, body: Box<Expr> },
 	LambdaFunction { params: Vec<FunctionParam>, body: Box<Expr> },
-	LambdaNode { input_param: String, body: Box<Expr> },
+	LambdaNode { input_param: String, params: Vec<FunctionParam>, body: Box<Expr> },
 	FunctionCall { function: Box<Expr>, args: Args },
 	PropertyAccess { assoc: Box<Expr>, name: String },
 	NodeWithArgs { node_def: Box<Expr>, args: Args },
 
 	FloatLiteral(f32),
+	UnitLiteral { value: f32, unit: Unit },
+	/// a4 や c#5 のような音名。ノート番号（c4 = 60）で持つ
+	NoteLiteral(i32),
 	TrackSetLiteral(Vec<String>),
 	IdentifierLiteral(String),
 	StringLiteral(String),
@@ -0,0 +1,140 @@
+use super::{
+	ast::*,
+	parser::compilation_unit,
+};
+use crate::{
+	common::{Location, Span},
+	mml::{
+		ast::Command,
+		default_mml_parser,
+		dialect::DEFAULT_DIALECT_NAME,
+		printer::print_commands,
+	},
+};
+
+use std::{
+	collections::hash_map::HashMap,
+	path::PathBuf,
+	rc::Rc,
+};
+
+/// ModDL ファイルを整形する。
+/// MML 文は AST から書き直し、連続する MML 文のブロックごとにトラック名の幅と（小節数が揃っていれば）小節線の位置を揃える。
+/// MML 文以外の行と、範囲コメントを含むか解析できない MML、@dialect で既定以外の方言を指定したトラックの MML はそのまま残す
+pub fn format_moddl<'a>(source: &'a str, path: Rc<PathBuf>) -> Result<String, nom::Err<nom::error::VerboseError<Span<'a>>>> {
+	let (_, CompilationUnit { statements }) = compilation_unit()(Span::new_extra(source, path.clone())) ?;
+	// MML 文の行（0 始まり）と、その行の中での開始位置（文字数）
+	let mml_lines: HashMap<_, _> = statements.iter().filter_map(|(stmt, loc)| match stmt {
+		Statement::Mml { .. } => Some((loc.line as usize - 1, loc.column - 1)),
+		_ => None,
+	}).collect();
+	let (all_tracks_in_dialect, tracks_in_dialect) = tracks_in_other_dialects(&statements);
+
+	let mut result = vec![];
+	let mut block = vec![];
+	for (i, line) in source.lines().enumerate() {
+		let reprints = |tracks: &str| ! tracks.chars().any(|track| tracks_in_dialect.get(&track.to_string()).copied().unwrap_or(all_tracks_in_dialect));
+		match mml_lines.get(&i).and_then(|column| split_mml_line(line, *column, &path, reprints)) {
+			Some(mml_line) => { block.push(mml_line); },
+			None => {
+				format_block(&mut block, &mut result);
+				result.push(line.to_string());
+			},
+		}
+	}
+	format_block(&mut block, &mut result);
+
+	let mut result = result.join("\n");
+	if source.ends_with('\n') { result.push('\n'); }
+	Ok(result)
+}
+
+struct MmlLine {
+	tracks: String,
+	/// 小節線で区切った MML。解析できなかったものは 1 つにまとめる
+	bars: Vec<String>,
+	/// 行コメント（// を含む）
+	comment: Option<String>,
+}
+
+/// 全トラックと、トラックごとに指定された方言が既定以外かどうか
+fn tracks_in_other_dialects(statements: &[(Statement, Location)]) -> (bool, HashMap<String, bool>) {
+	let mut all = false;
+	let mut tracks = HashMap::new();
+	for (stmt, _) in statements {
+		let Statement::Directive { name, args } = stmt else { continue; };
+		if name != "dialect" { continue; }
+		match args.iter().map(|arg| &arg.body).collect::<Vec<_>>().as_slice() {
+			[ExprBody::IdentifierLiteral(dialect)] => { all = dialect != DEFAULT_DIALECT_NAME; },
+			[ExprBody::TrackSetLiteral(track_set), ExprBody::IdentifierLiteral(dialect)] => {
+				for track in track_set {
+					tracks.insert(track.clone(), dialect != DEFAULT_DIALECT_NAME);
+				}
+			},
+			_ => { },
+		}
+	}
+
+	(all, tracks)
+}
+
+fn split_mml_line(line: &str, column: usize, path: &Rc<PathBuf>, reprints: impl Fn (&str) -> bool) -> Option<MmlLine> {
+	// 行頭と文の間に空白以外（範囲コメントなど）がある行はそのまま残す
+	if line.chars().take(column).any(|c| ! c.is_whitespace()) { return None; }
+	let rest: String = line.chars().skip(column).collect();
+	let tracks: String = rest.chars().take_while(|c| c.is_ascii_alphanumeric() || *c == '_').collect();
+	let body = &rest[tracks.len() ..];
+	if body.contains("/*") || ! reprints(&tracks) {
+		return Some(MmlLine { tracks, bars: vec![body.trim().to_string()], comment: None });
+	}
+
+	let (code, comment) = match body.find("//") {
+		Some(pos) => (&body[.. pos], Some(body[pos ..].trim_end().to_string())),
+		None => (body, None),
+	};
+	let bars = match default_mml_parser::compilation_unit()(Span::new_extra(code, path.clone())) {
+		Ok((_, unit)) => unit.commands.split(|command| matches!(command, Command::BarLine { .. }))
+				.map(print_commands)
+				.collect(),
+		Err(_) => vec![code.trim().to_string()],
+	};
+
+	Some(MmlLine { tracks, bars, comment })
+}
+
+fn format_block(block: &mut Vec<MmlLine>, result: &mut Vec<String>) {
+	if block.is_empty() { return; }
+
+	let tracks_width = block.iter().map(|line| line.tracks.len()).max().unwrap_or(0);
+	// 小節線を揃えるのは、2 行以上あってどの行も小節数が同じ場合だけ
+	let bar_count = block[0].bars.len();
+	let align_bars = block.len() >= 2 && bar_count >= 2 && block.iter().all(|line| line.bars.len() == bar_count);
+	let bar_widths: Vec<_> = (0 .. bar_count).map(|i| {
+		if align_bars { block.iter().map(|line| line.bars[i].chars().count()).max().unwrap_or(0) } else { 0 }
+	}).collect();
+
+	for MmlLine { tracks, bars, comment } in block.drain(..) {
+		let mut mml = String::new();
+		for (i, bar) in bars.iter().enumerate() {
+			if i == bars.len() - 1 {
+				mml.push_str(bar);
+				break;
+			}
+			let width = bar_widths.get(i).copied().unwrap_or(0);
+			mml.push_str(format!("{:<width$}", bar, width = width).as_str());
+			if width > 0 || ! bar.is_empty() { mml.push(' '); }
+			mml.push_str("| ");
+		}
+		let comment = comment.map(|comment| format!(" {}", comment)).unwrap_or_default();
+		let line = format!("{:<width$} {}{}", tracks, mml.trim_end(), comment, width = tracks_width);
+		result.push(line.trim_end().to_string());
+	}
+}
+
+#[cfg(test)]
+#[test]
+fn test_format_moddl() {
+	let source = "@tempo 120\n// melody\na o4l8cde|fga |\nbc o3  l4c|'ceg'8 r| // chord\n\nabc /* x */ c\n";
+	let formatted = format_moddl(source, Rc::new(PathBuf::new())).unwrap();
+	assert_eq!(formatted, "@tempo 120\n// melody\na  o4 l8 c d e | f g a    |\nbc o3 l4 c     | 'ceg'8 r | // chord\n\nabc /* x */ c\n");
+}
@@ -38,6 +38,48 @@ parser![float_literal, Box<Expr>, {
 	map_res(loc(float()), |(v, loc)| ok(Box::new(Expr::new(ExprBody::FloatLiteral(v), loc))))
 }];
 
+parser![unit_literal, Box<Expr>, {
+	// 250ms のように、数値と単位の間に空白は入れない
+	map_res(loc(tuple((float(), re_find(re(r"(ms|s|Hz|dB|ct|st)\b"))))), |((value, unit), loc)| {
+		let unit = match unit {
+			"ms" => Unit::Millisecond,
+			"s" => Unit::Second,
+			"Hz" => Unit::Hertz,
+			"dB" => Unit::Decibel,
+			"ct" => Unit::Cent,
+			_ => Unit::Semitone,
+		};
+		ok(Box::new(Expr::new(ExprBody::UnitLiteral { value, unit }, loc)))
+	})
+}];
+
+/// 音名（a4, c#5, bb3 など）のノート番号。音名でなければ None。
+/// # でシャープ、b でフラット。+ や - は演算子と紛らわしいので使わない
+pub fn note_number(name: &str) -> Option<i32> {
+	let mut chars = name.chars();
+	let pitch_class = match chars.next() ? {
+		'c' => 0, 'd' => 2, 'e' => 4, 'f' => 5, 'g' => 7, 'a' => 9, 'b' => 11,
+		_ => { return None; },
+	};
+	let rest = chars.as_str();
+	let (accidental, octave) = match rest.chars().next() ? {
+		'#' => (1, &rest[1 ..]),
+		'b' => (-1, &rest[1 ..]),
+		_ => (0, rest),
+	};
+	if octave.len() != 1 { return None; }
+	let octave = octave.parse::<i32>().ok() ?;
+
+	Some(12 * (octave + 1) + pitch_class + accidental)
+}
+
+// # のない音名は識別子と区別できないので、識別子として解析し、同名の変数がなければ評価時に音名として扱う
+parser![note_literal, Box<Expr>, {
+	map_res(loc(re_find(re(r"[a-g]#[0-9]\b"))), |(name, loc)| {
+		ok(Box::new(Expr::new(ExprBody::NoteLiteral(note_number(name).unwrap()), loc)))
+	})
+}];
+
 parser![track_set, Vec<String>, {
 	map_res(many1(re_find(re(r"[a-zA-Z0-9_]"))),
 			|tracks| { ok(tracks.iter().map(|t| t.to_string()).collect()) })
@@ -300,17 +342,12 @@ parser![let_expr, Box<Expr>, {
 			),
 			si!(expr()),
 		))),
-		// <id> = <def>; <body> は (<id> => <body>)(<def>) の糖衣構文
-		|((id, def, body), loc)| { ok(Box::new(Expr::new(ExprBody::FunctionCall {
-			function: Box::new(Expr::new(ExprBody::LambdaFunction {
-				params: vec![FunctionParam { name: id.to_string(), default: None }],
-				body,
-			}, loc.clone())),
-			args: Args {
-				unnamed: vec![def],
-				named: vec![],
-			}
-}		, loc))) }
+		// <def> の中からは <id> 自身を参照できる（再帰関数を定義するため）
+		|((id, def, body), loc)| { ok(Box::new(Expr::new(ExprBody::Let {
+			name: id.to_string(),
+			def,
+			body,
+		}, loc))) }
 	)
 }];
 
@@ -319,15 +356,33 @@ parser![lambda_node_expr, Box<Expr>, {
 		loc(preceded(
 			ss!(char('=')),
 			tuple((
+				ss!(identifier()),
+				// 名前つき引数（省略時の値を書ける）。{ name: value } の形で渡す
 				terminated(
-					ss!(identifier()),
+					opt(delimited(
+						ss!(char('(')),
+						separated_list0(ss!(char(',')), tuple((
+							ss!(identifier()),
+							opt(
+								preceded(
+									ss!(char('=')),
+									ss!(expr()),
+								)
+							)
+						))),
+						ss!(char(')')),
+					)),
 					ss!(tag("=>")),
 				),
 				si!(expr()),
 			)),
 		)),
-		|((input_param, body), loc)| { ok(Box::new(Expr::new(ExprBody::LambdaNode {
+		|((input_param, params, body), loc)| { ok(Box::new(Expr::new(ExprBody::LambdaNode {
 			input_param: input_param.to_string(),
+			params: params.unwrap_or_default().into_iter().map(|(name, default)| FunctionParam {
+				name: name.to_string(),
+				default,
+			}).collect(),
 			body,
 		}, loc))) },
 	)
@@ -354,6 +409,7 @@ parser![parenthesized_expr, Box<Expr>, {
 
 parser![primary_expr, Box<Expr>, {
 	alt((
+		unit_literal(), // 数値部分を float_literal に取られないよう先に試す
 		float_literal(),
 		track_set_literal(),
 		identifier_literal(),
@@ -369,6 +425,7 @@ parser![primary_expr, Box<Expr>, {
 		lambda_node_expr(), // キーワード node を処理するため identifier_expr よりも先に試す
 		do_expr(),
 		let_expr(),
+		note_literal(), // 音名の先頭を識別子に取られないよう identifier_expr よりも先に試す
 		identifier_expr(),
 		parenthesized_expr(),
 	))
@@ -491,10 +548,14 @@ parser![prefix_expr, Box<Expr>, {
 			let mut result = rhs;
 			for p in prefixes.into_iter().rev() {
 				let loc = p.1;
-				result = Box::new(match p.0 {
-					Prefix::Negate => Expr::new(ExprBody::Negate { arg: result }, loc),
-					Prefix::Plus => Expr::new(ExprBody::Plus { arg: result }, loc),
-					Prefix::Not => Expr::new(ExprBody::Not { arg: result }, loc),
+				let arg = *result;
+				result = Box::new(match (p.0, arg.body) {
+					// 単位つきの数値の符号は換算前の数値につける（-6dB は振幅の比 0.5 程度、-(6dB) ではない）
+					(Prefix::Negate, ExprBody::UnitLiteral { value, unit }) => Expr::new(ExprBody::UnitLiteral { value: - value, unit }, loc),
+					(Prefix::Plus, body @ ExprBody::UnitLiteral { .. }) => Expr::new(body, loc),
+					(Prefix::Negate, body) => Expr::new(ExprBody::Negate { arg: Box::new(Expr::new(body, arg.loc)) }, loc),
+					(Prefix::Plus, body) => Expr::new(ExprBody::Plus { arg: Box::new(Expr::new(body, arg.loc)) }, loc),
+					(Prefix::Not, body) => Expr::new(ExprBody::Not { arg: Box::new(Expr::new(body, arg.loc)) }, loc),
 				})
 			}
 
@@ -768,13 +829,14 @@ parser![mml_statement, Statement, {
 			tuple((
 				si!(track_set()),
 				terminated(
-					si!(re_find(re(r"[^\r\n]*"))),
+					si!(loc(re_find(re(r"[^\r\n]*")))),
 					statement_ending(),
 				),
 			)),
-			|(tracks, mml)| ok(Statement::Mml {
+			|(tracks, (mml, mml_loc))| ok(Statement::Mml {
 				tracks,
 				mml: mml.to_string() + "\n", // 改行は行コメントの終端に必要
+				mml_loc,
 			}))
 }];
 parser![statement, (Statement, Location), {
@@ -799,30 +861,30 @@ pub_parser![compilation_unit, CompilationUnit, {
 #[test]
 fn test_directive_statement() {
 	// TODO クソ書きづらい
-	// if let (_, Statement::Directive{name, args}) = directive_statement()("@tempo 120\n").unwrap() {
+	// if let (_, Statement::Directive{name, args}) = directive_statement()(test_span("@tempo 120\n")).unwrap() {
 	// 	assert_eq!(name, "tempo".to_string());
 	// } else {
 	// 	assert!(false);
 	// }
-	assert!(directive_statement()("@tempo").is_ok());
-	assert!(directive_statement()("@tempo\n").is_ok());
-	assert!(directive_statement()("@tempo 120\n").is_ok());
-	assert!(directive_statement()("@tempo 120,240\n").is_ok());
-	assert!(directive_statement()("@ tempo\t120 , 240   \n").is_ok());
-	assert!(directive_statement()("@tempo 120, (240)\n").is_ok());
-	assert!(directive_statement()("@tempo 2 | 3 | 4\n").is_ok());
-	assert!(directive_statement()("@tempo 2 + 3 - 4\n").is_ok());
-
-	assert!(directive_statement()("@tempo,120\n").is_err());
-	assert!(directive_statement()("@tempo 120 240\n").is_err());
+	assert!(directive_statement()(test_span("@tempo")).is_ok());
+	assert!(directive_statement()(test_span("@tempo\n")).is_ok());
+	assert!(directive_statement()(test_span("@tempo 120\n")).is_ok());
+	assert!(directive_statement()(test_span("@tempo 120,240\n")).is_ok());
+	assert!(directive_statement()(test_span("@ tempo\t120 , 240   \n")).is_ok());
+	assert!(directive_statement()(test_span("@tempo 120, (240)\n")).is_ok());
+	assert!(directive_statement()(test_span("@tempo 2 | 3 | 4\n")).is_ok());
+	assert!(directive_statement()(test_span("@tempo 2 + 3 - 4\n")).is_ok());
+
+	assert!(directive_statement()(test_span("@tempo,120\n")).is_err());
+	assert!(directive_statement()(test_span("@tempo 120 240\n")).is_err());
 }
 // TODO ちゃんとテストする
 #[cfg(test)]
 #[test]
 fn test_mml_statement() {
-	assert!(mml_statement()("abc o4l8v15 cde").is_ok());
-	assert!(mml_statement()("abc").is_ok());
-	assert!(mml_statement()("abc cde\r\n").is_ok());
+	assert!(mml_statement()(test_span("abc o4l8v15 cde")).is_ok());
+	assert!(mml_statement()(test_span("abc")).is_ok());
+	assert!(mml_statement()(test_span("abc cde\r\n")).is_ok());
 }
 
 // TODO ちゃんとテストする
@@ -839,7 +901,7 @@ fn test_compilation_unit() {
 abc o4l8v15 cde
 
 ";
-	assert!(compilation_unit()(moddl).is_ok());
+	assert!(compilation_unit()(test_span(moddl)).is_ok());
 	
 }
 
@@ -848,6 +910,6 @@ abc o4l8v15 cde
 #[test]
 fn test_args() {
 	let moddl = r"foo: 42, bar: a";
-	let result = args()(moddl);
+	let result = args()(test_span(moddl));
 	assert!(result.is_ok());
 }